@@ -0,0 +1,238 @@
+#[macro_use]
+extern crate serde;
+use candid::{Decode, Encode, Principal};
+use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
+use ic_stable_structures::{BoundedStorable, Cell, DefaultMemoryImpl, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+// Define memory and storage types
+type Memory = VirtualMemory<DefaultMemoryImpl>;
+
+// Health record moved out of the main canister. The record itself is kept as the
+// Candid bytes the main canister sent, so the archive does not depend on its schema.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct ArchivedRecord {
+    mother_id: u64,
+    id: u64,
+    data: Vec<u8>,
+}
+
+// Canister allowed to write to and read from the archive
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct ArchiveOwner {
+    owner: Option<Principal>,
+}
+
+// Archive key: ordered by mother, then by record id
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct RecordKey {
+    mother_id: u64,
+    id: u64,
+}
+
+impl Storable for RecordKey {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        // Big-endian, so byte order matches key order
+        let mut bytes = self.mother_id.to_be_bytes().to_vec();
+        bytes.extend_from_slice(&self.id.to_be_bytes());
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        let (mother_id, id) = bytes.split_at(8);
+        RecordKey {
+            mother_id: u64::from_be_bytes(mother_id.try_into().unwrap()),
+            id: u64::from_be_bytes(id.try_into().unwrap()),
+        }
+    }
+}
+
+impl BoundedStorable for RecordKey {
+    const MAX_SIZE: u32 = 16;
+    const IS_FIXED_SIZE: bool = true;
+}
+
+// Implement Storable for ArchivedRecord
+impl Storable for ArchivedRecord {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+// Implement BoundedStorable for ArchivedRecord
+impl BoundedStorable for ArchivedRecord {
+    const MAX_SIZE: u32 = MAX_RECORD_BYTES as u32 + 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Record in the map created when records were limited to 2048 bytes. A stable map's
+// value bound cannot grow, so it is only read to migrate it.
+struct LegacyRecord(ArchivedRecord);
+
+impl Storable for LegacyRecord {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        self.0.to_bytes()
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        LegacyRecord(ArchivedRecord::from_bytes(bytes))
+    }
+}
+
+impl BoundedStorable for LegacyRecord {
+    const MAX_SIZE: u32 = 2304;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Matches the health record bound in the main canister
+const MAX_RECORD_BYTES: usize = 16 * 1024;
+
+// Implement Storable for ArchiveOwner
+impl Storable for ArchiveOwner {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+thread_local! {
+    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> = RefCell::new(
+        MemoryManager::init(DefaultMemoryImpl::default())
+    );
+
+    static OWNER: RefCell<Cell<ArchiveOwner, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(0))), ArchiveOwner::default())
+            .expect("Cannot create archive owner")
+    );
+
+    // Emptied on upgrade
+    static LEGACY_RECORDS: RefCell<StableBTreeMap<RecordKey, LegacyRecord, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(1))))
+    );
+
+    static RECORDS: RefCell<StableBTreeMap<RecordKey, ArchivedRecord, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(2))))
+    );
+}
+
+// Error handling
+#[derive(candid::CandidType, Deserialize, Serialize)]
+enum Error {
+    AuthorizationError { msg: String },
+    InvalidInput { msg: String },
+}
+
+// The owning mama-pack canister is fixed at install time
+#[ic_cdk::init]
+fn init(owner: Principal) {
+    OWNER.with(|cell| {
+        cell.borrow_mut()
+            .set(ArchiveOwner { owner: Some(owner) })
+            .expect("Cannot store archive owner")
+    });
+}
+
+// Move records out of the map with the old 2048-byte bound
+#[ic_cdk::post_upgrade]
+fn post_upgrade() {
+    let legacy: Vec<(RecordKey, LegacyRecord)> =
+        LEGACY_RECORDS.with(|storage| storage.borrow().iter().collect());
+    for (key, record) in legacy {
+        RECORDS.with(|storage| storage.borrow_mut().insert(key, record.0));
+        LEGACY_RECORDS.with(|storage| storage.borrow_mut().remove(&key));
+    }
+}
+
+// Only the owning canister may read or write archived records
+fn ensure_owner() -> Result<(), Error> {
+    let owner = OWNER.with(|cell| cell.borrow().get().owner);
+    if owner == Some(ic_cdk::caller()) {
+        Ok(())
+    } else {
+        Err(Error::AuthorizationError {
+            msg: "Only the owning canister can access the archive".to_string(),
+        })
+    }
+}
+
+// Store records moved out of the owning canister. Re-sending a record overwrites it,
+// so a batch can be retried safely.
+#[ic_cdk::update]
+fn archive_records(records: Vec<ArchivedRecord>) -> Result<u64, Error> {
+    ensure_owner()?;
+
+    if let Some(record) = records.iter().find(|r| r.data.len() > MAX_RECORD_BYTES) {
+        return Err(Error::InvalidInput {
+            msg: format!("Record id={} is too large to archive", record.id),
+        });
+    }
+
+    RECORDS.with(|storage| {
+        let mut storage = storage.borrow_mut();
+        for record in &records {
+            let key = RecordKey {
+                mother_id: record.mother_id,
+                id: record.id,
+            };
+            storage.insert(key, record.clone());
+        }
+    });
+    Ok(records.len() as u64)
+}
+
+// Get all archived records for a mother, oldest first
+#[ic_cdk::query]
+fn get_archived_records(mother_id: u64) -> Result<Vec<ArchivedRecord>, Error> {
+    ensure_owner()?;
+
+    Ok(RECORDS.with(|storage| {
+        storage
+            .borrow()
+            .range(RecordKey { mother_id, id: 0 }..=RecordKey { mother_id, id: u64::MAX })
+            .map(|(_, record)| record)
+            .collect()
+    }))
+}
+
+// Delete every archived record of a mother, for erasure in the owning canister. Returns how
+// many were removed; erasing a mother with no records is not an error.
+#[ic_cdk::update]
+fn erase_mother(mother_id: u64) -> Result<u64, Error> {
+    ensure_owner()?;
+
+    let range = RecordKey { mother_id, id: 0 }..=RecordKey { mother_id, id: u64::MAX };
+    let keys: Vec<RecordKey> =
+        RECORDS.with(|storage| storage.borrow().range(range.clone()).map(|(key, _)| key).collect());
+    RECORDS.with(|storage| {
+        let mut storage = storage.borrow_mut();
+        for key in &keys {
+            storage.remove(key);
+        }
+    });
+    // Records still waiting in the pre-upgrade map would otherwise come back on the next upgrade
+    let legacy: Vec<RecordKey> =
+        LEGACY_RECORDS.with(|storage| storage.borrow().range(range).map(|(key, _)| key).collect());
+    LEGACY_RECORDS.with(|storage| {
+        let mut storage = storage.borrow_mut();
+        for key in &legacy {
+            storage.remove(key);
+        }
+    });
+    Ok((keys.len() + legacy.len()) as u64)
+}
+
+// Number of archived records
+#[ic_cdk::query]
+fn get_archive_size() -> u64 {
+    RECORDS.with(|storage| storage.borrow().len())
+}
+
+// Export Candid interface
+ic_cdk::export_candid!();
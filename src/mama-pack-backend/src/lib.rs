@@ -1,17 +1,21 @@
 #[macro_use]
 extern crate serde;
-use candid::{Decode, Encode};
-use ic_cdk::api::time;
+use candid::{Decode, Encode, Nat, Principal};
+use ic_cdk::api::call::RejectionCode;
 use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
-use ic_stable_structures::{BoundedStorable, Cell, DefaultMemoryImpl, StableBTreeMap, Storable};
+use ic_stable_structures::{BoundedStorable, Cell, DefaultMemoryImpl, StableBTreeMap, StableLog, Storable};
+use chacha20poly1305::aead::{Aead, AeadInPlace, KeyInit, Payload};
+use chacha20poly1305::{Tag, XChaCha20Poly1305, XNonce};
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
 use std::{borrow::Cow, cell::RefCell};
 
 // Define memory and storage types
-type Memory = VirtualMemory<DefaultMemoryImpl>;
+type Memory = VirtualMemory<GuardedMemory>;
 type IdCell = Cell<u64, Memory>;
 
 // Pregnancy Stage enum for tracking progress
-#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Debug, PartialEq)]
 enum PregnancyStage {
     FirstTrimester,
     SecondTrimester,
@@ -26,7 +30,7 @@ impl Default for PregnancyStage {
 }
 
 // Health Status enum
-#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Debug)]
 enum HealthStatus {
     Normal,
     NeedsAttention,
@@ -39,6 +43,21 @@ impl Default for HealthStatus {
     }
 } 
 
+// Insurance schemes a mother can be enrolled in
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq)]
+enum InsuranceScheme {
+    Nhif,
+    Private,
+    Other,
+}
+
+// Insurance cover held by the mother
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct InsuranceCover {
+    scheme: InsuranceScheme,
+    member_number: String,
+}
+
 // Mother's profile with essential health information
 #[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
 struct MotherProfile {
@@ -53,6 +72,49 @@ struct MotherProfile {
     last_checkup: u64,
     medical_history: Vec<String>,
     emergency_contact: String,
+    insurance: Option<InsuranceCover>,
+    facility_code: Option<String>,
+    delivery: Option<DeliveryRecord>,
+    origin: Option<BundleOrigin>,
+    // Incremented on every write; updates must quote the version they were based on
+    version: Option<u64>,
+    updated_at: Option<u64>,
+    // Stable external identifier, unique across canisters
+    ulid: Option<String>,
+    // Caesarean births before this pregnancy, from her obstetric history
+    previous_cesareans: Option<u8>,
+    national_id: Option<String>,
+}
+
+// Where an imported mother's history came from
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq)]
+struct BundleOrigin {
+    source_canister: Principal,
+    source_mother_id: u64,
+}
+
+// How the baby was delivered
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Debug)]
+enum DeliveryMode {
+    Vaginal,
+    AssistedVaginal,
+    Cesarean,
+}
+
+// Outcome of the birth
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Debug)]
+enum BirthOutcome {
+    LiveBirth,
+    StillBirth,
+}
+
+// Delivery details recorded when the pregnancy ends
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct DeliveryRecord {
+    delivery_date: u64,
+    facility_code: Option<String>,
+    mode: DeliveryMode,
+    outcome: BirthOutcome,
 }
 
 // Health Record for tracking checkups and vitals
@@ -67,373 +129,19313 @@ struct HealthRecord {
     notes: String,
     next_appointment: u64,
     health_status: HealthStatus,
+    insurance_eligible: Option<bool>,
+    facility_code: Option<String>,
+    // One entry per symptom, None where the registry had no code
+    symptom_codes: Option<Vec<Option<ClinicalCode>>>,
+    version: Option<u64>,
+    // `date` is when the visit took place; these track the stored record itself
+    created_at: Option<u64>,
+    updated_at: Option<u64>,
+    ulid: Option<String>,
+    signature: Option<RecordSignature>,
+    fundal_height_cm: Option<f32>,
+    fetal_heart_rate: Option<u16>,
+    // Fundal height that disagrees with gestational age
+    fundal_height_flag: Option<FundalHeightFlag>,
+    urine_protein: Option<Dipstick>,
+    urine_glucose: Option<Dipstick>,
+    // Raised blood pressure with proteinuria at this visit
+    preeclampsia_suspected: Option<bool>,
+    edema: Option<EdemaGrade>,
+    // Mid-upper arm circumference and the acute malnutrition it indicates
+    muac_cm: Option<f32>,
+    nutrition_status: Option<NutritionStatus>,
+    // Structured note: the template used, its sections and the checklist items ticked. `notes`
+    // then holds the sections as plain text.
+    note_template: Option<String>,
+    note_sections: Option<Vec<NoteSection>>,
+    checklist_done: Option<Vec<String>>,
+    // Next visit suggested by the ANC schedule and the mother's risk; `next_appointment` takes it
+    // when the visit did not set one
+    recommended_next_appointment: Option<u64>,
 }
 
-// Payload for creating/updating mother's profile
-#[derive(candid::CandidType, Serialize, Deserialize)]
-struct MotherProfilePayload {
-    name: String,
-    age: u8,
-    blood_type: String,
-    expected_delivery_date: u64,
-    medical_history: Vec<String>,
-    emergency_contact: String,
+// Acute malnutrition in a pregnant or lactating mother, classified by MUAC
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, Debug, PartialEq)]
+enum NutritionStatus {
+    Normal,
+    // MUAC 21 to under 23 cm
+    ModerateAcute,
+    // MUAC under 21 cm
+    SevereAcute,
 }
 
-// Payload for health record entry
+// A mother whose latest MUAC shows acute malnutrition
 #[derive(candid::CandidType, Serialize, Deserialize)]
-struct HealthRecordPayload {
+struct MalnourishedMother {
     mother_id: u64,
-    blood_pressure: String,
-    weight: f32,
-    symptoms: Vec<String>,
-    notes: String,
-    next_appointment: u64,
+    name: String,
+    facility_code: Option<String>,
+    muac_cm: f32,
+    nutrition_status: NutritionStatus,
+    measured_at: u64,
 }
 
-// Implement Storable for MotherProfile
-impl Storable for MotherProfile {
-    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
-        Cow::Owned(Encode!(self).unwrap())
-    }
+// Pitting edema: none, +, ++ or +++
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, Debug, PartialEq, PartialOrd)]
+enum EdemaGrade {
+    Absent,
+    OnePlus,
+    TwoPlus,
+    ThreePlus,
+}
 
-    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
-        Decode!(bytes.as_ref(), Self).unwrap()
-    }
+// Urine dipstick reading
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, Debug, PartialEq, PartialOrd)]
+enum Dipstick {
+    Negative,
+    Trace,
+    OnePlus,
+    TwoPlus,
+    ThreePlus,
 }
 
-// Implement BoundedStorable for MotherProfile
-impl BoundedStorable for MotherProfile {
-    const MAX_SIZE: u32 = 2048;
-    const IS_FIXED_SIZE: bool = false;
+// Fundal height more than 3 cm from the gestational age in weeks (assessed from 20 to 40 weeks)
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, Debug)]
+enum FundalHeightFlag {
+    // Suggests fetal growth restriction
+    SmallForDates,
+    // Suggests polyhydramnios, macrosomia or multiple pregnancy
+    LargeForDates,
 }
 
-// Implement Storable for HealthRecord
-impl Storable for HealthRecord {
-    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
-        Cow::Owned(Encode!(self).unwrap())
-    }
+// Clinician sign-off on a health record
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct RecordSignature {
+    clinician: Principal,
+    signed_at: u64,
+    // SHA-256 of the record's clinical content when it was signed
+    content_hash: Vec<u8>,
+}
 
-    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
-        Decode!(bytes.as_ref(), Self).unwrap()
-    }
+// Why an addendum was filed
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, Debug, PartialEq)]
+enum AddendumKind {
+    // Replaces something the record got wrong
+    Correction,
+    Clarification,
+    // Information from the visit that was not recorded at the time
+    LateEntry,
 }
 
-// Implement BoundedStorable for HealthRecord
-impl BoundedStorable for HealthRecord {
-    const MAX_SIZE: u32 = 2048;
-    const IS_FIXED_SIZE: bool = false;
+// Note filed against a health record; the record itself is never edited once signed
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct Addendum {
+    id: u64,
+    record_id: u64,
+    mother_id: u64,
+    kind: AddendumKind,
+    text: String,
+    author: Principal,
+    created_at: u64,
 }
 
-// Thread local storage
-thread_local! {
-    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> = RefCell::new(
-        MemoryManager::init(DefaultMemoryImpl::default())
-    );
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct AddendumPayload {
+    kind: AddendumKind,
+    text: String,
+}
 
-    static ID_COUNTER: RefCell<IdCell> = RefCell::new(
-        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(0))), 0)
-            .expect("Cannot create id counter")
-    );
+// Terminologies supported by the code registry
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Debug)]
+enum CodeSystem {
+    Loinc,
+    Snomed,
+}
 
-    static PROFILE_STORAGE: RefCell<StableBTreeMap<u64, MotherProfile, Memory>> = RefCell::new(
-        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(1))))
-    );
+// Standard clinical code attached to an observation
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct ClinicalCode {
+    system: CodeSystem,
+    code: String,
+    display: String,
+}
 
-    static HEALTH_RECORD_STORAGE: RefCell<StableBTreeMap<u64, HealthRecord, Memory>> = RefCell::new(
-        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(2))))
-    );
+// Laboratory test result
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct LabResult {
+    id: u64,
+    mother_id: u64,
+    test_name: String,
+    value: String,
+    unit: Option<String>,
+    date: u64,
+    code: Option<ClinicalCode>,
+    version: Option<u64>,
+    created_at: Option<u64>,
+    updated_at: Option<u64>,
+    ulid: Option<String>,
 }
 
-// Error handling
-#[derive(candid::CandidType, Deserialize, Serialize)]
-enum Error {
-    NotFound { msg: String },
-    InvalidInput { msg: String },
-    SystemError { msg: String },
-    AuthorizationError { msg: String },
-    ValidationError { msg: String },
+// Symptoms a mother reported herself between visits, with the triage outcome
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct SelfReport {
+    id: u64,
+    mother_id: u64,
+    symptoms: Vec<String>,
+    note: String,
+    triage: HealthStatus,
+    // Reported symptoms matching a danger sign
+    danger_signs: Vec<String>,
+    // Alert raised for her community health worker, if any
+    alert_id: Option<u64>,
+    reported_at: u64,
+    ulid: Option<String>,
 }
 
-// Helper function to determine pregnancy stage based on EDD
-fn calculate_pregnancy_stage(edd: u64) -> PregnancyStage {
-    let now = time();
-    let time_diff = if edd > now {
-        edd - now 
-    } else {
-        0
-    };
-    
-    let weeks_to_edd = time_diff / (7 * 24 * 60 * 60 * 1_000_000_000);
-    
-    match weeks_to_edd {
-        0 => PregnancyStage::PostPartum,
-        1..=13 => PregnancyStage::ThirdTrimester,
-        14..=27 => PregnancyStage::SecondTrimester,
-        _ => PregnancyStage::FirstTrimester,
-    }
+// Work item for a community health worker; unassigned alerts go to controllers and admins
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct ChwAlert {
+    id: u64,
+    mother_id: u64,
+    chw: Option<Principal>,
+    reason: String,
+    self_report_id: Option<u64>,
+    created_at: u64,
+    acknowledged_at: Option<u64>,
+    // Latest status of an ambulance dispatched for this alert; details in get_alert_dispatch
+    dispatch_status: Option<DispatchStatus>,
 }
-//Helper functions for code maintanability and reusability
 
-//Generate Unique ID
-fn generate_new_id() -> Result<u64, Error> {
-    ID_COUNTER.with(|counter| {
-        let current_value = *counter.borrow().get();
-        counter
-            .borrow_mut()
-            .set(current_value + 1)
-            .map_err(|_| Error::SystemError { msg: "Failed to increment ID counter".to_string() })
-    })
+// Dispatch API that sends ambulances to obstetric emergencies (controllers and admins)
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct DispatchApiConfig {
+    // https:// address dispatch requests are posted to
+    url: String,
+    // Sent as a bearer token; never returned once set
+    api_key: String,
+    // Secret path segment status updates must carry
+    callback_token: String,
+    enabled: bool,
 }
-//END OF Helper Functions 
 
-// Create new mother profile
-#[ic_cdk::update]
-fn create_mother_profile(payload: MotherProfilePayload) -> Result<MotherProfile, Error> {
-    // Validate the payload first
-    validate_mother_profile(&payload)?;
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct DispatchApiView {
+    url: String,
+    api_key_hint: String,
+    // Where the dispatch service should post status updates
+    callback_url: String,
+    enabled: bool,
+}
 
-    let id = generate_new_id()?;
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct DispatchSettings {
+    api: Option<DispatchApiConfig>,
+}
 
-    let stage = calculate_pregnancy_stage(payload.expected_delivery_date);
-    
-    let profile = MotherProfile {
-        id,
-        name: payload.name,
-        age: payload.age,
-        blood_type: payload.blood_type,
-        expected_delivery_date: payload.expected_delivery_date,
-        stage,
-        health_status: HealthStatus::Normal,
-        created_at: time(),
-        last_checkup: time(),
-        medical_history: payload.medical_history,
-        emergency_contact: payload.emergency_contact,
-    };
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+enum DispatchStatus {
+    // Posted to the dispatch API, waiting for its reply
+    Requesting,
+    // Accepted by the dispatch service
+    Requested,
+    Assigned,
+    EnRoute,
+    Arrived,
+    Completed,
+    Cancelled,
+    Failed,
+}
 
-    PROFILE_STORAGE.with(|storage| storage.borrow_mut().insert(id, profile.clone()));
-    Ok(profile)
+// What the health worker confirming the emergency sends
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct DispatchRequest {
+    location: String,
+    condition: String,
+    destination_facility: String,
 }
 
-// Add health record
-#[ic_cdk::update]
-fn add_health_record(payload: HealthRecordPayload) -> Result<HealthRecord, Error> {
-    // Verify mother exists
-    PROFILE_STORAGE.with(|storage| {
-        if !storage.borrow().contains_key(&payload.mother_id) {
-            return Err(Error::NotFound {
-                msg: format!("Mother with id={} not found", payload.mother_id),
-            });
-        }
-        Ok(())
-    })?;
+// Ambulance dispatch for a confirmed obstetric emergency, one per alert
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct Dispatch {
+    alert_id: u64,
+    mother_id: u64,
+    location: String,
+    condition: String,
+    destination_facility: String,
+    // Sent to the dispatch service, which echoes it in status updates
+    reference: String,
+    // The dispatch service's own id for the job
+    external_id: Option<String>,
+    status: DispatchStatus,
+    attempts: u8,
+    error: Option<String>,
+    requested_by: Principal,
+    requested_at: u64,
+    updated_at: u64,
+}
 
-    let id = generate_new_id()?;
+// A mother's wellness journal for one day; mood runs from 1 (very low) to 5 (very good)
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct WellnessEntry {
+    // Start of the day (UTC)
+    date: u64,
+    mood: u8,
+    sleep_hours: f64,
+    note: Option<String>,
+}
 
-    // Determine health status based on symptoms and vitals
-    let health_status = analyze_health_status(&payload);
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct WellnessTimeline {
+    entries: Vec<WellnessEntry>,
+    // Low mood on each of the latest logged days; see LOW_MOOD_RUN
+    sustained_low_mood: bool,
+}
 
-    let record = HealthRecord {
-    id,
-    mother_id: payload.mother_id,
-    date: time(),
-    blood_pressure: payload.blood_pressure,
-    weight: payload.weight,
-    symptoms: payload.symptoms,
-    notes: payload.notes,
-    next_appointment: payload.next_appointment,
-    health_status: health_status.clone(), // Add .clone() here
-    };
+// Fetal movements counted by the mother in one sitting
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct KickCountSession {
+    id: u64,
+    mother_id: u64,
+    started_at: u64,
+    movements: u32,
+    // Set when the mother finishes, or when a movement arrives after the counting window
+    finished_at: Option<u64>,
+    // Fewer than KICK_COUNT_TARGET movements in the window in the third trimester
+    flagged: bool,
+    alert_id: Option<u64>,
+}
 
-    // Update mother's profile with latest checkup and health status
-    update_mother_status(payload.mother_id, &health_status)?;
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct Contraction {
+    started_at: u64,
+    duration_seconds: u32,
+}
 
-    HEALTH_RECORD_STORAGE.with(|storage| storage.borrow_mut().insert(id, record.clone()));
-    Ok(record)
+// Contraction pattern over the last hour
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct ContractionSummary {
+    contractions: Vec<Contraction>,
+    // Mean time between the starts of consecutive contractions
+    average_interval_seconds: Option<u64>,
+    average_duration_seconds: Option<u32>,
+    // At least 4 in 20 minutes or 8 in an hour
+    regular: bool,
+    gestation_weeks: u64,
+    // Regular before 37 weeks
+    preterm_labor: bool,
 }
 
-// Helper function to analyze health status based on symptoms and vitals
-fn analyze_health_status(record: &HealthRecordPayload) -> HealthStatus {
-    // Parse blood pressure
-    let bp_parts: Vec<&str> = record.blood_pressure.split('/').collect();
-    if bp_parts.len() == 2 {
-        if let (Ok(systolic), Ok(diastolic)) = (
-            bp_parts[0].trim().parse::<i32>(),
-            bp_parts[1].trim().parse::<i32>()
-        ) {
-            // Check for concerning blood pressure
-            if systolic >= 140 || diastolic >= 90 || systolic < 90 || diastolic < 60 {
-                return HealthStatus::Critical;
-            }
-        }
-    }
+// Medication or supplement a mother takes on a daily schedule, e.g. iron and folic acid
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct Prescription {
+    id: u64,
+    mother_id: u64,
+    medication: String,
+    dose: String,
+    times_per_day: u8,
+    // Day numbers (days since the epoch, UTC); no end means until stopped
+    start_day: u64,
+    end_day: Option<u64>,
+    prescribed_by: Principal,
+    created_at: u64,
+    // Codes of the warnings the prescriber acknowledged
+    acknowledged_warnings: Option<Vec<String>>,
+}
 
-    // Check weight changes
-    if record.weight < 45.0 || record.weight > 100.0 {
-        return HealthStatus::NeedsAttention;
-    }
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct PrescriptionPayload {
+    mother_id: u64,
+    medication: String,
+    dose: String,
+    times_per_day: u8,
+    // Defaults to today
+    start_date: Option<u64>,
+    duration_days: Option<u32>,
+    // Codes of the warnings returned by check_prescription that the prescriber has seen
+    acknowledged_warnings: Option<Vec<String>>,
+}
 
-    // Check symptoms
-    let critical_symptoms = [
-        "severe", "emergency", "critical", "bleeding",
-        "seizure", "unconscious", "fever", "headache"
-    ];
-    
-    let concerning_symptoms = [
-        "nausea", "vomiting", "swelling", "pain",
-        "discomfort", "fatigue", "dizziness"
-    ];
+// What is known about a drug that makes it unsafe for some mothers
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct DrugRule {
+    // Matched against the prescribed medication, which may carry a strength or form
+    drug: String,
+    // Class checked against allergies too, e.g. "penicillin" for amoxicillin
+    drug_class: Option<String>,
+    // Trimesters (1-3) in which the drug should be avoided
+    avoid_in_trimesters: Vec<u8>,
+    // Conditions in the medical history that rule the drug out
+    contraindicated_conditions: Vec<String>,
+    // Drugs or drug classes it interacts with
+    interacts_with: Vec<String>,
+    reason: String,
+}
 
-    if record.symptoms.iter().any(|s| 
-        critical_symptoms.iter().any(|cs| s.to_lowercase().contains(cs))
-    ) {
-        HealthStatus::Critical
-    } else if record.symptoms.iter().any(|s|
-        concerning_symptoms.iter().any(|cs| s.to_lowercase().contains(cs))
-    ) {
-        HealthStatus::NeedsAttention
-    } else {
-        HealthStatus::Normal
-    }
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, Debug)]
+enum WarningKind {
+    Allergy,
+    Condition,
+    Pregnancy,
+    Interaction,
 }
 
-// Update mother's status based on health record
-fn update_mother_status(mother_id: u64, health_status: &HealthStatus) -> Result<(), Error> {
-    PROFILE_STORAGE.with(|storage| {
-        let mut storage = storage.borrow_mut();
-        match storage.get(&mother_id) {
-            Some(mut profile) => {
-                profile.health_status = health_status.clone();
-                profile.last_checkup = time();
-                storage.insert(mother_id, profile);
-                Ok(())
-            }
-            None => Err(Error::NotFound {
-                msg: format!("Mother with id={} not found", mother_id),
-            }),
-        }
-    })
+// Reason a prescription may be unsafe, which the prescriber must acknowledge by code
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct PrescriptionWarning {
+    kind: WarningKind,
+    // Stable for the same finding, e.g. "pregnancy:warfarin"
+    code: String,
+    detail: String,
 }
 
-// Get mother's profile
-#[ic_cdk::query]
-fn get_mother_profile(id: u64) -> Result<MotherProfile, Error> {
-    PROFILE_STORAGE.with(|storage| {
-        match storage.borrow().get(&id) {
-            Some(profile) => Ok(profile),
-            None => Err(Error::NotFound {
-                msg: format!("Mother with id={} not found", id),
-            }),
-        }
-    })
+// One scheduled dose on a given day
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct DoseReminder {
+    prescription_id: u64,
+    medication: String,
+    dose: String,
+    dose_index: u8,
+    due_at: u64,
+    taken_at: Option<u64>,
 }
 
-// Get mother's health records
-#[ic_cdk::query]
-fn get_mother_health_records(mother_id: u64) -> Result<Vec<HealthRecord>, Error> {
-    let records = HEALTH_RECORD_STORAGE.with(|storage| {
-        storage
-            .borrow()
-            .iter()
-            .filter(|(_, record)| record.mother_id == mother_id)
-            .map(|(_, record)| record.clone())
-            .collect::<Vec<HealthRecord>>()
-    });
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct Adherence {
+    prescription_id: u64,
+    medication: String,
+    // Doses scheduled up to and including today
+    doses_due: u64,
+    doses_taken: u64,
+    percent: u8,
+}
 
-    if records.is_empty() {
-        Err(Error::NotFound {
-            msg: format!("No health records found for mother_id={}", mother_id),
-        })
-    } else {
-        Ok(records)
-    }
+// Medication handed over at a facility pharmacy against a prescription
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct Dispensing {
+    id: u64,
+    prescription_id: u64,
+    mother_id: u64,
+    facility_code: String,
+    // Normalized, as named in the facility's stock
+    drug: String,
+    // Units handed over; one unit is one dose
+    quantity: u32,
+    batch: String,
+    dispensed_by: Principal,
+    dispensed_at: u64,
 }
 
-// Get high-risk profiles
-#[ic_cdk::query]
-fn get_high_risk_profiles() -> Vec<MotherProfile> {
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct DispensingPayload {
+    prescription_id: u64,
+    facility_code: String,
+    // Defaults to the prescribed medication
+    drug: Option<String>,
+    quantity: u32,
+    batch: String,
+}
+
+// Units of a drug held at a facility
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct StockLevel {
+    facility_code: String,
+    drug: String,
+    quantity: u64,
+    updated_by: Principal,
+    updated_at: u64,
+}
+
+// A started prescription with fewer units dispensed than prescribed
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct DispensingGap {
+    prescription_id: u64,
+    mother_id: u64,
+    // The mother's facility
+    facility_code: Option<String>,
+    medication: String,
+    // Doses in the whole course, or up to today while it has no end
+    prescribed: u64,
+    dispensed: u64,
+    last_dispensed_at: Option<u64>,
+}
+
+// Taken dose: prescription, day number, dose index
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct DoseKey {
+    prescription_id: u64,
+    day: u64,
+    dose_index: u8,
+}
+
+// Principals who may message a mother besides her assigned health worker
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct CareTeam {
+    members: Vec<AccessGrant>,
+}
+
+// What raised a clinician task
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+enum ClinicianTaskKind {
+    ReviewAbnormalLab,
+    // Critical for CRITICAL_FOLLOW_UP_HOURS with no visit resolving it
+    CriticalFollowUp,
+}
+
+// Work item for the clinicians on a mother's care team, raised by a clinical event. CHW field
+// work goes through ChwAlert instead.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct ClinicianTask {
+    id: u64,
+    mother_id: u64,
+    kind: ClinicianTaskKind,
+    lab_result_id: Option<u64>,
+    detail: String,
+    created_at: u64,
+    due_at: u64,
+    completed_at: Option<u64>,
+    completed_by: Option<Principal>,
+    note: Option<String>,
+}
+
+// How a teleconsultation is held
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+enum ConsultChannel {
+    Video,
+    Voice,
+    Chat,
+}
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+enum TeleConsultStatus {
+    Scheduled,
+    Completed,
+    // The mother did not join
+    Missed,
+    Cancelled,
+}
+
+// Where to join a teleconsultation, as issued by the video or voice platform
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct JoinLink {
+    // e.g. "jitsi" or "zoom"
+    provider: String,
+    url: String,
+    meeting_id: Option<String>,
+}
+
+// Remote follow-up with a clinician, kept apart from visits at a facility
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct TeleConsult {
+    id: u64,
+    mother_id: u64,
+    scheduled_at: u64,
+    channel: ConsultChannel,
+    join_link: Option<JoinLink>,
+    // Who scheduled it and is to hold it
+    clinician: Principal,
+    status: TeleConsultStatus,
+    created_at: u64,
+    // Set on completion, cancellation or when marked missed
+    closed_at: Option<u64>,
+    duration_minutes: Option<u32>,
+    outcome_notes: Option<String>,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct TeleConsultPayload {
+    mother_id: u64,
+    scheduled_at: u64,
+    channel: ConsultChannel,
+    join_link: Option<JoinLink>,
+}
+
+// How a teleconsultation went; a consultation the mother did not join is recorded as missed
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct TeleConsultOutcome {
+    attended: bool,
+    duration_minutes: Option<u32>,
+    notes: String,
+}
+
+// A mother's arrival at a facility, waiting to be seen
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct CheckIn {
+    id: u64,
+    mother_id: u64,
+    facility_code: String,
+    checked_in_at: u64,
+}
+
+// A waiting mother in triage order; `priority` is the sum of the four parts
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct TriageEntry {
+    check_in_id: u64,
+    mother_id: u64,
+    name: String,
+    health_status: HealthStatus,
+    risk_tier: String,
+    // Absent once delivered
+    gestational_age_weeks: Option<u64>,
+    checked_in_at: u64,
+    waiting_minutes: u64,
+    priority: u32,
+}
+
+// SMS providers the dispatcher can send through
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+enum SmsProvider {
+    AfricasTalking,
+    Twilio,
+}
+
+// SMS gateway settings (controllers and admins)
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct SmsGatewayConfig {
+    provider: SmsProvider,
+    // Africa's Talking username or Twilio account SID
+    account: String,
+    // Africa's Talking API key or Twilio auth token; never returned once set
+    api_key: String,
+    // Sender id or short code; Twilio's From number
+    sender_id: Option<String>,
+    // Relay that forwards one send per Idempotency-Key to the provider and answers repeats with
+    // the first reply. Required; messages are not sent to the provider's API directly.
+    base_url: Option<String>,
+    // Secret path segment delivery-status callbacks must carry
+    callback_token: String,
+    enabled: bool,
+}
+
+// The gateway settings as read back, with the secrets masked
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct SmsGatewayView {
+    provider: SmsProvider,
+    account: String,
+    api_key_hint: String,
+    sender_id: Option<String>,
+    base_url: Option<String>,
+    // Where the provider should post delivery reports
+    callback_url: String,
+    enabled: bool,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct SmsSettings {
+    gateway: Option<SmsGatewayConfig>,
+}
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+enum SmsKind {
+    AppointmentReminder,
+    Alert,
+}
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+enum SmsStatus {
+    Queued,
+    // Handed to the provider, waiting for its reply
+    Sending,
+    // Accepted by the provider
+    Sent,
+    Delivered,
+    Failed,
+}
+
+// A text message to a mother, queued once per idempotency key
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct SmsMessage {
+    id: u64,
+    mother_id: u64,
+    kind: SmsKind,
+    to: String,
+    body: String,
+    idempotency_key: String,
+    status: SmsStatus,
+    attempts: u8,
+    provider_message_id: Option<String>,
+    error: Option<String>,
+    created_at: u64,
+    updated_at: u64,
+}
+
+// A mother's USSD sign-in: her phone number and a hashed PIN, locked after repeated wrong PINs
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct UssdPin {
+    mother_id: u64,
+    pin_hash: String,
+    failed_attempts: u8,
+    locked_until: Option<u64>,
+    updated_at: u64,
+}
+
+// Notes an outgoing shift leaves for the incoming team at a facility
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct Handoff {
+    id: u64,
+    facility_code: String,
+    notes: String,
+    // Mothers needing attention; an erased mother is dropped from the list
+    flagged_mother_ids: Vec<u64>,
+    created_by: Principal,
+    created_at: u64,
+}
+
+// A flagged mother as the incoming team sees her, with her latest visit
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct FlaggedMother {
+    mother_id: u64,
+    name: String,
+    health_status: HealthStatus,
+    latest_record_id: Option<u64>,
+    latest_visit: Option<u64>,
+    next_appointment: Option<u64>,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct HandoffView {
+    handoff: Handoff,
+    // Flagged mothers who still exist, in the order flagged
+    flagged: Vec<FlaggedMother>,
+}
+
+// Message in a mother's thread with her care team; never edited or deleted
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct Message {
+    id: u64,
+    mother_id: u64,
+    sender: Principal,
+    // Sent by the mother herself rather than her care team
+    from_mother: bool,
+    body: String,
+    sent_at: u64,
+    // When the other side marked it read
+    read_at: Option<u64>,
+}
+
+// Danger signs the mother has been taught to act on
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct DangerSignChecklist {
+    vaginal_bleeding: bool,
+    severe_headache_or_blurred_vision: bool,
+    convulsions: bool,
+    fever: bool,
+    severe_abdominal_pain: bool,
+    reduced_fetal_movement: bool,
+    breaking_of_waters: bool,
+    swelling_of_face_or_hands: bool,
+}
+
+// Arrangements for the birth, made during the third trimester
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct BirthPlan {
+    mother_id: u64,
+    preferred_facility: String,
+    transport_arrangement: String,
+    birth_companion: String,
+    blood_donor: String,
+    danger_signs: DangerSignChecklist,
+    planned_delivery_mode: Option<DeliveryMode>,
+    // Every field filled in and every danger sign covered, and for a mother with a previous
+    // caesarean, the delivery mode planned
+    complete: bool,
+    version: Option<u64>,
+    updated_at: u64,
+    updated_by: Principal,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct BirthPlanPayload {
+    // 0 for a new plan
+    expected_version: u64,
+    preferred_facility: String,
+    transport_arrangement: String,
+    birth_companion: String,
+    blood_donor: String,
+    danger_signs: DangerSignChecklist,
+    planned_delivery_mode: Option<DeliveryMode>,
+}
+
+// A mother's rating of a visit; only facility-level aggregates are published
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct VisitFeedback {
+    record_id: u64,
+    facility_code: Option<String>,
+    // 1 (poor) to 5 (excellent)
+    waiting_time_rating: u8,
+    respectful_care_rating: u8,
+    comment: String,
+    submitted_at: u64,
+}
+
+// Feedback for one facility, with nothing that links it to a mother or visit
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct FacilityFeedback {
+    facility_code: String,
+    responses: u64,
+    average_waiting_time: f64,
+    average_respectful_care: f64,
+    // Free text, most recent first
+    comments: Vec<String>,
+}
+
+// Education article served to mothers by get_my_content
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct ContentArticle {
+    id: u64,
+    title: String,
+    body: String,
+    // Stage it applies to; None for every stage
+    stage: Option<PregnancyStage>,
+    // ISO 639-1 code, e.g. "en" or "sw"
+    language: String,
+    tags: Vec<String>,
+    // Shown only to mothers whose medical history mentions one of these; empty for everyone
+    conditions: Vec<String>,
+    created_at: u64,
+    updated_at: u64,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct ContentPayload {
+    title: String,
+    body: String,
+    stage: Option<PregnancyStage>,
+    language: String,
+    tags: Vec<String>,
+    conditions: Vec<String>,
+}
+
+// Stored journal day: mood, sleep in minutes and the note, packed into bytes
+struct WellnessDay {
+    mood: u8,
+    sleep_minutes: u16,
+    note: String,
+}
+
+// Payload for recording a lab result; the code is looked up in the registry when omitted
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct LabResultPayload {
+    mother_id: u64,
+    test_name: String,
+    value: String,
+    unit: Option<String>,
+    code: Option<ClinicalCode>,
+}
+
+// Payload for creating/updating mother's profile
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct MotherProfilePayload {
+    name: String,
+    age: u8,
+    blood_type: String,
+    expected_delivery_date: u64,
+    medical_history: Vec<String>,
+    emergency_contact: String,
+    insurance: Option<InsuranceCover>,
+    // Code of the facility the mother is registered at
+    facility_code: Option<String>,
+    // Client-generated key; retrying with the same key returns the original profile
+    idempotency_key: Option<String>,
+    previous_cesareans: Option<u8>,
+    national_id: Option<String>,
+}
+
+// Partial profile update: only supplied fields are validated and changed
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct MotherProfilePatch {
+    // Version the patch was based on (see check_version)
+    expected_version: u64,
+    name: Option<String>,
+    age: Option<u8>,
+    blood_type: Option<String>,
+    expected_delivery_date: Option<u64>,
+    medical_history: Option<Vec<String>>,
+    emergency_contact: Option<String>,
+    // Some(None) removes the cover
+    insurance: Option<Option<InsuranceCover>>,
+    previous_cesareans: Option<u8>,
+    national_id: Option<String>,
+    // Kept in the amendment trail, e.g. "Name misspelt at registration"
+    reason: Option<String>,
+}
+
+// Result of a profile patch: the profile as stored, and the identity edits held for a supervisor
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct ProfileUpdate {
+    profile: MotherProfile,
+    pending: Vec<ChangeRequest>,
+}
+
+// Payload for health record entry
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct HealthRecordPayload {
+    mother_id: u64,
+    blood_pressure: String,
+    weight: f32,
+    symptoms: Vec<String>,
+    notes: String,
+    next_appointment: u64,
+    // Whether the visit was covered by the mother's insurance; defaults to her enrolment status
+    insurance_eligible: Option<bool>,
+    // Facility where the visit took place; defaults to the mother's facility
+    facility_code: Option<String>,
+    // Client-generated key; retrying with the same key returns the original record
+    idempotency_key: Option<String>,
+    fundal_height_cm: Option<f32>,
+    // Beats per minute
+    fetal_heart_rate: Option<u16>,
+    urine_protein: Option<Dipstick>,
+    urine_glucose: Option<Dipstick>,
+    edema: Option<EdemaGrade>,
+    muac_cm: Option<f32>,
+    // Id of a note template; `sections` and `checklist_done` then follow it instead of `notes`
+    note_template: Option<String>,
+    note_sections: Option<Vec<NoteSection>>,
+    checklist_done: Option<Vec<String>>,
+}
+
+// What a payment is for
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Debug)]
+enum PaymentPurpose {
+    ServiceFee,
+    TransportVoucher,
+    DeliveryFee,
+}
+
+// Settlement state of a payment on the ledger
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Debug)]
+enum PaymentStatus {
+    Pending,
+    Processing,
+    Settled,
+    Failed,
+    // The ledger call ended without a reply, so the transfer may or may not have gone through
+    NeedsReconciliation,
+}
+
+// Payment for a visit or delivery, settled through the ICRC-1 ledger
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct Payment {
+    id: u64,
+    mother_id: u64,
+    record_id: Option<u64>,
+    purpose: PaymentPurpose,
+    amount: u64,
+    recipient: Principal,
+    status: PaymentStatus,
+    block_index: Option<Nat>,
+    failure_reason: Option<String>,
+    created_at: u64,
+    settled_at: Option<u64>,
+    version: Option<u64>,
+    updated_at: Option<u64>,
+    ulid: Option<String>,
+    // created_at_time of the first transfer attempt, reused on retries so the ledger deduplicates them
+    ledger_created_at: Option<u64>,
+}
+
+// Account payments may be sent to, optionally the payout account of a facility
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct PayoutAccount {
+    owner: Principal,
+    name: String,
+    facility_code: Option<String>,
+    registered_by: Principal,
+    registered_at: u64,
+}
+
+// Staff member of a facility, e.g. its reception or finance desk
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct FacilityStaff {
+    principal: Principal,
+    facility_code: String,
+    granted_at: u64,
+}
+
+// Payload for registering a payout account
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct PayoutAccountPayload {
+    owner: Principal,
+    name: String,
+    facility_code: Option<String>,
+}
+
+// Payload for recording a payment
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct PaymentPayload {
+    mother_id: u64,
+    record_id: Option<u64>,
+    purpose: PaymentPurpose,
+    amount: u64,
+    recipient: Principal,
+}
+
+// Payments grouped against the visit (and its scheduled appointment) they pay for
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct VisitPayments {
+    record_id: u64,
+    visit_date: u64,
+    next_appointment: u64,
+    payments: Vec<Payment>,
+    settled_amount: u64,
+    outstanding_amount: u64,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct PaymentReconciliation {
+    visits: Vec<VisitPayments>,
+    unlinked_payments: Vec<Payment>,
+}
+
+// Status of a referral to another facility
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Debug)]
+enum ReferralStatus {
+    Pending,
+    Completed,
+    Cancelled,
+}
+
+// Referral of a mother to another facility
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct Referral {
+    id: u64,
+    mother_id: u64,
+    from_facility: String,
+    to_facility: String,
+    reason: String,
+    status: ReferralStatus,
+    created_at: u64,
+    completed_at: Option<u64>,
+    version: Option<u64>,
+    updated_at: Option<u64>,
+    ulid: Option<String>,
+    // Travel distance to the receiving facility, as given by the referring clinician
+    distance_km: Option<u32>,
+}
+
+// Payload for referring a mother
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct ReferralPayload {
+    mother_id: u64,
+    from_facility: String,
+    to_facility: String,
+    reason: String,
+    // A referral this far or further issues a transport voucher
+    distance_km: Option<u32>,
+}
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+enum VoucherStatus {
+    Issued,
+    Redeemed,
+    // Its referral was cancelled
+    Cancelled,
+}
+
+// Fare for a mother referred to a distant facility, redeemed by the facility she travels to
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct TransportVoucher {
+    id: u64,
+    mother_id: u64,
+    referral_id: u64,
+    // Ledger units, like payments
+    amount: u64,
+    valid_facilities: Vec<String>,
+    status: VoucherStatus,
+    issued_at: u64,
+    expires_at: u64,
+    redeemed_at: Option<u64>,
+    redeemed_by: Option<Principal>,
+    redeemed_facility: Option<String>,
+    // Payment created on redemption, settled with settle_payment
+    payment_id: Option<u64>,
+}
+
+// Per-mother care progress used to maintain facility metrics incrementally
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct CareProgress {
+    visit_count: u32,
+    first_visit_at: Option<u64>,
+    critical_since: Option<u64>,
+}
+
+// Running totals per facility, updated on every write
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct FacilityMetrics {
+    registered_mothers: u64,
+    mothers_with_first_visit: u64,
+    total_time_to_first_visit: u64,
+    anc4_mothers: u64,
+    referrals_made: u64,
+    referrals_completed: u64,
+    critical_cases_opened: u64,
+    critical_cases_resolved: u64,
+    total_critical_resolution_time: u64,
+}
+
+// Facility performance indicators derived from the running totals
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct FacilityPerformance {
+    facility_code: String,
+    metrics: FacilityMetrics,
+    anc4_coverage_percent: f64,
+    avg_days_to_first_visit: Option<f64>,
+    referral_completion_percent: Option<f64>,
+    avg_critical_resolution_hours: Option<f64>,
+}
+
+// One row of the MOH 405 ANC register (one per antenatal contact)
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct AncRegisterRow {
+    visit_date: u64,
+    anc_number: u64,
+    visit_number: u32,
+    first_visit: bool,
+    full_name: String,
+    age: u8,
+    emergency_contact: String,
+    expected_delivery_date: u64,
+    gestation_weeks: u64,
+    weight: f32,
+    blood_pressure: String,
+    blood_group: String,
+    referred: bool,
+    remarks: String,
+}
+
+// A chunk of a digitized paper register in CSV form
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct CsvImportChunk {
+    data: String,
+    // Register row number of the first line in this chunk, used in error reports
+    first_row_number: u64,
+    has_header: bool,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct CsvRowError {
+    row: u64,
+    msg: String,
+}
+
+// Outcome of importing one CSV chunk
+#[derive(candid::CandidType, Serialize, Deserialize, Default)]
+struct CsvImportReport {
+    rows_processed: u64,
+    profiles_created: Vec<u64>,
+    records_created: Vec<u64>,
+    duplicate_rows: Vec<u64>,
+    errors: Vec<CsvRowError>,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct FhirEntryError {
+    // Position in the bundle's entry list, from 0
+    entry: u64,
+    msg: String,
+}
+
+// Outcome of ingesting one FHIR bundle; entries are numbered by position from 0
+#[derive(candid::CandidType, Serialize, Deserialize, Default)]
+struct FhirImportReport {
+    entries_processed: u64,
+    profiles_created: Vec<u64>,
+    // Patients matched to mothers already on file
+    profiles_matched: Vec<u64>,
+    records_created: Vec<u64>,
+    lab_results_created: Vec<u64>,
+    // Visits and lab results already on file
+    duplicate_entries: Vec<u64>,
+    // Resources and observations with nothing to map to
+    skipped_entries: Vec<u64>,
+    errors: Vec<FhirEntryError>,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct MigrationInfo {
+    version: u64,
+    description: String,
+    applied: bool,
+}
+
+// Schema version of stored data and the migration registry
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct SchemaStatus {
+    version: u64,
+    latest: u64,
+    migrations: Vec<MigrationInfo>,
+}
+
+// Serialization formats for per-mother exports
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize)]
+enum ExportFormat {
+    Json,
+    Csv,
+}
+
+// Mothers included in a bulk export; all mothers when both are absent
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct ExportFilter {
+    facility_code: Option<String>,
+    stage: Option<PregnancyStage>,
+}
+
+// Test a recommendation rule makes of a mother; terms match case-insensitively as substrings
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+enum RuleCondition {
+    // Pregnant, with a gestational age in this range of completed weeks
+    GestationWeeks { from: u64, to: u64 },
+    // Delivery recorded
+    Postpartum,
+    HistoryMentions(String),
+    // Risk tier over the pregnancy: "LOW", "MEDIUM" or "HIGH"
+    RiskTier(String),
+    // Neither a lab result nor the medical history mentions the term, e.g. a test not yet done
+    NotRecorded(String),
+    // The latest numeric result of a lab test is below / above the value
+    LabBelow { test: String, value: f64 },
+    LabAbove { test: String, value: f64 },
+    // Pregnant, and the last checkup was more than this many days ago
+    CheckupOverdueDays(u64),
+    // Fewer than this percentage of due doses of an active medication were marked taken
+    AdherenceBelow { medication: String, percent: u8 },
+}
+
+// Action suggested to a mother when every condition holds
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct RecommendationRule {
+    id: String,
+    action: String,
+    reason: String,
+    // Higher comes first
+    priority: u8,
+    conditions: Vec<RuleCondition>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct RecommendationRules {
+    rules: Vec<RecommendationRule>,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct Recommendation {
+    rule_id: String,
+    action: String,
+    reason: String,
+    priority: u8,
+}
+
+// How start_research_export de-identifies pregnancies
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct AnonymizationPolicy {
+    // Dates are reported as the start of their period; 7 means the week, starting Monday
+    date_granularity_days: u32,
+    // Ages are reported in bands this wide, e.g. "25-29"; 1 keeps exact ages
+    age_band_years: u8,
+    include_facility: bool,
+    // Facilities with fewer registered mothers are reported as null
+    min_facility_mothers: u64,
+    // Free text that may identify a mother; off unless a partner's agreement covers it
+    include_medical_history: bool,
+    include_symptom_codes: bool,
+}
+
+impl Default for RecommendationRules {
+    fn default() -> Self {
+        let rule = |id: &str, action: &str, reason: &str, priority: u8, conditions: Vec<RuleCondition>| {
+            RecommendationRule {
+                id: id.to_string(),
+                action: action.to_string(),
+                reason: reason.to_string(),
+                priority,
+                conditions,
+            }
+        };
+        RecommendationRules {
+            rules: vec![
+                rule(
+                    "anc-overdue",
+                    "Visit your clinic for your next ANC checkup",
+                    "Your last checkup was more than 6 weeks ago",
+                    95,
+                    vec![RuleCondition::CheckupOverdueDays(42)],
+                ),
+                rule(
+                    "low-haemoglobin",
+                    "Collect iron tablets and take them daily",
+                    "Your last haemoglobin result was low",
+                    90,
+                    vec![RuleCondition::LabBelow { test: "haemoglobin".to_string(), value: 11.0 }],
+                ),
+                rule(
+                    "high-risk-delivery",
+                    "Plan to deliver at a hospital",
+                    "Your pregnancy has been marked high risk",
+                    85,
+                    vec![RuleCondition::RiskTier("HIGH".to_string())],
+                ),
+                rule(
+                    "ogtt",
+                    "Book your OGTT",
+                    "Screening for gestational diabetes is due between 24 and 28 weeks",
+                    70,
+                    vec![RuleCondition::GestationWeeks { from: 24, to: 28 }, RuleCondition::NotRecorded("ogtt".to_string())],
+                ),
+                rule(
+                    "tt2",
+                    "TT2 due",
+                    "The second tetanus toxoid dose protects you and your baby at birth",
+                    60,
+                    vec![RuleCondition::GestationWeeks { from: 20, to: 36 }, RuleCondition::NotRecorded("tt2".to_string())],
+                ),
+                rule(
+                    "ifas",
+                    "Collect iron and folic acid tablets",
+                    "Daily iron and folic acid are recommended throughout pregnancy",
+                    40,
+                    vec![RuleCondition::GestationWeeks { from: 0, to: 42 }],
+                ),
+                rule(
+                    "postnatal-check",
+                    "Attend your postnatal checkup",
+                    "Mothers and babies should be seen in the weeks after birth",
+                    50,
+                    vec![RuleCondition::Postpartum],
+                ),
+            ],
+        }
+    }
+}
+
+// Heading of a structured note, with what to write under it
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct TemplateSection {
+    title: String,
+    prompt: String,
+    required: bool,
+}
+
+// What completes a care checklist item, looked for among the data dated in its trimester
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+enum ChecklistEvidence {
+    // A visit with a blood pressure reading
+    BloodPressure,
+    // A visit with a weight
+    Weight,
+    // A visit with urine protein or glucose recorded
+    Urinalysis,
+    // A lab result whose test name contains one of the terms
+    LabTest(Vec<String>),
+    // A prescription whose medication contains one of the terms
+    Prescription(Vec<String>),
+    // A visit whose note ticked this checklist item
+    Ticked(String),
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct CareChecklistItem {
+    id: String,
+    label: String,
+    // Any one of these completes the item
+    evidence: Vec<ChecklistEvidence>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct CareChecklist {
+    trimester: u8,
+    items: Vec<CareChecklistItem>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct CareChecklists {
+    checklists: Vec<CareChecklist>,
+}
+
+impl Default for CareChecklists {
+    fn default() -> Self {
+        let terms = |terms: &[&str]| terms.iter().map(|term| term.to_string()).collect::<Vec<_>>();
+        let item = |id: &str, label: &str, evidence: Vec<ChecklistEvidence>| CareChecklistItem {
+            id: id.to_string(),
+            label: label.to_string(),
+            evidence,
+        };
+        let routine = || {
+            vec![
+                item("bp", "Blood pressure", vec![ChecklistEvidence::BloodPressure]),
+                item("weight", "Weight", vec![ChecklistEvidence::Weight]),
+                item(
+                    "urinalysis",
+                    "Urinalysis",
+                    vec![ChecklistEvidence::Urinalysis, ChecklistEvidence::LabTest(terms(&["urinalysis", "urine"]))],
+                ),
+            ]
+        };
+        let haemoglobin = || item("hb", "Haemoglobin", vec![ChecklistEvidence::LabTest(terms(&HAEMOGLOBIN_TESTS))]);
+        let ticked = |id: &str, label: &str, ticked: &str| {
+            item(id, label, vec![ChecklistEvidence::Ticked(ticked.to_string())])
+        };
+
+        let mut first = routine();
+        first.push(haemoglobin());
+        first.push(item("ultrasound", "Dating ultrasound", vec![ChecklistEvidence::LabTest(terms(&["ultrasound", "scan"]))]));
+        first.push(ticked("counselling-danger-signs", "Danger signs counselling", "Danger signs explained"));
+
+        let mut second = routine();
+        second.push(item(
+            "tt",
+            "Tetanus toxoid",
+            vec![
+                ChecklistEvidence::LabTest(terms(&["tetanus", "tt1", "tt2", "tt3", "tt4", "tt5"])),
+                ChecklistEvidence::Prescription(terms(&["tetanus"])),
+                ChecklistEvidence::Ticked("Tetanus toxoid given".to_string()),
+            ],
+        ));
+        second.push(item("ifas", "Iron and folic acid", vec![ChecklistEvidence::Prescription(terms(&IFAS_TERMS))]));
+
+        let mut third = routine();
+        third.push(haemoglobin());
+        third.push(ticked("counselling-birth-plan", "Birth plan counselling", "Birth plan reviewed"));
+        third.push(ticked(
+            "counselling-breastfeeding",
+            "Breastfeeding and family planning counselling",
+            "Breastfeeding and family planning counselling",
+        ));
+
+        CareChecklists {
+            checklists: vec![
+                CareChecklist { trimester: 1, items: first },
+                CareChecklist { trimester: 2, items: second },
+                CareChecklist { trimester: 3, items: third },
+            ],
+        }
+    }
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct ChecklistItemStatus {
+    id: String,
+    label: String,
+    // When the earliest evidence is dated; absent while outstanding
+    done_at: Option<u64>,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct TrimesterCompletion {
+    trimester: u8,
+    items: Vec<ChecklistItemStatus>,
+    // Absent when the checklist is empty
+    percent: Option<f64>,
+}
+
+// A mother's checklists for the trimesters she has reached
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct ChecklistCompletion {
+    mother_id: u64,
+    trimesters: Vec<TrimesterCompletion>,
+    // Over every item of those trimesters
+    percent: Option<f64>,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct FacilityChecklistCompletion {
+    facility_code: String,
+    mothers: u64,
+    // Items done as a share of items due, per trimester, over the mothers who reached it
+    by_trimester: Vec<(u8, Option<f64>)>,
+    percent: Option<f64>,
+}
+
+// Structured visit note: sections to fill in and items to tick, optionally for one trimester
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct NoteTemplate {
+    id: String,
+    name: String,
+    // 1-3; unset for any stage
+    trimester: Option<u8>,
+    sections: Vec<TemplateSection>,
+    checklist: Vec<String>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct NoteTemplates {
+    templates: Vec<NoteTemplate>,
+}
+
+// One filled-in section of a structured note
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct NoteSection {
+    title: String,
+    text: String,
+}
+
+impl Default for NoteTemplates {
+    fn default() -> Self {
+        let soap = || {
+            [
+                ("Subjective", "What the mother reports: complaints, fetal movements, danger signs"),
+                ("Objective", "Examination findings and test results"),
+                ("Assessment", "Working diagnosis and risk"),
+                ("Plan", "Treatment, counselling, referrals and next visit"),
+            ]
+            .into_iter()
+            .map(|(title, prompt)| TemplateSection {
+                title: title.to_string(),
+                prompt: prompt.to_string(),
+                required: true,
+            })
+            .collect::<Vec<_>>()
+        };
+        let template = |id: &str, name: &str, trimester: Option<u8>, checklist: &[&str]| NoteTemplate {
+            id: id.to_string(),
+            name: name.to_string(),
+            trimester,
+            sections: soap(),
+            checklist: checklist.iter().map(|item| item.to_string()).collect(),
+        };
+        NoteTemplates {
+            templates: vec![
+                template("soap", "SOAP note", None, &[]),
+                template(
+                    "anc-first-trimester",
+                    "ANC visit, first trimester",
+                    Some(1),
+                    &[
+                        "Confirm pregnancy and date it",
+                        "HIV, syphilis and hepatitis B tests offered",
+                        "Blood group and haemoglobin",
+                        "Iron and folic acid started",
+                        "Danger signs explained",
+                    ],
+                ),
+                template(
+                    "anc-second-trimester",
+                    "ANC visit, second trimester",
+                    Some(2),
+                    &[
+                        "Fundal height measured",
+                        "Fetal heart rate heard",
+                        "Tetanus toxoid given",
+                        "Deworming and malaria prevention",
+                        "OGTT booked for 24 to 28 weeks",
+                    ],
+                ),
+                template(
+                    "anc-third-trimester",
+                    "ANC visit, third trimester",
+                    Some(3),
+                    &[
+                        "Fundal height and fetal lie",
+                        "Blood pressure and urine protein",
+                        "Birth plan reviewed",
+                        "Anti-D if Rh-negative",
+                        "Breastfeeding and family planning counselling",
+                    ],
+                ),
+            ],
+        }
+    }
+}
+
+impl Default for AnonymizationPolicy {
+    fn default() -> Self {
+        AnonymizationPolicy {
+            date_granularity_days: 7,
+            age_band_years: 5,
+            include_facility: true,
+            min_facility_mothers: 10,
+            include_medical_history: false,
+            include_symptom_codes: true,
+        }
+    }
+}
+
+// Bulk export prepared by start_export and read back in chunks
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct ExportJob {
+    id: u64,
+    filter: ExportFilter,
+    format: ExportFormat,
+    requested_by: Principal,
+    created_at: u64,
+    // Chunks are deleted after this
+    expires_at: u64,
+    mothers: u64,
+    total_bytes: u64,
+    chunk_count: u64,
+}
+
+// Export chunk key: ordered by job, then chunk index
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct ChunkKey {
+    job_id: u64,
+    index: u64,
+}
+
+struct ExportChunk(Vec<u8>);
+
+// Everything exported about one mother
+struct MotherExport {
+    profile: MotherProfile,
+    health_records: Vec<HealthRecord>,
+    addenda: Vec<Addendum>,
+    lab_results: Vec<LabResult>,
+    appointments: Vec<AppointmentSummary>,
+    referrals: Vec<Referral>,
+    payments: Vec<Payment>,
+    self_reports: Vec<SelfReport>,
+}
+
+// Appointment booked at a visit
+#[derive(Serialize)]
+struct AppointmentSummary {
+    record_id: u64,
+    booked_at: u64,
+    scheduled_for: u64,
+}
+
+// Current continuity-of-care bundle format
+const CARE_BUNDLE_FORMAT_VERSION: u16 = 1;
+
+// Everything about one pregnancy, carried between mama-pack canisters
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct CareBundleContent {
+    exported_at: u64,
+    profile: MotherProfile,
+    health_records: Vec<HealthRecord>,
+    referrals: Vec<Referral>,
+    lab_results: Option<Vec<LabResult>>,
+    addenda: Option<Vec<Addendum>>,
+}
+
+// Signed, versioned care bundle; `payload` is the candid-encoded CareBundleContent
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct SignedCareBundle {
+    format_version: u16,
+    source_canister: Principal,
+    payload: Vec<u8>,
+    signature: Vec<u8>,
+}
+
+// Canister secret used to sign exported payloads
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct SigningKey {
+    key: Vec<u8>,
+}
+
+// Canisters whose care bundles may be imported
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct TrustedPeers {
+    canisters: Vec<Principal>,
+}
+
+// Ledger used to settle payments
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct LedgerConfig {
+    ledger_canister: Option<Principal>,
+}
+
+// ICRC-1 ledger interface types
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct Account {
+    owner: Principal,
+    subaccount: Option<Vec<u8>>,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct TransferArg {
+    from_subaccount: Option<Vec<u8>>,
+    to: Account,
+    amount: Nat,
+    fee: Option<Nat>,
+    memo: Option<Vec<u8>>,
+    created_at_time: Option<u64>,
+}
+
+// Error details are only surfaced through Debug in failure reasons
+#[allow(dead_code)]
+#[derive(candid::CandidType, Deserialize, Debug)]
+enum TransferError {
+    BadFee { expected_fee: Nat },
+    BadBurn { min_burn_amount: Nat },
+    InsufficientFunds { balance: Nat },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    TemporarilyUnavailable,
+    Duplicate { duplicate_of: Nat },
+    GenericError { error_code: Nat, message: String },
+}
+
+// Identifier a mother has in another system, e.g. her KenyaEMR/OpenMRS UUID or a hospital number
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct ExternalIdentifier {
+    // Namespace of the issuing system, usually a URI
+    system: String,
+    value: String,
+    added_by: Principal,
+    added_at: u64,
+}
+
+// A mother's identifiers in other systems, at most one per system
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct ExternalIdentifiers {
+    identifiers: Vec<ExternalIdentifier>,
+}
+
+// Entity a ULID refers to
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize)]
+struct EntityRef {
+    entity_type: EntityType,
+    id: u64,
+}
+
+// Random seed (from raw_rand) for ULID generation
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct UlidSeed {
+    seed: Vec<u8>,
+}
+
+// Timestamp a time-filtered query selects and sorts on
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize)]
+enum TimestampField {
+    CreatedAt,
+    UpdatedAt,
+}
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize)]
+enum SortOrder {
+    Ascending,
+    Descending,
+}
+
+// Select entities whose timestamp falls in [from, to] and sort them by it
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct TimeFilter {
+    field: TimestampField,
+    from: Option<u64>,
+    to: Option<u64>,
+    order: SortOrder,
+}
+
+// Archive canister that takes old health records of mothers who have delivered
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct ArchiveConfig {
+    archive_canister: Option<Principal>,
+    // Defaults to DEFAULT_ARCHIVE_AFTER_DAYS
+    archive_after_days: Option<u64>,
+}
+
+// Where a mother's archived health records are kept
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct ArchivePointer {
+    // Every archive her records were sent to, in case the archive canister was changed
+    archive_canisters: Vec<Principal>,
+    record_count: u64,
+    last_archived_at: u64,
+}
+
+// Archive canister interface types
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct ArchivedRecord {
+    mother_id: u64,
+    id: u64,
+    // Candid-encoded HealthRecord
+    data: Vec<u8>,
+}
+
+// Error details are only surfaced through Debug in error messages
+#[allow(dead_code)]
+#[derive(candid::CandidType, Deserialize, Debug)]
+enum ArchiveError {
+    AuthorizationError { msg: String },
+    InvalidInput { msg: String },
+}
+
+// How the router assigns new mothers to shards
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+enum ShardStrategy {
+    // New mothers go to the newest shard
+    MotherIdRange,
+    // New mothers go to the shard serving their facility
+    Facility,
+}
+
+// Shard canister holding the entities whose ids fall in [id_start, id_end)
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct Shard {
+    canister_id: Principal,
+    id_start: u64,
+    id_end: u64,
+    // Facilities whose new mothers are registered here (Facility strategy)
+    facility_codes: Vec<String>,
+    created_at: u64,
+}
+
+// Router state: the shards spawned by this canister
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct ShardConfig {
+    // Defaults to MotherIdRange
+    strategy: Option<ShardStrategy>,
+    shards: Vec<Shard>,
+}
+
+// This canister's own place in a sharded deployment
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct ShardRole {
+    // Set on shards to the canister that spawned them
+    router: Option<Principal>,
+    // Ids this canister may hand out stop here; absent until sharding is used
+    id_end: Option<u64>,
+}
+
+// Sent by the router to a newly installed shard
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct ShardAssignment {
+    router: Principal,
+    id_start: u64,
+    id_end: u64,
+}
+
+// Which canister a call should go to
+#[derive(candid::CandidType, Serialize, Deserialize)]
+enum ShardRoute {
+    // The canister holding this mother
+    Mother(u64),
+    // The canister that should register a new mother, optionally at a facility
+    NewMother(Option<String>),
+}
+
+// Piece of the shard wasm module uploaded by a controller
+struct WasmChunk(Vec<u8>);
+
+// Inclusive bounds on a date (nanoseconds); either end may be open
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct DateRange {
+    from: Option<u64>,
+    to: Option<u64>,
+}
+
+// Kinds of entity exposed to syncing clients
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, Debug, PartialEq)]
+enum EntityType {
+    MotherProfile,
+    HealthRecord,
+    Referral,
+    Payment,
+    LabResult,
+    SelfReport,
+}
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, Debug, PartialEq)]
+enum ChangeKind {
+    Upserted,
+    Deleted,
+}
+
+// Latest change to an entity, keyed by change sequence number
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct ChangeEntry {
+    entity_type: EntityType,
+    id: u64,
+    kind: ChangeKind,
+    changed_at: u64,
+}
+
+// Current state of a changed entity
+#[derive(candid::CandidType, Serialize, Deserialize)]
+enum SyncEntity {
+    MotherProfile(MotherProfile),
+    HealthRecord(HealthRecord),
+    Referral(Referral),
+    Payment(Payment),
+    LabResult(LabResult),
+    SelfReport(SelfReport),
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct SyncChange {
+    seq: u64,
+    entity_type: EntityType,
+    id: u64,
+    kind: ChangeKind,
+    changed_at: u64,
+    // Absent for deletions
+    entity: Option<SyncEntity>,
+}
+
+// One page of the delta-sync stream
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct ChangePage {
+    changes: Vec<SyncChange>,
+    // Pass back as `cursor` to fetch the next page; absent once caught up
+    next_cursor: Option<u64>,
+    server_time: u64,
+    // Set when `since` is older than the tombstone retention window: deletions may have been
+    // missed, so the client should discard its copy and sync from 0
+    full_resync_required: bool,
+}
+
+// Id created under a client idempotency key, kept until the key's retry window has passed
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct IdempotentResult {
+    id: u64,
+    created_at: u64,
+}
+
+// Record of a deleted entity, kept for the retention window so offline clients learn of it
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct Tombstone {
+    entity_type: EntityType,
+    id: u64,
+    deleted_at: u64,
+}
+
+// Audit entry for an erased mother; holds no personal data
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct ErasureRecord {
+    mother_id: u64,
+    erased_at: u64,
+    erased_by: Principal,
+    health_records: u64,
+    lab_results: u64,
+    referrals: u64,
+    payments: u64,
+    // Archives that held her older records
+    archive_canisters: Vec<Principal>,
+    // Archives that could not be reached to erase them; see retry_archive_erasure
+    archive_erasure_pending: Option<Vec<Principal>>,
+    // Her contributions to the dashboard counters, which are kept
+    retained_counters: Vec<(String, u64)>,
+}
+
+// Vital-sign limits used to classify a visit's health status
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct RiskThresholds {
+    // Critical at or above these, or below the low limits
+    systolic_high: i32,
+    diastolic_high: i32,
+    systolic_low: i32,
+    diastolic_low: i32,
+    // Needs attention outside this range (kg)
+    weight_low: f32,
+    weight_high: f32,
+}
+
+impl Default for RiskThresholds {
+    fn default() -> Self {
+        RiskThresholds {
+            systolic_high: 140,
+            diastolic_high: 90,
+            systolic_low: 90,
+            diastolic_low: 60,
+            weight_low: 45.0,
+            weight_high: 100.0,
+        }
+    }
+}
+
+// Risk thresholds to change; absent fields keep their current value
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct ThresholdOverrides {
+    systolic_high: Option<i32>,
+    diastolic_high: Option<i32>,
+    systolic_low: Option<i32>,
+    diastolic_low: Option<i32>,
+    weight_low: Option<f32>,
+    weight_high: Option<f32>,
+}
+
+// Deployment settings passed at install or upgrade; absent fields keep their current value
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct InitArgs {
+    // Principals allowed to call controller-only endpoints, besides the controllers; replaces the list
+    admins: Option<Vec<Principal>>,
+    facility_name: Option<String>,
+    locale: Option<String>,
+    thresholds: Option<ThresholdOverrides>,
+    // Threshold key for field encryption: "key_1" on mainnet, "test_key_1" for testing, "dfx_test_key" locally
+    vetkd_key_name: Option<String>,
+}
+
+// Deployment settings, set through InitArgs and update_config
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct CanisterConfig {
+    admins: Vec<Principal>,
+    facility_name: Option<String>,
+    // BCP 47 tag, e.g. "en" or "sw"
+    locale: String,
+    thresholds: RiskThresholds,
+    // Default window of get_upcoming_appointments
+    appointment_reminder_days: u64,
+    default_page_size: u32,
+    max_page_size: u32,
+    // Feature flags set explicitly; others take their default from FEATURES
+    features: Vec<(String, bool)>,
+    // Unset means VETKD_DEFAULT_KEY_NAME
+    vetkd_key_name: Option<String>,
+    // Gestational week by which syphilis and hepatitis B screening is due; unset means
+    // SCREENING_DUE_WEEKS
+    screening_due_weeks: Option<u64>,
+    // Days a deleted profile or record can be restored; unset means DELETION_RETENTION_DAYS
+    deletion_retention_days: Option<u64>,
+    // Transport voucher for referrals at least the minimum distance away; unset amount means no
+    // vouchers, unset distance means TRANSPORT_VOUCHER_MIN_KM
+    transport_voucher_amount: Option<u64>,
+    transport_voucher_min_km: Option<u32>,
+}
+
+impl Default for CanisterConfig {
+    fn default() -> Self {
+        CanisterConfig {
+            admins: Vec::new(),
+            facility_name: None,
+            locale: "en".to_string(),
+            thresholds: RiskThresholds::default(),
+            appointment_reminder_days: 7,
+            default_page_size: 50,
+            max_page_size: 500,
+            features: Vec::new(),
+            vetkd_key_name: None,
+            screening_due_weeks: None,
+            deletion_retention_days: None,
+            transport_voucher_amount: None,
+            transport_voucher_min_km: None,
+        }
+    }
+}
+
+// Describes an encrypted snapshot of the canister's stable memory; needed to restore it
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct BackupManifest {
+    source_canister: Principal,
+    created_at: u64,
+    schema_version: u64,
+    nonce: Vec<u8>,
+    size_bytes: u64,
+    chunk_size: u64,
+    chunk_count: u64,
+    // The snapshot's data key sealed under the operator's key, with the fields above as
+    // associated data; opening it proves the key and that the manifest is unaltered
+    wrapped_key: Vec<u8>,
+}
+
+// One encrypted slice of stable memory
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct BackupChunk {
+    index: u64,
+    data: Vec<u8>,
+    tag: Vec<u8>,
+}
+
+// The data key is held on the heap only while the backup or restore runs
+enum Maintenance {
+    Backup { manifest: BackupManifest, data_key: [u8; 32] },
+    Restore { manifest: BackupManifest, data_key: [u8; 32], received: Vec<bool> },
+}
+
+// Consistency rules checked by check_invariants
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, Debug)]
+enum InvariantCheck {
+    // Clinical data whose mother no longer exists (reported, never repaired)
+    OrphanHealthRecord,
+    OrphanLabResult,
+    OrphanReferral,
+    OrphanPayment,
+    OrphanPrescription,
+    OrphanSelfReport,
+    OrphanChwAlert,
+    OrphanMessage,
+    // Record id -> mother index missing or pointing at the wrong mother
+    RecordIndexMismatch,
+    // Record id -> mother index entry without a record
+    StaleRecordIndex,
+    // ULID resolving to a missing entity
+    DanglingUlid,
+    // Change index entry without its feed entry
+    DanglingChangeIndex,
+    // Care progress or field clocks of a missing mother
+    OrphanCareProgress,
+    OrphanFieldClocks,
+    // Dashboard counter different from a recount
+    CounterDrift,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct InvariantIssue {
+    check: InvariantCheck,
+    // Entity the issue is about; absent for counters
+    id: Option<u64>,
+    detail: String,
+    repaired: bool,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct InvariantReport {
+    checked_at: u64,
+    profiles_scanned: u64,
+    health_records_scanned: u64,
+    issues: Vec<InvariantIssue>,
+    repaired: u64,
+}
+
+// Latest run of the daily orphan scan
+#[derive(candid::CandidType, Clone, Default, Serialize, Deserialize)]
+struct OrphanReport {
+    scanned_at: u64,
+    // Every orphan found; `orphans` holds at most MAX_ORPHANS_REPORTED of them
+    total: u64,
+    orphans: Vec<InvariantIssue>,
+}
+
+// Entities created or removed by seed_demo_data / clear_demo_data
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct DemoDataSummary {
+    mothers: u64,
+    health_records: u64,
+}
+
+// A switchable module and whether it is on
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct FeatureFlag {
+    name: String,
+    description: String,
+    enabled: bool,
+}
+
+// Runtime config changes; absent fields keep their current value
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct ConfigPatch {
+    thresholds: Option<ThresholdOverrides>,
+    appointment_reminder_days: Option<u64>,
+    default_page_size: Option<u32>,
+    max_page_size: Option<u32>,
+    // Toggles to set; features not listed keep their state
+    features: Option<Vec<(String, bool)>>,
+    screening_due_weeks: Option<u64>,
+    deletion_retention_days: Option<u64>,
+    transport_voucher_amount: Option<u64>,
+    transport_voucher_min_km: Option<u32>,
+}
+
+// Management canister vetKD interface
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+enum VetKdCurve {
+    #[serde(rename = "bls12_381_g2")]
+    Bls12381G2,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct VetKdKeyId {
+    curve: VetKdCurve,
+    name: String,
+}
+
+#[derive(candid::CandidType, Serialize)]
+struct VetKdPublicKeyArgs {
+    canister_id: Option<Principal>,
+    context: Vec<u8>,
+    key_id: VetKdKeyId,
+}
+
+#[derive(candid::CandidType, Deserialize)]
+struct VetKdPublicKeyResult {
+    public_key: Vec<u8>,
+}
+
+#[derive(candid::CandidType, Serialize)]
+struct VetKdDeriveKeyArgs {
+    input: Vec<u8>,
+    context: Vec<u8>,
+    transport_public_key: Vec<u8>,
+    key_id: VetKdKeyId,
+}
+
+#[derive(candid::CandidType, Deserialize)]
+struct VetKdDeriveKeyResult {
+    encrypted_key: Vec<u8>,
+}
+
+// One read of a mother's data
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct AccessEntry {
+    caller: Principal,
+    timestamp: u64,
+    method: String,
+}
+
+// Principal holding a permission granted by set_field_reader or set_sensitive_reader, or a
+// mother's assigned health worker
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct AccessGrant {
+    principal: Principal,
+    granted_at: u64,
+}
+
+// What a share token lets its holder read
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, Debug, PartialEq)]
+enum ShareScope {
+    Profile,
+    HealthRecords,
+    LabResults,
+    Full,
+}
+
+// Short-lived read access to one mother's data; only the token's hash is stored
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct ShareToken {
+    token_hash: String,
+    mother_id: u64,
+    scope: ShareScope,
+    created_by: Principal,
+    created_at: u64,
+    expires_at: u64,
+}
+
+// A new share token; the token itself is only returned here
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct IssuedShareToken {
+    token: String,
+    token_hash: String,
+    expires_at: u64,
+}
+
+// What a share token holder sees; parts outside the token's scope are left empty
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct SharedRecord {
+    scope: ShareScope,
+    expires_at: u64,
+    mother_id: u64,
+    // Only with a Profile or Full share
+    profile: Option<MotherProfile>,
+    health_records: Vec<HealthRecord>,
+    addenda: Vec<Addendum>,
+    lab_results: Vec<LabResult>,
+}
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, Debug, PartialEq)]
+enum HivStatus {
+    Negative,
+    Positive,
+    Unknown,
+    Declined,
+}
+
+// Fields only sensitive readers may see; kept apart from the profile so that no other
+// response, export, sync feed or changelog entry carries them
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct SensitiveFields {
+    mother_id: u64,
+    hiv_status: Option<HivStatus>,
+    hiv_tested_at: Option<u64>,
+    mental_health_notes: String,
+    version: Option<u64>,
+    updated_at: Option<u64>,
+    updated_by: Option<Principal>,
+}
+
+// Sensitive field changes; absent fields keep their value
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct SensitiveFieldsPatch {
+    // 0 when none have been recorded yet
+    expected_version: u64,
+    hiv_status: Option<HivStatus>,
+    hiv_tested_at: Option<u64>,
+    mental_health_notes: Option<String>,
+}
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, Debug, PartialEq)]
+enum ArtStatus {
+    NotStarted,
+    OnArt,
+    Interrupted,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct ViralLoad {
+    sampled_at: u64,
+    copies_per_ml: u32,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct InfantProphylaxis {
+    started_at: u64,
+    regimen: String,
+}
+
+// Prevention of mother-to-child transmission of HIV for a mother who tested positive. The test
+// result itself is her `hiv_status` sensitive field.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct PmtctRecord {
+    mother_id: u64,
+    art_status: Option<ArtStatus>,
+    art_started_at: Option<u64>,
+    // Oldest first; the latest PMTCT_VIRAL_LOADS are kept
+    viral_loads: Vec<ViralLoad>,
+    infant_prophylaxis: Option<InfantProphylaxis>,
+    version: Option<u64>,
+    updated_at: Option<u64>,
+    updated_by: Option<Principal>,
+}
+
+// PMTCT changes; absent fields keep their value
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct PmtctPatch {
+    // 0 when nothing has been recorded yet
+    expected_version: u64,
+    art_status: Option<ArtStatus>,
+    art_started_at: Option<u64>,
+    infant_prophylaxis: Option<InfantProphylaxis>,
+}
+
+// A mother's PMTCT care with her test result and what is due at her stage
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct PmtctView {
+    hiv_status: Option<HivStatus>,
+    hiv_tested_at: Option<u64>,
+    record: Option<PmtctRecord>,
+    suppressed: Option<bool>,
+    reminders: Vec<String>,
+}
+
+// How many mothers reach each step of the PMTCT cascade
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct PmtctCascade {
+    mothers: u64,
+    tested: u64,
+    positive: u64,
+    on_art: u64,
+    viral_load_done: u64,
+    suppressed: u64,
+    // Positive mothers who have delivered, and their infants started on prophylaxis
+    delivered_positive: u64,
+    infant_prophylaxis: u64,
+}
+
+// Shift applied to the canister clock while the test_clock flag is on
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct ClockOffset {
+    nanos: i64,
+}
+
+// Delta-sync settings
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct SyncConfig {
+    // Defaults to DEFAULT_TOMBSTONE_RETENTION_DAYS
+    tombstone_retention_days: Option<u64>,
+}
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, Debug, PartialEq)]
+enum ChangeEventKind {
+    Created,
+    Updated,
+    Deleted,
+}
+
+// A soft-deleted profile or health record, kept until `purge_after` so it can be restored
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct Deletion {
+    entity_type: EntityType,
+    id: u64,
+    mother_id: u64,
+    reason: String,
+    deleted_by: Principal,
+    deleted_at: u64,
+    purge_after: u64,
+}
+
+// Immutable changelog entry written for every mutation
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct ChangeEvent {
+    seq: u64,
+    entity_type: EntityType,
+    entity_id: u64,
+    kind: ChangeEventKind,
+    // JSON: the full entity when created or deleted, otherwise {"field": {"old": .., "new": ..}}
+    diff: String,
+    actor: Principal,
+    timestamp: u64,
+    // Why the change was made, given by the caller or implied by the operation
+    reason: Option<String>,
+}
+
+// A ChangeEvent as stored. Changes to a mother's entities keep their diff and reason sealed under
+// her change-log key, so destroying the key at erasure leaves them unreadable in the log and in
+// any backup taken afterwards.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct LoggedChange {
+    seq: u64,
+    entity_type: EntityType,
+    entity_id: u64,
+    kind: ChangeEventKind,
+    // Empty while `sealed` is set
+    diff: String,
+    actor: Principal,
+    timestamp: u64,
+    reason: Option<String>,
+    mother_id: Option<u64>,
+    // Encrypted (diff, reason)
+    sealed: Option<Vec<u8>>,
+}
+
+// Profiles found by get_mother_profiles, plus the ids that were not
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct ProfileLookup {
+    profiles: Vec<MotherProfile>,
+    missing_ids: Vec<u64>,
+}
+
+// One page of a list query
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct Page<T> {
+    items: Vec<T>,
+    // Pass back as `cursor` to fetch the next page; absent on the last page
+    next_cursor: Option<u64>,
+    // Number of matching items across all pages
+    total: u64,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct ChangeEventPage {
+    events: Vec<ChangeEvent>,
+    // Pass back as `start` to fetch the next page; absent at the end of the log
+    next_start: Option<u64>,
+}
+
+// Profile field edited offline
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+enum ProfileField {
+    Name(String),
+    Age(u8),
+    BloodType(String),
+    ExpectedDeliveryDate(u64),
+    MedicalHistory(Vec<String>),
+    EmergencyContact(String),
+    Insurance(Option<InsuranceCover>),
+    PreviousCesareans(u8),
+    NationalId(String),
+}
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+enum ChangeRequestStatus {
+    Pending,
+    Approved,
+    Rejected,
+}
+
+// Edit of a mother's identity fields waiting for a supervisor's decision
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct ChangeRequest {
+    id: u64,
+    mother_id: u64,
+    fields: Vec<ProfileField>,
+    reason: Option<String>,
+    requested_by: Principal,
+    requested_at: u64,
+    status: ChangeRequestStatus,
+    decided_by: Option<Principal>,
+    decided_at: Option<u64>,
+    decision_note: Option<String>,
+}
+
+// Mutation captured by an offline client, stamped with the client time it was made
+#[derive(candid::CandidType, Serialize, Deserialize)]
+enum OfflineMutation {
+    UpdateProfile { mother_id: u64, edited_at: u64, fields: Vec<ProfileField> },
+    AddHealthRecord { edited_at: u64, payload: Box<HealthRecordPayload> },
+}
+
+// Field edit that lost to a newer write already on the canister
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct FieldConflict {
+    field: String,
+    client_edited_at: u64,
+    server_updated_at: u64,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+enum MutationStatus {
+    Applied,
+    // Some fields were overridden by newer server writes
+    PartiallyApplied,
+    // Every field was overridden by newer server writes
+    Superseded,
+    // Only identity edits, now waiting for a supervisor
+    PendingApproval,
+    Rejected,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct MutationOutcome {
+    index: u64,
+    status: MutationStatus,
+    entity_id: Option<u64>,
+    conflicts: Vec<FieldConflict>,
+    // Identity edits filed as change requests
+    pending: Vec<ChangeRequest>,
+    error: Option<String>,
+}
+
+// Last-write time of each profile field, used for field-level last-writer-wins merges
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct FieldClocks {
+    clocks: Vec<(String, u64)>,
+}
+
+// HTTP gateway types (see the IC http_request interface)
+// Size of one stable store
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct StoreStats {
+    store: String,
+    entries: u64,
+    // Encoded size of the largest value
+    largest_entry_bytes: u64,
+}
+
+// Capacity figures for operators
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct StorageStats {
+    stores: Vec<StoreStats>,
+    // 64 KiB pages
+    stable_memory_pages: u64,
+    stable_memory_bytes: u64,
+    // Wasm heap size, which only grows
+    heap_bytes: u64,
+    largest_record_bytes: u64,
+    // Store holding the largest record
+    largest_record_store: Option<String>,
+}
+
+// Effect of compression on one store
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct CompressionStats {
+    store: String,
+    entries: u64,
+    // Candid-encoded size before compression
+    encoded_bytes: u64,
+    // Size as written to stable memory
+    stored_bytes: u64,
+}
+
+// Headline figures for the dashboard
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct DashboardStats {
+    registrations_this_month: u64,
+    // Undelivered mothers by stage
+    active_by_stage: Vec<(String, u64)>,
+    by_health_status: Vec<(String, u64)>,
+    critical_cases: u64,
+    pending_referrals: u64,
+    open_chw_alerts: u64,
+    // Critical cases, pending referrals and open health worker alerts
+    open_alerts: u64,
+    // Booked within the appointment reminder window, counted by day from today
+    upcoming_appointments: u64,
+    generated_at: u64,
+}
+
+// Care received by the mothers due to deliver in one month
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct CohortReport {
+    // "YYYY-MM" of the expected delivery date
+    edd_month: String,
+    mothers: u64,
+    average_visits: f64,
+    // Mothers by ANC visits: "0", "1-3", "4-7" and "8+"
+    visits: Vec<(String, u64)>,
+    // Mothers by risk tier over the pregnancy
+    risk: Vec<(String, u64)>,
+    delivered: u64,
+    outcomes: Vec<(String, u64)>,
+    delivery_modes: Vec<(String, u64)>,
+    facility_deliveries: u64,
+}
+
+// Why anti-D immunoglobulin was given
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, Debug)]
+enum AntiDIndication {
+    // Routine antenatal prophylaxis around 28 weeks
+    Antenatal,
+    // Within 72 hours of the birth of an Rh-positive baby
+    Postpartum,
+    // Bleeding, abdominal trauma, miscarriage or a procedure
+    SensitizingEvent,
+}
+
+// Anti-D immunoglobulin given to an Rh-negative mother
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct AntiDDose {
+    id: u64,
+    mother_id: u64,
+    given_at: u64,
+    dose_mcg: u32,
+    indication: AntiDIndication,
+    facility_code: Option<String>,
+    recorded_by: Principal,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct AntiDPayload {
+    // Defaults to now
+    given_at: Option<u64>,
+    dose_mcg: u32,
+    indication: AntiDIndication,
+    facility_code: Option<String>,
+}
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, Debug, PartialEq)]
+enum ScreeningTest {
+    Syphilis,
+    HepatitisB,
+}
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, Debug, PartialEq)]
+enum ScreeningResult {
+    Negative,
+    // Reactive syphilis test or HBsAg positive
+    Positive,
+}
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, Debug, PartialEq)]
+enum TreatmentStatus {
+    NotStarted,
+    InProgress,
+    Completed,
+    // Sent elsewhere for treatment
+    Referred,
+}
+
+// Syphilis or hepatitis B screening in a pregnancy, with treatment when positive
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct InfectionScreening {
+    id: u64,
+    mother_id: u64,
+    test: ScreeningTest,
+    screened_at: u64,
+    result: ScreeningResult,
+    // Set for positive results
+    treatment: Option<TreatmentStatus>,
+    // Benzathine penicillin injections for syphilis; unset when not counted in doses
+    doses_required: Option<u8>,
+    doses_given: Option<u8>,
+    facility_code: Option<String>,
+    recorded_by: Principal,
+    updated_at: u64,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct ScreeningPayload {
+    test: ScreeningTest,
+    // Defaults to now
+    screened_at: Option<u64>,
+    result: ScreeningResult,
+    facility_code: Option<String>,
+}
+
+// Treatment of a positive screening; absent fields keep their value
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct TreatmentPayload {
+    status: TreatmentStatus,
+    doses_required: Option<u8>,
+    doses_given: Option<u8>,
+}
+
+// A mother's syphilis and hepatitis B screening in the current pregnancy
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct ScreeningStatus {
+    mother_id: u64,
+    // Latest screening of each kind this pregnancy
+    syphilis: Option<InfectionScreening>,
+    hepatitis_b: Option<InfectionScreening>,
+    // Not screened this pregnancy and past the configured gestational age
+    overdue: Vec<ScreeningTest>,
+    // Positive and treatment not completed
+    incomplete_treatment: Vec<ScreeningTest>,
+}
+
+// A mother's Rh status and anti-D prophylaxis in the current pregnancy
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct RhStatus {
+    mother_id: u64,
+    rh_negative: bool,
+    doses: Vec<AntiDDose>,
+    // Rh-negative, from 28 weeks and no antenatal dose given in this pregnancy
+    prophylaxis_due: bool,
+}
+
+// A volunteer in the opt-in blood donor registry
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct BloodDonor {
+    id: u64,
+    name: String,
+    blood_type: String,
+    // Town, ward or sub-county
+    location: String,
+    // Facility the donor usually donates at
+    facility_code: Option<String>,
+    phone: String,
+    // Whether facilities may see the phone number and call the donor
+    contact_consent: bool,
+    last_donated_at: Option<u64>,
+    registered_by: Principal,
+    registered_at: u64,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct BloodDonorPayload {
+    name: String,
+    blood_type: String,
+    location: String,
+    facility_code: Option<String>,
+    phone: String,
+    contact_consent: bool,
+    last_donated_at: Option<u64>,
+}
+
+// A donor whose red cells a mother can receive
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct DonorMatch {
+    donor_id: u64,
+    name: String,
+    blood_type: String,
+    location: String,
+    facility_code: Option<String>,
+    // Only with the donor's contact consent
+    phone: Option<String>,
+    // Same ABO group and Rh factor as the mother
+    exact_match: bool,
+    last_donated_at: Option<u64>,
+}
+
+// Problems a data clerk should fix in a profile
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize)]
+enum DataQualityIssue {
+    MissingContact,
+    ImplausibleAge,
+    UnknownBloodType,
+    // More than 300 days away, longer than a pregnancy
+    ImplausibleEdd,
+    // EDD has passed and no delivery has been recorded
+    OverdueWithoutDelivery,
+    // Undelivered and not seen for more than 8 weeks
+    NoRecentVisit,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct DataQualityFlag {
+    mother_id: u64,
+    name: String,
+    facility_code: Option<String>,
+    issues: Vec<DataQualityIssue>,
+}
+
+// Counts `aggregate` can report, kept per day and facility
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize)]
+enum AggregateMetric {
+    Registrations,
+    Visits,
+    CriticalVisits,
+    Referrals,
+    Deliveries,
+}
+
+impl AggregateMetric {
+    fn counter_name(self) -> &'static str {
+        match self {
+            AggregateMetric::Registrations => "registrations",
+            AggregateMetric::Visits => "visits",
+            AggregateMetric::CriticalVisits => "critical_visits",
+            AggregateMetric::Referrals => "referrals",
+            AggregateMetric::Deliveries => "deliveries",
+        }
+    }
+}
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize)]
+enum AggregateGroup {
+    Total,
+    Facility,
+}
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize)]
+enum TimeBucket {
+    Day,
+    // Monday to Sunday
+    Week,
+    Month,
+}
+
+// One bucket of an aggregate; `group` is the facility when grouped by facility
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct AggregatePoint {
+    // "YYYY-MM-DD" (the Monday for weeks) or "YYYY-MM"
+    bucket: String,
+    group: Option<String>,
+    value: u64,
+}
+
+// Indicators facilities can be compared on
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize)]
+enum FacilityMetric {
+    Registrations,
+    Visits,
+    CriticalVisits,
+    ReferralsMade,
+    Deliveries,
+    // Percent of mothers due in the period with 4 or more visits
+    Anc4Coverage,
+}
+
+// A facility's value for a metric, ranked against the others
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct FacilityComparison {
+    facility_code: String,
+    value: f64,
+    // 1 is the highest value
+    rank: u32,
+    // Value over the period of the same length just before
+    previous_value: f64,
+    // "up", "down" or "flat" against the previous period
+    trend: String,
+}
+
+// Reports that can be generated on a schedule
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, Debug)]
+enum ReportKind {
+    // Registrations, visits, critical visits, referrals and deliveries per facility
+    FacilityMonthlySummary,
+    // Undelivered mothers who missed their last booked appointment
+    DefaulterList,
+}
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize)]
+enum ReportFrequency {
+    Daily,
+    Weekly,
+    Monthly,
+}
+
+// A recurring report; each run covers the period since the previous run boundary
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct ReportSchedule {
+    id: u64,
+    kind: ReportKind,
+    frequency: ReportFrequency,
+    // Limit the report to one facility
+    facility_code: Option<String>,
+    next_run_at: u64,
+    last_snapshot_id: Option<u64>,
+    created_by: Principal,
+    created_at: u64,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct ReportSchedulePayload {
+    kind: ReportKind,
+    frequency: ReportFrequency,
+    facility_code: Option<String>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct FacilitySummaryRow {
+    facility_code: String,
+    registrations: u64,
+    visits: u64,
+    critical_visits: u64,
+    referrals_made: u64,
+    deliveries: u64,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct Defaulter {
+    mother_id: u64,
+    name: String,
+    facility_code: Option<String>,
+    emergency_contact: String,
+    missed_appointment: u64,
+    last_visit: u64,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+enum ReportRow {
+    Facility(FacilitySummaryRow),
+    Defaulter(Defaulter),
+}
+
+// Stored part of a snapshot; its rows are kept in REPORT_ROWS
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct ReportSnapshotHeader {
+    id: u64,
+    schedule_id: u64,
+    kind: ReportKind,
+    facility_code: Option<String>,
+    period_start: u64,
+    period_end: u64,
+    generated_at: u64,
+    row_count: u64,
+}
+
+// A report as generated by its schedule
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct ReportSnapshot {
+    header: ReportSnapshotHeader,
+    rows: Vec<ReportRow>,
+}
+
+// A facility's weekly count that departs from its own recent weeks
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct Anomaly {
+    id: u64,
+    facility_code: String,
+    // "critical_cases", "anemia_flags" or "referrals"
+    metric: String,
+    week_start: u64,
+    count: u64,
+    baseline_mean: f64,
+    baseline_sd: f64,
+    // "spike" or "drop"
+    direction: String,
+    detected_at: u64,
+}
+
+// Vitals that can be charted over time
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize)]
+enum Vital {
+    BloodPressure,
+    Weight,
+    Haemoglobin,
+    // Graded 0 (none) to 3 (+++)
+    Edema,
+    // Mid-upper arm circumference in cm
+    Muac,
+}
+
+// One reading in a vital's trend; blood pressure carries the diastolic as `secondary`
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct VitalPoint {
+    date: u64,
+    value: f64,
+    secondary: Option<f64>,
+}
+
+// Standard ANC coverage indicators for one facility, as percentages of the mothers due in the period
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct AncCoverage {
+    facility_code: String,
+    mothers: u64,
+    anc1_before_12_weeks: f64,
+    anc4_plus: f64,
+    anc8_plus: f64,
+    tt2_plus: f64,
+    ifas: f64,
+}
+
+// Canister-wide counts, maintained incrementally
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct DashboardCounters {
+    mothers: u64,
+    by_stage: Vec<(String, u64)>,
+    by_health_status: Vec<(String, u64)>,
+    // Includes archived records
+    health_records: u64,
+    records_this_month: u64,
+    referrals: u64,
+    critical_cases: u64,
+    pending_referrals: u64,
+    // Critical cases plus pending referrals
+    open_alerts: u64,
+}
+
+// Only the method and URL are used; the remaining fields are part of the interface
+#[allow(dead_code)]
+#[derive(candid::CandidType, Deserialize)]
+struct HttpRequest {
+    method: String,
+    url: String,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+#[derive(candid::CandidType, Serialize)]
+struct HttpResponse {
+    status_code: u16,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+    // Asks the gateway to repeat the request as an update call (http_request_update)
+    upgrade: Option<bool>,
+}
+
+// Stored values are framed as [layout version][payload], so a change to a stored type can
+// ship a decoder for its old layout instead of trapping every read of existing data. Values
+// written before framing have no version byte (they start with Candid's "DIDL" magic or
+// LZ_MARKER) and read as version 0.
+const STORAGE_VERSION: u8 = 1;
+
+fn seal(payload: Vec<u8>) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(payload.len() + 1);
+    bytes.push(STORAGE_VERSION);
+    bytes.extend(payload);
+    bytes
+}
+
+fn open_envelope(bytes: &[u8]) -> (u8, &[u8]) {
+    match bytes.first() {
+        Some(&version) if version != b'D' && version != LZ_MARKER => (version, &bytes[1..]),
+        _ => (0, bytes),
+    }
+}
+
+// Decode a stored value by its layout version. Versions 0 and 1 share the Candid layout.
+// When a type changes in a way Candid cannot bridge (a field added without `Option`, renamed
+// or retyped), bump STORAGE_VERSION and decode the old layout into the new type here.
+fn decode_stored<T: candid::CandidType + serde::de::DeserializeOwned>(bytes: &[u8]) -> T {
+    let (version, payload) = open_envelope(bytes);
+    match version {
+        0 | 1 => Decode!(decompress_stored(payload).as_ref(), T).unwrap_or_else(|e| {
+            ic_cdk::trap(&format!(
+                "Cannot decode stored {} (storage version {}): {}",
+                std::any::type_name::<T>(),
+                version,
+                e
+            ))
+        }),
+        _ => ic_cdk::trap(&format!(
+            "Stored {} has storage version {}, newer than this build",
+            std::any::type_name::<T>(),
+            version
+        )),
+    }
+}
+
+// Stored profiles and health records are LZ-compressed when that makes them smaller.
+// Compressed values start with LZ_MARKER; Candid encodings start with "DIDL", so values
+// written before compression still decode.
+const LZ_MARKER: u8 = 0xC1;
+const LZ_MIN_MATCH: usize = 4;
+const LZ_MAX_MATCH: usize = LZ_MIN_MATCH + u8::MAX as usize;
+const LZ_WINDOW: usize = u16::MAX as usize;
+const LZ_HASH_BITS: u32 = 12;
+
+fn compress_encoded(encoded: Vec<u8>) -> Vec<u8> {
+    let compressed = lz_compress(&encoded);
+    if compressed.len() < encoded.len() {
+        compressed
+    } else {
+        encoded
+    }
+}
+
+fn decompress_stored(bytes: &[u8]) -> Cow<[u8]> {
+    if bytes.first() == Some(&LZ_MARKER) {
+        Cow::Owned(lz_decompress(bytes).expect("Corrupt compressed record"))
+    } else {
+        Cow::Borrowed(bytes)
+    }
+}
+
+// LZSS: the marker, the input length as a LEB128 varint, then groups of eight tokens each
+// preceded by a flag byte. A set flag bit is a match (u16 LE offset back, length - LZ_MIN_MATCH);
+// a clear one is a literal byte.
+fn lz_compress(input: &[u8]) -> Vec<u8> {
+    let mut out = vec![LZ_MARKER];
+    let mut len = input.len();
+    loop {
+        let byte = (len & 0x7f) as u8;
+        len >>= 7;
+        if len == 0 {
+            out.push(byte);
+            break;
+        }
+        out.push(byte | 0x80);
+    }
+
+    let mut table = vec![usize::MAX; 1 << LZ_HASH_BITS];
+    let mut i = 0;
+    while i < input.len() {
+        let flags_at = out.len();
+        out.push(0);
+        for bit in 0..8 {
+            if i >= input.len() {
+                break;
+            }
+
+            let mut matched = None;
+            if i + LZ_MIN_MATCH <= input.len() {
+                let word = u32::from_le_bytes([input[i], input[i + 1], input[i + 2], input[i + 3]]);
+                let slot = (word.wrapping_mul(2_654_435_761) >> (32 - LZ_HASH_BITS)) as usize;
+                let candidate = table[slot];
+                table[slot] = i;
+                if candidate != usize::MAX
+                    && i - candidate <= LZ_WINDOW
+                    && input[candidate..candidate + LZ_MIN_MATCH] == input[i..i + LZ_MIN_MATCH]
+                {
+                    let longest = (input.len() - i).min(LZ_MAX_MATCH);
+                    let mut length = LZ_MIN_MATCH;
+                    while length < longest && input[candidate + length] == input[i + length] {
+                        length += 1;
+                    }
+                    matched = Some((i - candidate, length));
+                }
+            }
+
+            match matched {
+                Some((offset, length)) => {
+                    out[flags_at] |= 1 << bit;
+                    out.extend_from_slice(&(offset as u16).to_le_bytes());
+                    out.push((length - LZ_MIN_MATCH) as u8);
+                    i += length;
+                }
+                None => {
+                    out.push(input[i]);
+                    i += 1;
+                }
+            }
+        }
+    }
+    out
+}
+
+fn lz_decompress(input: &[u8]) -> Option<Vec<u8>> {
+    let (&marker, input) = input.split_first()?;
+    if marker != LZ_MARKER {
+        return None;
+    }
+
+    let mut len = 0usize;
+    let mut pos = 0;
+    for shift in (0..64).step_by(7) {
+        let byte = *input.get(pos)?;
+        pos += 1;
+        len |= ((byte & 0x7f) as usize) << shift;
+        if byte & 0x80 == 0 {
+            break;
+        }
+    }
+
+    let mut out = Vec::with_capacity(len);
+    while out.len() < len {
+        let flags = *input.get(pos)?;
+        pos += 1;
+        for bit in 0..8 {
+            if out.len() >= len {
+                break;
+            }
+            if flags & (1 << bit) == 0 {
+                out.push(*input.get(pos)?);
+                pos += 1;
+                continue;
+            }
+
+            let offset = u16::from_le_bytes([*input.get(pos)?, *input.get(pos + 1)?]) as usize;
+            let length = *input.get(pos + 2)? as usize + LZ_MIN_MATCH;
+            pos += 3;
+            if offset == 0 || offset > out.len() {
+                return None;
+            }
+            // Byte by byte, since a match may overlap the bytes it produces
+            let start = out.len() - offset;
+            for k in 0..length {
+                out.push(out[start + k]);
+            }
+        }
+    }
+    (out.len() == len).then_some(out)
+}
+
+// Implement Storable for MotherProfile
+impl Storable for MotherProfile {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(seal(compress_encoded(Encode!(self).unwrap())))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_stored(&bytes)
+    }
+}
+
+// Implement BoundedStorable for MotherProfile
+impl BoundedStorable for MotherProfile {
+    const MAX_SIZE: u32 = 16 * 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Implement Storable for HealthRecord
+impl Storable for HealthRecord {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(seal(compress_encoded(Encode!(self).unwrap())))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_stored(&bytes)
+    }
+}
+
+// Implement BoundedStorable for HealthRecord
+impl BoundedStorable for HealthRecord {
+    const MAX_SIZE: u32 = 16 * 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Stable memory, refusing writes while a backup is read out or a restore written in. Every map
+// lives here, so this keeps snapshots consistent without a guard in each endpoint.
+#[derive(Clone, Default)]
+struct GuardedMemory(DefaultMemoryImpl);
+
+impl ic_stable_structures::Memory for GuardedMemory {
+    fn size(&self) -> u64 {
+        self.0.size()
+    }
+
+    fn grow(&self, pages: u64) -> i64 {
+        ensure_stable_writes();
+        self.0.grow(pages)
+    }
+
+    fn read(&self, offset: u64, dst: &mut [u8]) {
+        self.0.read(offset, dst)
+    }
+
+    fn write(&self, offset: u64, src: &[u8]) {
+        ensure_stable_writes();
+        self.0.write(offset, src)
+    }
+}
+
+fn ensure_stable_writes() {
+    if MAINTENANCE.with(|maintenance| maintenance.borrow().is_some()) {
+        ic_cdk::trap("The canister is read-only while a backup or restore is in progress");
+    }
+}
+
+// Value in a map created when profiles and health records were bounded at 2048 bytes.
+// A stable map's value bound cannot grow, so these maps are only read to migrate them.
+struct LegacyBounded<T>(T);
+
+impl<T: Storable> Storable for LegacyBounded<T> {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        self.0.to_bytes()
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        LegacyBounded(T::from_bytes(bytes))
+    }
+}
+
+impl<T: Storable> BoundedStorable for LegacyBounded<T> {
+    const MAX_SIZE: u32 = 2048;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Implement Storable for Payment
+impl Storable for Payment {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(seal(Encode!(self).unwrap()))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_stored(&bytes)
+    }
+}
+
+// Implement BoundedStorable for Payment
+impl BoundedStorable for Payment {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for PayoutAccount {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(seal(Encode!(self).unwrap()))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_stored(&bytes)
+    }
+}
+
+impl BoundedStorable for PayoutAccount {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for FacilityStaff {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(seal(Encode!(self).unwrap()))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_stored(&bytes)
+    }
+}
+
+impl BoundedStorable for FacilityStaff {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Implement Storable for Referral
+impl Storable for Referral {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(seal(Encode!(self).unwrap()))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_stored(&bytes)
+    }
+}
+
+// Implement BoundedStorable for Referral
+impl BoundedStorable for Referral {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Implement Storable for CareProgress
+impl Storable for CareProgress {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(seal(Encode!(self).unwrap()))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_stored(&bytes)
+    }
+}
+
+// Implement BoundedStorable for CareProgress
+impl BoundedStorable for CareProgress {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Implement Storable for FacilityMetrics
+impl Storable for FacilityMetrics {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(seal(Encode!(self).unwrap()))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_stored(&bytes)
+    }
+}
+
+// Implement BoundedStorable for FacilityMetrics
+impl BoundedStorable for FacilityMetrics {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// String key for stable maps keyed by codes (facility codes etc.)
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct StringKey(String);
+
+impl Storable for StringKey {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Borrowed(self.0.as_bytes())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        StringKey(String::from_utf8(bytes.into_owned()).unwrap())
+    }
+}
+
+impl BoundedStorable for StringKey {
+    const MAX_SIZE: u32 = 128;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Health record key: ordered by mother, then by record id
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct RecordKey {
+    mother_id: u64,
+    seq: u64,
+}
+
+impl Storable for RecordKey {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        // Big-endian, so byte order matches key order
+        let mut bytes = self.mother_id.to_be_bytes().to_vec();
+        bytes.extend_from_slice(&self.seq.to_be_bytes());
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        let (mother_id, seq) = bytes.split_at(8);
+        RecordKey {
+            mother_id: u64::from_be_bytes(mother_id.try_into().unwrap()),
+            seq: u64::from_be_bytes(seq.try_into().unwrap()),
+        }
+    }
+}
+
+impl BoundedStorable for RecordKey {
+    const MAX_SIZE: u32 = 16;
+    const IS_FIXED_SIZE: bool = true;
+}
+
+// Addendum key: ordered by health record, then by addendum id
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct AddendumKey {
+    record_id: u64,
+    id: u64,
+}
+
+impl Storable for AddendumKey {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        let mut bytes = self.record_id.to_be_bytes().to_vec();
+        bytes.extend_from_slice(&self.id.to_be_bytes());
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        let (record_id, id) = bytes.split_at(8);
+        AddendumKey {
+            record_id: u64::from_be_bytes(record_id.try_into().unwrap()),
+            id: u64::from_be_bytes(id.try_into().unwrap()),
+        }
+    }
+}
+
+impl BoundedStorable for AddendumKey {
+    const MAX_SIZE: u32 = 16;
+    const IS_FIXED_SIZE: bool = true;
+}
+
+impl Storable for Addendum {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(seal(Encode!(self).unwrap()))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_stored(&bytes)
+    }
+}
+
+impl BoundedStorable for Addendum {
+    const MAX_SIZE: u32 = 8192;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Key for maps holding entities of every type; ids are only unique within a type
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct EntityKey {
+    tag: u8,
+    id: u64,
+}
+
+impl EntityKey {
+    fn new(entity_type: EntityType, id: u64) -> Self {
+        let tag = match entity_type {
+            EntityType::MotherProfile => 0,
+            EntityType::HealthRecord => 1,
+            EntityType::Referral => 2,
+            EntityType::Payment => 3,
+            EntityType::LabResult => 4,
+            EntityType::SelfReport => 5,
+        };
+        EntityKey { tag, id }
+    }
+}
+
+impl Storable for EntityKey {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        let mut bytes = vec![self.tag];
+        bytes.extend_from_slice(&self.id.to_be_bytes());
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        EntityKey {
+            tag: bytes[0],
+            id: u64::from_be_bytes(bytes[1..9].try_into().unwrap()),
+        }
+    }
+}
+
+impl BoundedStorable for EntityKey {
+    const MAX_SIZE: u32 = 9;
+    const IS_FIXED_SIZE: bool = true;
+}
+
+// Changelog index key: ordered by entity, then by changelog sequence number
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct AmendmentKey {
+    entity: EntityKey,
+    seq: u64,
+}
+
+impl Storable for AmendmentKey {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        let mut bytes = self.entity.to_bytes().into_owned();
+        bytes.extend_from_slice(&self.seq.to_be_bytes());
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        AmendmentKey {
+            entity: EntityKey::from_bytes(Cow::Borrowed(&bytes[..9])),
+            seq: u64::from_be_bytes(bytes[9..17].try_into().unwrap()),
+        }
+    }
+}
+
+impl BoundedStorable for AmendmentKey {
+    const MAX_SIZE: u32 = 17;
+    const IS_FIXED_SIZE: bool = true;
+}
+
+// Implement Storable for ChangeEntry
+impl Storable for ChangeEntry {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(seal(Encode!(self).unwrap()))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_stored(&bytes)
+    }
+}
+
+// Implement BoundedStorable for ChangeEntry
+impl BoundedStorable for ChangeEntry {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Implement Storable for LoggedChange (log entries are unbounded)
+impl Storable for LoggedChange {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(seal(Encode!(self).unwrap()))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_stored(&bytes)
+    }
+}
+
+// Implement Storable for FieldClocks
+impl Storable for FieldClocks {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(seal(Encode!(self).unwrap()))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_stored(&bytes)
+    }
+}
+
+// Implement BoundedStorable for FieldClocks
+impl BoundedStorable for FieldClocks {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Implement Storable for ClinicalCode
+impl Storable for ClinicalCode {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(seal(Encode!(self).unwrap()))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_stored(&bytes)
+    }
+}
+
+// Implement BoundedStorable for ClinicalCode
+impl BoundedStorable for ClinicalCode {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Implement Storable for LabResult
+impl Storable for LabResult {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(seal(Encode!(self).unwrap()))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_stored(&bytes)
+    }
+}
+
+// Implement BoundedStorable for LabResult
+impl BoundedStorable for LabResult {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Implement Storable for LedgerConfig
+impl Storable for LedgerConfig {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(seal(Encode!(self).unwrap()))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_stored(&bytes)
+    }
+}
+
+// Implement Storable for EntityRef
+impl Storable for EntityRef {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(seal(Encode!(self).unwrap()))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_stored(&bytes)
+    }
+}
+
+// Implement BoundedStorable for EntityRef
+impl BoundedStorable for EntityRef {
+    const MAX_SIZE: u32 = 64;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Implement Storable for UlidSeed
+impl Storable for UlidSeed {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(seal(Encode!(self).unwrap()))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_stored(&bytes)
+    }
+}
+
+// Implement Storable for CanisterConfig
+impl Storable for CanisterConfig {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(seal(Encode!(self).unwrap()))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_stored(&bytes)
+    }
+}
+
+// Implement Storable for ClockOffset
+impl Storable for AccessEntry {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(seal(Encode!(self).unwrap()))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_stored(&bytes)
+    }
+}
+
+impl BoundedStorable for AccessEntry {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for WellnessDay {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        let mut bytes = vec![self.mood];
+        bytes.extend_from_slice(&self.sleep_minutes.to_be_bytes());
+        bytes.extend_from_slice(self.note.as_bytes());
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        WellnessDay {
+            mood: bytes[0],
+            sleep_minutes: u16::from_be_bytes(bytes[1..3].try_into().unwrap()),
+            note: String::from_utf8_lossy(&bytes[3..]).into_owned(),
+        }
+    }
+}
+
+impl BoundedStorable for WellnessDay {
+    const MAX_SIZE: u32 = 3 + MAX_WELLNESS_NOTE_BYTES as u32;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for KickCountSession {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(seal(Encode!(self).unwrap()))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_stored(&bytes)
+    }
+}
+
+impl BoundedStorable for KickCountSession {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for DoseKey {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        let mut bytes = self.prescription_id.to_be_bytes().to_vec();
+        bytes.extend_from_slice(&self.day.to_be_bytes());
+        bytes.push(self.dose_index);
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        DoseKey {
+            prescription_id: u64::from_be_bytes(bytes[0..8].try_into().unwrap()),
+            day: u64::from_be_bytes(bytes[8..16].try_into().unwrap()),
+            dose_index: bytes[16],
+        }
+    }
+}
+
+impl BoundedStorable for DoseKey {
+    const MAX_SIZE: u32 = 17;
+    const IS_FIXED_SIZE: bool = true;
+}
+
+impl Storable for Prescription {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(seal(Encode!(self).unwrap()))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_stored(&bytes)
+    }
+}
+
+impl BoundedStorable for Prescription {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for DrugRule {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(seal(Encode!(self).unwrap()))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_stored(&bytes)
+    }
+}
+
+impl BoundedStorable for DrugRule {
+    const MAX_SIZE: u32 = 4096;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for BirthPlan {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(seal(Encode!(self).unwrap()))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_stored(&bytes)
+    }
+}
+
+impl BoundedStorable for BirthPlan {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for ReportSchedule {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(seal(Encode!(self).unwrap()))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_stored(&bytes)
+    }
+}
+
+impl BoundedStorable for ReportSchedule {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for ReportSnapshotHeader {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(seal(Encode!(self).unwrap()))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_stored(&bytes)
+    }
+}
+
+impl BoundedStorable for ReportSnapshotHeader {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for ReportRow {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(seal(Encode!(self).unwrap()))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_stored(&bytes)
+    }
+}
+
+impl BoundedStorable for ReportRow {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for PmtctRecord {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(seal(Encode!(self).unwrap()))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_stored(&bytes)
+    }
+}
+
+impl BoundedStorable for PmtctRecord {
+    const MAX_SIZE: u32 = 2048;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for InfectionScreening {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(seal(Encode!(self).unwrap()))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_stored(&bytes)
+    }
+}
+
+impl BoundedStorable for InfectionScreening {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for AntiDDose {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(seal(Encode!(self).unwrap()))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_stored(&bytes)
+    }
+}
+
+impl BoundedStorable for AntiDDose {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for Anomaly {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(seal(Encode!(self).unwrap()))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_stored(&bytes)
+    }
+}
+
+impl BoundedStorable for Anomaly {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for VisitFeedback {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(seal(Encode!(self).unwrap()))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_stored(&bytes)
+    }
+}
+
+impl BoundedStorable for VisitFeedback {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for CareTeam {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(seal(Encode!(self).unwrap()))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_stored(&bytes)
+    }
+}
+
+impl BoundedStorable for CareTeam {
+    const MAX_SIZE: u32 = 2048;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for Message {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(seal(Encode!(self).unwrap()))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_stored(&bytes)
+    }
+}
+
+impl BoundedStorable for Message {
+    const MAX_SIZE: u32 = 3072;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for ContentArticle {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(seal(Encode!(self).unwrap()))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_stored(&bytes)
+    }
+}
+
+impl BoundedStorable for ContentArticle {
+    const MAX_SIZE: u32 = 12 * 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for AccessGrant {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(seal(Encode!(self).unwrap()))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_stored(&bytes)
+    }
+}
+
+impl BoundedStorable for AccessGrant {
+    const MAX_SIZE: u32 = 128;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for SelfReport {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(seal(Encode!(self).unwrap()))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_stored(&bytes)
+    }
+}
+
+impl BoundedStorable for SelfReport {
+    const MAX_SIZE: u32 = 4096;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for ChwAlert {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(seal(Encode!(self).unwrap()))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_stored(&bytes)
+    }
+}
+
+impl BoundedStorable for ChwAlert {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for ShareToken {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(seal(Encode!(self).unwrap()))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_stored(&bytes)
+    }
+}
+
+impl BoundedStorable for ShareToken {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for SensitiveFields {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(seal(Encode!(self).unwrap()))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_stored(&bytes)
+    }
+}
+
+impl BoundedStorable for SensitiveFields {
+    const MAX_SIZE: u32 = 8192;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for AnonymizationPolicy {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(seal(Encode!(self).unwrap()))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_stored(&bytes)
+    }
+}
+
+impl Storable for RecommendationRules {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(seal(Encode!(self).unwrap()))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_stored(&bytes)
+    }
+}
+
+impl Storable for Deletion {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(seal(Encode!(self).unwrap()))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_stored(&bytes)
+    }
+}
+
+impl BoundedStorable for Deletion {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for SmsSettings {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(seal(Encode!(self).unwrap()))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_stored(&bytes)
+    }
+}
+
+impl Storable for SmsMessage {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(seal(Encode!(self).unwrap()))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_stored(&bytes)
+    }
+}
+
+impl BoundedStorable for SmsMessage {
+    const MAX_SIZE: u32 = 2048;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for UssdPin {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(seal(Encode!(self).unwrap()))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_stored(&bytes)
+    }
+}
+
+impl BoundedStorable for UssdPin {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for TransportVoucher {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(seal(Encode!(self).unwrap()))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_stored(&bytes)
+    }
+}
+
+impl BoundedStorable for TransportVoucher {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for DispatchSettings {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(seal(Encode!(self).unwrap()))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_stored(&bytes)
+    }
+}
+
+impl Storable for Dispatch {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(seal(Encode!(self).unwrap()))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_stored(&bytes)
+    }
+}
+
+impl BoundedStorable for Dispatch {
+    const MAX_SIZE: u32 = 2048;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for BloodDonor {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(seal(Encode!(self).unwrap()))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_stored(&bytes)
+    }
+}
+
+impl BoundedStorable for BloodDonor {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for Dispensing {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(seal(Encode!(self).unwrap()))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_stored(&bytes)
+    }
+}
+
+impl BoundedStorable for Dispensing {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for StockLevel {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(seal(Encode!(self).unwrap()))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_stored(&bytes)
+    }
+}
+
+impl BoundedStorable for StockLevel {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for ExternalIdentifiers {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(seal(Encode!(self).unwrap()))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_stored(&bytes)
+    }
+}
+
+impl BoundedStorable for ExternalIdentifiers {
+    const MAX_SIZE: u32 = 8192;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for TeleConsult {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(seal(Encode!(self).unwrap()))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_stored(&bytes)
+    }
+}
+
+impl BoundedStorable for TeleConsult {
+    const MAX_SIZE: u32 = 8192;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for CheckIn {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(seal(Encode!(self).unwrap()))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_stored(&bytes)
+    }
+}
+
+impl BoundedStorable for CheckIn {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for Handoff {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(seal(Encode!(self).unwrap()))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_stored(&bytes)
+    }
+}
+
+impl BoundedStorable for Handoff {
+    const MAX_SIZE: u32 = 8192;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for ClinicianTask {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(seal(Encode!(self).unwrap()))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_stored(&bytes)
+    }
+}
+
+impl BoundedStorable for ClinicianTask {
+    const MAX_SIZE: u32 = 2048;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for ChangeRequest {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(seal(Encode!(self).unwrap()))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_stored(&bytes)
+    }
+}
+
+impl BoundedStorable for ChangeRequest {
+    const MAX_SIZE: u32 = 4096;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for OrphanReport {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(seal(Encode!(self).unwrap()))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_stored(&bytes)
+    }
+}
+
+impl Storable for CareChecklists {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(seal(Encode!(self).unwrap()))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_stored(&bytes)
+    }
+}
+
+impl Storable for NoteTemplates {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(seal(Encode!(self).unwrap()))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_stored(&bytes)
+    }
+}
+
+impl Storable for ClockOffset {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(seal(Encode!(self).unwrap()))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_stored(&bytes)
+    }
+}
+
+// Implement Storable for SyncConfig
+impl Storable for SyncConfig {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(seal(Encode!(self).unwrap()))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_stored(&bytes)
+    }
+}
+
+// Implement Storable for ArchiveConfig
+impl Storable for ArchiveConfig {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(seal(Encode!(self).unwrap()))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_stored(&bytes)
+    }
+}
+
+// Implement Storable for ArchivePointer
+impl Storable for ArchivePointer {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(seal(Encode!(self).unwrap()))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_stored(&bytes)
+    }
+}
+
+// Implement BoundedStorable for ArchivePointer
+impl BoundedStorable for ArchivePointer {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Implement Storable for ShardConfig
+impl Storable for ShardConfig {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(seal(Encode!(self).unwrap()))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_stored(&bytes)
+    }
+}
+
+// Implement Storable for ShardRole
+impl Storable for ShardRole {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(seal(Encode!(self).unwrap()))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_stored(&bytes)
+    }
+}
+
+// Implement Storable for WasmChunk
+impl Storable for WasmChunk {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Borrowed(&self.0)
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        WasmChunk(bytes.into_owned())
+    }
+}
+
+// Implement BoundedStorable for WasmChunk
+impl BoundedStorable for WasmChunk {
+    const MAX_SIZE: u32 = SHARD_WASM_CHUNK_SIZE as u32;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Implement Storable for ExportJob
+impl Storable for ExportJob {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(seal(Encode!(self).unwrap()))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_stored(&bytes)
+    }
+}
+
+// Implement BoundedStorable for ExportJob
+impl BoundedStorable for ExportJob {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for ChunkKey {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        // Big-endian, so byte order matches key order
+        let mut bytes = self.job_id.to_be_bytes().to_vec();
+        bytes.extend_from_slice(&self.index.to_be_bytes());
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        let (job_id, index) = bytes.split_at(8);
+        ChunkKey {
+            job_id: u64::from_be_bytes(job_id.try_into().unwrap()),
+            index: u64::from_be_bytes(index.try_into().unwrap()),
+        }
+    }
+}
+
+impl BoundedStorable for ChunkKey {
+    const MAX_SIZE: u32 = 16;
+    const IS_FIXED_SIZE: bool = true;
+}
+
+// Implement Storable for ExportChunk
+impl Storable for ExportChunk {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Borrowed(&self.0)
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        ExportChunk(bytes.into_owned())
+    }
+}
+
+// Implement BoundedStorable for ExportChunk
+impl BoundedStorable for ExportChunk {
+    const MAX_SIZE: u32 = EXPORT_CHUNK_SIZE as u32;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for IdempotentResult {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(seal(Encode!(self).unwrap()))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_stored(&bytes)
+    }
+}
+
+impl BoundedStorable for IdempotentResult {
+    const MAX_SIZE: u32 = 64;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Implement Storable for Tombstone
+impl Storable for Tombstone {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(seal(Encode!(self).unwrap()))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_stored(&bytes)
+    }
+}
+
+// Implement BoundedStorable for Tombstone
+impl BoundedStorable for Tombstone {
+    const MAX_SIZE: u32 = 128;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for ErasureRecord {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(seal(Encode!(self).unwrap()))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_stored(&bytes)
+    }
+}
+
+impl BoundedStorable for ErasureRecord {
+    const MAX_SIZE: u32 = 4096;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Implement Storable for SigningKey
+impl Storable for SigningKey {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(seal(Encode!(self).unwrap()))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_stored(&bytes)
+    }
+}
+
+impl BoundedStorable for SigningKey {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Implement Storable for TrustedPeers
+impl Storable for TrustedPeers {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(seal(Encode!(self).unwrap()))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_stored(&bytes)
+    }
+}
+
+// Thread local storage
+thread_local! {
+    static MEMORY_MANAGER: RefCell<MemoryManager<GuardedMemory>> = RefCell::new(
+        MemoryManager::init(GuardedMemory::default())
+    );
+
+    // Backup being read out or restore being written in; on the heap, so an upgrade ends it
+    static MAINTENANCE: RefCell<Option<Maintenance>> = const { RefCell::new(None) };
+
+    // Mother profile ids; shared by every entity type before each had its own sequence
+    static MOTHER_ID_SEQ: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(0))), 0)
+            .expect("Cannot create mother id sequence")
+    );
+
+    // Profiles bounded at 2048 bytes; emptied on upgrade
+    static LEGACY_PROFILE_STORAGE: RefCell<StableBTreeMap<u64, LegacyBounded<MotherProfile>, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(1))))
+    );
+
+    // Keyed by record id before health records were grouped by mother; emptied on upgrade
+    static LEGACY_HEALTH_RECORD_STORAGE: RefCell<StableBTreeMap<u64, LegacyBounded<HealthRecord>, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(2))))
+    );
+
+    static PAYMENT_STORAGE: RefCell<StableBTreeMap<u64, Payment, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(3))))
+    );
+
+    static LEDGER_CONFIG: RefCell<Cell<LedgerConfig, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(4))), LedgerConfig::default())
+            .expect("Cannot create ledger config")
+    );
+
+    static REFERRAL_STORAGE: RefCell<StableBTreeMap<u64, Referral, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(5))))
+    );
+
+    static CARE_PROGRESS: RefCell<StableBTreeMap<u64, CareProgress, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(6))))
+    );
+
+    static FACILITY_METRICS: RefCell<StableBTreeMap<StringKey, FacilityMetrics, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(7))))
+    );
+
+    static SIGNING_KEY: RefCell<Cell<SigningKey, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(8))), SigningKey::default())
+            .expect("Cannot create signing key")
+    );
+
+    static TRUSTED_PEERS: RefCell<Cell<TrustedPeers, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(9))), TrustedPeers::default())
+            .expect("Cannot create trusted peers")
+    );
+
+    static CODE_REGISTRY: RefCell<StableBTreeMap<StringKey, ClinicalCode, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(10))))
+    );
+
+    static LAB_RESULT_STORAGE: RefCell<StableBTreeMap<u64, LabResult, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(11))))
+    );
+
+    // Idempotency slot -> bare id, from before results carried their time; emptied on upgrade
+    static LEGACY_IDEMPOTENCY_KEYS: RefCell<StableBTreeMap<StringKey, u64, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(12))))
+    );
+
+    // Delta-sync feed: change sequence number -> latest change of one entity
+    static CHANGE_FEED: RefCell<StableBTreeMap<u64, ChangeEntry, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(13))))
+    );
+
+    // Change index keyed by bare id, from when ids were unique across entity types; emptied on upgrade
+    static LEGACY_CHANGE_INDEX: RefCell<StableBTreeMap<u64, u64, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(14))))
+    );
+
+    static CHANGE_SEQ: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(15))), 0)
+            .expect("Cannot create change sequence")
+    );
+
+    static PROFILE_FIELD_CLOCKS: RefCell<StableBTreeMap<u64, FieldClocks, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(16))))
+    );
+
+    // Memories 17 and 18 held the changelog before diffs were sealed; scrubbed by the migration
+    static CHANGE_LOG: RefCell<StableLog<LoggedChange, Memory, Memory>> = RefCell::new(
+        StableLog::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(137))),
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(138))),
+        )
+        .expect("Cannot create change log")
+    );
+
+    // Tombstones keyed by bare id; emptied on upgrade
+    static LEGACY_TOMBSTONES: RefCell<StableBTreeMap<u64, Tombstone, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(19))))
+    );
+
+    static SYNC_CONFIG: RefCell<Cell<SyncConfig, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(20))), SyncConfig::default())
+            .expect("Cannot create sync config")
+    );
+
+    static ULID_INDEX: RefCell<StableBTreeMap<StringKey, EntityRef, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(21))))
+    );
+
+    static ULID_SEED: RefCell<Cell<UlidSeed, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(22))), UlidSeed::default())
+            .expect("Cannot create ULID seed")
+    );
+
+    // Health records grouped by mother but bounded at 2048 bytes; emptied on upgrade
+    static LEGACY_KEYED_HEALTH_RECORD_STORAGE: RefCell<StableBTreeMap<RecordKey, LegacyBounded<HealthRecord>, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(23))))
+    );
+
+    // Record id -> mother id, to find a health record by id alone
+    static HEALTH_RECORD_MOTHERS: RefCell<StableBTreeMap<u64, u64, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(24))))
+    );
+
+    static ARCHIVE_CONFIG: RefCell<Cell<ArchiveConfig, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(25))), ArchiveConfig::default())
+            .expect("Cannot create archive config")
+    );
+
+    // Mother id -> where her archived health records are
+    static ARCHIVE_POINTERS: RefCell<StableBTreeMap<u64, ArchivePointer, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(26))))
+    );
+
+    static SHARD_CONFIG: RefCell<Cell<ShardConfig, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(27))), ShardConfig::default())
+            .expect("Cannot create shard config")
+    );
+
+    static SHARD_ROLE: RefCell<Cell<ShardRole, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(28))), ShardRole::default())
+            .expect("Cannot create shard role")
+    );
+
+    // Wasm module installed on new shards, in upload order
+    static SHARD_WASM: RefCell<StableBTreeMap<u64, WasmChunk, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(29))))
+    );
+
+    static PROFILE_STORAGE: RefCell<StableBTreeMap<u64, MotherProfile, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(30))))
+    );
+
+    // Health records keyed by (mother_id, record id), so a mother's history is one contiguous range
+    static HEALTH_RECORD_STORAGE: RefCell<StableBTreeMap<RecordKey, HealthRecord, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(31))))
+    );
+
+    // Dashboard counters ("mothers", "stage:<stage>", "records:<YYYY-MM>", ...) kept current on every write
+    static COUNTERS: RefCell<StableBTreeMap<StringKey, u64, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(32))))
+    );
+
+    static EXPORT_JOBS: RefCell<StableBTreeMap<u64, ExportJob, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(33))))
+    );
+
+    static EXPORT_CHUNKS: RefCell<StableBTreeMap<ChunkKey, ExportChunk, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(34))))
+    );
+
+    static EXPORT_JOB_SEQ: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(35))), 0)
+            .expect("Cannot create export job counter")
+    );
+
+    // Layout of the data in stable memory, as the index of the last migration applied
+    static SCHEMA_VERSION: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(36))), 0)
+            .expect("Cannot create schema version")
+    );
+
+    static CONFIG: RefCell<Cell<CanisterConfig, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(37))), CanisterConfig::default())
+            .expect("Cannot create canister config")
+    );
+
+    // Entity -> its current sequence number in the change feed
+    static CHANGE_INDEX: RefCell<StableBTreeMap<EntityKey, u64, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(38))))
+    );
+
+    static TOMBSTONES: RefCell<StableBTreeMap<EntityKey, Tombstone, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(39))))
+    );
+
+    static HEALTH_RECORD_ID_SEQ: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(40))), 0)
+            .expect("Cannot create health record id sequence")
+    );
+
+    static REFERRAL_ID_SEQ: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(41))), 0)
+            .expect("Cannot create referral id sequence")
+    );
+
+    static PAYMENT_ID_SEQ: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(42))), 0)
+            .expect("Cannot create payment id sequence")
+    );
+
+    static LAB_RESULT_ID_SEQ: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(43))), 0)
+            .expect("Cannot create lab result id sequence")
+    );
+
+    static CLOCK_OFFSET: RefCell<Cell<ClockOffset, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(45))), ClockOffset::default())
+            .expect("Cannot create clock offset")
+    );
+
+    // Mother id -> when seed_demo_data created her
+    static DEMO_MOTHERS: RefCell<StableBTreeMap<u64, u64, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(44))))
+    );
+
+    // Principal text -> when it was granted field key access
+    static FIELD_READERS: RefCell<StableBTreeMap<StringKey, u64, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(46))))
+    );
+
+    // Mother id -> audit entry of her erasure
+    static ERASURES: RefCell<StableBTreeMap<u64, ErasureRecord, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(47))))
+    );
+
+    // Entities removed by an erasure -> mother id; their changelog events are masked
+    static ERASED_ENTITIES: RefCell<StableBTreeMap<EntityKey, u64, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(48))))
+    );
+
+    // Principal text -> the mother who signs in with it
+    static MOTHER_PRINCIPALS: RefCell<StableBTreeMap<StringKey, u64, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(49))))
+    );
+
+    static RESEARCH_POLICY: RefCell<Cell<AnonymizationPolicy, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(50))), AnonymizationPolicy::default())
+            .expect("Cannot create research policy")
+    );
+
+    static SENSITIVE_FIELDS: RefCell<StableBTreeMap<u64, SensitiveFields, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(51))))
+    );
+
+    // Principal text -> when it was granted access to sensitive fields
+    static SENSITIVE_READERS: RefCell<StableBTreeMap<StringKey, u64, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(52))))
+    );
+
+    // Reads of each mother's data, oldest first within a mother
+    static ACCESS_LOG: RefCell<StableBTreeMap<RecordKey, AccessEntry, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(53))))
+    );
+
+    static ACCESS_SEQ: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(54))), 0)
+            .expect("Cannot create access log sequence")
+    );
+
+    static ADDENDA: RefCell<StableBTreeMap<AddendumKey, Addendum, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(55))))
+    );
+
+    static ADDENDUM_ID_SEQ: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(56))), 0)
+            .expect("Cannot create addendum id sequence")
+    );
+
+    // Hex SHA-256 of a share token -> the token's grant
+    static SHARE_TOKENS: RefCell<StableBTreeMap<StringKey, ShareToken, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(57))))
+    );
+
+    static SELF_REPORT_STORAGE: RefCell<StableBTreeMap<u64, SelfReport, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(58))))
+    );
+
+    static SELF_REPORT_ID_SEQ: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(59))), 0)
+            .expect("Cannot create self report id sequence")
+    );
+
+    // Mother id -> the community health worker following her up
+    static MOTHER_CHWS: RefCell<StableBTreeMap<u64, AccessGrant, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(60))))
+    );
+
+    static CHW_ALERTS: RefCell<StableBTreeMap<u64, ChwAlert, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(61))))
+    );
+
+    static CHW_ALERT_ID_SEQ: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(62))), 0)
+            .expect("Cannot create alert id sequence")
+    );
+
+    // (mother id, day number) -> that day's wellness journal entry
+    static WELLNESS_JOURNAL: RefCell<StableBTreeMap<RecordKey, WellnessDay, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(63))))
+    );
+
+    // (mother id, session id) -> kick count session
+    static KICK_COUNTS: RefCell<StableBTreeMap<RecordKey, KickCountSession, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(64))))
+    );
+
+    // (mother id, start time) -> duration in seconds
+    static CONTRACTIONS: RefCell<StableBTreeMap<RecordKey, u32, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(66))))
+    );
+
+    static CONTENT_LIBRARY: RefCell<StableBTreeMap<u64, ContentArticle, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(67))))
+    );
+
+    static CONTENT_ID_SEQ: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(68))), 0)
+            .expect("Cannot create content id sequence")
+    );
+
+    static RECOMMENDATION_RULES: RefCell<Cell<RecommendationRules, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(69))), RecommendationRules::default())
+            .expect("Cannot create recommendation rules")
+    );
+
+    static PRESCRIPTIONS: RefCell<StableBTreeMap<u64, Prescription, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(70))))
+    );
+
+    static PRESCRIPTION_ID_SEQ: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(71))), 0)
+            .expect("Cannot create prescription id sequence")
+    );
+
+    // Dose -> when the mother marked it taken
+    static DOSES_TAKEN: RefCell<StableBTreeMap<DoseKey, u64, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(72))))
+    );
+
+    static CARE_TEAMS: RefCell<StableBTreeMap<u64, CareTeam, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(73))))
+    );
+
+    // (mother id, message id) -> message
+    static MESSAGES: RefCell<StableBTreeMap<RecordKey, Message, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(74))))
+    );
+
+    static MESSAGE_ID_SEQ: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(75))), 0)
+            .expect("Cannot create message id sequence")
+    );
+
+    static BIRTH_PLANS: RefCell<StableBTreeMap<u64, BirthPlan, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(76))))
+    );
+
+    // Health record id -> feedback on that visit
+    static VISIT_FEEDBACK: RefCell<StableBTreeMap<u64, VisitFeedback, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(77))))
+    );
+
+    static KICK_COUNT_ID_SEQ: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(65))), 0)
+            .expect("Cannot create kick count id sequence")
+    );
+
+    static ANOMALIES: RefCell<StableBTreeMap<u64, Anomaly, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(78))))
+    );
+
+    static ANOMALY_ID_SEQ: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(79))), 0)
+            .expect("Cannot create anomaly id sequence")
+    );
+
+    // Last week (Monday-based, counted from the epoch) checked for anomalies
+    static ANOMALY_SCAN_WEEK: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(80))), 0)
+            .expect("Cannot create anomaly scan week")
+    );
+
+    static REPORT_SCHEDULES: RefCell<StableBTreeMap<u64, ReportSchedule, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(81))))
+    );
+
+    static REPORT_SCHEDULE_ID_SEQ: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(82))), 0)
+            .expect("Cannot create report schedule id sequence")
+    );
+
+    static REPORT_SNAPSHOTS: RefCell<StableBTreeMap<u64, ReportSnapshotHeader, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(83))))
+    );
+
+    // (snapshot id, row index) -> row; rows are stored apart so a large report is not one value
+    static REPORT_ROWS: RefCell<StableBTreeMap<RecordKey, ReportRow, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(84))))
+    );
+
+    static REPORT_SNAPSHOT_ID_SEQ: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(85))), 0)
+            .expect("Cannot create report snapshot id sequence")
+    );
+
+    // Principal text -> when it was granted the county role, which can compare facilities
+    static COUNTY_OFFICERS: RefCell<StableBTreeMap<StringKey, u64, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(86))))
+    );
+
+    // (mother id, dose id) -> anti-D immunoglobulin given to her
+    static ANTI_D_DOSES: RefCell<StableBTreeMap<RecordKey, AntiDDose, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(87))))
+    );
+
+    static ANTI_D_ID_SEQ: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(88))), 0)
+            .expect("Cannot create anti-D id sequence")
+    );
+
+    // Mother id -> PMTCT care; sensitive readers only, like SENSITIVE_FIELDS
+    static PMTCT_RECORDS: RefCell<StableBTreeMap<u64, PmtctRecord, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(89))))
+    );
+
+    // (mother id, screening id) -> syphilis or hepatitis B screening and its treatment
+    static SCREENINGS: RefCell<StableBTreeMap<RecordKey, InfectionScreening, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(90))))
+    );
+
+    static SCREENING_ID_SEQ: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(91))), 0)
+            .expect("Cannot create screening id sequence")
+    );
+
+    // Normalized drug name -> contraindications and interactions checked on prescribing
+    static DRUG_RULES: RefCell<StableBTreeMap<StringKey, DrugRule, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(92))))
+    );
+
+    static NOTE_TEMPLATES: RefCell<Cell<NoteTemplates, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(93))), NoteTemplates::default())
+            .expect("Cannot create note templates")
+    );
+
+    // (entity, changelog seq) -> change time; finds an entity's changes without scanning the log
+    static AMENDMENT_INDEX: RefCell<StableBTreeMap<AmendmentKey, u64, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(94))))
+    );
+
+    // Soft deletions awaiting purge. A deleted profile moves to DELETED_PROFILES with her health
+    // records; a record deleted on its own moves to DELETED_RECORDS with an entry of its own here.
+    static DELETIONS: RefCell<StableBTreeMap<EntityKey, Deletion, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(95))))
+    );
+
+    static DELETED_PROFILES: RefCell<StableBTreeMap<u64, MotherProfile, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(96))))
+    );
+
+    static DELETED_RECORDS: RefCell<StableBTreeMap<RecordKey, HealthRecord, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(97))))
+    );
+
+    static ORPHAN_REPORT: RefCell<Cell<OrphanReport, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(98))), OrphanReport::default())
+            .expect("Cannot create orphan report")
+    );
+
+    static CHANGE_REQUESTS: RefCell<StableBTreeMap<u64, ChangeRequest, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(99))))
+    );
+
+    static CHANGE_REQUEST_ID_SEQ: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(100))), 0)
+            .expect("Cannot create change request id sequence")
+    );
+
+    // Principals who approve identity edits, besides controllers and admins
+    static SUPERVISORS: RefCell<GrantStore> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(101))))
+    );
+
+    static CLINICIAN_TASKS: RefCell<StableBTreeMap<u64, ClinicianTask, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(102))))
+    );
+
+    static CLINICIAN_TASK_ID_SEQ: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(103))), 0)
+            .expect("Cannot create clinician task id sequence")
+    );
+
+    static HANDOFFS: RefCell<StableBTreeMap<u64, Handoff, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(104))))
+    );
+
+    static HANDOFF_ID_SEQ: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(105))), 0)
+            .expect("Cannot create handoff id sequence")
+    );
+
+    // Facility code -> id of its latest handoff
+    static LATEST_HANDOFFS: RefCell<StableBTreeMap<StringKey, u64, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(106))))
+    );
+
+    static CHECK_INS: RefCell<StableBTreeMap<u64, CheckIn, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(107))))
+    );
+
+    static CHECK_IN_ID_SEQ: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(108))), 0)
+            .expect("Cannot create check-in id sequence")
+    );
+
+    static CARE_CHECKLISTS: RefCell<Cell<CareChecklists, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(109))), CareChecklists::default())
+            .expect("Cannot create care checklists")
+    );
+
+    static SMS_SETTINGS: RefCell<Cell<SmsSettings, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(110))), SmsSettings::default())
+            .expect("Cannot create SMS settings")
+    );
+
+    static SMS_OUTBOX: RefCell<StableBTreeMap<u64, SmsMessage, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(111))))
+    );
+
+    static SMS_ID_SEQ: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(112))), 0)
+            .expect("Cannot create SMS id sequence")
+    );
+
+    // Idempotency key -> message id, so a message is queued at most once
+    static SMS_KEYS: RefCell<StableBTreeMap<StringKey, u64, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(113))))
+    );
+
+    // Provider message id -> message id, for delivery reports
+    static SMS_PROVIDER_IDS: RefCell<StableBTreeMap<StringKey, u64, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(114))))
+    );
+
+    // E.164 phone number -> USSD sign-in
+    static USSD_PINS: RefCell<StableBTreeMap<StringKey, UssdPin, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(115))))
+    );
+
+    // Principals of the USSD gateway services allowed to sign mothers in by phone and PIN
+    static USSD_GATEWAYS: RefCell<GrantStore> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(116))))
+    );
+
+    static TRANSPORT_VOUCHERS: RefCell<StableBTreeMap<u64, TransportVoucher, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(117))))
+    );
+
+    static TRANSPORT_VOUCHER_ID_SEQ: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(118))), 0)
+            .expect("Cannot create transport voucher id sequence")
+    );
+
+    static DISPATCH_SETTINGS: RefCell<Cell<DispatchSettings, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(119))), DispatchSettings::default())
+            .expect("Cannot create dispatch settings")
+    );
+
+    // Alert id -> its ambulance dispatch
+    static DISPATCHES: RefCell<StableBTreeMap<u64, Dispatch, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(120))))
+    );
+
+    static BLOOD_DONORS: RefCell<StableBTreeMap<u64, BloodDonor, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(121))))
+    );
+
+    static BLOOD_DONOR_ID_SEQ: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(122))), 0)
+            .expect("Cannot create blood donor id sequence")
+    );
+}
+
+// Continues the block above, which is as long as one `thread_local!` call can expand
+thread_local! {
+    static DISPENSINGS: RefCell<StableBTreeMap<u64, Dispensing, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(123))))
+    );
+
+    static DISPENSING_ID_SEQ: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(124))), 0)
+            .expect("Cannot create dispensing id sequence")
+    );
+
+    // "{facility_code}:{drug}" -> units held
+    static FACILITY_STOCK: RefCell<StableBTreeMap<StringKey, StockLevel, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(125))))
+    );
+
+    // Principals who dispense medication and keep facility stock
+    static PHARMACISTS: RefCell<GrantStore> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(126))))
+    );
+
+    static EXTERNAL_IDENTIFIERS: RefCell<StableBTreeMap<u64, ExternalIdentifiers, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(127))))
+    );
+
+    // Hash of (system, value) -> mother id
+    static EXTERNAL_IDENTIFIER_INDEX: RefCell<StableBTreeMap<StringKey, u64, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(128))))
+    );
+
+    static TELECONSULTS: RefCell<StableBTreeMap<u64, TeleConsult, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(129))))
+    );
+
+    static TELECONSULT_ID_SEQ: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(130))), 0)
+            .expect("Cannot create teleconsult id sequence")
+    );
+
+    // Owner principal -> account payments may be sent to
+    static PAYOUT_ACCOUNTS: RefCell<StableBTreeMap<StringKey, PayoutAccount, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(131))))
+    );
+
+    // Principals who record and settle payments, besides controllers and admins
+    static FINANCE_OFFICERS: RefCell<GrantStore> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(132))))
+    );
+
+    // HMAC key of the USSD PIN hashes, from raw_rand; never returned by any endpoint
+    static USSD_PIN_KEY: RefCell<Cell<SigningKey, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(133))), SigningKey::default())
+            .expect("Cannot create USSD PIN key")
+    );
+
+    // Principal -> the facility they work at
+    static FACILITY_STAFF: RefCell<StableBTreeMap<StringKey, FacilityStaff, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(134))))
+    );
+
+    // State the change-log keys are drawn from; each key advances it one way
+    static LOG_KEY_RATCHET: RefCell<Cell<SigningKey, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(135))), SigningKey::default())
+            .expect("Cannot create change-log key ratchet")
+    );
+
+    // Mother id -> key her changelog diffs are sealed under; removed when she is erased
+    static MOTHER_LOG_KEYS: RefCell<StableBTreeMap<u64, SigningKey, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(136))))
+    );
+
+    // Idempotency slot -> the profile or record created for it, purged after the retry window
+    static IDEMPOTENCY_KEYS: RefCell<StableBTreeMap<StringKey, IdempotentResult, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(139))))
+    );
+}
+
+// Error handling
+#[derive(candid::CandidType, Deserialize, Serialize)]
+enum Error {
+    NotFound { msg: String },
+    InvalidInput { msg: String },
+    SystemError { msg: String },
+    AuthorizationError { msg: String },
+    ValidationError { msg: String },
+    Conflict { msg: String },
+    FeatureDisabled { msg: String },
+    // Warnings the prescriber has not acknowledged
+    PrescriptionWarnings { msg: String, warnings: Vec<PrescriptionWarning> },
+}
+
+impl Error {
+    fn variant_name(&self) -> &'static str {
+        match self {
+            Error::NotFound { .. } => "NotFound",
+            Error::InvalidInput { .. } => "InvalidInput",
+            Error::SystemError { .. } => "SystemError",
+            Error::AuthorizationError { .. } => "AuthorizationError",
+            Error::ValidationError { .. } => "ValidationError",
+            Error::Conflict { .. } => "Conflict",
+            Error::FeatureDisabled { .. } => "FeatureDisabled",
+            Error::PrescriptionWarnings { .. } => "PrescriptionWarnings",
+        }
+    }
+}
+
+// Writes of a mutation that spans several maps, applied together by `commit`. Build and validate
+// every value first, then stage its writes: `commit` has no error to return, so a call either
+// fails before touching storage or makes all of its writes. A trap inside `commit` rolls the
+// whole call back. Ids are still taken up front; a failed call only leaves a gap in the sequence.
+#[derive(Default)]
+struct WriteBatch {
+    writes: Vec<Box<dyn FnOnce()>>,
+}
+
+impl WriteBatch {
+    fn stage(&mut self, write: impl FnOnce() + 'static) {
+        self.writes.push(Box::new(write));
+    }
+
+    fn commit(self) {
+        for write in self.writes {
+            write();
+        }
+    }
+}
+
+// Refuse a value too large for its stable map, which would otherwise trap on insert
+fn ensure_fits<T: Storable + BoundedStorable>(entity: &str, id: u64, value: &T) -> Result<(), Error> {
+    let size = value.to_bytes().len();
+    if size > T::MAX_SIZE as usize {
+        return Err(Error::ValidationError {
+            msg: format!(
+                "{} id={} is {} bytes, over the {}-byte limit; shorten its notes or medical history",
+                entity,
+                id,
+                size,
+                T::MAX_SIZE
+            ),
+        });
+    }
+    Ok(())
+}
+
+// Reject a write based on a stale copy of an entity (entities written before versioning are version 0)
+fn check_version(entity: &str, id: u64, current: Option<u64>, expected: u64) -> Result<(), Error> {
+    let current = current.unwrap_or(0);
+    if current != expected {
+        return Err(Error::Conflict {
+            msg: format!(
+                "{} with id={} was modified by someone else (version {}, expected {}); reload and retry",
+                entity, id, current, expected
+            ),
+        });
+    }
+    Ok(())
+}
+
+fn next_version(current: Option<u64>) -> Option<u64> {
+    Some(current.unwrap_or(0) + 1)
+}
+
+// Current time in nanoseconds. Everything reads the clock through here, so tests and demos can
+// move it with set_time_offset (only while the test_clock flag is on).
+fn time() -> u64 {
+    let now = ic_cdk::api::time();
+    let offset = CLOCK_OFFSET.with(|offset| offset.borrow().get().nanos);
+    if offset == 0 || !feature_enabled("test_clock") {
+        return now;
+    }
+    now.saturating_add_signed(offset)
+}
+
+// Move the canister clock by `nanos` from real time, e.g. 30 days ahead to exercise overdue
+// appointments; 0 restores real time (controllers only, test_clock flag). Returns the new time.
+#[ic_cdk::update]
+fn set_time_offset(nanos: i64) -> Result<u64, Error> {
+    ensure_controller()?;
+    ensure_feature("test_clock")?;
+
+    CLOCK_OFFSET.with(|offset| {
+        offset
+            .borrow_mut()
+            .set(ClockOffset { nanos })
+            .map(|_| ())
+            .map_err(|_| Error::SystemError { msg: "Failed to store clock offset".to_string() })
+    })?;
+    Ok(time())
+}
+
+// Helper function to determine pregnancy stage based on EDD
+fn calculate_pregnancy_stage(edd: u64) -> PregnancyStage {
+    let now = time();
+    let time_diff = if edd > now {
+        edd - now 
+    } else {
+        0
+    };
+    
+    let weeks_to_edd = time_diff / (7 * 24 * 60 * 60 * 1_000_000_000);
+    
+    match weeks_to_edd {
+        0 => PregnancyStage::PostPartum,
+        1..=13 => PregnancyStage::ThirdTrimester,
+        14..=27 => PregnancyStage::SecondTrimester,
+        _ => PregnancyStage::FirstTrimester,
+    }
+}
+//Helper functions for code maintanability and reusability
+
+// Id sequence of each entity type
+fn id_sequence(entity_type: EntityType) -> &'static std::thread::LocalKey<RefCell<IdCell>> {
+    match entity_type {
+        EntityType::MotherProfile => &MOTHER_ID_SEQ,
+        EntityType::HealthRecord => &HEALTH_RECORD_ID_SEQ,
+        EntityType::Referral => &REFERRAL_ID_SEQ,
+        EntityType::Payment => &PAYMENT_ID_SEQ,
+        EntityType::LabResult => &LAB_RESULT_ID_SEQ,
+        EntityType::SelfReport => &SELF_REPORT_ID_SEQ,
+    }
+}
+
+const ID_SEQUENCES: [EntityType; 6] = [
+    EntityType::MotherProfile,
+    EntityType::HealthRecord,
+    EntityType::Referral,
+    EntityType::Payment,
+    EntityType::LabResult,
+    EntityType::SelfReport,
+];
+
+//Generate Unique ID within an entity type
+fn generate_new_id(entity_type: EntityType) -> Result<u64, Error> {
+    let id_end = SHARD_ROLE.with(|role| role.borrow().get().id_end);
+    id_sequence(entity_type).with(|counter| {
+        let current_value = *counter.borrow().get();
+        if id_end.is_some_and(|end| current_value >= end) {
+            return Err(Error::SystemError {
+                msg: "This canister's id range is used up; register new mothers on another shard".to_string(),
+            });
+        }
+        counter
+            .borrow_mut()
+            .set(current_value + 1)
+            .map_err(|_| Error::SystemError { msg: "Failed to increment ID counter".to_string() })
+    })
+}
+
+// Only canister controllers may change canister-wide settings
+fn ensure_controller() -> Result<(), Error> {
+    let caller = ic_cdk::caller();
+    if ic_cdk::api::is_controller(&caller) || canister_config().admins.contains(&caller) {
+        Ok(())
+    } else {
+        Err(Error::AuthorizationError {
+            msg: "Only canister controllers or admins can perform this action".to_string(),
+        })
+    }
+}
+
+fn canister_config() -> CanisterConfig {
+    CONFIG.with(|config| config.borrow().get().clone())
+}
+
+// Apply install or upgrade arguments over the stored config
+fn apply_init_args(args: InitArgs) {
+    let mut config = canister_config();
+    if let Some(admins) = args.admins {
+        config.admins = admins;
+    }
+    if let Some(facility_name) = args.facility_name {
+        config.facility_name = Some(facility_name);
+    }
+    if let Some(locale) = args.locale {
+        config.locale = locale;
+    }
+    if let Some(overrides) = args.thresholds {
+        apply_threshold_overrides(&mut config.thresholds, overrides);
+    }
+    if let Some(key_name) = args.vetkd_key_name {
+        config.vetkd_key_name = Some(key_name);
+    }
+    if let Err(msg) = validate_config(&config) {
+        ic_cdk::trap(&format!("Invalid init arguments: {}", msg));
+    }
+    CONFIG.with(|cell| cell.borrow_mut().set(config).expect("Cannot store canister config"));
+}
+
+fn apply_threshold_overrides(thresholds: &mut RiskThresholds, overrides: ThresholdOverrides) {
+    thresholds.systolic_high = overrides.systolic_high.unwrap_or(thresholds.systolic_high);
+    thresholds.diastolic_high = overrides.diastolic_high.unwrap_or(thresholds.diastolic_high);
+    thresholds.systolic_low = overrides.systolic_low.unwrap_or(thresholds.systolic_low);
+    thresholds.diastolic_low = overrides.diastolic_low.unwrap_or(thresholds.diastolic_low);
+    thresholds.weight_low = overrides.weight_low.unwrap_or(thresholds.weight_low);
+    thresholds.weight_high = overrides.weight_high.unwrap_or(thresholds.weight_high);
+}
+
+// Modules that can be switched off per deployment: (name, description, enabled by default)
+const FEATURES: &[(&str, &str, bool)] = &[
+    ("payments", "ICP ledger payments: create_payment, settle_payment", true),
+    ("fhir_export", "FHIR R4 patient and observation export", true),
+    ("fhir_import", "FHIR R4 Patient and Observation bundle ingest from EMRs", true),
+    ("dhis2_reporting", "DHIS2 monthly aggregates and the ANC register", true),
+    ("care_bundles", "Signed care bundle export and import between canisters", true),
+    ("mother_card", "Mother-held QR card payloads", true),
+    ("http_gateway", "Read-only JSON over plain HTTPS", true),
+    ("demo_data", "seed_demo_data for trainings and demos; keep off in production", false),
+    ("test_clock", "set_time_offset for integration tests and demos; keep off in production", false),
+    ("field_encryption", "vetKD keys for client-side encryption; medical history and notes must then be ciphertext", false),
+];
+
+// A module's flag as set through set_feature_flag or update_config, else its default
+fn feature_enabled(name: &str) -> bool {
+    let default = FEATURES
+        .iter()
+        .find(|(feature, _, _)| *feature == name)
+        .is_some_and(|(_, _, default)| *default);
+    canister_config()
+        .features
+        .iter()
+        .find(|(feature, _)| feature == name)
+        .map_or(default, |(_, enabled)| *enabled)
+}
+
+// Guard for the endpoints of a switchable module
+fn ensure_feature(name: &str) -> Result<(), Error> {
+    if feature_enabled(name) {
+        Ok(())
+    } else {
+        Err(Error::FeatureDisabled {
+            msg: format!("The {} module is disabled on this canister", name),
+        })
+    }
+}
+
+fn ensure_known_feature(name: &str) -> Result<(), Error> {
+    if FEATURES.iter().any(|(feature, _, _)| *feature == name) {
+        Ok(())
+    } else {
+        Err(Error::InvalidInput {
+            msg: format!("Unknown feature flag: {}", name),
+        })
+    }
+}
+
+// Every feature flag with its current state
+#[ic_cdk::query]
+fn get_feature_flags() -> Vec<FeatureFlag> {
+    FEATURES
+        .iter()
+        .map(|(name, description, _)| FeatureFlag {
+            name: name.to_string(),
+            description: description.to_string(),
+            enabled: feature_enabled(name),
+        })
+        .collect()
+}
+
+// Switch a module on or off (controllers and admins only)
+#[ic_cdk::update]
+fn set_feature_flag(name: String, enabled: bool) -> Result<(), Error> {
+    update_config(ConfigPatch {
+        features: Some(vec![(name, enabled)]),
+        ..Default::default()
+    })
+    .map(|_| ())
+}
+
+// Largest page a config may allow, so a full page of records stays under the 2MB reply limit
+const PAGE_SIZE_CEILING: u32 = 1000;
+
+fn validate_config(config: &CanisterConfig) -> Result<(), String> {
+    let limits = &config.thresholds;
+    if limits.systolic_low >= limits.systolic_high || limits.diastolic_low >= limits.diastolic_high {
+        return Err("blood pressure low limits must be below the high limits".to_string());
+    }
+    if limits.weight_low.partial_cmp(&limits.weight_high) != Some(std::cmp::Ordering::Less) {
+        return Err("weight_low must be below weight_high".to_string());
+    }
+    if config.appointment_reminder_days == 0 || config.appointment_reminder_days > 280 {
+        return Err("appointment_reminder_days must be between 1 and 280".to_string());
+    }
+    if config.max_page_size == 0 || config.max_page_size > PAGE_SIZE_CEILING {
+        return Err(format!("max_page_size must be between 1 and {}", PAGE_SIZE_CEILING));
+    }
+    if config.default_page_size == 0 || config.default_page_size > config.max_page_size {
+        return Err("default_page_size must be between 1 and max_page_size".to_string());
+    }
+    if config.vetkd_key_name.as_ref().is_some_and(|name| name.trim().is_empty()) {
+        return Err("vetkd_key_name must not be empty".to_string());
+    }
+    if config.screening_due_weeks.is_some_and(|weeks| !(4..=40).contains(&weeks)) {
+        return Err("screening_due_weeks must be between 4 and 40".to_string());
+    }
+    if config.deletion_retention_days.is_some_and(|days| !(1..=365).contains(&days)) {
+        return Err("deletion_retention_days must be between 1 and 365".to_string());
+    }
+    if config.transport_voucher_amount == Some(0) {
+        return Err("transport_voucher_amount must be greater than zero".to_string());
+    }
+    if config.transport_voucher_min_km.is_some_and(|km| !(1..=1000).contains(&km)) {
+        return Err("transport_voucher_min_km must be between 1 and 1000".to_string());
+    }
+    Ok(())
+}
+
+// Current runtime config (controllers and admins only)
+#[ic_cdk::query]
+fn get_config() -> Result<CanisterConfig, Error> {
+    ensure_controller()?;
+    Ok(canister_config())
+}
+
+// Change thresholds, lead times, page sizes or feature toggles; takes effect on the next call
+// (controllers and admins only)
+#[ic_cdk::update]
+fn update_config(patch: ConfigPatch) -> Result<CanisterConfig, Error> {
+    ensure_controller()?;
+
+    let mut config = canister_config();
+    if let Some(overrides) = patch.thresholds {
+        apply_threshold_overrides(&mut config.thresholds, overrides);
+    }
+    if let Some(days) = patch.appointment_reminder_days {
+        config.appointment_reminder_days = days;
+    }
+    if let Some(size) = patch.default_page_size {
+        config.default_page_size = size;
+    }
+    if let Some(size) = patch.max_page_size {
+        config.max_page_size = size;
+    }
+    if let Some(weeks) = patch.screening_due_weeks {
+        config.screening_due_weeks = Some(weeks);
+    }
+    if let Some(days) = patch.deletion_retention_days {
+        config.deletion_retention_days = Some(days);
+    }
+    if let Some(amount) = patch.transport_voucher_amount {
+        config.transport_voucher_amount = Some(amount);
+    }
+    if let Some(km) = patch.transport_voucher_min_km {
+        config.transport_voucher_min_km = Some(km);
+    }
+    for (name, enabled) in patch.features.unwrap_or_default() {
+        ensure_known_feature(&name)?;
+        match config.features.iter_mut().find(|(existing, _)| *existing == name) {
+            Some(feature) => feature.1 = enabled,
+            None => config.features.push((name, enabled)),
+        }
+    }
+    validate_config(&config).map_err(|msg| Error::ValidationError { msg })?;
+
+    CONFIG.with(|cell| {
+        cell.borrow_mut()
+            .set(config.clone())
+            .map(|_| ())
+            .map_err(|_| Error::SystemError { msg: "Failed to store config".to_string() })
+    })?;
+    Ok(config)
+}
+
+// Get the canister signing secret, generating it from raw_rand on first use
+async fn signing_key() -> Result<Vec<u8>, Error> {
+    let existing = SIGNING_KEY.with(|key| key.borrow().get().key.clone());
+    if !existing.is_empty() {
+        return Ok(existing);
+    }
+
+    let (random,) = ic_cdk::api::management_canister::main::raw_rand()
+        .await
+        .map_err(|(_, msg)| Error::SystemError { msg: format!("Failed to generate signing key: {}", msg) })?;
+
+    // Another call may have initialised the key while we were waiting
+    SIGNING_KEY.with(|key| {
+        let mut key = key.borrow_mut();
+        if key.get().key.is_empty() {
+            key.set(SigningKey { key: random })
+                .map_err(|_| Error::SystemError { msg: "Failed to store signing key".to_string() })?;
+        }
+        Ok(key.get().key.clone())
+    })
+}
+
+// HMAC-SHA256 of a message under the given key
+fn hmac_sha256(key: &[u8], message: &[u8]) -> [u8; 32] {
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(key).expect("HMAC takes keys of any length");
+    mac.update(message);
+    mac.finalize().into_bytes().into()
+}
+
+const VETKD_DEFAULT_KEY_NAME: &str = "key_1";
+// Domain separator for field keys; the input picks the mother
+const VETKD_FIELD_CONTEXT: &[u8] = b"mama-pack/fields/v1";
+// Fee for vetkd_derive_key with key_1; the management canister refunds what it does not use
+const VETKD_DERIVE_KEY_CYCLES: u128 = 26_153_846_153;
+// Marks a field value as client-side ciphertext: the prefix followed by base64
+const ENCRYPTED_FIELD_PREFIX: &str = "enc:v1:";
+
+fn vetkd_key_id() -> VetKdKeyId {
+    VetKdKeyId {
+        curve: VetKdCurve::Bls12381G2,
+        name: canister_config()
+            .vetkd_key_name
+            .unwrap_or_else(|| VETKD_DEFAULT_KEY_NAME.to_string()),
+    }
+}
+
+fn ensure_field_reader() -> Result<(), Error> {
+    let caller = ic_cdk::caller();
+    let granted = FIELD_READERS.with(|readers| readers.borrow().contains_key(&StringKey(caller.to_text())));
+    if granted {
+        return Ok(());
+    }
+    ensure_controller().map_err(|_| Error::AuthorizationError {
+        msg: "Only field readers can derive field encryption keys".to_string(),
+    })
+}
+
+// Reject plaintext in a sensitive field while field encryption is on; empty values are allowed
+fn validate_sensitive_field(name: &str, value: &str) -> Result<(), Error> {
+    if value.is_empty() || !feature_enabled("field_encryption") {
+        return Ok(());
+    }
+    let encoded = value.strip_prefix(ENCRYPTED_FIELD_PREFIX).unwrap_or_default();
+    let is_base64 = !encoded.is_empty()
+        && encoded.trim_end_matches('=').bytes().all(|b| b.is_ascii_alphanumeric() || b == b'+' || b == b'/');
+    if is_base64 {
+        Ok(())
+    } else {
+        Err(Error::ValidationError {
+            msg: format!("{} must be encrypted client-side and sent as {}<base64>", name, ENCRYPTED_FIELD_PREFIX),
+        })
+    }
+}
+
+fn validate_medical_history(history: &[String]) -> Result<(), Error> {
+    history
+        .iter()
+        .try_for_each(|entry| validate_sensitive_field("medical_history", entry))
+}
+
+// Kenyan ID and passport numbers: 5 to 20 letters and digits
+fn validate_national_id(national_id: &str) -> Result<(), Error> {
+    let national_id = national_id.trim();
+    if !(5..=20).contains(&national_id.len()) || !national_id.chars().all(|c| c.is_ascii_alphanumeric()) {
+        return Err(Error::ValidationError {
+            msg: "national_id must be 5 to 20 letters and digits".to_string(),
+        });
+    }
+    Ok(())
+}
+
+fn validate_previous_cesareans(count: u8) -> Result<(), Error> {
+    if count > 10 {
+        return Err(Error::ValidationError {
+            msg: "previous_cesareans must be at most 10".to_string(),
+        });
+    }
+    Ok(())
+}
+
+// Public key that field keys are derived under, for clients to verify the keys they decrypt
+#[ic_cdk::update]
+async fn get_field_encryption_public_key() -> Result<Vec<u8>, Error> {
+    ensure_feature("field_encryption")?;
+
+    let args = VetKdPublicKeyArgs {
+        canister_id: None,
+        context: VETKD_FIELD_CONTEXT.to_vec(),
+        key_id: vetkd_key_id(),
+    };
+    let result: Result<(VetKdPublicKeyResult,), _> =
+        ic_cdk::call(Principal::management_canister(), "vetkd_public_key", (args,)).await;
+    result
+        .map(|(reply,)| reply.public_key)
+        .map_err(|(code, msg)| Error::SystemError { msg: format!("vetkd_public_key failed: {:?} {}", code, msg) })
+}
+
+// Derive the key for one mother's sensitive fields, encrypted to the caller's transport key so
+// neither the canister nor node providers see it (field readers, controllers and admins only)
+#[ic_cdk::update]
+async fn get_encrypted_field_key(mother_id: u64, transport_public_key: Vec<u8>) -> Result<Vec<u8>, Error> {
+    ensure_feature("field_encryption")?;
+    ensure_field_reader()?;
+    load_mother_profile(mother_id)?;
+
+    let args = VetKdDeriveKeyArgs {
+        input: mother_id.to_be_bytes().to_vec(),
+        context: VETKD_FIELD_CONTEXT.to_vec(),
+        transport_public_key,
+        key_id: vetkd_key_id(),
+    };
+    let result: Result<(VetKdDeriveKeyResult,), _> = ic_cdk::api::call::call_with_payment128(
+        Principal::management_canister(),
+        "vetkd_derive_key",
+        (args,),
+        VETKD_DERIVE_KEY_CYCLES,
+    )
+    .await;
+    result
+        .map(|(reply,)| reply.encrypted_key)
+        .map_err(|(code, msg)| Error::SystemError { msg: format!("vetkd_derive_key failed: {:?} {}", code, msg) })
+}
+
+type GrantStore = StableBTreeMap<StringKey, u64, Memory>;
+
+fn set_grant(store: &'static std::thread::LocalKey<RefCell<GrantStore>>, principal: Principal, allowed: bool) {
+    let key = StringKey(principal.to_text());
+    store.with(|grants| {
+        let mut grants = grants.borrow_mut();
+        if allowed {
+            grants.insert(key, time());
+        } else {
+            grants.remove(&key);
+        }
+    });
+}
+
+fn list_grants(store: &'static std::thread::LocalKey<RefCell<GrantStore>>) -> Result<Vec<AccessGrant>, Error> {
+    store.with(|grants| {
+        grants
+            .borrow()
+            .iter()
+            .map(|(key, granted_at)| {
+                Principal::from_text(&key.0)
+                    .map(|principal| AccessGrant { principal, granted_at })
+                    .map_err(|e| Error::SystemError { msg: format!("Corrupt grant for {}: {}", key.0, e) })
+            })
+            .collect()
+    })
+}
+
+// Allow or revoke a principal's access to field keys (controllers and admins only)
+#[ic_cdk::update]
+fn set_field_reader(principal: Principal, allowed: bool) -> Result<(), Error> {
+    ensure_controller()?;
+    set_grant(&FIELD_READERS, principal, allowed);
+    Ok(())
+}
+
+#[ic_cdk::query]
+fn get_field_readers() -> Result<Vec<AccessGrant>, Error> {
+    ensure_controller()?;
+    list_grants(&FIELD_READERS)
+}
+
+// Sensitive fields need their own grant; being a controller or admin is not enough
+fn ensure_sensitive_reader() -> Result<(), Error> {
+    let caller = StringKey(ic_cdk::caller().to_text());
+    if SENSITIVE_READERS.with(|readers| readers.borrow().contains_key(&caller)) {
+        Ok(())
+    } else {
+        Err(Error::AuthorizationError {
+            msg: "Reading or recording HIV status and mental health notes needs the sensitive reader permission"
+                .to_string(),
+        })
+    }
+}
+
+// HIV status and mental health notes of a mother; None when nothing has been recorded
+// (sensitive readers only)
+#[ic_cdk::update]
+fn get_sensitive_fields(mother_id: u64) -> Result<Option<SensitiveFields>, Error> {
+    ensure_sensitive_reader()?;
+    load_mother_profile(mother_id)?;
+    log_read(mother_id, "get_sensitive_fields");
+    Ok(SENSITIVE_FIELDS.with(|storage| storage.borrow().get(&mother_id)))
+}
+
+// Record or change a mother's sensitive fields (sensitive readers only)
+#[ic_cdk::update]
+fn update_sensitive_fields(mother_id: u64, patch: SensitiveFieldsPatch) -> Result<SensitiveFields, Error> {
+    ensure_sensitive_reader()?;
+    load_mother_profile(mother_id)?;
+    if let Some(notes) = &patch.mental_health_notes {
+        if notes.len() > MAX_SENSITIVE_NOTES_BYTES {
+            return Err(Error::ValidationError {
+                msg: format!("mental_health_notes may be at most {} bytes", MAX_SENSITIVE_NOTES_BYTES),
+            });
+        }
+        validate_sensitive_field("mental_health_notes", notes)?;
+    }
+
+    let mut fields = SENSITIVE_FIELDS
+        .with(|storage| storage.borrow().get(&mother_id))
+        .unwrap_or(SensitiveFields { mother_id, ..Default::default() });
+    check_version("Sensitive fields of mother", mother_id, fields.version, patch.expected_version)?;
+
+    if let Some(status) = patch.hiv_status {
+        fields.hiv_status = Some(status);
+    }
+    if let Some(tested_at) = patch.hiv_tested_at {
+        fields.hiv_tested_at = Some(tested_at);
+    }
+    if let Some(notes) = patch.mental_health_notes {
+        fields.mental_health_notes = notes;
+    }
+    fields.version = next_version(fields.version);
+    fields.updated_at = Some(time());
+    fields.updated_by = Some(ic_cdk::caller());
+    SENSITIVE_FIELDS.with(|storage| storage.borrow_mut().insert(mother_id, fields.clone()));
+    Ok(fields)
+}
+
+const MAX_SENSITIVE_NOTES_BYTES: usize = 6 * 1024;
+const PMTCT_VIRAL_LOADS: usize = 24;
+// Below this a viral load counts as suppressed
+const VIRAL_SUPPRESSION_COPIES: u32 = 1000;
+// A viral load is due this long after starting ART or after the previous one
+const VIRAL_LOAD_INTERVAL_DAYS: u64 = 90;
+
+// Record or change a mother's ART status and infant prophylaxis (sensitive readers only)
+#[ic_cdk::update]
+fn update_pmtct(mother_id: u64, patch: PmtctPatch) -> Result<PmtctRecord, Error> {
+    ensure_sensitive_reader()?;
+    load_mother_profile(mother_id)?;
+    if let Some(prophylaxis) = &patch.infant_prophylaxis {
+        if prophylaxis.regimen.trim().is_empty() || prophylaxis.regimen.len() > 100 {
+            return Err(Error::InvalidInput {
+                msg: "The infant prophylaxis regimen must be between 1 and 100 characters".to_string(),
+            });
+        }
+    }
+
+    let mut record = load_pmtct(mother_id);
+    check_version("PMTCT record of mother", mother_id, record.version, patch.expected_version)?;
+    if let Some(status) = patch.art_status {
+        record.art_status = Some(status);
+    }
+    if let Some(started_at) = patch.art_started_at {
+        record.art_started_at = Some(started_at);
+    }
+    if let Some(prophylaxis) = patch.infant_prophylaxis {
+        record.infant_prophylaxis = Some(prophylaxis);
+    }
+    store_pmtct(record)
+}
+
+// Add a viral load result (sensitive readers only)
+#[ic_cdk::update]
+fn add_viral_load(mother_id: u64, sampled_at: u64, copies_per_ml: u32) -> Result<PmtctRecord, Error> {
+    ensure_sensitive_reader()?;
+    load_mother_profile(mother_id)?;
+    if sampled_at > time() {
+        return Err(Error::InvalidInput {
+            msg: "sampled_at cannot be in the future".to_string(),
+        });
+    }
+
+    let mut record = load_pmtct(mother_id);
+    record.viral_loads.push(ViralLoad { sampled_at, copies_per_ml });
+    record.viral_loads.sort_by_key(|load| load.sampled_at);
+    let excess = record.viral_loads.len().saturating_sub(PMTCT_VIRAL_LOADS);
+    record.viral_loads.drain(..excess);
+    store_pmtct(record)
+}
+
+// A mother's test result, PMTCT care and the steps due at her stage (sensitive readers only;
+// reads are logged)
+#[ic_cdk::update]
+fn get_pmtct(mother_id: u64) -> Result<PmtctView, Error> {
+    ensure_sensitive_reader()?;
+    let profile = load_mother_profile(mother_id)?;
+    let fields = SENSITIVE_FIELDS.with(|storage| storage.borrow().get(&mother_id)).unwrap_or_default();
+    let record = PMTCT_RECORDS.with(|storage| storage.borrow().get(&mother_id));
+    log_read(mother_id, "get_pmtct");
+    Ok(PmtctView {
+        hiv_status: fields.hiv_status,
+        hiv_tested_at: fields.hiv_tested_at,
+        suppressed: record.as_ref().and_then(viral_suppression),
+        reminders: pmtct_reminders(&profile, fields.hiv_status, record.as_ref(), time()),
+        record,
+    })
+}
+
+// Mothers at a facility (or all) at each step of the cascade: tested, positive, on ART, viral load
+// done, suppressed, and for those delivered, infant prophylaxis (sensitive readers only)
+#[ic_cdk::query]
+fn get_pmtct_cascade(facility_code: Option<String>) -> Result<PmtctCascade, Error> {
+    ensure_sensitive_reader()?;
+    let profiles: Vec<MotherProfile> = PROFILE_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, profile)| profile)
+            .filter(|profile| facility_code.is_none() || profile.facility_code == facility_code)
+            .collect()
+    });
+
+    let mut cascade = PmtctCascade {
+        mothers: profiles.len() as u64,
+        tested: 0,
+        positive: 0,
+        on_art: 0,
+        viral_load_done: 0,
+        suppressed: 0,
+        delivered_positive: 0,
+        infant_prophylaxis: 0,
+    };
+    for profile in &profiles {
+        let status = SENSITIVE_FIELDS.with(|storage| storage.borrow().get(&profile.id)).and_then(|fields| fields.hiv_status);
+        if matches!(status, Some(HivStatus::Negative | HivStatus::Positive)) {
+            cascade.tested += 1;
+        }
+        if status != Some(HivStatus::Positive) {
+            continue;
+        }
+        cascade.positive += 1;
+        let record = load_pmtct(profile.id);
+        cascade.on_art += u64::from(record.art_status == Some(ArtStatus::OnArt));
+        cascade.viral_load_done += u64::from(!record.viral_loads.is_empty());
+        cascade.suppressed += u64::from(viral_suppression(&record) == Some(true));
+        if profile.delivery.is_some() {
+            cascade.delivered_positive += 1;
+            cascade.infant_prophylaxis += u64::from(record.infant_prophylaxis.is_some());
+        }
+    }
+    Ok(cascade)
+}
+
+fn load_pmtct(mother_id: u64) -> PmtctRecord {
+    PMTCT_RECORDS
+        .with(|storage| storage.borrow().get(&mother_id))
+        .unwrap_or(PmtctRecord { mother_id, ..Default::default() })
+}
+
+fn store_pmtct(mut record: PmtctRecord) -> Result<PmtctRecord, Error> {
+    record.version = next_version(record.version);
+    record.updated_at = Some(time());
+    record.updated_by = Some(ic_cdk::caller());
+    ensure_fits("PMTCT record of mother", record.mother_id, &record)?;
+    PMTCT_RECORDS.with(|storage| storage.borrow_mut().insert(record.mother_id, record.clone()));
+    Ok(record)
+}
+
+// Whether the latest viral load is suppressed; None before the first
+fn viral_suppression(record: &PmtctRecord) -> Option<bool> {
+    record.viral_loads.last().map(|load| load.copies_per_ml < VIRAL_SUPPRESSION_COPIES)
+}
+
+// Next PMTCT steps for a mother at her current stage
+fn pmtct_reminders(profile: &MotherProfile, status: Option<HivStatus>, record: Option<&PmtctRecord>, now: u64) -> Vec<String> {
+    let mut reminders = Vec::new();
+    let delivered = profile.delivery.is_some();
+    match status {
+        Some(HivStatus::Positive) => {}
+        Some(HivStatus::Negative) => {
+            // Retest in the third trimester after a negative test early in pregnancy
+            let retested = SENSITIVE_FIELDS
+                .with(|storage| storage.borrow().get(&profile.id))
+                .and_then(|fields| fields.hiv_tested_at)
+                .is_some_and(|tested_at| gestational_age_weeks(profile.expected_delivery_date, tested_at) >= 28);
+            if !delivered && gestational_age_weeks(profile.expected_delivery_date, now) >= 28 && !retested {
+                reminders.push("Repeat the HIV test in the third trimester".to_string());
+            }
+            return reminders;
+        }
+        _ => {
+            if !delivered {
+                reminders.push("Offer an HIV test".to_string());
+            }
+            return reminders;
+        }
+    }
+
+    let empty = PmtctRecord::default();
+    let record = record.unwrap_or(&empty);
+    if record.art_status != Some(ArtStatus::OnArt) {
+        reminders.push("Start or resume ART".to_string());
+    }
+    let last_check = record.viral_loads.last().map(|load| load.sampled_at).or(record.art_started_at);
+    if last_check.is_some_and(|at| at + VIRAL_LOAD_INTERVAL_DAYS * NANOS_PER_DAY <= now) {
+        reminders.push("Viral load due".to_string());
+    }
+    if viral_suppression(record) == Some(false) {
+        reminders.push("Viral load not suppressed: enhanced adherence counselling and a repeat test".to_string());
+    }
+    if delivered && record.infant_prophylaxis.is_none() {
+        reminders.push("Start infant prophylaxis".to_string());
+    }
+    reminders
+}
+
+// Grant or revoke access to sensitive fields (controllers and admins only)
+#[ic_cdk::update]
+fn set_sensitive_reader(principal: Principal, allowed: bool) -> Result<(), Error> {
+    ensure_controller()?;
+    set_grant(&SENSITIVE_READERS, principal, allowed);
+    Ok(())
+}
+
+#[ic_cdk::query]
+fn get_sensitive_readers() -> Result<Vec<AccessGrant>, Error> {
+    ensure_controller()?;
+    list_grants(&SENSITIVE_READERS)
+}
+
+// Grant or revoke the county role (controllers and admins only)
+#[ic_cdk::update]
+fn set_county_officer(principal: Principal, allowed: bool) -> Result<(), Error> {
+    ensure_controller()?;
+    set_grant(&COUNTY_OFFICERS, principal, allowed);
+    Ok(())
+}
+
+#[ic_cdk::query]
+fn get_county_officers() -> Result<Vec<AccessGrant>, Error> {
+    ensure_controller()?;
+    list_grants(&COUNTY_OFFICERS)
+}
+
+// County officers, controllers and admins
+fn ensure_county_officer() -> Result<(), Error> {
+    let caller = StringKey(ic_cdk::caller().to_text());
+    if COUNTY_OFFICERS.with(|officers| officers.borrow().contains_key(&caller)) {
+        return Ok(());
+    }
+    ensure_controller().map_err(|_| Error::AuthorizationError {
+        msg: "Comparing facilities needs the county role".to_string(),
+    })
+}
+//END OF Helper Functions 
+
+// Create new mother profile
+#[ic_cdk::update]
+fn create_mother_profile(payload: MotherProfilePayload) -> Result<MotherProfile, Error> {
+    observe_call("create_mother_profile", register_mother_profile(payload))
+}
+
+fn register_mother_profile(payload: MotherProfilePayload) -> Result<MotherProfile, Error> {
+    // A retried call returns the profile created by the first attempt
+    let slot = idempotency_slot("profile", payload.idempotency_key.as_deref())?;
+    if let Some(id) = slot.as_ref().and_then(idempotent_result) {
+        return load_mother_profile(id);
+    }
+
+    // Validate the payload first
+    validate_mother_profile(&payload)?;
+
+    let profile = insert_mother_profile(payload)?;
+    remember_idempotent_result(slot, profile.id);
+    Ok(profile)
+}
+
+// Store a new profile built from an already validated payload
+fn insert_mother_profile(payload: MotherProfilePayload) -> Result<MotherProfile, Error> {
+    let id = generate_new_id(EntityType::MotherProfile)?;
+
+    let stage = calculate_pregnancy_stage(payload.expected_delivery_date);
+    
+    let profile = MotherProfile {
+        id,
+        name: payload.name,
+        age: payload.age,
+        blood_type: payload.blood_type,
+        expected_delivery_date: payload.expected_delivery_date,
+        stage,
+        health_status: HealthStatus::Normal,
+        created_at: time(),
+        last_checkup: time(),
+        medical_history: payload.medical_history,
+        emergency_contact: payload.emergency_contact,
+        insurance: payload.insurance,
+        facility_code: payload.facility_code,
+        delivery: None,
+        origin: None,
+        version: Some(1),
+        ulid: Some(new_ulid(EntityType::MotherProfile, id)),
+        updated_at: Some(time()),
+        previous_cesareans: payload.previous_cesareans,
+        national_id: payload.national_id.map(|national_id| national_id.trim().to_uppercase()),
+    };
+    ensure_fits("Mother", id, &profile)?;
+
+    let mut batch = WriteBatch::default();
+    let stored = profile.clone();
+    batch.stage(move || {
+        if let Some(ulid) = &stored.ulid {
+            index_ulid(ulid, EntityType::MotherProfile, id);
+        }
+        PROFILE_STORAGE.with(|storage| storage.borrow_mut().insert(id, stored.clone()));
+        log_change(EntityType::MotherProfile, id, None, Some(&stored));
+        if let Some(facility_code) = &stored.facility_code {
+            update_facility_metrics(facility_code, |metrics| metrics.registered_mothers += 1);
+        }
+    });
+    batch.commit();
+
+    Ok(profile)
+}
+
+// Add health record
+#[ic_cdk::update]
+fn add_health_record(payload: HealthRecordPayload) -> Result<HealthRecord, Error> {
+    observe_call("add_health_record", add_health_record_at(payload, time()))
+}
+
+// Create a health record for a visit at `date`, honouring the payload's idempotency key
+fn add_health_record_at(payload: HealthRecordPayload, date: u64) -> Result<HealthRecord, Error> {
+    // A retried call returns the record created by the first attempt
+    let slot = idempotency_slot("health-record", payload.idempotency_key.as_deref())?;
+    if let Some(id) = slot.as_ref().and_then(idempotent_result) {
+        return get_health_record(id).ok_or(Error::NotFound {
+                msg: format!("Health record with id={} not found", id),
+            });
+    }
+
+    let record = insert_health_record(payload, date)?;
+    remember_idempotent_result(slot, record.id);
+    Ok(record)
+}
+
+// Create several profiles in one call; each item succeeds or fails on its own
+#[ic_cdk::update]
+fn create_mother_profiles_batch(
+    payloads: Vec<MotherProfilePayload>,
+) -> Result<Vec<Result<MotherProfile, Error>>, Error> {
+    ensure_batch_size(payloads.len())?;
+    Ok(payloads.into_iter().map(create_mother_profile).collect())
+}
+
+// Add several health records in one call (e.g. an outreach day); each item succeeds or fails on its own
+#[ic_cdk::update]
+fn add_health_records_batch(
+    payloads: Vec<HealthRecordPayload>,
+) -> Result<Vec<Result<HealthRecord, Error>>, Error> {
+    ensure_batch_size(payloads.len())?;
+    Ok(payloads.into_iter().map(add_health_record).collect())
+}
+
+fn ensure_batch_size(len: usize) -> Result<(), Error> {
+    if len > MAX_BATCH_SIZE {
+        return Err(Error::InvalidInput {
+            msg: format!("At most {} items can be submitted at once", MAX_BATCH_SIZE),
+        });
+    }
+    Ok(())
+}
+
+// Storage slot for a client idempotency key, scoped to the caller and the kind of entity created
+fn idempotency_slot(kind: &str, key: Option<&str>) -> Result<Option<StringKey>, Error> {
+    let Some(key) = key else {
+        return Ok(None);
+    };
+    if key.is_empty() || key.len() > 64 {
+        return Err(Error::InvalidInput {
+            msg: "Idempotency key must be between 1 and 64 characters".to_string(),
+        });
+    }
+
+    let mut hasher = Sha256::new();
+    hasher.update(ic_cdk::caller().as_slice());
+    hasher.update(kind.as_bytes());
+    hasher.update([0]);
+    hasher.update(key.as_bytes());
+    let slot = hasher.finalize().iter().map(|byte| format!("{:02x}", byte)).collect();
+    Ok(Some(StringKey(slot)))
+}
+
+fn idempotent_result(slot: &StringKey) -> Option<u64> {
+    IDEMPOTENCY_KEYS.with(|keys| keys.borrow().get(slot)).map(|result| result.id)
+}
+
+fn remember_idempotent_result(slot: Option<StringKey>, id: u64) {
+    if let Some(slot) = slot {
+        let result = IdempotentResult { id, created_at: time() };
+        IDEMPOTENCY_KEYS.with(|keys| keys.borrow_mut().insert(slot, result));
+    }
+}
+
+// How long a retry under the same idempotency key returns the first result; offline devices
+// can take weeks to reconnect and re-upload
+const IDEMPOTENCY_KEY_TTL_NANOS: u64 = 30 * NANOS_PER_DAY;
+
+fn idempotency_key_expired(result: &IdempotentResult, now: u64) -> bool {
+    now.saturating_sub(result.created_at) > IDEMPOTENCY_KEY_TTL_NANOS
+}
+
+// Forget idempotency keys past their retry window; runs with the daily purge
+fn purge_idempotency_keys(now: u64) {
+    let expired: Vec<StringKey> = IDEMPOTENCY_KEYS.with(|keys| {
+        keys.borrow()
+            .iter()
+            .filter(|(_, result)| idempotency_key_expired(result, now))
+            .map(|(slot, _)| slot)
+            .collect()
+    });
+    for slot in &expired {
+        IDEMPOTENCY_KEYS.with(|keys| keys.borrow_mut().remove(slot));
+    }
+    observe_job("purge_idempotency_keys", Some(expired.len() as u64));
+}
+
+// Validate a visit payload, returning the notes to store
+fn validate_visit_payload(payload: &HealthRecordPayload) -> Result<String, Error> {
+    if let Some(facility_code) = &payload.facility_code {
+        validate_facility_code(facility_code)?;
+    }
+    validate_sensitive_field("notes", &payload.notes)?;
+    validate_fetal_findings(payload)?;
+    structured_notes(payload)
+}
+
+// Create a health record for a visit that took place at `date`
+fn insert_health_record(payload: HealthRecordPayload, date: u64) -> Result<HealthRecord, Error> {
+    // Verify mother exists
+    let profile = load_mother_profile(payload.mother_id)?;
+    let notes = validate_visit_payload(&payload)?;
+
+    let id = generate_new_id(EntityType::HealthRecord)?;
+
+    // Determine health status based on symptoms and vitals
+    let fundal_height_flag = payload
+        .fundal_height_cm
+        .and_then(|height| assess_fundal_height(height, gestational_age_weeks(profile.expected_delivery_date, date)));
+    let mut health_status = analyze_health_status(&payload);
+    let preeclampsia = preeclampsia_suspected(&payload);
+    if fundal_height_flag.is_some() && matches!(health_status, HealthStatus::Normal) {
+        health_status = HealthStatus::NeedsAttention;
+    }
+    let symptom_codes = payload.symptoms.iter().map(|s| find_symptom_code(s)).collect();
+    let recommended = recommended_next_appointment(&profile, date, &health_status);
+
+    let record = HealthRecord {
+    id,
+    mother_id: payload.mother_id,
+    date,
+    blood_pressure: payload.blood_pressure,
+    weight: payload.weight,
+    symptoms: payload.symptoms,
+    notes,
+    next_appointment: match payload.next_appointment {
+        0 => recommended.unwrap_or_default(),
+        next_appointment => next_appointment,
+    },
+    health_status: health_status.clone(), // Add .clone() here
+    insurance_eligible: Some(payload.insurance_eligible.unwrap_or(profile.insurance.is_some())),
+    facility_code: payload.facility_code.or_else(|| profile.facility_code.clone()),
+    symptom_codes: Some(symptom_codes),
+    version: Some(1),
+    ulid: Some(new_ulid(EntityType::HealthRecord, id)),
+    created_at: Some(time()),
+    updated_at: Some(time()),
+    signature: None,
+    fundal_height_cm: payload.fundal_height_cm,
+    fetal_heart_rate: payload.fetal_heart_rate,
+    fundal_height_flag,
+    urine_protein: payload.urine_protein,
+    urine_glucose: payload.urine_glucose,
+    preeclampsia_suspected: Some(preeclampsia),
+    edema: payload.edema,
+    muac_cm: payload.muac_cm,
+    nutrition_status: payload.muac_cm.map(classify_muac),
+    note_template: payload.note_template,
+    note_sections: payload.note_sections,
+    checklist_done: payload.checklist_done,
+    recommended_next_appointment: recommended,
+    };
+    ensure_fits("Health record", id, &record)?;
+
+    // Update mother's profile with latest checkup and health status
+    let mut updated = profile.clone();
+    updated.health_status = health_status;
+    updated.last_checkup = date;
+    updated.version = next_version(updated.version);
+    updated.updated_at = Some(time());
+    ensure_fits("Mother", updated.id, &updated)?;
+
+    let mut batch = WriteBatch::default();
+    let stored = record.clone();
+    batch.stage(move || {
+        PROFILE_STORAGE.with(|storage| storage.borrow_mut().insert(updated.id, updated.clone()));
+        log_change_with_reason(EntityType::MotherProfile, updated.id, Some(&profile), Some(&updated), Some("Visit recorded"));
+        if let Some(ulid) = &stored.ulid {
+            index_ulid(ulid, EntityType::HealthRecord, stored.id);
+        }
+        store_health_record(&stored);
+        log_change(EntityType::HealthRecord, stored.id, None, Some(&stored));
+        track_care_progress(&profile, &stored);
+    });
+    batch.commit();
+
+    Ok(record)
+}
+
+// Gestational weeks of the WHO 8-contact ANC schedule
+const ANC_CONTACT_WEEKS: [u64; 8] = [12, 20, 26, 30, 34, 36, 38, 40];
+// Fewest days between two antenatal contacts, and the most for each risk tier
+const MIN_APPOINTMENT_DAYS: u64 = 7;
+const MEDIUM_RISK_APPOINTMENT_DAYS: u64 = 14;
+const HIGH_RISK_APPOINTMENT_DAYS: u64 = 7;
+// Review interval after a critical visit, and once the schedule's last contact has passed
+const CRITICAL_APPOINTMENT_DAYS: u64 = 2;
+const POST_TERM_APPOINTMENT_DAYS: u64 = 3;
+
+// Next antenatal contact after a visit at `date`: the schedule's next contact week, at least a week
+// away, brought forward for mothers at medium or high risk (counting this visit's status). None
+// once she has delivered.
+fn recommended_next_appointment(profile: &MotherProfile, date: u64, health_status: &HealthStatus) -> Option<u64> {
+    if profile.delivery.is_some() {
+        return None;
+    }
+
+    let pregnancy_start = profile.expected_delivery_date.saturating_sub(280 * NANOS_PER_DAY);
+    let weeks = gestational_age_weeks(profile.expected_delivery_date, date);
+    let earliest = date + MIN_APPOINTMENT_DAYS * NANOS_PER_DAY;
+    let mut next = match ANC_CONTACT_WEEKS.iter().find(|&&week| week > weeks) {
+        Some(week) => (pregnancy_start + week * 7 * NANOS_PER_DAY).max(earliest),
+        None => date + POST_TERM_APPOINTMENT_DAYS * NANOS_PER_DAY,
+    };
+
+    let cap_days = match (health_status, risk_tier(profile.id)) {
+        (HealthStatus::Critical, _) => Some(CRITICAL_APPOINTMENT_DAYS),
+        (_, "HIGH") => Some(HIGH_RISK_APPOINTMENT_DAYS),
+        (HealthStatus::NeedsAttention, _) | (_, "MEDIUM") => Some(MEDIUM_RISK_APPOINTMENT_DAYS),
+        _ => None,
+    };
+    if let Some(days) = cap_days {
+        next = next.min(date + days * NANOS_PER_DAY);
+    }
+    Some(next)
+}
+
+// Sign off a health record as the calling clinician. The signature holds a hash of the record's
+// clinical content, so any later change to it can be detected with verify_record_signature.
+#[ic_cdk::update]
+fn sign_record(record_id: u64) -> Result<HealthRecord, Error> {
+    let clinician = ic_cdk::caller();
+    if clinician == Principal::anonymous() {
+        return Err(Error::AuthorizationError {
+            msg: "Records must be signed by an authenticated clinician".to_string(),
+        });
+    }
+    let mut record = get_health_record(record_id).ok_or_else(|| Error::NotFound {
+        msg: format!("Health record with id={} not found", record_id),
+    })?;
+    if let Some(signature) = &record.signature {
+        return Err(Error::Conflict {
+            msg: format!("Health record id={} was already signed by {}", record_id, signature.clinician),
+        });
+    }
+
+    let previous = record.clone();
+    let now = time();
+    record.signature = Some(RecordSignature {
+        clinician,
+        signed_at: now,
+        content_hash: record_content_hash(&record),
+    });
+    record.version = next_version(record.version);
+    record.updated_at = Some(now);
+    store_health_record(&record);
+    log_change_with_reason(EntityType::HealthRecord, record_id, Some(&previous), Some(&record), Some("Signed"));
+    Ok(record)
+}
+
+// Whether a signed record still matches the content its clinician signed; false when unsigned
+#[ic_cdk::query]
+fn verify_record_signature(record_id: u64) -> Result<bool, Error> {
+    let record = get_health_record(record_id).ok_or_else(|| Error::NotFound {
+        msg: format!("Health record with id={} not found", record_id),
+    })?;
+    Ok(record
+        .signature
+        .as_ref()
+        .is_some_and(|signature| signature.content_hash == record_content_hash(&record)))
+}
+
+// File an addendum against a health record as the calling clinician. This is how a record is
+// corrected or completed: the original entry, signed or not, is kept as it was.
+#[ic_cdk::update]
+fn add_record_addendum(record_id: u64, payload: AddendumPayload) -> Result<Addendum, Error> {
+    let author = ic_cdk::caller();
+    if author == Principal::anonymous() {
+        return Err(Error::AuthorizationError {
+            msg: "Addenda must be filed by an authenticated clinician".to_string(),
+        });
+    }
+    let record = get_health_record(record_id).ok_or_else(|| Error::NotFound {
+        msg: format!("Health record with id={} not found", record_id),
+    })?;
+    if payload.text.trim().is_empty() {
+        return Err(Error::InvalidInput {
+            msg: "Addendum text is required".to_string(),
+        });
+    }
+    if payload.text.len() > MAX_ADDENDUM_BYTES {
+        return Err(Error::ValidationError {
+            msg: format!("Addendum text may be at most {} bytes", MAX_ADDENDUM_BYTES),
+        });
+    }
+    validate_sensitive_field("addendum text", &payload.text)?;
+
+    let id = next_addendum_id();
+    let addendum = Addendum {
+        id,
+        record_id,
+        mother_id: record.mother_id,
+        kind: payload.kind,
+        text: payload.text,
+        author,
+        created_at: time(),
+    };
+    ADDENDA.with(|storage| storage.borrow_mut().insert(AddendumKey { record_id, id }, addendum.clone()));
+    Ok(addendum)
+}
+
+const MAX_ADDENDUM_BYTES: usize = 6 * 1024;
+
+fn next_addendum_id() -> u64 {
+    ADDENDUM_ID_SEQ.with(|counter| {
+        let next = *counter.borrow().get() + 1;
+        counter.borrow_mut().set(next).expect("Cannot update addendum id sequence");
+        next
+    })
+}
+
+// Addenda filed against a health record, oldest first, also once the record is archived; reads
+// are logged in the mother's access log
+#[ic_cdk::update]
+fn get_record_addenda(record_id: u64) -> Result<Vec<Addendum>, Error> {
+    let addenda = record_addenda(record_id);
+    let mother_id = match get_health_record(record_id) {
+        Some(record) => record.mother_id,
+        None => addenda.first().map(|addendum| addendum.mother_id).ok_or_else(|| Error::NotFound {
+            msg: format!("Health record with id={} not found", record_id),
+        })?,
+    };
+    log_read(mother_id, "get_record_addenda");
+    Ok(addenda)
+}
+
+fn remove_record_addenda(record_id: u64) {
+    for addendum in record_addenda(record_id) {
+        ADDENDA.with(|storage| storage.borrow_mut().remove(&AddendumKey { record_id, id: addendum.id }));
+    }
+}
+
+fn record_addenda(record_id: u64) -> Vec<Addendum> {
+    let keys = AddendumKey { record_id, id: 0 }..=AddendumKey { record_id, id: u64::MAX };
+    ADDENDA.with(|storage| storage.borrow().range(keys).map(|(_, addendum)| addendum).collect())
+}
+
+// Hash of what a clinician attests to: everything but identifiers and write bookkeeping, so the
+// hash survives moving the record to another canister in a care bundle
+fn record_content_hash(record: &HealthRecord) -> Vec<u8> {
+    let content = HealthRecord {
+        id: 0,
+        mother_id: 0,
+        ulid: None,
+        version: None,
+        created_at: None,
+        updated_at: None,
+        signature: None,
+        ..record.clone()
+    };
+    Sha256::digest(Encode!(&content).unwrap()).to_vec()
+}
+
+// Update per-mother progress and the registering facility's metrics after a visit
+fn track_care_progress(profile: &MotherProfile, record: &HealthRecord) {
+    let mut progress = CARE_PROGRESS
+        .with(|storage| storage.borrow().get(&profile.id))
+        .unwrap_or_default();
+
+    progress.visit_count += 1;
+    let first_visit = progress.first_visit_at.is_none();
+    if first_visit {
+        progress.first_visit_at = Some(record.date);
+    }
+
+    // A critical episode opens on the first critical visit and resolves on the next non-critical one
+    let mut opened_critical = false;
+    let mut resolution_time = None;
+    match (&record.health_status, progress.critical_since) {
+        (HealthStatus::Critical, None) => {
+            progress.critical_since = Some(record.date);
+            opened_critical = true;
+        }
+        (HealthStatus::Critical, Some(_)) => {}
+        (_, Some(since)) => {
+            progress.critical_since = None;
+            resolution_time = Some(record.date.saturating_sub(since));
+        }
+        (_, None) => {}
+    }
+
+    let visit_count = progress.visit_count;
+    CARE_PROGRESS.with(|storage| storage.borrow_mut().insert(profile.id, progress));
+
+    if let Some(facility_code) = &profile.facility_code {
+        update_facility_metrics(facility_code, |metrics| {
+            if first_visit {
+                metrics.mothers_with_first_visit += 1;
+                metrics.total_time_to_first_visit += record.date.saturating_sub(profile.created_at);
+            }
+            if visit_count == 4 {
+                metrics.anc4_mothers += 1;
+            }
+            if opened_critical {
+                metrics.critical_cases_opened += 1;
+            }
+            if let Some(elapsed) = resolution_time {
+                metrics.critical_cases_resolved += 1;
+                metrics.total_critical_resolution_time += elapsed;
+            }
+        });
+    }
+}
+
+// Apply an in-place change to a facility's running metrics
+fn update_facility_metrics(facility_code: &str, update: impl FnOnce(&mut FacilityMetrics)) {
+    FACILITY_METRICS.with(|storage| {
+        let mut storage = storage.borrow_mut();
+        let key = StringKey(facility_code.to_string());
+        let mut metrics = storage.get(&key).unwrap_or_default();
+        update(&mut metrics);
+        storage.insert(key, metrics);
+    })
+}
+
+// Helper function to analyze health status based on symptoms and vitals
+fn analyze_health_status(record: &HealthRecordPayload) -> HealthStatus {
+    let limits = canister_config().thresholds;
+
+    if preeclampsia_suspected(record) {
+        return HealthStatus::Critical;
+    }
+
+    // Severe edema with raised pressure
+    if record.edema == Some(EdemaGrade::ThreePlus) && hypertensive(record) {
+        return HealthStatus::Critical;
+    }
+
+    // Parse blood pressure
+    if let Some((systolic, diastolic)) = parse_blood_pressure(&record.blood_pressure) {
+        // Check for concerning blood pressure
+        if systolic >= limits.systolic_high
+            || diastolic >= limits.diastolic_high
+            || systolic < limits.systolic_low
+            || diastolic < limits.diastolic_low
+        {
+            return HealthStatus::Critical;
+        }
+    }
+
+    // Fetal heart rate outside 110-160 bpm; well outside it suggests fetal distress
+    if let Some(rate) = record.fetal_heart_rate {
+        if !(FHR_ALARM_LOW..=FHR_ALARM_HIGH).contains(&rate) {
+            return HealthStatus::Critical;
+        }
+        if !(FHR_NORMAL_LOW..=FHR_NORMAL_HIGH).contains(&rate) {
+            return HealthStatus::NeedsAttention;
+        }
+    }
+
+    // Proteinuria without raised pressure, glycosuria suggesting gestational diabetes, marked edema
+    // or acute malnutrition
+    if record.urine_protein.is_some_and(|protein| protein >= Dipstick::OnePlus)
+        || record.urine_glucose.is_some_and(|glucose| glucose >= Dipstick::TwoPlus)
+        || record.edema.is_some_and(|edema| edema >= EdemaGrade::TwoPlus)
+        || record.muac_cm.is_some_and(|muac| classify_muac(muac) != NutritionStatus::Normal)
+    {
+        return HealthStatus::NeedsAttention;
+    }
+
+    // Check weight changes
+    if record.weight < limits.weight_low || record.weight > limits.weight_high {
+        return HealthStatus::NeedsAttention;
+    }
+
+    // Check symptoms
+    triage_symptoms(&record.symptoms)
+}
+
+// Clinical definition of raised pressure in pregnancy, independent of the configured thresholds
+const HYPERTENSION_SYSTOLIC: i32 = 140;
+const HYPERTENSION_DIASTOLIC: i32 = 90;
+
+fn hypertensive(record: &HealthRecordPayload) -> bool {
+    parse_blood_pressure(&record.blood_pressure).is_some_and(|(systolic, diastolic)| {
+        systolic >= HYPERTENSION_SYSTOLIC || diastolic >= HYPERTENSION_DIASTOLIC
+    })
+}
+
+// Blood pressure of 140/90 or more with proteinuria of 1+ or more
+fn preeclampsia_suspected(record: &HealthRecordPayload) -> bool {
+    hypertensive(record) && record.urine_protein.is_some_and(|protein| protein >= Dipstick::OnePlus)
+}
+
+const FHR_NORMAL_LOW: u16 = 110;
+const FHR_NORMAL_HIGH: u16 = 160;
+const FHR_ALARM_LOW: u16 = 100;
+const FHR_ALARM_HIGH: u16 = 180;
+// Allowed gap between fundal height in cm and gestational age in weeks
+const FUNDAL_HEIGHT_TOLERANCE_CM: f32 = 3.0;
+
+// Reject readings that cannot be real; abnormal but possible ones are recorded and flagged
+fn validate_fetal_findings(payload: &HealthRecordPayload) -> Result<(), Error> {
+    if payload.fetal_heart_rate.is_some_and(|rate| !(50..=240).contains(&rate)) {
+        return Err(Error::ValidationError {
+            msg: "Fetal heart rate must be between 50 and 240 bpm".to_string(),
+        });
+    }
+    if payload.fundal_height_cm.is_some_and(|height| !(5.0..=50.0).contains(&height)) {
+        return Err(Error::ValidationError {
+            msg: "Fundal height must be between 5 and 50 cm".to_string(),
+        });
+    }
+    if payload.muac_cm.is_some_and(|muac| !(10.0..=50.0).contains(&muac)) {
+        return Err(Error::ValidationError {
+            msg: "MUAC must be between 10 and 50 cm".to_string(),
+        });
+    }
+    Ok(())
+}
+
+// MUAC cut-offs for pregnant and lactating women
+const MUAC_SEVERE_CM: f32 = 21.0;
+const MUAC_MODERATE_CM: f32 = 23.0;
+
+fn classify_muac(muac_cm: f32) -> NutritionStatus {
+    if muac_cm < MUAC_SEVERE_CM {
+        NutritionStatus::SevereAcute
+    } else if muac_cm < MUAC_MODERATE_CM {
+        NutritionStatus::ModerateAcute
+    } else {
+        NutritionStatus::Normal
+    }
+}
+
+// Mothers whose latest MUAC shows moderate or severe acute malnutrition, for supplementary
+// feeding referrals; `severe_only` leaves out moderate cases. Paged by mother id.
+#[ic_cdk::query]
+fn get_malnourished_mothers(
+    facility_code: Option<String>,
+    severe_only: Option<bool>,
+    cursor: Option<u64>,
+    limit: Option<u32>,
+) -> Page<MalnourishedMother> {
+    let mut latest: std::collections::BTreeMap<u64, HealthRecord> = std::collections::BTreeMap::new();
+    HEALTH_RECORD_STORAGE.with(|storage| {
+        for (_, record) in storage.borrow().iter().filter(|(_, record)| record.muac_cm.is_some()) {
+            match latest.get(&record.mother_id) {
+                Some(seen) if seen.date >= record.date => {}
+                _ => {
+                    latest.insert(record.mother_id, record);
+                }
+            }
+        }
+    });
+
+    let mothers: Vec<(u64, MalnourishedMother)> = latest
+        .into_iter()
+        .filter_map(|(mother_id, record)| {
+            let muac_cm = record.muac_cm?;
+            let nutrition_status = classify_muac(muac_cm);
+            let included = match nutrition_status {
+                NutritionStatus::Normal => false,
+                NutritionStatus::ModerateAcute => !severe_only.unwrap_or(false),
+                NutritionStatus::SevereAcute => true,
+            };
+            if !included {
+                return None;
+            }
+            let profile = PROFILE_STORAGE.with(|storage| storage.borrow().get(&mother_id))?;
+            if facility_code.is_some() && profile.facility_code != facility_code {
+                return None;
+            }
+            Some((
+                mother_id,
+                MalnourishedMother {
+                    mother_id,
+                    name: profile.name,
+                    facility_code: profile.facility_code,
+                    muac_cm,
+                    nutrition_status,
+                    measured_at: record.date,
+                },
+            ))
+        })
+        .collect();
+    paginate(mothers.into_iter(), cursor, limit)
+}
+
+// Check a structured note against its template and return the notes text to store: the free-text
+// notes, followed by each section under its title
+fn structured_notes(payload: &HealthRecordPayload) -> Result<String, Error> {
+    let Some(template_id) = &payload.note_template else {
+        if payload.note_sections.is_some() || payload.checklist_done.is_some() {
+            return Err(Error::InvalidInput {
+                msg: "note_sections and checklist_done need a note_template".to_string(),
+            });
+        }
+        return Ok(payload.notes.clone());
+    };
+    let template = NOTE_TEMPLATES
+        .with(|cell| cell.borrow().get().templates.iter().find(|template| &template.id == template_id).cloned())
+        .ok_or_else(|| Error::NotFound {
+            msg: format!("Note template '{}' not found", template_id),
+        })?;
+
+    let sections = payload.note_sections.as_deref().unwrap_or_default();
+    for section in sections {
+        if !template.sections.iter().any(|expected| expected.title == section.title) {
+            return Err(Error::ValidationError {
+                msg: format!("Template '{}' has no section '{}'", template.id, section.title),
+            });
+        }
+        validate_sensitive_field("note section", &section.text)?;
+    }
+    for expected in template.sections.iter().filter(|expected| expected.required) {
+        if !sections.iter().any(|section| section.title == expected.title && !section.text.trim().is_empty()) {
+            return Err(Error::ValidationError {
+                msg: format!("Section '{}' of template '{}' is required", expected.title, template.id),
+            });
+        }
+    }
+    if let Some(item) = payload.checklist_done.iter().flatten().find(|item| !template.checklist.contains(item)) {
+        return Err(Error::ValidationError {
+            msg: format!("Template '{}' has no checklist item '{}'", template.id, item),
+        });
+    }
+
+    let mut notes = payload.notes.trim().to_string();
+    for section in sections {
+        if !notes.is_empty() {
+            notes.push('\n');
+        }
+        notes.push_str(&format!("{}: {}", section.title, section.text.trim()));
+    }
+    Ok(notes)
+}
+
+// From 20 weeks fundal height in cm roughly equals gestational age in weeks
+fn assess_fundal_height(height_cm: f32, weeks: u64) -> Option<FundalHeightFlag> {
+    if !(20..=40).contains(&weeks) {
+        return None;
+    }
+    let expected = weeks as f32;
+    if height_cm < expected - FUNDAL_HEIGHT_TOLERANCE_CM {
+        Some(FundalHeightFlag::SmallForDates)
+    } else if height_cm > expected + FUNDAL_HEIGHT_TOLERANCE_CM {
+        Some(FundalHeightFlag::LargeForDates)
+    } else {
+        None
+    }
+}
+
+const CRITICAL_SYMPTOMS: [&str; 8] = [
+    "severe", "emergency", "critical", "bleeding",
+    "seizure", "unconscious", "fever", "headache"
+];
+
+const CONCERNING_SYMPTOMS: [&str; 7] = [
+    "nausea", "vomiting", "swelling", "pain",
+    "discomfort", "fatigue", "dizziness"
+];
+
+// Triage on symptoms alone: CRITICAL on any danger sign, NEEDS ATTENTION on a concerning symptom
+fn triage_symptoms(symptoms: &[String]) -> HealthStatus {
+    if !danger_signs(symptoms).is_empty() {
+        HealthStatus::Critical
+    } else if symptoms.iter().any(|s|
+        CONCERNING_SYMPTOMS.iter().any(|cs| s.to_lowercase().contains(cs))
+    ) {
+        HealthStatus::NeedsAttention
+    } else {
+        HealthStatus::Normal
+    }
+}
+
+fn danger_signs(symptoms: &[String]) -> Vec<String> {
+    symptoms
+        .iter()
+        .filter(|s| CRITICAL_SYMPTOMS.iter().any(|cs| s.to_lowercase().contains(cs)))
+        .cloned()
+        .collect()
+}
+
+// Helper to parse a "systolic/diastolic" blood pressure reading
+fn parse_blood_pressure(blood_pressure: &str) -> Option<(i32, i32)> {
+    let (systolic, diastolic) = blood_pressure.split_once('/')?;
+    match (systolic.trim().parse::<i32>(), diastolic.trim().parse::<i32>()) {
+        (Ok(systolic), Ok(diastolic)) => Some((systolic, diastolic)),
+        _ => None,
+    }
+}
+
+// Get mother's profile; reads are logged in her access log
+#[ic_cdk::update]
+fn get_mother_profile(id: u64) -> Result<MotherProfile, Error> {
+    let profile = load_mother_profile(id)?;
+    ensure_mother_reader(&profile)?;
+    log_read(id, "get_mother_profile");
+    Ok(profile)
+}
+
+// Refuse a write that would reference a mother who does not exist or is deleted
+fn ensure_mother_exists(id: u64) -> Result<(), Error> {
+    if PROFILE_STORAGE.with(|storage| storage.borrow().contains_key(&id)) {
+        Ok(())
+    } else {
+        Err(Error::NotFound {
+            msg: format!("Mother with id={} not found", id),
+        })
+    }
+}
+
+fn load_mother_profile(id: u64) -> Result<MotherProfile, Error> {
+    PROFILE_STORAGE.with(|storage| {
+        match storage.borrow().get(&id) {
+            Some(profile) => Ok(profile),
+            None => Err(Error::NotFound {
+                msg: format!("Mother with id={} not found", id),
+            }),
+        }
+    })
+}
+
+// Get up to 100 profiles by id in one call, in the order requested
+#[ic_cdk::update]
+fn get_mother_profiles(ids: Vec<u64>) -> Result<ProfileLookup, Error> {
+    ensure_batch_size(ids.len())?;
+
+    let mut lookup = ProfileLookup {
+        profiles: Vec::new(),
+        missing_ids: Vec::new(),
+    };
+    PROFILE_STORAGE.with(|storage| {
+        let storage = storage.borrow();
+        for id in ids {
+            match storage.get(&id) {
+                Some(profile) => lookup.profiles.push(profile),
+                None => lookup.missing_ids.push(id),
+            }
+        }
+    });
+    for profile in &lookup.profiles {
+        log_read(profile.id, "get_mother_profiles");
+    }
+    Ok(lookup)
+}
+
+// Get mother's health records, including any moved to the archive canister
+#[ic_cdk::update]
+async fn get_mother_health_records(
+    mother_id: u64,
+    dates: Option<DateRange>,
+    cursor: Option<u64>,
+    limit: Option<u32>,
+) -> Result<Page<HealthRecord>, Error> {
+    let dates = dates.unwrap_or_default();
+    let from = dates.from.unwrap_or(0);
+    let to = dates.to.unwrap_or(u64::MAX);
+
+    let mut records = mother_health_records(mother_id).await?;
+    records.retain(|record| (from..=to).contains(&record.date));
+    let page = paginate(records.into_iter().map(|record| (record.id, record)), cursor, limit);
+
+    if page.total == 0 {
+        Err(Error::NotFound {
+            msg: format!("No health records found for mother_id={}", mother_id),
+        })
+    } else {
+        log_read(mother_id, "get_mother_health_records");
+        Ok(page)
+    }
+}
+
+// Page through `(key, item)` pairs in key order, starting after the `cursor` key
+fn paginate<T>(items: impl Iterator<Item = (u64, T)>, cursor: Option<u64>, limit: Option<u32>) -> Page<T> {
+    let config = canister_config();
+    let limit = limit.unwrap_or(config.default_page_size).clamp(1, config.max_page_size) as usize;
+    let mut page = Page {
+        items: Vec::new(),
+        next_cursor: None,
+        total: 0,
+    };
+    let mut last_key = None;
+
+    for (key, item) in items {
+        page.total += 1;
+        if cursor.is_some_and(|cursor| key <= cursor) {
+            continue;
+        }
+        if page.items.len() < limit {
+            page.items.push(item);
+            last_key = Some(key);
+        } else if page.next_cursor.is_none() {
+            page.next_cursor = last_key;
+        }
+    }
+    page
+}
+
+// Pages a list that is already in the order it is shown in. The cursor is a position in the
+// list, so an item that moves between calls can be skipped or repeated.
+fn paginate_in_order<T>(items: Vec<T>, cursor: Option<u64>, limit: Option<u32>) -> Page<T> {
+    paginate((1..).zip(items), cursor, limit)
+}
+
+// Get profiles created or updated in a time range, sorted by that timestamp; reads are logged
+#[ic_cdk::update]
+fn get_profiles_by_time(
+    filter: TimeFilter,
+    cursor: Option<u64>,
+    limit: Option<u32>,
+) -> Result<Page<MotherProfile>, Error> {
+    let scope = list_scope()?;
+    let profiles = PROFILE_STORAGE.with(|storage| {
+        storage.borrow().iter().map(|(_, p)| p).filter(|profile| in_list_scope(&scope, profile)).collect()
+    });
+    let profiles = filter_by_time(profiles, &filter, |profile, field| match field {
+        TimestampField::CreatedAt => profile.created_at,
+        TimestampField::UpdatedAt => profile.updated_at.unwrap_or(profile.created_at),
+    });
+    Ok(log_page_reads(paginate_in_order(profiles, cursor, limit), |profile| profile.id, "get_profiles_by_time"))
+}
+
+// Get health records (optionally for one mother) created or updated in a time range, sorted by that
+// timestamp; reads are logged
+#[ic_cdk::update]
+fn get_health_records_by_time(
+    mother_id: Option<u64>,
+    filter: TimeFilter,
+    cursor: Option<u64>,
+    limit: Option<u32>,
+) -> Result<Page<HealthRecord>, Error> {
+    let records = match mother_id {
+        Some(mother_id) => {
+            ensure_mother_reader(&load_mother_profile(mother_id)?)?;
+            HEALTH_RECORD_STORAGE.with(|storage| {
+                storage.borrow().range(mother_record_keys(mother_id)).map(|(_, record)| record).collect()
+            })
+        }
+        None => {
+            let scope = list_scope()?;
+            HEALTH_RECORD_STORAGE.with(|storage| {
+                storage
+                    .borrow()
+                    .iter()
+                    .map(|(_, record)| record)
+                    .filter(|record| {
+                        scope.is_none()
+                            || load_mother_profile(record.mother_id).is_ok_and(|profile| in_list_scope(&scope, &profile))
+                    })
+                    .collect()
+            })
+        }
+    };
+    // Records stored before these timestamps existed fall back to the visit date
+    let records = filter_by_time(records, &filter, |record, field| {
+        let created_at = record.created_at.unwrap_or(record.date);
+        match field {
+            TimestampField::CreatedAt => created_at,
+            TimestampField::UpdatedAt => record.updated_at.unwrap_or(created_at),
+        }
+    });
+    Ok(log_page_reads(paginate_in_order(records, cursor, limit), |record| record.mother_id, "get_health_records_by_time"))
+}
+
+fn filter_by_time<T>(items: Vec<T>, filter: &TimeFilter, timestamp: impl Fn(&T, TimestampField) -> u64) -> Vec<T> {
+    let from = filter.from.unwrap_or(0);
+    let to = filter.to.unwrap_or(u64::MAX);
+    let mut items: Vec<(u64, T)> = items
+        .into_iter()
+        .map(|item| (timestamp(&item, filter.field), item))
+        .filter(|(at, _)| (from..=to).contains(at))
+        .collect();
+
+    items.sort_by_key(|(at, _)| *at);
+    if matches!(filter.order, SortOrder::Descending) {
+        items.reverse();
+    }
+    items.into_iter().map(|(_, item)| item).collect()
+}
+
+// Get high-risk profiles: critical mothers, and from 36 weeks those with a previous caesarean;
+// reads are logged
+#[ic_cdk::update]
+fn get_high_risk_profiles(cursor: Option<u64>, limit: Option<u32>) -> Result<Page<MotherProfile>, Error> {
+    let scope = list_scope()?;
+    let now = time();
+    let page = PROFILE_STORAGE.with(|storage| {
+        paginate(
+            storage.borrow().iter().filter(|(_, profile)| {
+                (matches!(profile.health_status, HealthStatus::Critical) || prior_cesarean_near_term(profile, now))
+                    && in_list_scope(&scope, profile)
+            }),
+            cursor,
+            limit,
+        )
+    });
+    Ok(log_page_reads(page, |profile| profile.id, "get_high_risk_profiles"))
+}
+
+// From this gestational age a mother with a previous caesarean is reviewed as high risk
+const PRIOR_CESAREAN_REVIEW_WEEK: u64 = 36;
+
+fn prior_cesarean(profile: &MotherProfile) -> bool {
+    profile.previous_cesareans.is_some_and(|count| count > 0)
+}
+
+// Undelivered, with a previous caesarean and at or past PRIOR_CESAREAN_REVIEW_WEEK
+fn prior_cesarean_near_term(profile: &MotherProfile, now: u64) -> bool {
+    prior_cesarean(profile)
+        && profile.delivery.is_none()
+        && gestational_age_weeks(profile.expected_delivery_date, now) >= PRIOR_CESAREAN_REVIEW_WEEK
+}
+
+// Get critical cases; reads are logged
+#[ic_cdk::update]
+fn get_critical_cases(cursor: Option<u64>, limit: Option<u32>) -> Result<Page<MotherProfile>, Error> {
+    let scope = list_scope()?;
+    let page = PROFILE_STORAGE.with(|storage| {
+        paginate(
+            storage.borrow().iter().filter(|(_, profile)| {
+                matches!(profile.health_status, HealthStatus::Critical) && in_list_scope(&scope, profile)
+            }),
+            cursor,
+            limit,
+        )
+    });
+    Ok(log_page_reads(page, |profile| profile.id, "get_critical_cases"))
+}
+
+// Update or remove a mother's insurance cover
+#[ic_cdk::update]
+fn update_insurance(
+    mother_id: u64,
+    expected_version: u64,
+    insurance: Option<InsuranceCover>,
+) -> Result<MotherProfile, Error> {
+    if let Some(cover) = &insurance {
+        validate_insurance(cover)?;
+    }
+
+    PROFILE_STORAGE.with(|storage| {
+        let mut storage = storage.borrow_mut();
+        match storage.get(&mother_id) {
+            Some(mut profile) => {
+                check_version("Mother", mother_id, profile.version, expected_version)?;
+                profile.insurance = insurance;
+                profile.version = next_version(profile.version);
+                profile.updated_at = Some(time());
+                ensure_fits("Mother", mother_id, &profile)?;
+                set_field_clock(mother_id, "insurance", time());
+                let previous = storage.insert(mother_id, profile.clone());
+                log_change_with_reason(EntityType::MotherProfile, mother_id, previous.as_ref(), Some(&profile), Some("Insurance updated"));
+                Ok(profile)
+            }
+            None => Err(Error::NotFound {
+                msg: format!("Mother with id={} not found", mother_id),
+            }),
+        }
+    })
+}
+
+// Get high-risk mothers who are still pregnant and have no usable insurance cover,
+// either because they are not enrolled or their latest visit was not eligible; reads are logged
+#[ic_cdk::update]
+fn get_uninsured_high_risk_mothers(cursor: Option<u64>, limit: Option<u32>) -> Result<Page<MotherProfile>, Error> {
+    let scope = list_scope()?;
+    let page = PROFILE_STORAGE.with(|storage| {
+        let storage = storage.borrow();
+        let mothers = storage
+            .iter()
+            .filter(|(_, profile)| in_list_scope(&scope, profile))
+            .filter(|(_, profile)| matches!(profile.health_status, HealthStatus::Critical))
+            .filter(|(_, profile)| !matches!(profile.stage, PregnancyStage::PostPartum))
+            .filter(|(id, profile)| {
+                profile.insurance.is_none()
+                    || matches!(
+                        latest_health_record(*id).and_then(|record| record.insurance_eligible),
+                        Some(false)
+                    )
+            });
+        paginate(mothers, cursor, limit)
+    });
+    Ok(log_page_reads(page, |profile| profile.id, "get_uninsured_high_risk_mothers"))
+}
+
+// Key range holding all of a mother's health records
+fn mother_record_keys(mother_id: u64) -> std::ops::RangeInclusive<RecordKey> {
+    RecordKey { mother_id, seq: 0 }..=RecordKey { mother_id, seq: u64::MAX }
+}
+
+// Look up a health record by its id
+fn get_health_record(id: u64) -> Option<HealthRecord> {
+    let mother_id = HEALTH_RECORD_MOTHERS.with(|index| index.borrow().get(&id))?;
+    HEALTH_RECORD_STORAGE.with(|storage| storage.borrow().get(&RecordKey { mother_id, seq: id }))
+}
+
+// Insert or replace a health record, returning the previous version
+fn store_health_record(record: &HealthRecord) -> Option<HealthRecord> {
+    HEALTH_RECORD_MOTHERS.with(|index| index.borrow_mut().insert(record.id, record.mother_id));
+    HEALTH_RECORD_STORAGE.with(|storage| {
+        storage.borrow_mut().insert(
+            RecordKey {
+                mother_id: record.mother_id,
+                seq: record.id,
+            },
+            record.clone(),
+        )
+    })
+}
+
+// Helper to find the most recent health record for a mother
+fn latest_health_record(mother_id: u64) -> Option<HealthRecord> {
+    HEALTH_RECORD_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .range(mother_record_keys(mother_id))
+            .map(|(_, record)| record)
+            .max_by_key(|record| record.date)
+    })
+}
+
+// Get upcoming appointments; reads are logged
+#[ic_cdk::update]
+fn get_upcoming_appointments(
+    days: Option<u64>,
+    cursor: Option<u64>,
+    limit: Option<u32>,
+) -> Result<Page<(MotherProfile, HealthRecord)>, Error> {
+    let scope = list_scope()?;
+    let days = days.unwrap_or_else(|| canister_config().appointment_reminder_days);
+    let now = time();
+    let target = now + (days * 24 * 60 * 60 * 1_000_000_000);
+    
+    let page = HEALTH_RECORD_STORAGE.with(|record_storage| {
+        PROFILE_STORAGE.with(|profile_storage| {
+            let records = record_storage.borrow();
+            let profiles = profile_storage.borrow();
+            
+            // Paged by health record id. Storage is ordered by mother first, so the matches are
+            // sorted by id before paging.
+            let mut appointments: Vec<(u64, (MotherProfile, HealthRecord))> = records
+                .iter()
+                .filter(|(_, record)| {
+                    record.next_appointment > now && record.next_appointment <= target
+                })
+                .filter_map(|(_, record)| {
+                    profiles
+                        .get(&record.mother_id)
+                        .filter(|profile| in_list_scope(&scope, profile))
+                        .map(|profile| (record.id, (profile, record)))
+                })
+                .collect();
+            appointments.sort_by_key(|(id, _)| *id);
+            paginate(appointments.into_iter(), cursor, limit)
+        })
+    });
+    Ok(log_page_reads(page, |(profile, _)| profile.id, "get_upcoming_appointments"))
+}
+
+// Configure the ICRC-1 ledger used to settle payments
+#[ic_cdk::update]
+fn set_ledger_canister(ledger_canister: Principal) -> Result<(), Error> {
+    ensure_controller()?;
+
+    LEDGER_CONFIG.with(|config| {
+        config
+            .borrow_mut()
+            .set(LedgerConfig { ledger_canister: Some(ledger_canister) })
+            .map(|_| ())
+            .map_err(|_| Error::SystemError { msg: "Failed to store ledger config".to_string() })
+    })
+}
+
+// Grant or revoke the finance role (controllers and admins only)
+#[ic_cdk::update]
+fn set_finance_officer(principal: Principal, allowed: bool) -> Result<(), Error> {
+    ensure_controller()?;
+    set_grant(&FINANCE_OFFICERS, principal, allowed);
+    Ok(())
+}
+
+#[ic_cdk::query]
+fn get_finance_officers() -> Result<Vec<AccessGrant>, Error> {
+    ensure_controller()?;
+    list_grants(&FINANCE_OFFICERS)
+}
+
+// Finance officers, controllers and admins
+fn ensure_finance_officer() -> Result<(), Error> {
+    let caller = StringKey(ic_cdk::caller().to_text());
+    if FINANCE_OFFICERS.with(|officers| officers.borrow().contains_key(&caller)) {
+        return Ok(());
+    }
+    ensure_controller().map_err(|_| Error::AuthorizationError {
+        msg: "Recording and settling payments needs the finance role".to_string(),
+    })
+}
+
+// Bind a principal to the facility they work at, or with no facility remove them (controllers and
+// admins only)
+#[ic_cdk::update]
+fn set_facility_staff(principal: Principal, facility_code: Option<String>) -> Result<(), Error> {
+    ensure_controller()?;
+    let key = StringKey(principal.to_text());
+    match facility_code {
+        Some(facility_code) => {
+            validate_facility_code(&facility_code)?;
+            let staff = FacilityStaff {
+                principal,
+                facility_code,
+                granted_at: time(),
+            };
+            FACILITY_STAFF.with(|staff_list| staff_list.borrow_mut().insert(key, staff));
+        }
+        None => {
+            FACILITY_STAFF.with(|staff_list| staff_list.borrow_mut().remove(&key));
+        }
+    }
+    Ok(())
+}
+
+// Facility staff, optionally of one facility (controllers and admins only)
+#[ic_cdk::query]
+fn get_facility_staff(facility_code: Option<String>) -> Result<Vec<FacilityStaff>, Error> {
+    ensure_controller()?;
+    Ok(FACILITY_STAFF.with(|staff_list| {
+        staff_list
+            .borrow()
+            .iter()
+            .map(|(_, staff)| staff)
+            .filter(|staff| facility_code.is_none() || facility_code.as_ref() == Some(&staff.facility_code))
+            .collect()
+    }))
+}
+
+// Staff of the given facility, controllers and admins
+fn ensure_facility_staff(facility_code: &str) -> Result<(), Error> {
+    let caller = StringKey(ic_cdk::caller().to_text());
+    let staff = FACILITY_STAFF.with(|staff_list| staff_list.borrow().get(&caller));
+    if staff.is_some_and(|staff| staff.facility_code == facility_code) {
+        return Ok(());
+    }
+    ensure_controller().map_err(|_| Error::AuthorizationError {
+        msg: format!("This needs the staff role at {}", facility_code),
+    })
+}
+
+// Register an account payments may be sent to (controllers and admins only)
+#[ic_cdk::update]
+fn set_payout_account(payload: PayoutAccountPayload) -> Result<PayoutAccount, Error> {
+    ensure_controller()?;
+
+    let name = payload.name.trim().to_string();
+    if name.is_empty() || name.len() > 100 {
+        return Err(Error::InvalidInput {
+            msg: "Payout account name must be 1-100 characters".to_string(),
+        });
+    }
+    if let Some(code) = &payload.facility_code {
+        validate_facility_code(code)?;
+        // A facility is paid into exactly one account
+        if let Some(existing) = facility_payout_account(code) {
+            if existing.owner != payload.owner {
+                return Err(Error::InvalidInput {
+                    msg: format!("Facility {} already pays out to {}", code, existing.owner),
+                });
+            }
+        }
+    }
+
+    let account = PayoutAccount {
+        owner: payload.owner,
+        name,
+        facility_code: payload.facility_code,
+        registered_by: ic_cdk::caller(),
+        registered_at: time(),
+    };
+    PAYOUT_ACCOUNTS.with(|accounts| {
+        accounts.borrow_mut().insert(StringKey(account.owner.to_text()), account.clone())
+    });
+    Ok(account)
+}
+
+// Stop allowing payments to an account (controllers and admins only)
+#[ic_cdk::update]
+fn remove_payout_account(owner: Principal) -> Result<(), Error> {
+    ensure_controller()?;
+    PAYOUT_ACCOUNTS
+        .with(|accounts| accounts.borrow_mut().remove(&StringKey(owner.to_text())))
+        .map(|_| ())
+        .ok_or(Error::NotFound {
+            msg: format!("Payout account {} not found", owner),
+        })
+}
+
+#[ic_cdk::query]
+fn get_payout_accounts() -> Result<Vec<PayoutAccount>, Error> {
+    ensure_finance_officer()?;
+    Ok(PAYOUT_ACCOUNTS.with(|accounts| accounts.borrow().iter().map(|(_, account)| account).collect()))
+}
+
+// The account registered as the given facility's payout account
+fn facility_payout_account(facility_code: &str) -> Option<PayoutAccount> {
+    PAYOUT_ACCOUNTS.with(|accounts| {
+        accounts
+            .borrow()
+            .iter()
+            .map(|(_, account)| account)
+            .find(|account| account.facility_code.as_deref() == Some(facility_code))
+    })
+}
+
+// Record a payment for a visit or delivery (finance officers)
+#[ic_cdk::update]
+fn create_payment(payload: PaymentPayload) -> Result<Payment, Error> {
+    observe_call("create_payment", ensure_finance_officer().and_then(|_| insert_payment(payload)))
+}
+
+fn insert_payment(payload: PaymentPayload) -> Result<Payment, Error> {
+    let mut batch = WriteBatch::default();
+    let payment = stage_payment(payload, &mut batch)?;
+    batch.commit();
+    Ok(payment)
+}
+
+// Validate a payment and stage its writes, for callers that write more alongside it
+fn stage_payment(payload: PaymentPayload, batch: &mut WriteBatch) -> Result<Payment, Error> {
+    ensure_feature("payments")?;
+    load_mother_profile(payload.mother_id)?;
+
+    let registered = PAYOUT_ACCOUNTS
+        .with(|accounts| accounts.borrow().contains_key(&StringKey(payload.recipient.to_text())));
+    if !registered {
+        return Err(Error::InvalidInput {
+            msg: format!("{} is not a registered payout account", payload.recipient),
+        });
+    }
+
+    if payload.amount == 0 {
+        return Err(Error::InvalidInput {
+            msg: "Payment amount must be greater than zero".to_string(),
+        });
+    }
+
+    // A linked visit must belong to the same mother
+    if let Some(record_id) = payload.record_id {
+        let record = get_health_record(record_id);
+        match record {
+            Some(record) if record.mother_id == payload.mother_id => {}
+            Some(_) => {
+                return Err(Error::InvalidInput {
+                    msg: format!("Health record id={} belongs to another mother", record_id),
+                })
+            }
+            None => {
+                return Err(Error::NotFound {
+                    msg: format!("Health record with id={} not found", record_id),
+                })
+            }
+        }
+    }
+
+    let id = generate_new_id(EntityType::Payment)?;
+
+    let payment = Payment {
+        id,
+        mother_id: payload.mother_id,
+        record_id: payload.record_id,
+        purpose: payload.purpose,
+        amount: payload.amount,
+        recipient: payload.recipient,
+        status: PaymentStatus::Pending,
+        block_index: None,
+        failure_reason: None,
+        created_at: time(),
+        settled_at: None,
+        version: Some(1),
+        ulid: Some(new_ulid(EntityType::Payment, id)),
+        updated_at: Some(time()),
+        ledger_created_at: None,
+    };
+
+    let stored = payment.clone();
+    batch.stage(move || {
+        if let Some(ulid) = &stored.ulid {
+            index_ulid(ulid, EntityType::Payment, id);
+        }
+        PAYMENT_STORAGE.with(|storage| storage.borrow_mut().insert(id, stored.clone()));
+        log_change(EntityType::Payment, id, None, Some(&stored));
+    });
+    Ok(payment)
+}
+
+// How long the ledger remembers a transfer's (created_at_time, memo) for deduplication
+const LEDGER_TX_WINDOW_NANOS: u64 = 24 * 60 * 60 * 1_000_000_000;
+
+// Settle a pending, failed or unconfirmed payment through the ICRC-1 ledger (finance officers)
+#[ic_cdk::update]
+async fn settle_payment(id: u64) -> Result<Payment, Error> {
+    ensure_feature("payments")?;
+    ensure_finance_officer()?;
+    let mut payment = get_payment(id)?;
+    if !matches!(
+        payment.status,
+        PaymentStatus::Pending | PaymentStatus::Failed | PaymentStatus::NeedsReconciliation
+    ) {
+        return Err(Error::InvalidInput {
+            msg: format!("Payment with id={} is not awaiting settlement", id),
+        });
+    }
+
+    let ledger = LEDGER_CONFIG
+        .with(|config| config.borrow().get().ledger_canister)
+        .ok_or_else(|| Error::SystemError { msg: "Ledger canister is not configured".to_string() })?;
+
+    let now = time();
+    let unconfirmed = matches!(payment.status, PaymentStatus::NeedsReconciliation);
+    let created_at_time = ledger_created_at_time(&payment, now);
+
+    // Mark as in flight so concurrent calls cannot settle the same payment twice
+    payment.status = PaymentStatus::Processing;
+    payment.ledger_created_at = Some(created_at_time);
+    payment.version = next_version(payment.version);
+    payment.updated_at = Some(now);
+    let previous = PAYMENT_STORAGE.with(|storage| storage.borrow_mut().insert(id, payment.clone()));
+    log_change(EntityType::Payment, id, previous.as_ref(), Some(&payment));
+
+    let arg = TransferArg {
+        from_subaccount: None,
+        to: Account { owner: payment.recipient, subaccount: None },
+        amount: Nat::from(payment.amount),
+        fee: None,
+        memo: Some(id.to_be_bytes().to_vec()),
+        created_at_time: Some(created_at_time),
+    };
+
+    let result: Result<(Result<Nat, TransferError>,), _> =
+        ic_cdk::call(ledger, "icrc1_transfer", (arg,)).await;
+
+    apply_transfer_outcome(&mut payment, result, unconfirmed, time());
+
+    payment.version = next_version(payment.version);
+    payment.updated_at = Some(time());
+    let previous = PAYMENT_STORAGE.with(|storage| storage.borrow_mut().insert(id, payment.clone()));
+    log_change(EntityType::Payment, id, previous.as_ref(), Some(&payment));
+    Ok(payment)
+}
+
+// created_at_time for a transfer attempt. Retries carry the first attempt's time and memo so the
+// ledger answers Duplicate instead of paying twice. Only once every earlier attempt was refused
+// outright, and the ledger's deduplication window has passed, is a new time taken.
+fn ledger_created_at_time(payment: &Payment, now: u64) -> u64 {
+    let unconfirmed = matches!(payment.status, PaymentStatus::NeedsReconciliation);
+    match payment.ledger_created_at {
+        Some(first) if unconfirmed || now.saturating_sub(first) <= LEDGER_TX_WINDOW_NANOS => first,
+        _ => now,
+    }
+}
+
+// Record the ledger's answer to a transfer on the payment
+fn apply_transfer_outcome(
+    payment: &mut Payment,
+    result: Result<(Result<Nat, TransferError>,), (RejectionCode, String)>,
+    unconfirmed: bool,
+    now: u64,
+) {
+    match result {
+        Ok((Ok(block_index),)) | Ok((Err(TransferError::Duplicate { duplicate_of: block_index }),)) => {
+            payment.status = PaymentStatus::Settled;
+            payment.block_index = Some(block_index);
+            payment.failure_reason = None;
+            payment.settled_at = Some(now);
+        }
+        // The original attempt is outside the ledger's window, so a retry cannot tell whether it landed
+        Ok((Err(TransferError::TooOld),)) if unconfirmed => {
+            payment.status = PaymentStatus::NeedsReconciliation;
+            payment.failure_reason =
+                Some("Transfer is too old to retry; look it up on the ledger and resolve it".to_string());
+        }
+        Ok((Err(err),)) => {
+            payment.status = PaymentStatus::Failed;
+            payment.failure_reason = Some(format!("Ledger rejected transfer: {:?}", err));
+        }
+        // The ledger never ran the transfer, or trapped and rolled it back
+        Err((
+            code @ (RejectionCode::DestinationInvalid | RejectionCode::CanisterReject | RejectionCode::CanisterError),
+            msg,
+        )) => {
+            payment.status = PaymentStatus::Failed;
+            payment.failure_reason = Some(format!("Ledger call failed: {:?} {}", code, msg));
+        }
+        Err((code, msg)) => {
+            payment.status = PaymentStatus::NeedsReconciliation;
+            payment.failure_reason = Some(format!("Ledger call outcome unknown: {:?} {}", code, msg));
+        }
+    }
+}
+
+// A payment still in flight a full deduplication window after it was sent was stranded by a
+// callback that trapped, so the ledger call's outcome was never recorded
+fn stranded_in_flight(payment: &Payment, now: u64) -> bool {
+    matches!(payment.status, PaymentStatus::Processing)
+        && now.saturating_sub(payment.updated_at.unwrap_or(payment.created_at)) > LEDGER_TX_WINDOW_NANOS
+}
+
+// Close an unconfirmed payment, or one stranded in flight for longer than the ledger's
+// deduplication window, after looking it up on the ledger: pass the block it landed in,
+// or none if it never did (finance officers)
+#[ic_cdk::update]
+fn resolve_payment(id: u64, block_index: Option<Nat>) -> Result<Payment, Error> {
+    ensure_finance_officer()?;
+    let mut payment = get_payment(id)?;
+    if !matches!(payment.status, PaymentStatus::NeedsReconciliation) && !stranded_in_flight(&payment, time()) {
+        return Err(Error::InvalidInput {
+            msg: format!("Payment with id={} does not need reconciliation", id),
+        });
+    }
+
+    match block_index {
+        Some(block_index) => {
+            payment.status = PaymentStatus::Settled;
+            payment.block_index = Some(block_index);
+            payment.failure_reason = None;
+            payment.settled_at = Some(time());
+        }
+        None => {
+            payment.status = PaymentStatus::Failed;
+            payment.failure_reason = Some("Transfer not found on the ledger".to_string());
+        }
+    }
+
+    payment.version = next_version(payment.version);
+    payment.updated_at = Some(time());
+    let previous = PAYMENT_STORAGE.with(|storage| storage.borrow_mut().insert(id, payment.clone()));
+    log_change(EntityType::Payment, id, previous.as_ref(), Some(&payment));
+    Ok(payment)
+}
+
+// Get payment by ID
+#[ic_cdk::query]
+fn get_payment(id: u64) -> Result<Payment, Error> {
+    PAYMENT_STORAGE.with(|storage| {
+        storage.borrow().get(&id).ok_or(Error::NotFound {
+            msg: format!("Payment with id={} not found", id),
+        })
+    })
+}
+
+// Get all payments for a mother
+#[ic_cdk::query]
+fn get_mother_payments(mother_id: u64) -> Vec<Payment> {
+    PAYMENT_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .filter(|(_, payment)| payment.mother_id == mother_id)
+            .map(|(_, payment)| payment)
+            .collect()
+    })
+}
+
+// Reconcile a mother's payments against her visits and scheduled appointments
+#[ic_cdk::query]
+fn get_payment_reconciliation(mother_id: u64) -> Result<PaymentReconciliation, Error> {
+    load_mother_profile(mother_id)?;
+
+    let mut unlinked_payments = Vec::new();
+    let mut by_record: std::collections::BTreeMap<u64, Vec<Payment>> = std::collections::BTreeMap::new();
+    for payment in get_mother_payments(mother_id) {
+        match payment.record_id {
+            Some(record_id) => by_record.entry(record_id).or_default().push(payment),
+            None => unlinked_payments.push(payment),
+        }
+    }
+
+    let visits = HEALTH_RECORD_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .range(mother_record_keys(mother_id))
+            .map(|(key, record)| {
+                let id = key.seq;
+                let payments = by_record.remove(&id).unwrap_or_default();
+                let settled_amount = payments
+                    .iter()
+                    .filter(|p| matches!(p.status, PaymentStatus::Settled))
+                    .map(|p| p.amount)
+                    .sum();
+                let outstanding_amount = payments
+                    .iter()
+                    .filter(|p| !matches!(p.status, PaymentStatus::Settled))
+                    .map(|p| p.amount)
+                    .sum();
+                VisitPayments {
+                    record_id: id,
+                    visit_date: record.date,
+                    next_appointment: record.next_appointment,
+                    payments,
+                    settled_amount,
+                    outstanding_amount,
+                }
+            })
+            .collect()
+    });
+
+    Ok(PaymentReconciliation { visits, unlinked_payments })
+}
+
+// Refer a mother to another facility
+#[ic_cdk::update]
+fn create_referral(payload: ReferralPayload) -> Result<Referral, Error> {
+    observe_call("create_referral", insert_referral(payload))
+}
+
+fn insert_referral(payload: ReferralPayload) -> Result<Referral, Error> {
+    load_mother_profile(payload.mother_id)?;
+    validate_facility_code(&payload.from_facility)?;
+    validate_facility_code(&payload.to_facility)?;
+
+    if payload.reason.trim().is_empty() {
+        return Err(Error::InvalidInput {
+            msg: "Referral reason is required".to_string(),
+        });
+    }
+    if payload.distance_km.is_some_and(|km| km > MAX_REFERRAL_DISTANCE_KM) {
+        return Err(Error::InvalidInput {
+            msg: format!("Referral distance must be at most {} km", MAX_REFERRAL_DISTANCE_KM),
+        });
+    }
+    // The distance decides whether the mother gets a transport voucher, so only the referring
+    // facility's staff may state it
+    if payload.distance_km.is_some() {
+        ensure_facility_staff(&payload.from_facility)?;
+    }
+
+    let id = generate_new_id(EntityType::Referral)?;
+
+    let referral = Referral {
+        id,
+        mother_id: payload.mother_id,
+        from_facility: payload.from_facility,
+        to_facility: payload.to_facility,
+        reason: payload.reason,
+        status: ReferralStatus::Pending,
+        created_at: time(),
+        completed_at: None,
+        version: Some(1),
+        ulid: Some(new_ulid(EntityType::Referral, id)),
+        updated_at: Some(time()),
+        distance_km: payload.distance_km,
+    };
+
+    let mut batch = WriteBatch::default();
+    if let Some(voucher) = transport_voucher_for(&referral) {
+        batch.stage(move || {
+            TRANSPORT_VOUCHERS.with(|vouchers| vouchers.borrow_mut().insert(voucher.id, voucher));
+        });
+    }
+    let stored = referral.clone();
+    batch.stage(move || {
+        if let Some(ulid) = &stored.ulid {
+            index_ulid(ulid, EntityType::Referral, id);
+        }
+        REFERRAL_STORAGE.with(|storage| storage.borrow_mut().insert(id, stored.clone()));
+        log_change(EntityType::Referral, id, None, Some(&stored));
+        update_facility_metrics(&stored.from_facility, |metrics| metrics.referrals_made += 1);
+    });
+    batch.commit();
+
+    Ok(referral)
+}
+
+// Mark a referral as completed once the mother arrives at the receiving facility
+#[ic_cdk::update]
+fn complete_referral(id: u64, expected_version: u64) -> Result<Referral, Error> {
+    let result = close_referral(id, expected_version, ReferralStatus::Completed);
+    if let Ok(referral) = &result {
+        update_facility_metrics(&referral.from_facility, |metrics| metrics.referrals_completed += 1);
+    }
+    observe_call("complete_referral", result)
+}
+
+// Cancel a pending referral, and its transport voucher if not yet redeemed
+#[ic_cdk::update]
+fn cancel_referral(id: u64, expected_version: u64) -> Result<Referral, Error> {
+    let result = close_referral(id, expected_version, ReferralStatus::Cancelled);
+    if result.is_ok() {
+        cancel_transport_vouchers(id);
+    }
+    observe_call("cancel_referral", result)
+}
+
+// Helper to move a pending referral to its final status
+fn close_referral(id: u64, expected_version: u64, status: ReferralStatus) -> Result<Referral, Error> {
+    REFERRAL_STORAGE.with(|storage| {
+        let mut storage = storage.borrow_mut();
+        match storage.get(&id) {
+            Some(mut referral) => {
+                check_version("Referral", id, referral.version, expected_version)?;
+                if !matches!(referral.status, ReferralStatus::Pending) {
+                    return Err(Error::InvalidInput {
+                        msg: format!("Referral with id={} is already closed", id),
+                    });
+                }
+                let reason = match status {
+                    ReferralStatus::Completed => "Referral completed",
+                    _ => "Referral cancelled",
+                };
+                referral.status = status;
+                referral.completed_at = Some(time());
+                referral.version = next_version(referral.version);
+                referral.updated_at = Some(time());
+                let previous = storage.insert(id, referral.clone());
+                log_change_with_reason(EntityType::Referral, id, previous.as_ref(), Some(&referral), Some(reason));
+                Ok(referral)
+            }
+            None => Err(Error::NotFound {
+                msg: format!("Referral with id={} not found", id),
+            }),
+        }
+    })
+}
+
+const MAX_REFERRAL_DISTANCE_KM: u32 = 2000;
+const TRANSPORT_VOUCHER_MIN_KM: u32 = 20;
+const TRANSPORT_VOUCHER_VALID_DAYS: u64 = 14;
+
+// The voucher a new referral earns: vouchers are configured and the receiving facility is at least
+// the minimum distance away. The id is drawn here; the caller stores it with the referral.
+fn transport_voucher_for(referral: &Referral) -> Option<TransportVoucher> {
+    let config = canister_config();
+    let amount = config.transport_voucher_amount?;
+    let min_km = config.transport_voucher_min_km.unwrap_or(TRANSPORT_VOUCHER_MIN_KM);
+    if referral.distance_km? < min_km {
+        return None;
+    }
+
+    let id = TRANSPORT_VOUCHER_ID_SEQ.with(|counter| {
+        let next = *counter.borrow().get() + 1;
+        counter.borrow_mut().set(next).expect("Cannot update transport voucher id sequence");
+        next
+    });
+    Some(TransportVoucher {
+        id,
+        mother_id: referral.mother_id,
+        referral_id: referral.id,
+        amount,
+        valid_facilities: vec![referral.to_facility.clone()],
+        status: VoucherStatus::Issued,
+        issued_at: referral.created_at,
+        expires_at: referral.created_at + TRANSPORT_VOUCHER_VALID_DAYS * NANOS_PER_DAY,
+        redeemed_at: None,
+        redeemed_by: None,
+        redeemed_facility: None,
+        payment_id: None,
+    })
+}
+
+// Redeem a voucher at one of its facilities once the mother has arrived (that facility's staff).
+// A transport payment to the facility's payout account is created for settlement through the
+// ledger (settle_payment).
+#[ic_cdk::update]
+fn redeem_transport_voucher(voucher_id: u64, facility_code: String) -> Result<TransportVoucher, Error> {
+    ensure_facility_staff(&facility_code)?;
+    let mut voucher = TRANSPORT_VOUCHERS
+        .with(|vouchers| vouchers.borrow().get(&voucher_id))
+        .ok_or_else(|| Error::NotFound {
+            msg: format!("Transport voucher with id={} not found", voucher_id),
+        })?;
+    match voucher.status {
+        VoucherStatus::Issued => {}
+        VoucherStatus::Redeemed => {
+            return Err(Error::Conflict {
+                msg: format!("Transport voucher id={} was already redeemed", voucher_id),
+            })
+        }
+        VoucherStatus::Cancelled => {
+            return Err(Error::InvalidInput {
+                msg: format!("Transport voucher id={} was cancelled with its referral", voucher_id),
+            })
+        }
+    }
+    let now = time();
+    if now > voucher.expires_at {
+        return Err(Error::InvalidInput {
+            msg: format!("Transport voucher id={} has expired", voucher_id),
+        });
+    }
+    if !voucher.valid_facilities.contains(&facility_code) {
+        return Err(Error::InvalidInput {
+            msg: format!("Transport voucher id={} is not valid at {}", voucher_id, facility_code),
+        });
+    }
+
+    let account = facility_payout_account(&facility_code).ok_or_else(|| Error::InvalidInput {
+        msg: format!("Facility {} has no payout account to pay the voucher into", facility_code),
+    })?;
+
+    let mut batch = WriteBatch::default();
+    let payment = stage_payment(
+        PaymentPayload {
+            mother_id: voucher.mother_id,
+            record_id: None,
+            purpose: PaymentPurpose::TransportVoucher,
+            amount: voucher.amount,
+            recipient: account.owner,
+        },
+        &mut batch,
+    )?;
+    voucher.payment_id = Some(payment.id);
+    voucher.status = VoucherStatus::Redeemed;
+    voucher.redeemed_at = Some(now);
+    voucher.redeemed_by = Some(ic_cdk::caller());
+    voucher.redeemed_facility = Some(facility_code);
+    let stored = voucher.clone();
+    batch.stage(move || {
+        TRANSPORT_VOUCHERS.with(|vouchers| vouchers.borrow_mut().insert(voucher_id, stored));
+    });
+    batch.commit();
+    Ok(voucher)
+}
+
+#[ic_cdk::query]
+fn get_mother_transport_vouchers(mother_id: u64) -> Vec<TransportVoucher> {
+    TRANSPORT_VOUCHERS.with(|vouchers| {
+        vouchers
+            .borrow()
+            .iter()
+            .map(|(_, voucher)| voucher)
+            .filter(|voucher| voucher.mother_id == mother_id)
+            .collect()
+    })
+}
+
+fn cancel_transport_vouchers(referral_id: u64) {
+    let issued: Vec<TransportVoucher> = TRANSPORT_VOUCHERS.with(|vouchers| {
+        vouchers
+            .borrow()
+            .iter()
+            .map(|(_, voucher)| voucher)
+            .filter(|voucher| voucher.referral_id == referral_id && voucher.status == VoucherStatus::Issued)
+            .collect()
+    });
+    for mut voucher in issued {
+        voucher.status = VoucherStatus::Cancelled;
+        TRANSPORT_VOUCHERS.with(|vouchers| vouchers.borrow_mut().insert(voucher.id, voucher));
+    }
+}
+
+fn remove_transport_vouchers(mother_id: u64) {
+    let ids: Vec<u64> = TRANSPORT_VOUCHERS.with(|vouchers| {
+        vouchers
+            .borrow()
+            .iter()
+            .filter(|(_, voucher)| voucher.mother_id == mother_id)
+            .map(|(id, _)| id)
+            .collect()
+    });
+    TRANSPORT_VOUCHERS.with(|vouchers| {
+        let mut vouchers = vouchers.borrow_mut();
+        for id in &ids {
+            vouchers.remove(id);
+        }
+    });
+}
+
+// Get all referrals for a mother
+#[ic_cdk::query]
+fn get_mother_referrals(mother_id: u64) -> Vec<Referral> {
+    REFERRAL_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .filter(|(_, referral)| referral.mother_id == mother_id)
+            .map(|(_, referral)| referral)
+            .collect()
+    })
+}
+
+// Get comparative performance metrics for every facility
+#[ic_cdk::query]
+fn get_facility_dashboard() -> Vec<FacilityPerformance> {
+    const NANOS_PER_HOUR: f64 = 60.0 * 60.0 * 1_000_000_000.0;
+
+    FACILITY_METRICS.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(key, metrics)| FacilityPerformance {
+                facility_code: key.0,
+                anc4_coverage_percent: percent(metrics.anc4_mothers, metrics.registered_mothers)
+                    .unwrap_or(0.0),
+                avg_days_to_first_visit: average(
+                    metrics.total_time_to_first_visit,
+                    metrics.mothers_with_first_visit,
+                )
+                .map(|nanos| nanos / (24.0 * NANOS_PER_HOUR)),
+                referral_completion_percent: percent(metrics.referrals_completed, metrics.referrals_made),
+                avg_critical_resolution_hours: average(
+                    metrics.total_critical_resolution_time,
+                    metrics.critical_cases_resolved,
+                )
+                .map(|nanos| nanos / NANOS_PER_HOUR),
+                metrics,
+            })
+            .collect()
+    })
+}
+
+// Helpers for dashboard ratios; None when there is nothing to divide by
+fn percent(part: u64, total: u64) -> Option<f64> {
+    average(part * 100, total)
+}
+
+fn average(sum: u64, count: u64) -> Option<f64> {
+    if count == 0 {
+        None
+    } else {
+        Some(sum as f64 / count as f64)
+    }
+}
+
+// Import mothers and visits from a chunk of a digitized register.
+// Columns: name, age, blood_type, expected_delivery_date, emergency_contact, medical_history,
+// facility_code, visit_date, blood_pressure, weight, symptoms, notes, next_appointment.
+// Dates are YYYY-MM-DD; list columns are ';'-separated; visit columns may be left empty.
+// Rows for a mother already on file (same name, contact and EDD) add visits to her profile.
+// A past EDD registers a mother who has already delivered, in the PostPartum stage.
+#[ic_cdk::update]
+fn import_mothers_csv(chunk: CsvImportChunk) -> Result<CsvImportReport, Error> {
+    let mut lines = chunk.data.lines().filter(|line| !line.trim().is_empty());
+    if chunk.has_header {
+        lines.next();
+    }
+    let lines: Vec<&str> = lines.collect();
+    if lines.len() > MAX_IMPORT_ROWS {
+        return Err(Error::InvalidInput {
+            msg: format!("A chunk may contain at most {} rows", MAX_IMPORT_ROWS),
+        });
+    }
+
+    // Parse and validate every row before anything is stored, so a bad visit never leaves its mother behind
+    let rows: Vec<(u64, Result<ImportRow, String>)> = lines
+        .iter()
+        .enumerate()
+        .map(|(offset, line)| {
+            let parsed = parse_import_row(line)
+                .and_then(|parsed| validate_import_row(&parsed).map(|_| parsed).map_err(error_message));
+            (chunk.first_row_number + offset as u64, parsed)
+        })
+        .collect();
+
+    // Look up the chunk's mothers in a single pass over the stored profiles
+    let wanted: std::collections::BTreeSet<(String, String, u64)> = rows
+        .iter()
+        .filter_map(|(_, parsed)| parsed.as_ref().ok())
+        .map(|parsed| parsed.dedup_key())
+        .collect();
+    let mut known_mothers: std::collections::BTreeMap<(String, String, u64), u64> = PROFILE_STORAGE
+        .with(|storage| {
+            storage
+                .borrow()
+                .iter()
+                .map(|(id, profile)| {
+                    (import_dedup_key(&profile.name, &profile.emergency_contact, profile.expected_delivery_date), id)
+                })
+                .filter(|(key, _)| wanted.contains(key))
+                .collect()
+        });
+
+    let mut report = CsvImportReport::default();
+    for (row, parsed) in rows {
+        report.rows_processed += 1;
+        if let Err(msg) = parsed.and_then(|parsed| import_csv_row(parsed, row, &mut known_mothers, &mut report)) {
+            report.errors.push(CsvRowError { row, msg });
+        }
+    }
+
+    Ok(report)
+}
+
+const MAX_IMPORT_ROWS: usize = 500;
+const CSV_IMPORT_COLUMNS: usize = 13;
+
+// One register row: the mother and, when the visit columns are filled in, the date and details of her visit
+struct ImportRow {
+    profile: MotherProfilePayload,
+    visit: Option<(u64, HealthRecordPayload)>,
+}
+
+impl ImportRow {
+    fn dedup_key(&self) -> (String, String, u64) {
+        import_dedup_key(&self.profile.name, &self.profile.emergency_contact, self.profile.expected_delivery_date)
+    }
+}
+
+// Parse a register row; the visit's mother_id is filled in once the mother is known
+fn parse_import_row(line: &str) -> Result<ImportRow, String> {
+    let fields = parse_csv_line(line)?;
+    if fields.len() != CSV_IMPORT_COLUMNS {
+        return Err(format!("Expected {} columns, found {}", CSV_IMPORT_COLUMNS, fields.len()));
+    }
+    let field = |index: usize| fields[index].trim();
+    let list = |index: usize| -> Vec<String> {
+        field(index)
+            .split(';')
+            .map(|item| item.trim().to_string())
+            .filter(|item| !item.is_empty())
+            .collect()
+    };
+    let optional = |index: usize| Some(field(index).to_string()).filter(|value| !value.is_empty());
+
+    let age = field(1).parse::<u8>().map_err(|_| "Invalid age".to_string())?;
+    let expected_delivery_date = parse_date(field(3)).ok_or("Invalid expected delivery date")?;
+    let profile = MotherProfilePayload {
+        name: field(0).to_string(),
+        age,
+        blood_type: field(2).to_string(),
+        expected_delivery_date,
+        medical_history: list(5),
+        emergency_contact: field(4).to_string(),
+        insurance: None,
+        facility_code: optional(6),
+        idempotency_key: None,
+        previous_cesareans: None,
+        national_id: None,
+    };
+
+    // Rows without visit details only register the mother
+    let Some(visit_date) = optional(7) else {
+        return Ok(ImportRow { profile, visit: None });
+    };
+    let visit_date = parse_date(&visit_date).ok_or("Invalid visit date")?;
+    let weight = field(9).parse::<f32>().map_err(|_| "Invalid weight".to_string())?;
+    let next_appointment = match optional(12) {
+        Some(date) => parse_date(&date).ok_or("Invalid next appointment date")?,
+        None => 0,
+    };
+    let visit = HealthRecordPayload {
+        mother_id: 0,
+        blood_pressure: field(8).to_string(),
+        weight,
+        symptoms: list(10),
+        notes: field(11).to_string(),
+        next_appointment,
+        insurance_eligible: None,
+        facility_code: optional(6),
+        idempotency_key: None,
+        fundal_height_cm: None,
+        fetal_heart_rate: None,
+        urine_protein: None,
+        urine_glucose: None,
+        edema: None,
+        muac_cm: None,
+        note_template: None,
+        note_sections: None,
+        checklist_done: None,
+    };
+
+    Ok(ImportRow {
+        profile,
+        visit: Some((visit_date, visit)),
+    })
+}
+
+// Registers record mothers who have already delivered, so a past EDD is accepted; it places her in PostPartum
+fn validate_import_row(row: &ImportRow) -> Result<(), Error> {
+    validate_profile_fields(&row.profile)?;
+    if let Some((_, visit)) = &row.visit {
+        validate_visit_payload(visit)?;
+    }
+    Ok(())
+}
+
+// Store a validated register row, creating the mother if needed and her visit if present
+fn import_csv_row(
+    parsed: ImportRow,
+    row: u64,
+    known_mothers: &mut std::collections::BTreeMap<(String, String, u64), u64>,
+    report: &mut CsvImportReport,
+) -> Result<(), String> {
+    let key = parsed.dedup_key();
+    let (mother_id, created) = match known_mothers.get(&key) {
+        Some(id) => (*id, false),
+        None => {
+            let profile = insert_mother_profile(parsed.profile).map_err(error_message)?;
+            known_mothers.insert(key, profile.id);
+            report.profiles_created.push(profile.id);
+            (profile.id, true)
+        }
+    };
+
+    let Some((visit_date, mut visit)) = parsed.visit else {
+        if !created {
+            report.duplicate_rows.push(row);
+        }
+        return Ok(());
+    };
+
+    // Skip visits already on file for the same day
+    let visit_day = visit_date / NANOS_PER_DAY;
+    let already_recorded = HEALTH_RECORD_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .range(mother_record_keys(mother_id))
+            .any(|(_, record)| record.date / NANOS_PER_DAY == visit_day)
+    });
+    if already_recorded {
+        report.duplicate_rows.push(row);
+        return Ok(());
+    }
+
+    visit.mother_id = mother_id;
+    let record = insert_health_record(visit, visit_date).map_err(error_message)?;
+    report.records_created.push(record.id);
+
+    Ok(())
+}
+
+fn import_dedup_key(name: &str, emergency_contact: &str, expected_delivery_date: u64) -> (String, String, u64) {
+    (
+        name.trim().to_lowercase(),
+        emergency_contact.trim().to_string(),
+        expected_delivery_date,
+    )
+}
+
+// Split one CSV line into fields, honouring double-quoted fields with "" escapes
+fn parse_csv_line(line: &str) -> Result<Vec<String>, String> {
+    let mut fields = Vec::new();
+    let mut current = String::new();
+    let mut in_quotes = false;
+    let mut chars = line.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if in_quotes && chars.peek() == Some(&'"') => {
+                current.push('"');
+                chars.next();
+            }
+            '"' => in_quotes = !in_quotes,
+            ',' if !in_quotes => fields.push(std::mem::take(&mut current)),
+            _ => current.push(c),
+        }
+    }
+
+    if in_quotes {
+        return Err("Unterminated quoted field".to_string());
+    }
+    fields.push(current);
+    Ok(fields)
+}
+
+// Extract the message carried by an error
+fn error_message(error: Error) -> String {
+    match error {
+        Error::NotFound { msg }
+        | Error::InvalidInput { msg }
+        | Error::SystemError { msg }
+        | Error::AuthorizationError { msg }
+        | Error::ValidationError { msg }
+        | Error::Conflict { msg }
+        | Error::FeatureDisabled { msg }
+        | Error::PrescriptionWarnings { msg, .. } => msg,
+    }
+}
+
+// Record a delivery, moving the mother to the postpartum stage
+#[ic_cdk::update]
+fn record_delivery(mother_id: u64, expected_version: u64, delivery: DeliveryRecord) -> Result<MotherProfile, Error> {
+    observe_call("record_delivery", store_delivery(mother_id, expected_version, delivery))
+}
+
+fn store_delivery(mother_id: u64, expected_version: u64, delivery: DeliveryRecord) -> Result<MotherProfile, Error> {
+    if let Some(facility_code) = &delivery.facility_code {
+        validate_facility_code(facility_code)?;
+    }
+
+    if delivery.delivery_date > time() {
+        return Err(Error::InvalidInput {
+            msg: "Delivery date cannot be in the future".to_string(),
+        });
+    }
+
+    PROFILE_STORAGE.with(|storage| {
+        let mut storage = storage.borrow_mut();
+        match storage.get(&mother_id) {
+            Some(mut profile) => {
+                check_version("Mother", mother_id, profile.version, expected_version)?;
+                if profile.delivery.is_some() {
+                    return Err(Error::InvalidInput {
+                        msg: format!("Delivery already recorded for mother id={}", mother_id),
+                    });
+                }
+                profile.delivery = Some(delivery);
+                profile.stage = PregnancyStage::PostPartum;
+                profile.version = next_version(profile.version);
+                profile.updated_at = Some(time());
+                ensure_fits("Mother", mother_id, &profile)?;
+                let previous = storage.insert(mother_id, profile.clone());
+                log_change_with_reason(EntityType::MotherProfile, mother_id, previous.as_ref(), Some(&profile), Some("Delivery recorded"));
+                Ok(profile)
+            }
+            None => Err(Error::NotFound {
+                msg: format!("Mother with id={} not found", mother_id),
+            }),
+        }
+    })
+}
+
+// Build the DHIS2 monthly aggregate data value set for a facility, period format "YYYYMM"
+#[ic_cdk::query]
+fn get_dhis2_aggregate(facility_code: String, period: String) -> Result<String, Error> {
+    ensure_feature("dhis2_reporting")?;
+    validate_facility_code(&facility_code)?;
+    let (start, end) = parse_monthly_period(&period)?;
+    let in_period = |timestamp: u64| timestamp >= start && timestamp < end;
+
+    // ANC 1st and 4th visits are a mother's first and fourth recorded visits
+    let mut visits_by_mother: std::collections::BTreeMap<u64, Vec<HealthRecord>> =
+        std::collections::BTreeMap::new();
+    HEALTH_RECORD_STORAGE.with(|storage| {
+        for (_, record) in storage.borrow().iter() {
+            visits_by_mother.entry(record.mother_id).or_default().push(record);
+        }
+    });
+
+    let mut anc_first_visits = 0;
+    let mut anc_fourth_visits = 0;
+    for visits in visits_by_mother.values_mut() {
+        visits.sort_by_key(|record| record.date);
+        for (index, record) in visits.iter().enumerate() {
+            if record.facility_code.as_deref() != Some(facility_code.as_str()) || !in_period(record.date) {
+                continue;
+            }
+            match index {
+                0 => anc_first_visits += 1,
+                3 => anc_fourth_visits += 1,
+                _ => {}
+            }
+        }
+    }
+
+    let deliveries = PROFILE_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .filter_map(|(_, profile)| profile.delivery)
+            .filter(|delivery| {
+                delivery.facility_code.as_deref() == Some(facility_code.as_str())
+                    && in_period(delivery.delivery_date)
+            })
+            .count()
+    });
+
+    let referrals = REFERRAL_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .filter(|(_, referral)| referral.from_facility == facility_code && in_period(referral.created_at))
+            .count()
+    });
+
+    let data_value = |data_element: &str, value: usize| {
+        serde_json::json!({ "dataElement": data_element, "value": value.to_string() })
+    };
+
+    let payload = serde_json::json!({
+        "dataElementIdScheme": "CODE",
+        "orgUnitIdScheme": "CODE",
+        "period": period,
+        "orgUnit": facility_code,
+        "completeDate": format_iso8601(time())[..10],
+        "dataValues": [
+            data_value(DHIS2_ANC_FIRST_VISITS, anc_first_visits),
+            data_value(DHIS2_ANC_FOURTH_VISITS, anc_fourth_visits),
+            data_value(DHIS2_DELIVERIES, deliveries),
+            data_value(DHIS2_REFERRALS, referrals),
+        ],
+    });
+    Ok(payload.to_string())
+}
+
+// Produce MOH 405 ANC register rows for visits at a facility within [from, to]
+#[ic_cdk::query]
+fn get_anc_register(facility_code: String, from: u64, to: u64) -> Result<Vec<AncRegisterRow>, Error> {
+    ensure_feature("dhis2_reporting")?;
+    validate_facility_code(&facility_code)?;
+    if from > to {
+        return Err(Error::InvalidInput {
+            msg: "Start of the date range must not be after its end".to_string(),
+        });
+    }
+
+    // Visit numbers count every contact the mother has had, at any facility
+    let mut visits_by_mother: std::collections::BTreeMap<u64, Vec<HealthRecord>> =
+        std::collections::BTreeMap::new();
+    HEALTH_RECORD_STORAGE.with(|storage| {
+        for (_, record) in storage.borrow().iter() {
+            visits_by_mother.entry(record.mother_id).or_default().push(record);
+        }
+    });
+
+    let mut rows = Vec::new();
+    for (mother_id, visits) in visits_by_mother.iter_mut() {
+        visits.sort_by_key(|record| record.date);
+        let Some(profile) = PROFILE_STORAGE.with(|storage| storage.borrow().get(mother_id)) else {
+            continue;
+        };
+        let referrals = get_mother_referrals(*mother_id);
+
+        for (index, record) in visits.iter().enumerate() {
+            if record.facility_code.as_deref() != Some(facility_code.as_str())
+                || record.date < from
+                || record.date > to
+            {
+                continue;
+            }
+
+            let visit_day = record.date / NANOS_PER_DAY;
+            rows.push(AncRegisterRow {
+                visit_date: record.date,
+                anc_number: profile.id,
+                visit_number: index as u32 + 1,
+                first_visit: index == 0,
+                full_name: profile.name.clone(),
+                age: profile.age,
+                emergency_contact: profile.emergency_contact.clone(),
+                expected_delivery_date: profile.expected_delivery_date,
+                gestation_weeks: gestational_age_weeks(profile.expected_delivery_date, record.date),
+                weight: record.weight,
+                blood_pressure: record.blood_pressure.clone(),
+                blood_group: profile.blood_type.clone(),
+                referred: referrals.iter().any(|referral| {
+                    referral.from_facility == facility_code && referral.created_at / NANOS_PER_DAY == visit_day
+                }),
+                remarks: record.notes.clone(),
+            });
+        }
+    }
+
+    rows.sort_by_key(|row| (row.visit_date, row.anc_number));
+    Ok(rows)
+}
+
+// Export everything held about a mother as JSON or CSV, e.g. to attach to a physical referral
+#[ic_cdk::update]
+fn export_mother(id: u64, format: ExportFormat) -> Result<String, Error> {
+    let export = mother_export(load_mother_profile(id)?);
+    log_read(id, "export_mother");
+    match format {
+        ExportFormat::Json => Ok(export_mother_json(&export)),
+        ExportFormat::Csv => Ok(export_mother_csv(&export)),
+    }
+}
+
+// Gather everything held about a mother for export
+fn mother_export(profile: MotherProfile) -> MotherExport {
+    let id = profile.id;
+
+    let mut health_records = HEALTH_RECORD_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .range(mother_record_keys(id))
+            .map(|(_, record)| record)
+            .collect::<Vec<HealthRecord>>()
+    });
+    health_records.sort_by_key(|record| record.date);
+
+    let appointments: Vec<AppointmentSummary> = health_records
+        .iter()
+        .filter(|record| record.next_appointment > 0)
+        .map(|record| AppointmentSummary {
+            record_id: record.id,
+            booked_at: record.date,
+            scheduled_for: record.next_appointment,
+        })
+        .collect();
+    let addenda = health_records.iter().flat_map(|record| record_addenda(record.id)).collect();
+    MotherExport {
+        profile,
+        health_records,
+        addenda,
+        lab_results: mother_lab_results(id),
+        appointments,
+        referrals: get_mother_referrals(id),
+        payments: get_mother_payments(id),
+        self_reports: mother_self_reports(id),
+    }
+}
+
+const EXPORT_CHUNK_SIZE: usize = 512 * 1024;
+const EXPORT_JOB_TTL: u64 = NANOS_PER_DAY;
+
+// Prepare a bulk export of every mother matching the filter (controllers only). Returns the job
+// id; read the result with get_export_chunk. JSON is an array of per-mother exports, CSV the
+// per-mother exports one after another.
+#[ic_cdk::update]
+fn start_export(filter: ExportFilter, format: ExportFormat) -> Result<u64, Error> {
+    ensure_controller()?;
+    purge_expired_exports();
+
+    let profiles = filtered_profiles(&filter);
+    let mothers = profiles.len() as u64;
+    let exports = profiles.into_iter().map(mother_export);
+    let content = match format {
+        ExportFormat::Json => format!(
+            "[{}]",
+            exports.map(|export| export_mother_json(&export)).collect::<Vec<_>>().join(",")
+        ),
+        ExportFormat::Csv => exports.map(|export| export_mother_csv(&export)).collect::<Vec<_>>().join("\n"),
+    };
+    Ok(store_export(filter, format, mothers, content))
+}
+
+fn filtered_profiles(filter: &ExportFilter) -> Vec<MotherProfile> {
+    PROFILE_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, profile)| profile)
+            .filter(|profile| match &filter.facility_code {
+                Some(code) => profile.facility_code.as_deref() == Some(code.as_str()),
+                None => true,
+            })
+            .filter(|profile| match &filter.stage {
+                Some(stage) => format!("{:?}", stage) == format!("{:?}", profile.stage),
+                None => true,
+            })
+            .collect()
+    })
+}
+
+// Split finished export content into chunks and register the job; returns its id
+fn store_export(filter: ExportFilter, format: ExportFormat, mothers: u64, content: String) -> u64 {
+    let job_id = EXPORT_JOB_SEQ.with(|counter| {
+        let next = *counter.borrow().get() + 1;
+        counter.borrow_mut().set(next).expect("Cannot update export job counter");
+        next
+    });
+    let chunks: Vec<&[u8]> = content.as_bytes().chunks(EXPORT_CHUNK_SIZE).collect();
+    EXPORT_CHUNKS.with(|storage| {
+        let mut storage = storage.borrow_mut();
+        for (index, chunk) in chunks.iter().enumerate() {
+            let key = ChunkKey {
+                job_id,
+                index: index as u64,
+            };
+            storage.insert(key, ExportChunk(chunk.to_vec()));
+        }
+    });
+
+    let now = time();
+    let job = ExportJob {
+        id: job_id,
+        filter,
+        format,
+        requested_by: ic_cdk::caller(),
+        created_at: now,
+        expires_at: now + EXPORT_JOB_TTL,
+        mothers,
+        total_bytes: content.len() as u64,
+        chunk_count: chunks.len() as u64,
+    };
+    EXPORT_JOBS.with(|storage| storage.borrow_mut().insert(job_id, job));
+    job_id
+}
+
+// Prepare a de-identified JSON dataset of the pregnancies matching the filter and their outcomes,
+// for research partners (controllers and admins only). Ids are replaced by keyed hashes that stay
+// the same across exports, names and contacts are left out, and dates and ages are coarsened by
+// the anonymization policy. Read it with get_export_chunk.
+#[ic_cdk::update]
+async fn start_research_export(filter: ExportFilter) -> Result<u64, Error> {
+    ensure_controller()?;
+    let pseudonym_key = hmac_sha256(&signing_key().await?, b"mama-pack/research-pseudonyms");
+    purge_expired_exports();
+
+    let policy = research_policy();
+    let profiles = filtered_profiles(&filter);
+    let pregnancies: Vec<serde_json::Value> = profiles
+        .iter()
+        .map(|profile| research_row(profile, &policy, &pseudonym_key))
+        .collect();
+    let content = serde_json::json!({
+        "generated": coarsen_date(time(), policy.date_granularity_days),
+        "policy": policy,
+        "pregnancies": pregnancies,
+    })
+    .to_string();
+    Ok(store_export(filter, ExportFormat::Json, profiles.len() as u64, content))
+}
+
+// One pregnancy with its visits, lab results and outcome, de-identified by `policy`
+fn research_row(profile: &MotherProfile, policy: &AnonymizationPolicy, pseudonym_key: &[u8]) -> serde_json::Value {
+    let date = |timestamp: u64| coarsen_date(timestamp, policy.date_granularity_days);
+
+    let mut records: Vec<HealthRecord> = HEALTH_RECORD_STORAGE
+        .with(|storage| storage.borrow().range(mother_record_keys(profile.id)).map(|(_, record)| record).collect());
+    records.sort_by_key(|record| record.date);
+    let visits: Vec<serde_json::Value> = records
+        .iter()
+        .map(|record| {
+            let codes: Option<Vec<&str>> = policy.include_symptom_codes.then(|| {
+                record.symptom_codes.iter().flatten().flatten().map(|code| code.code.as_str()).collect()
+            });
+            serde_json::json!({
+                "period": date(record.date),
+                "blood_pressure": record.blood_pressure,
+                "weight": record.weight,
+                "symptom_codes": codes,
+                "health_status": format!("{:?}", record.health_status),
+            })
+        })
+        .collect();
+    let lab_results: Vec<serde_json::Value> = mother_lab_results(profile.id)
+        .iter()
+        .map(|result| {
+            serde_json::json!({
+                "period": date(result.date),
+                "test": result.code.as_ref().map_or(result.test_name.as_str(), |code| code.code.as_str()),
+                "value": result.value,
+                "unit": result.unit,
+            })
+        })
+        .collect();
+
+    let facility = profile.facility_code.as_ref().filter(|code| {
+        policy.include_facility
+            && FACILITY_METRICS.with(|metrics| metrics.borrow().get(&StringKey(code.to_string())))
+                .is_some_and(|metrics| metrics.registered_mothers >= policy.min_facility_mothers)
+    });
+    let delivery = profile.delivery.as_ref().map(|delivery| {
+        serde_json::json!({
+            "period": date(delivery.delivery_date),
+            "mode": delivery.mode,
+            "outcome": delivery.outcome,
+        })
+    });
+
+    serde_json::json!({
+        "pregnancy": pseudonym(pseudonym_key, profile.id),
+        "age": age_band(profile.age, policy.age_band_years),
+        "blood_type": profile.blood_type,
+        "facility": facility,
+        "booked": date(profile.created_at),
+        "expected_delivery": date(profile.expected_delivery_date),
+        "stage": format!("{:?}", profile.stage),
+        "health_status": format!("{:?}", profile.health_status),
+        "medical_history": policy.include_medical_history.then_some(&profile.medical_history),
+        "referrals": get_mother_referrals(profile.id).len(),
+        "visits": visits,
+        "lab_results": lab_results,
+        "delivery": delivery,
+    })
+}
+
+// Keyed hash of a mother id; without the key it cannot be traced back to her
+fn pseudonym(key: &[u8], mother_id: u64) -> String {
+    hmac_sha256(key, &mother_id.to_be_bytes())[..16]
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+fn age_band(age: u8, width: u8) -> String {
+    if width <= 1 {
+        return age.to_string();
+    }
+    let start = age / width * width;
+    format!("{}-{}", start, start.saturating_add(width - 1))
+}
+
+// Start of the `days`-long period containing `timestamp`, as YYYY-MM-DD; periods start on a Monday
+fn coarsen_date(timestamp: u64, days: u32) -> String {
+    // 1970-01-01 was a Thursday
+    const MONDAY_OFFSET: u64 = 3 * NANOS_PER_DAY;
+    let period = u64::from(days.max(1)) * NANOS_PER_DAY;
+    let start = (timestamp + MONDAY_OFFSET) / period * period;
+    format_iso8601(start.saturating_sub(MONDAY_OFFSET))[..10].to_string()
+}
+
+fn research_policy() -> AnonymizationPolicy {
+    RESEARCH_POLICY.with(|policy| policy.borrow().get().clone())
+}
+
+#[ic_cdk::query]
+fn get_research_policy() -> Result<AnonymizationPolicy, Error> {
+    ensure_controller()?;
+    Ok(research_policy())
+}
+
+// Replace the anonymization policy used by later research exports (controllers and admins only)
+#[ic_cdk::update]
+fn set_research_policy(policy: AnonymizationPolicy) -> Result<AnonymizationPolicy, Error> {
+    ensure_controller()?;
+    if !(1..=90).contains(&policy.date_granularity_days) {
+        return Err(Error::ValidationError {
+            msg: "date_granularity_days must be between 1 and 90".to_string(),
+        });
+    }
+    if !(1..=20).contains(&policy.age_band_years) {
+        return Err(Error::ValidationError {
+            msg: "age_band_years must be between 1 and 20".to_string(),
+        });
+    }
+    RESEARCH_POLICY.with(|cell| {
+        cell.borrow_mut()
+            .set(policy.clone())
+            .map(|_| ())
+            .map_err(|_| Error::SystemError { msg: "Failed to store research policy".to_string() })
+    })?;
+    Ok(policy)
+}
+
+// Size and chunk count of an export job (requester only)
+#[ic_cdk::query]
+fn get_export_job(job_id: u64) -> Result<ExportJob, Error> {
+    let job = EXPORT_JOBS
+        .with(|storage| storage.borrow().get(&job_id))
+        .filter(|job| job.expires_at > time())
+        .ok_or(Error::NotFound {
+            msg: format!("Export job id={} not found or expired", job_id),
+        })?;
+    if job.requested_by != ic_cdk::caller() {
+        return Err(Error::AuthorizationError {
+            msg: "Only the requester can read an export".to_string(),
+        });
+    }
+    Ok(job)
+}
+
+// Chunk `index` (from 0) of an export's bytes; concatenate chunk_count chunks to rebuild it
+#[ic_cdk::query]
+fn get_export_chunk(job_id: u64, index: u64) -> Result<Vec<u8>, Error> {
+    let job = get_export_job(job_id)?;
+    if index >= job.chunk_count {
+        return Err(Error::InvalidInput {
+            msg: format!("Export job id={} has {} chunks", job_id, job.chunk_count),
+        });
+    }
+
+    EXPORT_CHUNKS
+        .with(|storage| storage.borrow().get(&ChunkKey { job_id, index }))
+        .map(|chunk| chunk.0)
+        .ok_or(Error::NotFound {
+            msg: format!("Chunk {} of export job id={} not found", index, job_id),
+        })
+}
+
+// Drop export jobs past their expiry, with their chunks
+fn purge_expired_exports() {
+    let now = time();
+    let expired: Vec<ExportJob> = EXPORT_JOBS.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, job)| job)
+            .filter(|job| job.expires_at <= now)
+            .collect()
+    });
+    expired.iter().for_each(remove_export_job);
+}
+
+fn remove_export_job(job: &ExportJob) {
+    EXPORT_CHUNKS.with(|storage| {
+        let mut storage = storage.borrow_mut();
+        for index in 0..job.chunk_count {
+            storage.remove(&ChunkKey { job_id: job.id, index });
+        }
+    });
+    EXPORT_JOBS.with(|storage| storage.borrow_mut().remove(&job.id));
+}
+
+// Export a signed continuity-of-care bundle so the mother can carry her history to another canister
+#[ic_cdk::update]
+async fn export_care_bundle(mother_id: u64) -> Result<SignedCareBundle, Error> {
+    ensure_feature("care_bundles")?;
+    let profile = load_mother_profile(mother_id)?;
+    ensure_mother_reader(&profile)?;
+    let health_records: Vec<HealthRecord> = HEALTH_RECORD_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .range(mother_record_keys(mother_id))
+            .map(|(_, record)| record)
+            .collect()
+    });
+    let addenda = health_records.iter().flat_map(|record| record_addenda(record.id)).collect();
+
+    let content = CareBundleContent {
+        exported_at: time(),
+        profile,
+        health_records,
+        referrals: get_mother_referrals(mother_id),
+        lab_results: Some(mother_lab_results(mother_id)),
+        addenda: Some(addenda),
+    };
+    let payload = Encode!(&content).map_err(|e| Error::SystemError { msg: e.to_string() })?;
+
+    let key = signing_key().await?;
+    let digest = care_bundle_digest(CARE_BUNDLE_FORMAT_VERSION, &payload);
+    log_read(mother_id, "export_care_bundle");
+
+    Ok(SignedCareBundle {
+        format_version: CARE_BUNDLE_FORMAT_VERSION,
+        source_canister: ic_cdk::id(),
+        payload,
+        signature: hmac_sha256(&key, &digest).to_vec(),
+    })
+}
+
+// Check a bundle digest/signature pair produced by this canister
+#[ic_cdk::query]
+fn verify_care_bundle(digest: Vec<u8>, signature: Vec<u8>) -> bool {
+    let key = SIGNING_KEY.with(|key| key.borrow().get().key.clone());
+    if key.is_empty() {
+        return false;
+    }
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(&key).expect("HMAC takes keys of any length");
+    mac.update(&digest);
+    // Constant time, so a forger learns nothing from how long a wrong signature takes to refuse
+    mac.verify_slice(&signature).is_ok()
+}
+
+// Import a care bundle exported by this or a trusted canister, registering the mother locally
+#[ic_cdk::update]
+async fn import_care_bundle(bundle: SignedCareBundle, facility_code: Option<String>) -> Result<MotherProfile, Error> {
+    ensure_feature("care_bundles")?;
+    if let Some(facility_code) = &facility_code {
+        validate_facility_code(facility_code)?;
+    }
+
+    if bundle.format_version != CARE_BUNDLE_FORMAT_VERSION {
+        return Err(Error::ValidationError {
+            msg: format!("Unsupported care bundle format version {}", bundle.format_version),
+        });
+    }
+
+    // Bundles are verified by the canister that signed them
+    let digest = care_bundle_digest(bundle.format_version, &bundle.payload);
+    let verified = if bundle.source_canister == ic_cdk::id() {
+        verify_care_bundle(digest, bundle.signature)
+    } else {
+        let trusted = TRUSTED_PEERS.with(|peers| peers.borrow().get().canisters.contains(&bundle.source_canister));
+        if !trusted {
+            return Err(Error::AuthorizationError {
+                msg: format!("Canister {} is not a trusted source", bundle.source_canister),
+            });
+        }
+        let result: Result<(bool,), _> =
+            ic_cdk::call(bundle.source_canister, "verify_care_bundle", (digest, bundle.signature)).await;
+        result
+            .map(|(valid,)| valid)
+            .map_err(|(_, msg)| Error::SystemError { msg: format!("Failed to verify bundle: {}", msg) })?
+    };
+    if !verified {
+        return Err(Error::ValidationError {
+            msg: "Care bundle signature is invalid".to_string(),
+        });
+    }
+
+    let content = Decode!(&bundle.payload, CareBundleContent)
+        .map_err(|e| Error::ValidationError { msg: format!("Malformed care bundle: {}", e) })?;
+    validate_medical_history(&content.profile.medical_history)?;
+    content
+        .health_records
+        .iter()
+        .try_for_each(|record| validate_sensitive_field("notes", &record.notes))?;
+    content
+        .addenda
+        .iter()
+        .flatten()
+        .try_for_each(|addendum| validate_sensitive_field("addendum text", &addendum.text))?;
+
+    let origin = BundleOrigin {
+        source_canister: bundle.source_canister,
+        source_mother_id: content.profile.id,
+    };
+    let already_imported = PROFILE_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .any(|(_, profile)| profile.origin.as_ref() == Some(&origin))
+    });
+    if already_imported {
+        return Err(Error::InvalidInput {
+            msg: "This care bundle has already been imported".to_string(),
+        });
+    }
+
+    let id = generate_new_id(EntityType::MotherProfile)?;
+    let profile = MotherProfile {
+        id,
+        facility_code: facility_code.or(content.profile.facility_code.clone()),
+        origin: Some(origin),
+        version: Some(1),
+        ulid: Some(adopt_ulid(EntityType::MotherProfile, id, content.profile.ulid.clone())),
+        updated_at: Some(time()),
+        ..content.profile
+    };
+    ensure_fits("Mother", id, &profile)?;
+
+    // Nothing is written until every id is taken and every entity fits, so a failing import
+    // leaves no partial mother behind
+    let mut batch = WriteBatch::default();
+    let stored = profile.clone();
+    batch.stage(move || {
+        if let Some(ulid) = &stored.ulid {
+            index_ulid(ulid, EntityType::MotherProfile, id);
+        }
+        PROFILE_STORAGE.with(|storage| storage.borrow_mut().insert(id, stored.clone()));
+        log_change(EntityType::MotherProfile, id, None, Some(&stored));
+        if let Some(facility_code) = &stored.facility_code {
+            update_facility_metrics(facility_code, |metrics| metrics.registered_mothers += 1);
+        }
+    });
+
+    // Carry over history under new local ids
+    let mut progress = CareProgress::default();
+    let mut record_ids = std::collections::BTreeMap::new();
+    let mut health_records = content.health_records;
+    health_records.sort_by_key(|record| record.date);
+    for record in health_records {
+        let record_id = generate_new_id(EntityType::HealthRecord)?;
+        record_ids.insert(record.id, record_id);
+        progress.visit_count += 1;
+        progress.first_visit_at.get_or_insert(record.date);
+        progress.critical_since = match record.health_status {
+            HealthStatus::Critical => progress.critical_since.or(Some(record.date)),
+            _ => None,
+        };
+        let record = HealthRecord {
+            id: record_id,
+            mother_id: id,
+            version: Some(1),
+            ulid: Some(adopt_ulid(EntityType::HealthRecord, record_id, record.ulid.clone())),
+            created_at: Some(time()),
+            updated_at: Some(time()),
+            ..record
+        };
+        ensure_fits("Health record", record_id, &record)?;
+        batch.stage(move || {
+            if let Some(ulid) = &record.ulid {
+                index_ulid(ulid, EntityType::HealthRecord, record_id);
+            }
+            store_health_record(&record);
+            log_change(EntityType::HealthRecord, record_id, None, Some(&record));
+        });
+    }
+    batch.stage(move || {
+        CARE_PROGRESS.with(|storage| storage.borrow_mut().insert(id, progress));
+    });
+
+    for referral in content.referrals {
+        let referral_id = generate_new_id(EntityType::Referral)?;
+        let referral = Referral {
+            id: referral_id,
+            mother_id: id,
+            version: Some(1),
+            ulid: Some(adopt_ulid(EntityType::Referral, referral_id, referral.ulid.clone())),
+            updated_at: Some(time()),
+            ..referral
+        };
+        batch.stage(move || {
+            if let Some(ulid) = &referral.ulid {
+                index_ulid(ulid, EntityType::Referral, referral_id);
+            }
+            REFERRAL_STORAGE.with(|storage| storage.borrow_mut().insert(referral_id, referral.clone()));
+            log_change(EntityType::Referral, referral_id, None, Some(&referral));
+        });
+    }
+
+    for lab_result in content.lab_results.unwrap_or_default() {
+        let lab_id = generate_new_id(EntityType::LabResult)?;
+        let lab_result = LabResult {
+            id: lab_id,
+            mother_id: id,
+            version: Some(1),
+            ulid: Some(adopt_ulid(EntityType::LabResult, lab_id, lab_result.ulid.clone())),
+            created_at: Some(time()),
+            updated_at: Some(time()),
+            ..lab_result
+        };
+        batch.stage(move || {
+            if let Some(ulid) = &lab_result.ulid {
+                index_ulid(ulid, EntityType::LabResult, lab_id);
+            }
+            LAB_RESULT_STORAGE.with(|storage| storage.borrow_mut().insert(lab_id, lab_result.clone()));
+            log_change(EntityType::LabResult, lab_id, None, Some(&lab_result));
+        });
+    }
+
+    for addendum in content.addenda.unwrap_or_default() {
+        let Some(&record_id) = record_ids.get(&addendum.record_id) else {
+            continue;
+        };
+        let addendum_id = next_addendum_id();
+        let addendum = Addendum {
+            id: addendum_id,
+            record_id,
+            mother_id: id,
+            ..addendum
+        };
+        batch.stage(move || {
+            ADDENDA.with(|storage| storage.borrow_mut().insert(AddendumKey { record_id, id: addendum_id }, addendum));
+        });
+    }
+    batch.commit();
+
+    Ok(profile)
+}
+
+// Allow care bundles signed by another mama-pack canister to be imported
+#[ic_cdk::update]
+fn add_trusted_peer(canister: Principal) -> Result<(), Error> {
+    ensure_controller()?;
+    update_trusted_peers(|canisters| {
+        if !canisters.contains(&canister) {
+            canisters.push(canister);
+        }
+    })
+}
+
+// Stop accepting care bundles from a canister
+#[ic_cdk::update]
+fn remove_trusted_peer(canister: Principal) -> Result<(), Error> {
+    ensure_controller()?;
+    update_trusted_peers(|canisters| canisters.retain(|c| *c != canister))
+}
+
+// List canisters whose care bundles are accepted
+#[ic_cdk::query]
+fn get_trusted_peers() -> Vec<Principal> {
+    TRUSTED_PEERS.with(|peers| peers.borrow().get().canisters.clone())
+}
+
+fn update_trusted_peers(update: impl FnOnce(&mut Vec<Principal>)) -> Result<(), Error> {
+    TRUSTED_PEERS.with(|peers| {
+        let mut peers = peers.borrow_mut();
+        let mut canisters = peers.get().canisters.clone();
+        update(&mut canisters);
+        peers
+            .set(TrustedPeers { canisters })
+            .map(|_| ())
+            .map_err(|_| Error::SystemError { msg: "Failed to store trusted peers".to_string() })
+    })
+}
+
+// Digest signed for a care bundle: SHA-256 over the format version and payload
+fn care_bundle_digest(format_version: u16, payload: &[u8]) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.update(format_version.to_be_bytes());
+    hasher.update(payload);
+    hasher.finalize().to_vec()
+}
+
+// Compact signed payload for the QR code on a mother-held card:
+// MP1|id|name|blood type|EDD|risk tier|latest status|signature
+#[ic_cdk::update]
+async fn get_card_payload(mother_id: u64) -> Result<String, Error> {
+    ensure_feature("mother_card")?;
+    let profile = load_mother_profile(mother_id)?;
+    let latest_status = latest_health_record(mother_id)
+        .map(|record| record.health_status)
+        .unwrap_or(profile.health_status);
+
+    let body = [
+        CARE_CARD_PREFIX.to_string(),
+        profile.id.to_string(),
+        profile.name.replace('|', " "),
+        profile.blood_type,
+        format_iso8601(profile.expected_delivery_date)[..10].to_string(),
+        risk_tier(mother_id).to_string(),
+        format!("{:?}", latest_status),
+    ]
+    .join("|");
+
+    let key = signing_key().await?;
+    Ok(format!("{}|{}", body, card_signature(&key, &body)))
+}
+
+// Look up the mother named on a scanned card after checking it was signed by this canister
+#[ic_cdk::update]
+fn resolve_card(payload: String) -> Result<MotherProfile, Error> {
+    ensure_feature("mother_card")?;
+    let invalid = || Error::ValidationError {
+        msg: "Card payload is invalid or was not issued by this canister".to_string(),
+    };
+
+    let (body, signature) = payload.trim().rsplit_once('|').ok_or_else(invalid)?;
+    let key = SIGNING_KEY.with(|key| key.borrow().get().key.clone());
+    if key.is_empty() || !signature.eq_ignore_ascii_case(&card_signature(&key, body)) {
+        return Err(invalid());
+    }
+
+    let mut fields = body.split('|');
+    if fields.next() != Some(CARE_CARD_PREFIX) {
+        return Err(invalid());
+    }
+    let mother_id = fields.next().and_then(|id| id.parse().ok()).ok_or_else(invalid)?;
+    let profile = load_mother_profile(mother_id)?;
+    log_read(mother_id, "resolve_card");
+    Ok(profile)
+}
+
+const CARE_CARD_PREFIX: &str = "MP1";
+
+// Truncated HMAC, hex encoded, keeping the QR code small
+fn card_signature(key: &[u8], body: &str) -> String {
+    let mut message = b"mama-pack-card:".to_vec();
+    message.extend_from_slice(body.as_bytes());
+    hmac_sha256(key, &message)[..16]
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+// Risk tier over the whole pregnancy: HIGH after any critical visit, MEDIUM after any needing attention
+fn risk_tier(mother_id: u64) -> &'static str {
+    HEALTH_RECORD_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .range(mother_record_keys(mother_id))
+            .map(|(_, record)| match record.health_status {
+                HealthStatus::Critical => 2,
+                HealthStatus::NeedsAttention => 1,
+                HealthStatus::Normal => 0,
+            })
+            .max()
+    })
+    .map_or("LOW", |tier| ["LOW", "MEDIUM", "HIGH"][tier])
+}
+
+// Register (or replace) the standard code for a term such as a symptom or lab test name
+#[ic_cdk::update]
+fn register_clinical_code(term: String, code: ClinicalCode) -> Result<(), Error> {
+    ensure_controller()?;
+
+    let term = normalize_term(&term);
+    if term.is_empty() || term.len() > 100 {
+        return Err(Error::InvalidInput {
+            msg: "Term must be between 1 and 100 characters".to_string(),
+        });
+    }
+    if code.code.trim().is_empty() || code.code.len() > 32 || code.display.len() > 200 {
+        return Err(Error::InvalidInput {
+            msg: "Invalid clinical code".to_string(),
+        });
+    }
+
+    CODE_REGISTRY.with(|registry| registry.borrow_mut().insert(StringKey(term), code));
+    Ok(())
+}
+
+// Remove a term from the code registry
+#[ic_cdk::update]
+fn remove_clinical_code(term: String) -> Result<(), Error> {
+    ensure_controller()?;
+
+    CODE_REGISTRY
+        .with(|registry| registry.borrow_mut().remove(&StringKey(normalize_term(&term))))
+        .map(|_| ())
+        .ok_or(Error::NotFound {
+            msg: format!("No clinical code registered for '{}'", term),
+        })
+}
+
+// List all registered terms and their codes
+#[ic_cdk::query]
+fn get_clinical_codes() -> Vec<(String, ClinicalCode)> {
+    CODE_REGISTRY.with(|registry| {
+        registry
+            .borrow()
+            .iter()
+            .map(|(term, code)| (term.0, code))
+            .collect()
+    })
+}
+
+// Look up the code registered for a term
+#[ic_cdk::query]
+fn lookup_clinical_code(term: String) -> Option<ClinicalCode> {
+    CODE_REGISTRY.with(|registry| registry.borrow().get(&StringKey(normalize_term(&term))))
+}
+
+// Record a lab result for a mother
+#[ic_cdk::update]
+fn add_lab_result(payload: LabResultPayload) -> Result<LabResult, Error> {
+    observe_call("add_lab_result", insert_lab_result(payload))
+}
+
+fn insert_lab_result(payload: LabResultPayload) -> Result<LabResult, Error> {
+    insert_lab_result_at(payload, time())
+}
+
+// Create a lab result for a sample taken at `date`
+fn insert_lab_result_at(payload: LabResultPayload, date: u64) -> Result<LabResult, Error> {
+    load_mother_profile(payload.mother_id)?;
+
+    if payload.test_name.trim().is_empty() || payload.value.trim().is_empty() {
+        return Err(Error::InvalidInput {
+            msg: "Lab test name and value are required".to_string(),
+        });
+    }
+
+    let id = generate_new_id(EntityType::LabResult)?;
+    let code = payload
+        .code
+        .or_else(|| lookup_clinical_code(payload.test_name.clone()));
+
+    let lab_result = LabResult {
+        id,
+        mother_id: payload.mother_id,
+        test_name: payload.test_name,
+        value: payload.value,
+        unit: payload.unit,
+        date,
+        code,
+        version: Some(1),
+        ulid: Some(new_ulid(EntityType::LabResult, id)),
+        created_at: Some(time()),
+        updated_at: Some(time()),
+    };
+
+    let mut batch = WriteBatch::default();
+    let stored = lab_result.clone();
+    batch.stage(move || {
+        if let Some(ulid) = &stored.ulid {
+            index_ulid(ulid, EntityType::LabResult, id);
+        }
+        LAB_RESULT_STORAGE.with(|storage| storage.borrow_mut().insert(id, stored.clone()));
+        log_change(EntityType::LabResult, id, None, Some(&stored));
+        if let Some(finding) = lab_abnormality(&stored) {
+            raise_clinician_task(
+                stored.mother_id,
+                ClinicianTaskKind::ReviewAbnormalLab,
+                Some(id),
+                finding,
+                stored.date + ABNORMAL_LAB_REVIEW_HOURS * NANOS_PER_HOUR,
+            );
+        }
+    });
+    batch.commit();
+    Ok(lab_result)
+}
+
+// Get all lab results for a mother; reads are logged in her access log
+#[ic_cdk::update]
+fn get_mother_lab_results(mother_id: u64) -> Result<Vec<LabResult>, Error> {
+    ensure_mother_reader(&load_mother_profile(mother_id)?)?;
+    let lab_results = mother_lab_results(mother_id);
+    if !lab_results.is_empty() {
+        log_read(mother_id, "get_mother_lab_results");
+    }
+    Ok(lab_results)
+}
+
+fn mother_lab_results(mother_id: u64) -> Vec<LabResult> {
+    LAB_RESULT_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .filter(|(_, lab_result)| lab_result.mother_id == mother_id)
+            .map(|(_, lab_result)| lab_result)
+            .collect()
+    })
+}
+
+const MAX_SELF_REPORT_SYMPTOMS: usize = 20;
+const MAX_SELF_REPORT_NOTE_BYTES: usize = 1024;
+
+// Symptom check-in by the calling mother between visits. It is triaged like a visit's symptoms;
+// danger signs raise an alert for her community health worker.
+#[ic_cdk::update]
+fn report_symptoms(symptoms: Vec<String>, note: String) -> Result<SelfReport, Error> {
+    let mother_id = caller_mother_id()?;
+    let symptoms: Vec<String> = symptoms
+        .into_iter()
+        .map(|symptom| symptom.trim().to_string())
+        .filter(|symptom| !symptom.is_empty())
+        .collect();
+    if symptoms.is_empty() && note.trim().is_empty() {
+        return Err(Error::InvalidInput {
+            msg: "Report at least one symptom or a note".to_string(),
+        });
+    }
+    if symptoms.len() > MAX_SELF_REPORT_SYMPTOMS || symptoms.iter().any(|symptom| symptom.len() > 100) {
+        return Err(Error::ValidationError {
+            msg: format!("At most {} symptoms of up to 100 characters each", MAX_SELF_REPORT_SYMPTOMS),
+        });
+    }
+    if note.len() > MAX_SELF_REPORT_NOTE_BYTES {
+        return Err(Error::ValidationError {
+            msg: format!("note must be at most {} bytes", MAX_SELF_REPORT_NOTE_BYTES),
+        });
+    }
+    validate_sensitive_field("note", &note)?;
+
+    let id = generate_new_id(EntityType::SelfReport)?;
+    let danger_signs = danger_signs(&symptoms);
+    let alert_id = if danger_signs.is_empty() {
+        None
+    } else {
+        Some(raise_chw_alert(
+            mother_id,
+            format!("Self-reported danger signs: {}", danger_signs.join(", ")),
+            Some(id),
+        ))
+    };
+    let report = SelfReport {
+        id,
+        mother_id,
+        triage: triage_symptoms(&symptoms),
+        symptoms,
+        note,
+        danger_signs,
+        alert_id,
+        reported_at: time(),
+        ulid: Some(assign_ulid(EntityType::SelfReport, id)),
+    };
+    SELF_REPORT_STORAGE.with(|storage| storage.borrow_mut().insert(id, report.clone()));
+    log_change(EntityType::SelfReport, id, None, Some(&report));
+    Ok(report)
+}
+
+// A mother's self-reported check-ins, oldest first
+#[ic_cdk::query]
+fn get_self_reports(mother_id: u64) -> Vec<SelfReport> {
+    mother_self_reports(mother_id)
+}
+
+fn mother_self_reports(mother_id: u64) -> Vec<SelfReport> {
+    SELF_REPORT_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, report)| report)
+            .filter(|report| report.mother_id == mother_id)
+            .collect()
+    })
+}
+
+// Make a principal the community health worker following up a mother, or clear it (controllers and admins)
+#[ic_cdk::update]
+fn assign_chw(mother_id: u64, chw: Option<Principal>) -> Result<(), Error> {
+    ensure_controller()?;
+    load_mother_profile(mother_id)?;
+    MOTHER_CHWS.with(|chws| match chw {
+        Some(principal) => chws.borrow_mut().insert(mother_id, AccessGrant { principal, granted_at: time() }),
+        None => chws.borrow_mut().remove(&mother_id),
+    });
+    Ok(())
+}
+
+#[ic_cdk::query]
+fn get_assigned_chw(mother_id: u64) -> Option<AccessGrant> {
+    MOTHER_CHWS.with(|chws| chws.borrow().get(&mother_id))
+}
+
+// Queue an alert for the mother's community health worker; returns its id
+fn raise_chw_alert(mother_id: u64, reason: String, self_report_id: Option<u64>) -> u64 {
+    let id = CHW_ALERT_ID_SEQ.with(|counter| {
+        let next = *counter.borrow().get() + 1;
+        counter.borrow_mut().set(next).expect("Cannot update alert id sequence");
+        next
+    });
+    let alert = ChwAlert {
+        id,
+        mother_id,
+        chw: get_assigned_chw(mother_id).map(|grant| grant.principal),
+        reason,
+        self_report_id,
+        created_at: time(),
+        acknowledged_at: None,
+        dispatch_status: None,
+    };
+    CHW_ALERTS.with(|alerts| alerts.borrow_mut().insert(id, alert));
+    adjust_counter(OPEN_CHW_ALERTS_COUNTER, 1);
+    queue_sms(
+        mother_id,
+        SmsKind::Alert,
+        format!("alert:{}", id),
+        "MamaPack: your health worker has been alerted and will contact you. If you are bleeding, have a severe headache or fits, or the baby stops moving, go to the nearest facility now.".to_string(),
+    );
+    id
+}
+
+const OPEN_CHW_ALERTS_COUNTER: &str = "chw_alerts:open";
+
+// Open alerts for the calling health worker; controllers and admins see every open alert
+#[ic_cdk::query]
+fn get_chw_alerts(cursor: Option<u64>, limit: Option<u32>) -> Page<ChwAlert> {
+    let caller = ic_cdk::caller();
+    let all = ensure_controller().is_ok();
+    CHW_ALERTS.with(|alerts| {
+        let alerts = alerts.borrow();
+        let open = alerts
+            .iter()
+            .filter(|(_, alert)| alert.acknowledged_at.is_none() && (all || alert.chw == Some(caller)));
+        paginate(open, cursor, limit)
+    })
+}
+
+// Mark an alert as followed up (its health worker, controllers and admins)
+#[ic_cdk::update]
+fn acknowledge_chw_alert(id: u64) -> Result<ChwAlert, Error> {
+    let mut alert = CHW_ALERTS
+        .with(|alerts| alerts.borrow().get(&id))
+        .ok_or_else(|| Error::NotFound {
+            msg: format!("Alert with id={} not found", id),
+        })?;
+    if alert.chw != Some(ic_cdk::caller()) {
+        ensure_controller()?;
+    }
+    if alert.acknowledged_at.is_none() {
+        alert.acknowledged_at = Some(time());
+        CHW_ALERTS.with(|alerts| alerts.borrow_mut().insert(id, alert.clone()));
+        adjust_counter(OPEN_CHW_ALERTS_COUNTER, -1);
+    }
+    Ok(alert)
+}
+
+const MAX_WELLNESS_NOTE_BYTES: usize = 512;
+// Mood at or below this counts as low
+const LOW_MOOD_MAX: u8 = 2;
+// Consecutive logged low-mood days, all within LOW_MOOD_WINDOW_DAYS, that flag sustained low mood
+const LOW_MOOD_RUN: usize = 5;
+const LOW_MOOD_WINDOW_DAYS: u64 = 14;
+const LOW_MOOD_ALERT: &str = "Sustained low mood in the wellness journal: screen for postpartum depression";
+
+// Record today's mood and sleep for the calling mother; a second entry on the same day replaces
+// the first. Sustained low mood raises an alert for her health worker.
+#[ic_cdk::update]
+fn log_wellness(mood: u8, sleep_hours: f64, note: Option<String>) -> Result<WellnessEntry, Error> {
+    let mother_id = caller_mother_id()?;
+    if !(1..=5).contains(&mood) {
+        return Err(Error::ValidationError {
+            msg: "mood must be between 1 (very low) and 5 (very good)".to_string(),
+        });
+    }
+    if !(0.0..=24.0).contains(&sleep_hours) {
+        return Err(Error::ValidationError {
+            msg: "sleep_hours must be between 0 and 24".to_string(),
+        });
+    }
+    let note = note.unwrap_or_default();
+    if note.len() > MAX_WELLNESS_NOTE_BYTES {
+        return Err(Error::ValidationError {
+            msg: format!("note must be at most {} bytes", MAX_WELLNESS_NOTE_BYTES),
+        });
+    }
+    validate_sensitive_field("note", &note)?;
+
+    let day = time() / NANOS_PER_DAY;
+    let entry = WellnessDay {
+        mood,
+        sleep_minutes: (sleep_hours * 60.0).round() as u16,
+        note,
+    };
+    WELLNESS_JOURNAL.with(|journal| journal.borrow_mut().insert(RecordKey { mother_id, seq: day }, entry));
+
+    if sustained_low_mood(mother_id, day) && !has_open_alert(mother_id, LOW_MOOD_ALERT) {
+        raise_chw_alert(mother_id, LOW_MOOD_ALERT.to_string(), None);
+    }
+    Ok(wellness_entries(mother_id, day..=day).remove(0))
+}
+
+// A mother's journal over the last `days` days (default 90), oldest first. Readable by the mother
+// herself, her assigned health worker and sensitive readers; reads are logged.
+#[ic_cdk::update]
+fn get_wellness_timeline(mother_id: u64, days: Option<u32>) -> Result<WellnessTimeline, Error> {
+    let caller = ic_cdk::caller();
+    let own = caller_mother_id().ok() == Some(mother_id);
+    let assigned = get_assigned_chw(mother_id).is_some_and(|grant| grant.principal == caller);
+    if !own && !assigned {
+        ensure_sensitive_reader()?;
+    }
+    load_mother_profile(mother_id)?;
+
+    let today = time() / NANOS_PER_DAY;
+    let from = today.saturating_sub(u64::from(days.unwrap_or(90).max(1)) - 1);
+    log_read(mother_id, "get_wellness_timeline");
+    Ok(WellnessTimeline {
+        entries: wellness_entries(mother_id, from..=today),
+        sustained_low_mood: sustained_low_mood(mother_id, today),
+    })
+}
+
+// Lab test names read as haemoglobin results
+const HAEMOGLOBIN_TESTS: [&str; 4] = ["haemoglobin", "hemoglobin", "hb", "hgb"];
+
+// A mother's readings of one vital between `from` and `to`, oldest first, for charting. Blood
+// pressure, weight, edema and MUAC come from her visits (archived ones included), haemoglobin from her
+// lab results; readings that cannot be parsed are skipped. Readable by the mother herself, her
+// assigned health worker and sensitive readers; reads are logged.
+#[ic_cdk::update]
+async fn get_vital_trend(
+    mother_id: u64,
+    vital: Vital,
+    from: Option<u64>,
+    to: Option<u64>,
+) -> Result<Vec<VitalPoint>, Error> {
+    let caller = ic_cdk::caller();
+    let own = caller_mother_id().ok() == Some(mother_id);
+    let assigned = get_assigned_chw(mother_id).is_some_and(|grant| grant.principal == caller);
+    if !own && !assigned {
+        ensure_sensitive_reader()?;
+    }
+    load_mother_profile(mother_id)?;
+    let dates = from.unwrap_or(0)..=to.unwrap_or(u64::MAX);
+
+    let mut points: Vec<VitalPoint> = match vital {
+        Vital::BloodPressure | Vital::Weight | Vital::Edema | Vital::Muac => {
+            mother_health_records(mother_id)
+                .await?
+                .into_iter()
+                .filter(|record| dates.contains(&record.date))
+                .filter_map(|record| match vital {
+                    Vital::BloodPressure => {
+                        parse_blood_pressure(&record.blood_pressure).map(|(systolic, diastolic)| VitalPoint {
+                            date: record.date,
+                            value: f64::from(systolic),
+                            secondary: Some(f64::from(diastolic)),
+                        })
+                    }
+                    Vital::Edema => record.edema.map(|grade| VitalPoint {
+                        date: record.date,
+                        value: f64::from(grade as u8),
+                        secondary: None,
+                    }),
+                    Vital::Muac => record.muac_cm.map(|muac| VitalPoint {
+                        date: record.date,
+                        value: f64::from(muac),
+                        secondary: None,
+                    }),
+                    _ => Some(record.weight)
+                        .filter(|weight| *weight > 0.0)
+                        .map(|weight| VitalPoint { date: record.date, value: f64::from(weight), secondary: None }),
+                })
+                .collect()
+        }
+        Vital::Haemoglobin => mother_lab_results(mother_id)
+            .into_iter()
+            .filter(|result| dates.contains(&result.date))
+            .filter(|result| HAEMOGLOBIN_TESTS.contains(&normalize_term(&result.test_name).as_str()))
+            .filter_map(|result| {
+                let value = result.value.trim().parse::<f64>().ok()?;
+                Some(VitalPoint { date: result.date, value, secondary: None })
+            })
+            .collect(),
+    };
+    points.sort_by_key(|point| point.date);
+
+    log_read(mother_id, "get_vital_trend");
+    Ok(points)
+}
+
+fn wellness_entries(mother_id: u64, days: std::ops::RangeInclusive<u64>) -> Vec<WellnessEntry> {
+    let keys = RecordKey { mother_id, seq: *days.start() }..=RecordKey { mother_id, seq: *days.end() };
+    WELLNESS_JOURNAL.with(|journal| {
+        journal
+            .borrow()
+            .range(keys)
+            .map(|(key, day)| WellnessEntry {
+                date: key.seq * NANOS_PER_DAY,
+                mood: day.mood,
+                sleep_hours: f64::from(day.sleep_minutes) / 60.0,
+                note: Some(day.note).filter(|note| !note.is_empty()),
+            })
+            .collect()
+    })
+}
+
+// The latest LOW_MOOD_RUN entries up to `day` are all low and fall within the window
+fn sustained_low_mood(mother_id: u64, day: u64) -> bool {
+    let window = wellness_entries(mother_id, day.saturating_sub(LOW_MOOD_WINDOW_DAYS - 1)..=day);
+    window.len() >= LOW_MOOD_RUN && window.iter().rev().take(LOW_MOOD_RUN).all(|entry| entry.mood <= LOW_MOOD_MAX)
+}
+
+// Movements expected within the counting window from 28 weeks
+const KICK_COUNT_TARGET: u32 = 10;
+const KICK_COUNT_WINDOW: u64 = 2 * 60 * 60 * 1_000_000_000;
+const KICK_COUNT_FROM_WEEK: u64 = 28;
+// Sessions kept per mother; older ones are dropped
+const KICK_COUNT_HISTORY: usize = 100;
+
+// Start counting fetal movements for the calling mother. Any unfinished session is finished first.
+#[ic_cdk::update]
+fn start_kick_count() -> Result<KickCountSession, Error> {
+    let mother_id = caller_mother_id()?;
+    if let Some(open) = open_kick_count(mother_id) {
+        finish_kick_session(open);
+    }
+
+    let id = KICK_COUNT_ID_SEQ.with(|counter| {
+        let next = *counter.borrow().get() + 1;
+        counter.borrow_mut().set(next).expect("Cannot update kick count id sequence");
+        next
+    });
+    let session = KickCountSession {
+        id,
+        mother_id,
+        started_at: time(),
+        movements: 0,
+        finished_at: None,
+        flagged: false,
+        alert_id: None,
+    };
+    KICK_COUNTS.with(|sessions| {
+        let mut sessions = sessions.borrow_mut();
+        sessions.insert(RecordKey { mother_id, seq: id }, session.clone());
+        let keys: Vec<RecordKey> = sessions.range(mother_record_keys(mother_id)).map(|(key, _)| key).collect();
+        for key in keys.iter().take(keys.len().saturating_sub(KICK_COUNT_HISTORY)) {
+            sessions.remove(key);
+        }
+    });
+    Ok(session)
+}
+
+// Add movements to the caller's open session. Once the window has passed the session is
+// finished instead, and the movements are not counted.
+#[ic_cdk::update]
+fn record_kick_movements(movements: u32) -> Result<KickCountSession, Error> {
+    let mother_id = caller_mother_id()?;
+    let mut session = open_kick_count(mother_id).ok_or_else(|| Error::NotFound {
+        msg: "No kick count in progress; call start_kick_count first".to_string(),
+    })?;
+    if time() > session.started_at + KICK_COUNT_WINDOW {
+        return Ok(finish_kick_session(session));
+    }
+    if movements == 0 || movements > 50 {
+        return Err(Error::InvalidInput {
+            msg: "movements must be between 1 and 50".to_string(),
+        });
+    }
+    session.movements += movements;
+    KICK_COUNTS.with(|sessions| sessions.borrow_mut().insert(RecordKey { mother_id, seq: session.id }, session.clone()));
+    Ok(session)
+}
+
+#[ic_cdk::update]
+fn finish_kick_count() -> Result<KickCountSession, Error> {
+    let mother_id = caller_mother_id()?;
+    open_kick_count(mother_id)
+        .map(finish_kick_session)
+        .ok_or_else(|| Error::NotFound {
+            msg: "No kick count in progress".to_string(),
+        })
+}
+
+// A mother's recent kick count sessions, oldest first
+#[ic_cdk::query]
+fn get_kick_counts(mother_id: u64) -> Vec<KickCountSession> {
+    KICK_COUNTS.with(|sessions| sessions.borrow().range(mother_record_keys(mother_id)).map(|(_, session)| session).collect())
+}
+
+fn open_kick_count(mother_id: u64) -> Option<KickCountSession> {
+    KICK_COUNTS.with(|sessions| {
+        sessions
+            .borrow()
+            .range(mother_record_keys(mother_id))
+            .last()
+            .map(|(_, session)| session)
+            .filter(|session| session.finished_at.is_none())
+    })
+}
+
+// Close a session and flag it when the target was missed over a full window from 28 weeks.
+// A session finished early with few movements is not flagged; the count was incomplete.
+fn finish_kick_session(mut session: KickCountSession) -> KickCountSession {
+    let now = time();
+    session.finished_at = Some(now);
+    let full_window = now >= session.started_at + KICK_COUNT_WINDOW;
+    let third_trimester = load_mother_profile(session.mother_id).is_ok_and(|profile| {
+        profile.delivery.is_none()
+            && gestational_age_weeks(profile.expected_delivery_date, now) >= KICK_COUNT_FROM_WEEK
+    });
+    if full_window && third_trimester && session.movements < KICK_COUNT_TARGET {
+        session.flagged = true;
+        session.alert_id = Some(raise_chw_alert(
+            session.mother_id,
+            format!(
+                "Reduced fetal movements: {} in 2 hours (kick count {})",
+                session.movements, session.id
+            ),
+            None,
+        ));
+    }
+    KICK_COUNTS.with(|sessions| {
+        sessions
+            .borrow_mut()
+            .insert(RecordKey { mother_id: session.mother_id, seq: session.id }, session.clone())
+    });
+    session
+}
+
+const CONTRACTION_HISTORY: usize = 200;
+const PRETERM_WEEKS: u64 = 37;
+const PRETERM_LABOR_ALERT: &str = "Regular contractions before 37 weeks: assess for preterm labour";
+
+// Log a contraction for the calling mother: when it started (at most a day ago) and how long it
+// lasted. Regular contractions before 37 weeks alert her health worker.
+#[ic_cdk::update]
+fn log_contraction(started_at: u64, duration_seconds: u32) -> Result<ContractionSummary, Error> {
+    let mother_id = caller_mother_id()?;
+    let now = time();
+    if started_at > now || started_at + NANOS_PER_DAY < now {
+        return Err(Error::InvalidInput {
+            msg: "started_at must be within the last 24 hours".to_string(),
+        });
+    }
+    if !(1..=300).contains(&duration_seconds) {
+        return Err(Error::InvalidInput {
+            msg: "duration_seconds must be between 1 and 300".to_string(),
+        });
+    }
+    if load_mother_profile(mother_id)?.delivery.is_some() {
+        return Err(Error::InvalidInput {
+            msg: "Contractions are logged during pregnancy; this delivery is already recorded".to_string(),
+        });
+    }
+
+    CONTRACTIONS.with(|contractions| {
+        let mut contractions = contractions.borrow_mut();
+        contractions.insert(RecordKey { mother_id, seq: started_at }, duration_seconds);
+        let keys: Vec<RecordKey> = contractions.range(mother_record_keys(mother_id)).map(|(key, _)| key).collect();
+        for key in keys.iter().take(keys.len().saturating_sub(CONTRACTION_HISTORY)) {
+            contractions.remove(key);
+        }
+    });
+
+    let summary = contraction_summary(mother_id)?;
+    if summary.preterm_labor && !has_open_alert(mother_id, PRETERM_LABOR_ALERT) {
+        raise_chw_alert(mother_id, PRETERM_LABOR_ALERT.to_string(), None);
+    }
+    Ok(summary)
+}
+
+// Frequency and duration of a mother's contractions over the last hour
+#[ic_cdk::query]
+fn get_contraction_summary(mother_id: u64) -> Result<ContractionSummary, Error> {
+    contraction_summary(mother_id)
+}
+
+fn contraction_summary(mother_id: u64) -> Result<ContractionSummary, Error> {
+    const MINUTE: u64 = 60 * 1_000_000_000;
+    let profile = load_mother_profile(mother_id)?;
+    let now = time();
+    let keys = RecordKey { mother_id, seq: now.saturating_sub(60 * MINUTE) }..=RecordKey { mother_id, seq: now };
+    let contractions: Vec<Contraction> = CONTRACTIONS.with(|contractions| {
+        contractions
+            .borrow()
+            .range(keys)
+            .map(|(key, duration_seconds)| Contraction { started_at: key.seq, duration_seconds })
+            .collect()
+    });
+
+    let count = contractions.len();
+    let average_interval_seconds = (count > 1).then(|| {
+        (contractions[count - 1].started_at - contractions[0].started_at) / (count as u64 - 1) / 1_000_000_000
+    });
+    let average_duration_seconds = (count > 0)
+        .then(|| contractions.iter().map(|c| c.duration_seconds).sum::<u32>() / count as u32);
+    let last_20_minutes = contractions
+        .iter()
+        .filter(|c| c.started_at >= now.saturating_sub(20 * MINUTE))
+        .count();
+    let regular = last_20_minutes >= 4 || count >= 8;
+    let gestation_weeks = gestational_age_weeks(profile.expected_delivery_date, now);
+
+    Ok(ContractionSummary {
+        contractions,
+        average_interval_seconds,
+        average_duration_seconds,
+        regular,
+        gestation_weeks,
+        preterm_labor: regular && profile.delivery.is_none() && gestation_weeks < PRETERM_WEEKS,
+    })
+}
+
+fn has_open_alert(mother_id: u64, reason: &str) -> bool {
+    CHW_ALERTS.with(|alerts| {
+        alerts
+            .borrow()
+            .iter()
+            .any(|(_, alert)| alert.mother_id == mother_id && alert.reason == reason && alert.acknowledged_at.is_none())
+    })
+}
+
+fn remove_wellness_journal(mother_id: u64) {
+    let keys: Vec<RecordKey> =
+        WELLNESS_JOURNAL.with(|journal| journal.borrow().range(mother_record_keys(mother_id)).map(|(key, _)| key).collect());
+    WELLNESS_JOURNAL.with(|journal| {
+        let mut journal = journal.borrow_mut();
+        for key in &keys {
+            journal.remove(key);
+        }
+    });
+}
+
+fn remove_contractions(mother_id: u64) {
+    let keys: Vec<RecordKey> = CONTRACTIONS
+        .with(|contractions| contractions.borrow().range(mother_record_keys(mother_id)).map(|(key, _)| key).collect());
+    CONTRACTIONS.with(|contractions| {
+        let mut contractions = contractions.borrow_mut();
+        for key in &keys {
+            contractions.remove(key);
+        }
+    });
+}
+
+fn remove_kick_counts(mother_id: u64) {
+    let keys: Vec<RecordKey> =
+        KICK_COUNTS.with(|sessions| sessions.borrow().range(mother_record_keys(mother_id)).map(|(key, _)| key).collect());
+    KICK_COUNTS.with(|sessions| {
+        let mut sessions = sessions.borrow_mut();
+        for key in &keys {
+            sessions.remove(key);
+        }
+    });
+}
+
+fn remove_chw_alerts(mother_id: u64) {
+    let removed: Vec<ChwAlert> = CHW_ALERTS.with(|alerts| {
+        alerts.borrow().iter().map(|(_, alert)| alert).filter(|alert| alert.mother_id == mother_id).collect()
+    });
+    CHW_ALERTS.with(|alerts| {
+        let mut alerts = alerts.borrow_mut();
+        for alert in &removed {
+            alerts.remove(&alert.id);
+        }
+    });
+    let open = removed.iter().filter(|alert| alert.acknowledged_at.is_none()).count() as i64;
+    adjust_counter(OPEN_CHW_ALERTS_COUNTER, -open);
+}
+
+const MAX_CONTENT_BODY_BYTES: usize = 8 * 1024;
+const DEFAULT_CONTENT_LANGUAGE: &str = "en";
+
+// Add an article to the education library (controllers and admins)
+#[ic_cdk::update]
+fn add_content(payload: ContentPayload) -> Result<ContentArticle, Error> {
+    ensure_controller()?;
+    let payload = validate_content(payload)?;
+    let id = CONTENT_ID_SEQ.with(|counter| {
+        let next = *counter.borrow().get() + 1;
+        counter.borrow_mut().set(next).expect("Cannot update content id sequence");
+        next
+    });
+    let now = time();
+    let article = ContentArticle {
+        id,
+        title: payload.title,
+        body: payload.body,
+        stage: payload.stage,
+        language: payload.language,
+        tags: payload.tags,
+        conditions: payload.conditions,
+        created_at: now,
+        updated_at: now,
+    };
+    CONTENT_LIBRARY.with(|library| library.borrow_mut().insert(id, article.clone()));
+    Ok(article)
+}
+
+// Replace an article's content (controllers and admins)
+#[ic_cdk::update]
+fn update_content(id: u64, payload: ContentPayload) -> Result<ContentArticle, Error> {
+    ensure_controller()?;
+    let payload = validate_content(payload)?;
+    let mut article = CONTENT_LIBRARY
+        .with(|library| library.borrow().get(&id))
+        .ok_or_else(|| Error::NotFound {
+            msg: format!("Article with id={} not found", id),
+        })?;
+    article.title = payload.title;
+    article.body = payload.body;
+    article.stage = payload.stage;
+    article.language = payload.language;
+    article.tags = payload.tags;
+    article.conditions = payload.conditions;
+    article.updated_at = time();
+    CONTENT_LIBRARY.with(|library| library.borrow_mut().insert(id, article.clone()));
+    Ok(article)
+}
+
+#[ic_cdk::update]
+fn remove_content(id: u64) -> Result<(), Error> {
+    ensure_controller()?;
+    CONTENT_LIBRARY
+        .with(|library| library.borrow_mut().remove(&id))
+        .map(|_| ())
+        .ok_or_else(|| Error::NotFound {
+            msg: format!("Article with id={} not found", id),
+        })
+}
+
+// The whole library, for editing
+#[ic_cdk::query]
+fn list_content() -> Vec<ContentArticle> {
+    CONTENT_LIBRARY.with(|library| library.borrow().iter().map(|(_, article)| article).collect())
+}
+
+// Articles for the calling mother's current stage and conditions in her language (default
+// English, which is also used when nothing matches in her language). Articles written for one of
+// her conditions come first.
+#[ic_cdk::query]
+fn get_my_content(language: Option<String>) -> Result<Vec<ContentArticle>, Error> {
+    let profile = load_mother_profile(caller_mother_id()?)?;
+    let stage = if profile.delivery.is_some() {
+        PregnancyStage::PostPartum
+    } else {
+        calculate_pregnancy_stage(profile.expected_delivery_date)
+    };
+    let history: Vec<String> = profile.medical_history.iter().map(|entry| normalize_term(entry)).collect();
+    let has_condition = |condition: &String| history.iter().any(|entry| entry.contains(condition.as_str()));
+
+    let relevant = |language: &str| -> Vec<ContentArticle> {
+        let mut articles: Vec<ContentArticle> = CONTENT_LIBRARY.with(|library| {
+            library
+                .borrow()
+                .iter()
+                .map(|(_, article)| article)
+                .filter(|article| article.language == language)
+                .filter(|article| article.stage.is_none() || article.stage.as_ref() == Some(&stage))
+                .filter(|article| article.conditions.is_empty() || article.conditions.iter().any(has_condition))
+                .collect()
+        });
+        articles.sort_by_key(|article| (article.conditions.is_empty(), article.id));
+        articles
+    };
+
+    let language = language.map(|l| normalize_term(&l)).unwrap_or_else(|| DEFAULT_CONTENT_LANGUAGE.to_string());
+    let articles = relevant(&language);
+    if articles.is_empty() && language != DEFAULT_CONTENT_LANGUAGE {
+        return Ok(relevant(DEFAULT_CONTENT_LANGUAGE));
+    }
+    Ok(articles)
+}
+
+fn validate_content(mut payload: ContentPayload) -> Result<ContentPayload, Error> {
+    if payload.title.trim().is_empty() || payload.title.len() > 200 {
+        return Err(Error::ValidationError {
+            msg: "title must be between 1 and 200 characters".to_string(),
+        });
+    }
+    if payload.body.trim().is_empty() || payload.body.len() > MAX_CONTENT_BODY_BYTES {
+        return Err(Error::ValidationError {
+            msg: format!("body must be between 1 and {} bytes", MAX_CONTENT_BODY_BYTES),
+        });
+    }
+    payload.language = normalize_term(&payload.language);
+    if !(2..=8).contains(&payload.language.len()) {
+        return Err(Error::ValidationError {
+            msg: "language must be a language code such as \"en\" or \"sw\"".to_string(),
+        });
+    }
+    payload.tags = payload.tags.iter().map(|tag| normalize_term(tag)).filter(|tag| !tag.is_empty()).collect();
+    payload.conditions = payload
+        .conditions
+        .iter()
+        .map(|condition| normalize_term(condition))
+        .filter(|condition| !condition.is_empty())
+        .collect();
+    if payload.tags.len() + payload.conditions.len() > 20
+        || payload.tags.iter().chain(&payload.conditions).any(|term| term.len() > 50)
+    {
+        return Err(Error::ValidationError {
+            msg: "At most 20 tags and conditions of up to 50 characters each".to_string(),
+        });
+    }
+    Ok(payload)
+}
+
+// Actions for a mother from the recommendation rules, highest priority first
+#[ic_cdk::query]
+fn get_recommendations(mother_id: u64) -> Result<Vec<Recommendation>, Error> {
+    let profile = load_mother_profile(mother_id)?;
+    let now = time();
+    let pregnant_weeks =
+        profile.delivery.is_none().then(|| gestational_age_weeks(profile.expected_delivery_date, now));
+    let history: Vec<String> = profile.medical_history.iter().map(|entry| normalize_term(entry)).collect();
+    let mut lab_results = mother_lab_results(mother_id);
+    lab_results.sort_by_key(|result| result.date);
+    let tier = risk_tier(mother_id);
+    let adherence = mother_adherence(mother_id, true);
+
+    let latest_value = |test: &str| {
+        lab_results
+            .iter()
+            .rev()
+            .find(|result| normalize_term(&result.test_name).contains(&normalize_term(test)))
+            .and_then(|result| result.value.trim().parse::<f64>().ok())
+    };
+    let holds = |condition: &RuleCondition| match condition {
+        RuleCondition::GestationWeeks { from, to } => pregnant_weeks.is_some_and(|weeks| (*from..=*to).contains(&weeks)),
+        RuleCondition::Postpartum => profile.delivery.is_some(),
+        RuleCondition::HistoryMentions(term) => history.iter().any(|entry| entry.contains(&normalize_term(term))),
+        RuleCondition::RiskTier(expected) => tier.eq_ignore_ascii_case(expected.trim()),
+        RuleCondition::NotRecorded(term) => {
+            let term = normalize_term(term);
+            !history.iter().any(|entry| entry.contains(&term))
+                && !lab_results.iter().any(|result| normalize_term(&result.test_name).contains(&term))
+        }
+        RuleCondition::LabBelow { test, value } => latest_value(test).is_some_and(|latest| latest < *value),
+        RuleCondition::LabAbove { test, value } => latest_value(test).is_some_and(|latest| latest > *value),
+        RuleCondition::CheckupOverdueDays(days) => {
+            pregnant_weeks.is_some() && profile.last_checkup + days * NANOS_PER_DAY < now
+        }
+        RuleCondition::AdherenceBelow { medication, percent } => adherence.iter().any(|entry| {
+            normalize_term(&entry.medication).contains(&normalize_term(medication)) && entry.percent < *percent
+        }),
+    };
+
+    let rules = RECOMMENDATION_RULES.with(|cell| cell.borrow().get().rules.clone());
+    let mut recommendations: Vec<Recommendation> = rules
+        .into_iter()
+        .filter(|rule| rule.conditions.iter().all(holds))
+        .map(|rule| Recommendation {
+            rule_id: rule.id,
+            action: rule.action,
+            reason: rule.reason,
+            priority: rule.priority,
+        })
+        .collect();
+    recommendations.sort_by_key(|recommendation| std::cmp::Reverse(recommendation.priority));
+    Ok(recommendations)
+}
+
+#[ic_cdk::query]
+fn get_recommendation_rules() -> RecommendationRules {
+    RECOMMENDATION_RULES.with(|cell| cell.borrow().get().clone())
+}
+
+#[ic_cdk::query]
+fn get_note_templates() -> NoteTemplates {
+    NOTE_TEMPLATES.with(|cell| cell.borrow().get().clone())
+}
+
+// Note templates for a mother's current trimester, plus those for any stage
+#[ic_cdk::query]
+fn get_note_templates_for_mother(mother_id: u64) -> Result<Vec<NoteTemplate>, Error> {
+    let profile = load_mother_profile(mother_id)?;
+    let trimester = match gestational_age_weeks(profile.expected_delivery_date, time()) {
+        _ if profile.delivery.is_some() => 0,
+        0..=13 => 1,
+        14..=27 => 2,
+        _ => 3,
+    };
+    Ok(get_note_templates()
+        .templates
+        .into_iter()
+        .filter(|template| template.trimester.is_none() || template.trimester == Some(trimester))
+        .collect())
+}
+
+// Replace the note templates (controllers and admins only)
+#[ic_cdk::update]
+fn set_note_templates(templates: NoteTemplates) -> Result<NoteTemplates, Error> {
+    ensure_controller()?;
+    if templates.templates.len() > 50 {
+        return Err(Error::ValidationError {
+            msg: "At most 50 note templates".to_string(),
+        });
+    }
+    let mut ids = std::collections::BTreeSet::new();
+    for template in &templates.templates {
+        if template.id.trim().is_empty() || template.id.len() > 50 || !ids.insert(template.id.as_str()) {
+            return Err(Error::ValidationError {
+                msg: format!("Template ids must be unique and 1 to 50 characters: \"{}\"", template.id),
+            });
+        }
+        if template.trimester.is_some_and(|trimester| !(1..=3).contains(&trimester)) {
+            return Err(Error::ValidationError {
+                msg: format!("Template {} has a trimester other than 1, 2 or 3", template.id),
+            });
+        }
+        if template.sections.is_empty() || template.sections.len() > 20 || template.checklist.len() > 30 {
+            return Err(Error::ValidationError {
+                msg: format!("Template {} needs 1 to 20 sections and at most 30 checklist items", template.id),
+            });
+        }
+        let titles: std::collections::BTreeSet<&str> =
+            template.sections.iter().map(|section| section.title.as_str()).collect();
+        if titles.len() != template.sections.len() || titles.iter().any(|title| title.trim().is_empty() || title.len() > 50) {
+            return Err(Error::ValidationError {
+                msg: format!("Template {} needs distinct section titles of 1 to 50 characters", template.id),
+            });
+        }
+    }
+    NOTE_TEMPLATES.with(|cell| {
+        cell.borrow_mut()
+            .set(templates.clone())
+            .map(|_| ())
+            .map_err(|_| Error::SystemError { msg: "Failed to store note templates".to_string() })
+    })?;
+    Ok(templates)
+}
+
+#[ic_cdk::query]
+fn get_care_checklists() -> CareChecklists {
+    CARE_CHECKLISTS.with(|cell| cell.borrow().get().clone())
+}
+
+// Replace the per-trimester care checklists (controllers and admins only)
+#[ic_cdk::update]
+fn set_care_checklists(checklists: CareChecklists) -> Result<CareChecklists, Error> {
+    ensure_controller()?;
+    let mut trimesters = std::collections::BTreeSet::new();
+    for checklist in &checklists.checklists {
+        if !(1..=3).contains(&checklist.trimester) || !trimesters.insert(checklist.trimester) {
+            return Err(Error::ValidationError {
+                msg: "Each trimester (1, 2 or 3) may have one checklist".to_string(),
+            });
+        }
+        if checklist.items.len() > 30 {
+            return Err(Error::ValidationError {
+                msg: format!("The trimester {} checklist has more than 30 items", checklist.trimester),
+            });
+        }
+        let mut ids = std::collections::BTreeSet::new();
+        for item in &checklist.items {
+            if item.id.trim().is_empty() || item.id.len() > 50 || !ids.insert(item.id.as_str()) {
+                return Err(Error::ValidationError {
+                    msg: format!("Item ids must be unique and 1 to 50 characters: \"{}\"", item.id),
+                });
+            }
+            if item.label.trim().is_empty() || item.label.len() > 100 || item.evidence.is_empty() || item.evidence.len() > 5 {
+                return Err(Error::ValidationError {
+                    msg: format!("Item {} needs a label of 1 to 100 characters and 1 to 5 kinds of evidence", item.id),
+                });
+            }
+        }
+    }
+    CARE_CHECKLISTS.with(|cell| {
+        cell.borrow_mut()
+            .set(checklists.clone())
+            .map(|_| ())
+            .map_err(|_| Error::SystemError { msg: "Failed to store care checklists".to_string() })
+    })?;
+    Ok(checklists)
+}
+
+// Which checklist items a mother has completed in each trimester she has reached, from her
+// visits, lab results and prescriptions
+#[ic_cdk::query]
+fn get_checklist_completion(mother_id: u64) -> Result<ChecklistCompletion, Error> {
+    let profile = load_mother_profile(mother_id)?;
+    Ok(checklist_completion(&profile, &get_care_checklists(), time()))
+}
+
+// Checklist completion over the mothers registered at a facility
+#[ic_cdk::query]
+fn get_facility_checklist_completion(facility_code: String) -> Result<FacilityChecklistCompletion, Error> {
+    validate_facility_code(&facility_code)?;
+    let checklists = get_care_checklists();
+    let now = time();
+    let profiles: Vec<MotherProfile> = PROFILE_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, profile)| profile)
+            .filter(|profile| profile.facility_code.as_deref() == Some(facility_code.as_str()))
+            .collect()
+    });
+
+    // Trimester -> (items done, items due)
+    let mut tally: std::collections::BTreeMap<u8, (u64, u64)> = std::collections::BTreeMap::new();
+    for profile in &profiles {
+        for trimester in checklist_completion(profile, &checklists, now).trimesters {
+            let entry = tally.entry(trimester.trimester).or_default();
+            entry.0 += trimester.items.iter().filter(|item| item.done_at.is_some()).count() as u64;
+            entry.1 += trimester.items.len() as u64;
+        }
+    }
+    let (done, due) = tally.values().fold((0, 0), |(done, due), (d, n)| (done + d, due + n));
+    Ok(FacilityChecklistCompletion {
+        facility_code,
+        mothers: profiles.len() as u64,
+        by_trimester: tally.iter().map(|(trimester, (done, due))| (*trimester, percent(*done, *due))).collect(),
+        percent: percent(done, due),
+    })
+}
+
+fn checklist_completion(profile: &MotherProfile, checklists: &CareChecklists, now: u64) -> ChecklistCompletion {
+    let pregnancy_start = profile.expected_delivery_date.saturating_sub(280 * NANOS_PER_DAY);
+    let end = profile.delivery.as_ref().map_or(now, |delivery| delivery.delivery_date);
+    let week = |weeks: u64| pregnancy_start + weeks * 7 * NANOS_PER_DAY;
+    // Same boundaries as the trimester note templates: weeks 0-13, 14-27, 28 to delivery
+    let window = |trimester: u8| match trimester {
+        1 => week(0)..week(14),
+        2 => week(14)..week(28),
+        _ => week(28)..end.saturating_add(1),
+    };
+
+    let visits: Vec<HealthRecord> = HEALTH_RECORD_STORAGE.with(|storage| {
+        storage.borrow().range(mother_record_keys(profile.id)).map(|(_, record)| record).collect()
+    });
+    let labs = mother_lab_results(profile.id);
+    let prescriptions: Vec<Prescription> = PRESCRIPTIONS.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, prescription)| prescription)
+            .filter(|prescription| prescription.mother_id == profile.id)
+            .collect()
+    });
+    let mentions = |text: &str, terms: &[String]| {
+        let text = normalize_term(text);
+        terms.iter().any(|term| text.contains(&normalize_term(term)))
+    };
+
+    let mut trimesters = Vec::new();
+    let mut checklists: Vec<&CareChecklist> = checklists.checklists.iter().collect();
+    checklists.sort_by_key(|checklist| checklist.trimester);
+    for checklist in checklists {
+        let window = window(checklist.trimester);
+        if window.start > end {
+            continue;
+        }
+        let evidence_at = |evidence: &ChecklistEvidence| -> Option<u64> {
+            match evidence {
+                ChecklistEvidence::BloodPressure => visits
+                    .iter()
+                    .filter(|visit| !visit.blood_pressure.trim().is_empty())
+                    .map(|visit| visit.date)
+                    .filter(|date| window.contains(date))
+                    .min(),
+                ChecklistEvidence::Weight => visits
+                    .iter()
+                    .filter(|visit| visit.weight > 0.0)
+                    .map(|visit| visit.date)
+                    .filter(|date| window.contains(date))
+                    .min(),
+                ChecklistEvidence::Urinalysis => visits
+                    .iter()
+                    .filter(|visit| visit.urine_protein.is_some() || visit.urine_glucose.is_some())
+                    .map(|visit| visit.date)
+                    .filter(|date| window.contains(date))
+                    .min(),
+                ChecklistEvidence::LabTest(terms) => labs
+                    .iter()
+                    .filter(|lab| mentions(&lab.test_name, terms))
+                    .map(|lab| lab.date)
+                    .filter(|date| window.contains(date))
+                    .min(),
+                ChecklistEvidence::Prescription(terms) => prescriptions
+                    .iter()
+                    .filter(|prescription| mentions(&prescription.medication, terms))
+                    .map(|prescription| prescription.created_at)
+                    .filter(|date| window.contains(date))
+                    .min(),
+                ChecklistEvidence::Ticked(ticked) => visits
+                    .iter()
+                    .filter(|visit| visit.checklist_done.iter().flatten().any(|item| item == ticked))
+                    .map(|visit| visit.date)
+                    .filter(|date| window.contains(date))
+                    .min(),
+            }
+        };
+        let items: Vec<ChecklistItemStatus> = checklist
+            .items
+            .iter()
+            .map(|item| ChecklistItemStatus {
+                id: item.id.clone(),
+                label: item.label.clone(),
+                done_at: item.evidence.iter().filter_map(evidence_at).min(),
+            })
+            .collect();
+        let done = items.iter().filter(|item| item.done_at.is_some()).count() as u64;
+        trimesters.push(TrimesterCompletion {
+            trimester: checklist.trimester,
+            percent: percent(done, items.len() as u64),
+            items,
+        });
+    }
+
+    let done = trimesters.iter().flat_map(|t| &t.items).filter(|item| item.done_at.is_some()).count() as u64;
+    let due = trimesters.iter().map(|t| t.items.len() as u64).sum();
+    ChecklistCompletion {
+        mother_id: profile.id,
+        trimesters,
+        percent: percent(done, due),
+    }
+}
+
+// Replace the recommendation rules (controllers and admins only)
+#[ic_cdk::update]
+fn set_recommendation_rules(rules: RecommendationRules) -> Result<RecommendationRules, Error> {
+    ensure_controller()?;
+    if rules.rules.len() > 100 {
+        return Err(Error::ValidationError {
+            msg: "At most 100 recommendation rules".to_string(),
+        });
+    }
+    let mut ids = std::collections::BTreeSet::new();
+    for rule in &rules.rules {
+        if rule.id.trim().is_empty() || !ids.insert(rule.id.as_str()) {
+            return Err(Error::ValidationError {
+                msg: format!("Rule ids must be unique and non-empty: \"{}\"", rule.id),
+            });
+        }
+        if rule.action.trim().is_empty() || rule.action.len() > 200 || rule.reason.len() > 500 {
+            return Err(Error::ValidationError {
+                msg: format!("Rule {} needs an action of up to 200 characters and a reason of up to 500", rule.id),
+            });
+        }
+        if rule.conditions.is_empty() {
+            return Err(Error::ValidationError {
+                msg: format!("Rule {} needs at least one condition", rule.id),
+            });
+        }
+    }
+    RECOMMENDATION_RULES.with(|cell| {
+        cell.borrow_mut()
+            .set(rules.clone())
+            .map(|_| ())
+            .map_err(|_| Error::SystemError { msg: "Failed to store recommendation rules".to_string() })
+    })?;
+    Ok(rules)
+}
+
+// Start a daily medication or supplement for a mother, e.g. iron and folic acid once a day. Fails
+// with PrescriptionWarnings until every warning from check_prescription is acknowledged.
+#[ic_cdk::update]
+fn add_prescription(payload: PrescriptionPayload) -> Result<Prescription, Error> {
+    let warnings = prescription_warnings(&payload)?;
+    let acknowledged = payload.acknowledged_warnings.unwrap_or_default();
+    let unacknowledged: Vec<PrescriptionWarning> =
+        warnings.into_iter().filter(|warning| !acknowledged.contains(&warning.code)).collect();
+    if !unacknowledged.is_empty() {
+        return Err(Error::PrescriptionWarnings {
+            msg: format!("{} warning(s) must be acknowledged before prescribing", unacknowledged.len()),
+            warnings: unacknowledged,
+        });
+    }
+    if !(1..=4).contains(&payload.times_per_day) {
+        return Err(Error::InvalidInput {
+            msg: "times_per_day must be between 1 and 4".to_string(),
+        });
+    }
+    if payload.duration_days == Some(0) {
+        return Err(Error::InvalidInput {
+            msg: "duration_days must be at least 1".to_string(),
+        });
+    }
+
+    let id = PRESCRIPTION_ID_SEQ.with(|counter| {
+        let next = *counter.borrow().get() + 1;
+        counter.borrow_mut().set(next).expect("Cannot update prescription id sequence");
+        next
+    });
+    let start_day = payload.start_date.unwrap_or_else(time) / NANOS_PER_DAY;
+    let prescription = Prescription {
+        id,
+        mother_id: payload.mother_id,
+        medication: payload.medication.trim().to_string(),
+        dose: payload.dose.trim().to_string(),
+        times_per_day: payload.times_per_day,
+        start_day,
+        end_day: payload.duration_days.map(|days| start_day + u64::from(days) - 1),
+        prescribed_by: ic_cdk::caller(),
+        created_at: time(),
+        acknowledged_warnings: Some(acknowledged).filter(|codes| !codes.is_empty()),
+    };
+    ensure_fits("Prescription", id, &prescription)?;
+    PRESCRIPTIONS.with(|storage| storage.borrow_mut().insert(id, prescription.clone()));
+    Ok(prescription)
+}
+
+// Warnings for a prescription: allergies and conditions in the mother's medical history, the drug
+// to be avoided at her stage of pregnancy, and interactions with her current prescriptions
+#[ic_cdk::query]
+fn check_prescription(payload: PrescriptionPayload) -> Result<Vec<PrescriptionWarning>, Error> {
+    prescription_warnings(&payload)
+}
+
+fn prescription_warnings(payload: &PrescriptionPayload) -> Result<Vec<PrescriptionWarning>, Error> {
+    let profile = load_mother_profile(payload.mother_id)?;
+    if payload.medication.trim().is_empty() || payload.medication.len() > 100 || payload.dose.len() > 100 {
+        return Err(Error::InvalidInput {
+            msg: "A medication of up to 100 characters is required, and the dose is at most 100".to_string(),
+        });
+    }
+    let mut warnings = Vec::new();
+    let Some(rule) = find_drug_rule(&payload.medication) else {
+        return Ok(warnings);
+    };
+    let mut warn = |kind: WarningKind, term: &str, detail: String| {
+        let code = format!("{}:{}", format!("{:?}", kind).to_lowercase(), term);
+        if !warnings.iter().any(|warning: &PrescriptionWarning| warning.code == code) {
+            warnings.push(PrescriptionWarning { kind, code, detail });
+        }
+    };
+
+    let drug_terms: Vec<&str> = std::iter::once(rule.drug.as_str()).chain(rule.drug_class.as_deref()).collect();
+    for entry in profile.medical_history.iter().map(|entry| normalize_term(entry)) {
+        if entry.contains("allerg") {
+            if let Some(term) = drug_terms.iter().find(|term| entry.contains(*term)) {
+                warn(WarningKind::Allergy, term, format!("Medical history records an allergy: \"{}\"", entry));
+            }
+            continue;
+        }
+        for condition in rule.contraindicated_conditions.iter().filter(|condition| entry.contains(condition.as_str())) {
+            warn(WarningKind::Condition, condition, format!("{} is contraindicated with {}: {}", rule.drug, condition, rule.reason));
+        }
+    }
+
+    if profile.delivery.is_none() {
+        let trimester = match gestational_age_weeks(profile.expected_delivery_date, time()) {
+            0..=13 => 1,
+            14..=27 => 2,
+            _ => 3,
+        };
+        if rule.avoid_in_trimesters.contains(&trimester) {
+            warn(
+                WarningKind::Pregnancy,
+                &rule.drug,
+                format!("{} should be avoided in trimester {}: {}", rule.drug, trimester, rule.reason),
+            );
+        }
+    }
+
+    let today = time() / NANOS_PER_DAY;
+    let current = mother_prescriptions(payload.mother_id)
+        .into_iter()
+        .filter(|prescription| prescription.end_day.unwrap_or(u64::MAX) >= today);
+    for other in current {
+        let other_rule = find_drug_rule(&other.medication);
+        let other_terms: Vec<String> = std::iter::once(normalize_term(&other.medication))
+            .chain(other_rule.iter().flat_map(|other_rule| {
+                std::iter::once(other_rule.drug.clone()).chain(other_rule.drug_class.clone())
+            }))
+            .collect();
+        let ours = rule.interacts_with.iter().any(|term| other_terms.iter().any(|other| other.contains(term.as_str())));
+        let theirs = other_rule.as_ref().is_some_and(|other_rule| {
+            other_rule.interacts_with.iter().any(|term| drug_terms.contains(&term.as_str()))
+        });
+        if ours || theirs {
+            warn(
+                WarningKind::Interaction,
+                &normalize_term(&other.medication),
+                format!("{} interacts with her current prescription of {}", rule.drug, other.medication),
+            );
+        }
+    }
+    Ok(warnings)
+}
+
+// Rule for a medication: an exact match, else the longest registered drug name it mentions
+fn find_drug_rule(medication: &str) -> Option<DrugRule> {
+    let medication = normalize_term(medication);
+    DRUG_RULES.with(|rules| {
+        let rules = rules.borrow();
+        rules.get(&StringKey(medication.clone())).or_else(|| {
+            rules
+                .iter()
+                .filter(|(drug, _)| medication.contains(drug.0.as_str()))
+                .max_by_key(|(drug, _)| drug.0.len())
+                .map(|(_, rule)| rule)
+        })
+    })
+}
+
+// Register (or replace) the contraindications and interactions of a drug (controllers only)
+#[ic_cdk::update]
+fn register_drug_rule(rule: DrugRule) -> Result<(), Error> {
+    ensure_controller()?;
+
+    let normalize = |terms: Vec<String>| -> Vec<String> {
+        terms.iter().map(|term| normalize_term(term)).filter(|term| !term.is_empty()).collect()
+    };
+    let rule = DrugRule {
+        drug: normalize_term(&rule.drug),
+        drug_class: rule.drug_class.map(|class| normalize_term(&class)).filter(|class| !class.is_empty()),
+        avoid_in_trimesters: rule.avoid_in_trimesters,
+        contraindicated_conditions: normalize(rule.contraindicated_conditions),
+        interacts_with: normalize(rule.interacts_with),
+        reason: rule.reason.trim().to_string(),
+    };
+    let terms = [&rule.contraindicated_conditions, &rule.interacts_with];
+    if rule.drug.is_empty()
+        || rule.drug.len() > 100
+        || rule.drug_class.as_ref().is_some_and(|class| class.len() > 100)
+        || rule.reason.len() > 300
+        || terms.iter().any(|terms| terms.len() > 20 || terms.iter().any(|term| term.len() > 100))
+    {
+        return Err(Error::InvalidInput {
+            msg: "The drug and class must be 1 to 100 characters, the reason at most 300, and at most 20 conditions and interactions of up to 100 characters".to_string(),
+        });
+    }
+    if rule.avoid_in_trimesters.iter().any(|trimester| !(1..=3).contains(trimester)) {
+        return Err(Error::InvalidInput {
+            msg: "Trimesters must be 1, 2 or 3".to_string(),
+        });
+    }
+
+    DRUG_RULES.with(|rules| rules.borrow_mut().insert(StringKey(rule.drug.clone()), rule));
+    Ok(())
+}
+
+// Remove a drug from the contraindication list (controllers only)
+#[ic_cdk::update]
+fn remove_drug_rule(drug: String) -> Result<(), Error> {
+    ensure_controller()?;
+
+    DRUG_RULES
+        .with(|rules| rules.borrow_mut().remove(&StringKey(normalize_term(&drug))))
+        .map(|_| ())
+        .ok_or(Error::NotFound {
+            msg: format!("No drug rule registered for '{}'", drug),
+        })
+}
+
+#[ic_cdk::query]
+fn get_drug_rules() -> Vec<DrugRule> {
+    DRUG_RULES.with(|rules| rules.borrow().iter().map(|(_, rule)| rule).collect())
+}
+
+// End a prescription after today's doses
+#[ic_cdk::update]
+fn stop_prescription(id: u64) -> Result<Prescription, Error> {
+    let mut prescription = PRESCRIPTIONS
+        .with(|storage| storage.borrow().get(&id))
+        .ok_or_else(|| Error::NotFound {
+            msg: format!("Prescription with id={} not found", id),
+        })?;
+    let today = time() / NANOS_PER_DAY;
+    prescription.end_day = Some(prescription.end_day.unwrap_or(u64::MAX).min(today).max(prescription.start_day));
+    PRESCRIPTIONS.with(|storage| storage.borrow_mut().insert(id, prescription.clone()));
+    Ok(prescription)
+}
+
+#[ic_cdk::query]
+fn get_prescriptions(mother_id: u64) -> Vec<Prescription> {
+    mother_prescriptions(mother_id)
+}
+
+// The calling mother's doses on the day containing `date`, in order
+#[ic_cdk::query]
+fn get_my_reminders(date: u64) -> Result<Vec<DoseReminder>, Error> {
+    let mother_id = caller_mother_id()?;
+    let day = date / NANOS_PER_DAY;
+    let mut reminders = Vec::new();
+    for prescription in mother_prescriptions(mother_id).into_iter().filter(|p| prescribed_on(p, day)) {
+        for dose_index in 0..prescription.times_per_day {
+            let key = DoseKey { prescription_id: prescription.id, day, dose_index };
+            reminders.push(DoseReminder {
+                prescription_id: prescription.id,
+                medication: prescription.medication.clone(),
+                dose: prescription.dose.clone(),
+                dose_index,
+                due_at: day * NANOS_PER_DAY + dose_hour(prescription.times_per_day, dose_index) * 60 * 60 * 1_000_000_000,
+                taken_at: DOSES_TAKEN.with(|doses| doses.borrow().get(&key)),
+            });
+        }
+    }
+    reminders.sort_by_key(|reminder| (reminder.due_at, reminder.prescription_id));
+    Ok(reminders)
+}
+
+// Mark one of the calling mother's doses as taken; doses can be marked up to 7 days late
+#[ic_cdk::update]
+fn mark_dose_taken(prescription_id: u64, date: u64, dose_index: u8) -> Result<(), Error> {
+    let mother_id = caller_mother_id()?;
+    let prescription = PRESCRIPTIONS
+        .with(|storage| storage.borrow().get(&prescription_id))
+        .filter(|prescription| prescription.mother_id == mother_id)
+        .ok_or_else(|| Error::NotFound {
+            msg: format!("Prescription with id={} not found", prescription_id),
+        })?;
+    let day = date / NANOS_PER_DAY;
+    let today = time() / NANOS_PER_DAY;
+    if day > today || day + 7 < today || !prescribed_on(&prescription, day) || dose_index >= prescription.times_per_day {
+        return Err(Error::InvalidInput {
+            msg: "No such dose due in the last 7 days".to_string(),
+        });
+    }
+    DOSES_TAKEN.with(|doses| doses.borrow_mut().insert(DoseKey { prescription_id, day, dose_index }, time()));
+    Ok(())
+}
+
+// Share of scheduled doses marked taken, per prescription, up to and including today
+#[ic_cdk::query]
+fn get_adherence(mother_id: u64) -> Vec<Adherence> {
+    mother_adherence(mother_id, false)
+}
+
+fn mother_adherence(mother_id: u64, active_only: bool) -> Vec<Adherence> {
+    let today = time() / NANOS_PER_DAY;
+    mother_prescriptions(mother_id)
+        .into_iter()
+        .filter(|prescription| prescription.start_day <= today)
+        .filter(|prescription| !active_only || prescribed_on(prescription, today))
+        .map(|prescription| {
+            let last_day = prescription.end_day.unwrap_or(today).min(today);
+            let doses_due = (last_day - prescription.start_day + 1) * u64::from(prescription.times_per_day);
+            let doses_taken = DOSES_TAKEN.with(|doses| {
+                let from = DoseKey { prescription_id: prescription.id, day: 0, dose_index: 0 };
+                let to = DoseKey { prescription_id: prescription.id, day: u64::MAX, dose_index: u8::MAX };
+                doses.borrow().range(from..=to).count() as u64
+            });
+            Adherence {
+                prescription_id: prescription.id,
+                medication: prescription.medication,
+                doses_due,
+                doses_taken,
+                percent: (doses_taken * 100 / doses_due.max(1)).min(100) as u8,
+            }
+        })
+        .collect()
+}
+
+fn mother_prescriptions(mother_id: u64) -> Vec<Prescription> {
+    PRESCRIPTIONS.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, prescription)| prescription)
+            .filter(|prescription| prescription.mother_id == mother_id)
+            .collect()
+    })
+}
+
+fn prescribed_on(prescription: &Prescription, day: u64) -> bool {
+    day >= prescription.start_day && prescription.end_day.unwrap_or(u64::MAX) >= day
+}
+
+// Hour of the day (UTC) a dose is due: 08:00 for one dose, spread evenly to 20:00 for more
+fn dose_hour(times_per_day: u8, dose_index: u8) -> u64 {
+    if times_per_day <= 1 {
+        return 8;
+    }
+    8 + u64::from(dose_index) * 12 / u64::from(times_per_day - 1)
+}
+
+fn remove_prescriptions(mother_id: u64) {
+    for prescription in mother_prescriptions(mother_id) {
+        let from = DoseKey { prescription_id: prescription.id, day: 0, dose_index: 0 };
+        let to = DoseKey { prescription_id: prescription.id, day: u64::MAX, dose_index: u8::MAX };
+        let keys: Vec<DoseKey> = DOSES_TAKEN.with(|doses| doses.borrow().range(from..=to).map(|(key, _)| key).collect());
+        DOSES_TAKEN.with(|doses| {
+            let mut doses = doses.borrow_mut();
+            for key in &keys {
+                doses.remove(key);
+            }
+        });
+        PRESCRIPTIONS.with(|storage| storage.borrow_mut().remove(&prescription.id));
+    }
+}
+
+// Grant or revoke the pharmacist role (controllers and admins only)
+#[ic_cdk::update]
+fn set_pharmacist(principal: Principal, allowed: bool) -> Result<(), Error> {
+    ensure_controller()?;
+    set_grant(&PHARMACISTS, principal, allowed);
+    Ok(())
+}
+
+#[ic_cdk::query]
+fn get_pharmacists() -> Result<Vec<AccessGrant>, Error> {
+    ensure_controller()?;
+    list_grants(&PHARMACISTS)
+}
+
+// Pharmacists, controllers and admins
+fn ensure_pharmacist() -> Result<(), Error> {
+    let caller = StringKey(ic_cdk::caller().to_text());
+    if PHARMACISTS.with(|pharmacists| pharmacists.borrow().contains_key(&caller)) {
+        return Ok(());
+    }
+    ensure_controller().map_err(|_| Error::AuthorizationError {
+        msg: "Dispensing and stock keeping need the pharmacist role".to_string(),
+    })
+}
+
+// Drug name as stock is kept under it; at most 80 characters so the stock key fits
+fn stock_drug(drug: &str) -> Result<String, Error> {
+    let drug = normalize_term(drug);
+    if drug.is_empty() || drug.len() > 80 {
+        return Err(Error::InvalidInput {
+            msg: "The drug must be 1 to 80 characters".to_string(),
+        });
+    }
+    Ok(drug)
+}
+
+fn stock_key(facility_code: &str, drug: &str) -> StringKey {
+    StringKey(format!("{}:{}", facility_code, drug))
+}
+
+fn store_stock_level(facility_code: String, drug: String, quantity: u64) -> StockLevel {
+    let level = StockLevel {
+        facility_code,
+        drug,
+        quantity,
+        updated_by: ic_cdk::caller(),
+        updated_at: time(),
+    };
+    FACILITY_STOCK.with(|stock| {
+        stock.borrow_mut().insert(stock_key(&level.facility_code, &level.drug), level.clone())
+    });
+    level
+}
+
+// Add units of a drug delivered to a facility (pharmacists)
+#[ic_cdk::update]
+fn receive_stock(facility_code: String, drug: String, quantity: u32) -> Result<StockLevel, Error> {
+    ensure_pharmacist()?;
+    validate_facility_code(&facility_code)?;
+    let drug = stock_drug(&drug)?;
+    if quantity == 0 {
+        return Err(Error::InvalidInput {
+            msg: "quantity must be at least 1".to_string(),
+        });
+    }
+    let held = FACILITY_STOCK
+        .with(|stock| stock.borrow().get(&stock_key(&facility_code, &drug)))
+        .map_or(0, |level| level.quantity);
+    Ok(store_stock_level(facility_code, drug, held + u64::from(quantity)))
+}
+
+// Replace the units held after a stock count (pharmacists)
+#[ic_cdk::update]
+fn set_stock_level(facility_code: String, drug: String, quantity: u64) -> Result<StockLevel, Error> {
+    ensure_pharmacist()?;
+    validate_facility_code(&facility_code)?;
+    let drug = stock_drug(&drug)?;
+    Ok(store_stock_level(facility_code, drug, quantity))
+}
+
+// Every drug a facility holds, by name (pharmacists and supervisors)
+#[ic_cdk::query]
+fn get_facility_stock(facility_code: String) -> Result<Vec<StockLevel>, Error> {
+    ensure_pharmacist().or_else(|_| ensure_supervisor())?;
+    let prefix = format!("{}:", facility_code);
+    Ok(FACILITY_STOCK.with(|stock| {
+        stock
+            .borrow()
+            .range(StringKey(prefix.clone())..)
+            .take_while(|(key, _)| key.0.starts_with(&prefix))
+            .map(|(_, level)| level)
+            .collect()
+    }))
+}
+
+// Hand over medication for a prescription at a facility pharmacy, taking it out of the
+// facility's stock. Fails with Conflict when the facility holds too little of the drug.
+#[ic_cdk::update]
+fn dispense_prescription(payload: DispensingPayload) -> Result<Dispensing, Error> {
+    ensure_pharmacist()?;
+    validate_facility_code(&payload.facility_code)?;
+    let prescription = PRESCRIPTIONS
+        .with(|storage| storage.borrow().get(&payload.prescription_id))
+        .ok_or_else(|| Error::NotFound {
+            msg: format!("Prescription with id={} not found", payload.prescription_id),
+        })?;
+    if prescription.end_day.is_some_and(|end_day| end_day < time() / NANOS_PER_DAY) {
+        return Err(Error::InvalidInput {
+            msg: format!("Prescription id={} has ended", prescription.id),
+        });
+    }
+    let drug = stock_drug(payload.drug.as_deref().unwrap_or(&prescription.medication))?;
+    let batch_number = payload.batch.trim().to_string();
+    if !(1..=1000).contains(&payload.quantity) || batch_number.is_empty() || batch_number.len() > 50 {
+        return Err(Error::InvalidInput {
+            msg: "quantity must be between 1 and 1000, and a batch number of up to 50 characters is required".to_string(),
+        });
+    }
+    let held = FACILITY_STOCK
+        .with(|stock| stock.borrow().get(&stock_key(&payload.facility_code, &drug)))
+        .map_or(0, |level| level.quantity);
+    if held < u64::from(payload.quantity) {
+        return Err(Error::Conflict {
+            msg: format!("Only {} unit(s) of {} in stock at {}", held, drug, payload.facility_code),
+        });
+    }
+
+    let id = DISPENSING_ID_SEQ.with(|counter| {
+        let next = *counter.borrow().get() + 1;
+        counter.borrow_mut().set(next).expect("Cannot update dispensing id sequence");
+        next
+    });
+    let dispensing = Dispensing {
+        id,
+        prescription_id: prescription.id,
+        mother_id: prescription.mother_id,
+        facility_code: payload.facility_code,
+        drug,
+        quantity: payload.quantity,
+        batch: batch_number,
+        dispensed_by: ic_cdk::caller(),
+        dispensed_at: time(),
+    };
+    ensure_fits("Dispensing", id, &dispensing)?;
+
+    let mut batch = WriteBatch::default();
+    let stored = dispensing.clone();
+    batch.stage(move || {
+        store_stock_level(stored.facility_code.clone(), stored.drug.clone(), held - u64::from(stored.quantity));
+        DISPENSINGS.with(|storage| storage.borrow_mut().insert(stored.id, stored));
+    });
+    batch.commit();
+    Ok(dispensing)
+}
+
+// A mother's dispensings, oldest first
+#[ic_cdk::query]
+fn get_dispensings(mother_id: u64) -> Vec<Dispensing> {
+    mother_dispensings(mother_id)
+}
+
+// Started prescriptions with fewer units dispensed than prescribed, counting one unit per dose,
+// optionally for the mothers of one facility (pharmacists and supervisors)
+#[ic_cdk::query]
+fn get_dispensing_gaps(
+    facility_code: Option<String>,
+    cursor: Option<u64>,
+    limit: Option<u32>,
+) -> Result<Page<DispensingGap>, Error> {
+    ensure_pharmacist().or_else(|_| ensure_supervisor())?;
+    let mut dispensed: std::collections::BTreeMap<u64, (u64, u64)> = std::collections::BTreeMap::new();
+    DISPENSINGS.with(|storage| {
+        for (_, dispensing) in storage.borrow().iter() {
+            let entry = dispensed.entry(dispensing.prescription_id).or_insert((0, 0));
+            entry.0 += u64::from(dispensing.quantity);
+            entry.1 = entry.1.max(dispensing.dispensed_at);
+        }
+    });
+
+    let today = time() / NANOS_PER_DAY;
+    let prescriptions: Vec<Prescription> = PRESCRIPTIONS.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, prescription)| prescription)
+            .filter(|prescription| prescription.start_day <= today)
+            .collect()
+    });
+    let gaps = prescriptions.into_iter().filter_map(|prescription| {
+        let last_day = prescription.end_day.unwrap_or(today);
+        let prescribed = (last_day - prescription.start_day + 1) * u64::from(prescription.times_per_day);
+        let (units, last_dispensed_at) = dispensed.get(&prescription.id).copied().unwrap_or_default();
+        if units >= prescribed {
+            return None;
+        }
+        let mother_facility = load_mother_profile(prescription.mother_id).ok().and_then(|profile| profile.facility_code);
+        if facility_code.is_some() && mother_facility != facility_code {
+            return None;
+        }
+        Some((
+            prescription.id,
+            DispensingGap {
+                prescription_id: prescription.id,
+                mother_id: prescription.mother_id,
+                facility_code: mother_facility,
+                medication: prescription.medication,
+                prescribed,
+                dispensed: units,
+                last_dispensed_at: Some(last_dispensed_at).filter(|_| units > 0),
+            },
+        ))
+    });
+    Ok(paginate(gaps, cursor, limit))
+}
+
+fn mother_dispensings(mother_id: u64) -> Vec<Dispensing> {
+    DISPENSINGS.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, dispensing)| dispensing)
+            .filter(|dispensing| dispensing.mother_id == mother_id)
+            .collect()
+    })
+}
+
+// Stock already handed over stays out of the facility's count
+fn remove_dispensings(mother_id: u64) {
+    for dispensing in mother_dispensings(mother_id) {
+        DISPENSINGS.with(|storage| storage.borrow_mut().remove(&dispensing.id));
+    }
+}
+
+// A birth plan is made from this gestational age
+const BIRTH_PLAN_FROM_WEEK: u64 = 28;
+
+// Record or replace a mother's birth plan, from 28 weeks until the delivery is recorded
+#[ic_cdk::update]
+fn save_birth_plan(mother_id: u64, payload: BirthPlanPayload) -> Result<BirthPlan, Error> {
+    let profile = load_mother_profile(mother_id)?;
+    if profile.delivery.is_some()
+        || gestational_age_weeks(profile.expected_delivery_date, time()) < BIRTH_PLAN_FROM_WEEK
+    {
+        return Err(Error::InvalidInput {
+            msg: format!("A birth plan is made from {} weeks until delivery", BIRTH_PLAN_FROM_WEEK),
+        });
+    }
+    let fields = [
+        &payload.preferred_facility,
+        &payload.transport_arrangement,
+        &payload.birth_companion,
+        &payload.blood_donor,
+    ];
+    if fields.iter().any(|field| field.len() > 200) {
+        return Err(Error::ValidationError {
+            msg: "Birth plan fields must be at most 200 characters".to_string(),
+        });
+    }
+
+    let current = BIRTH_PLANS.with(|plans| plans.borrow().get(&mother_id));
+    check_version("Birth plan of mother", mother_id, current.and_then(|plan| plan.version), payload.expected_version)?;
+
+    let checklist = &payload.danger_signs;
+    let all_signs_covered = checklist.vaginal_bleeding
+        && checklist.severe_headache_or_blurred_vision
+        && checklist.convulsions
+        && checklist.fever
+        && checklist.severe_abdominal_pain
+        && checklist.reduced_fetal_movement
+        && checklist.breaking_of_waters
+        && checklist.swelling_of_face_or_hands;
+    let mode_planned = payload.planned_delivery_mode.is_some() || !prior_cesarean(&profile);
+    let plan = BirthPlan {
+        mother_id,
+        complete: all_signs_covered && mode_planned && fields.iter().all(|field| !field.trim().is_empty()),
+        preferred_facility: payload.preferred_facility.trim().to_string(),
+        transport_arrangement: payload.transport_arrangement.trim().to_string(),
+        birth_companion: payload.birth_companion.trim().to_string(),
+        blood_donor: payload.blood_donor.trim().to_string(),
+        danger_signs: payload.danger_signs,
+        planned_delivery_mode: payload.planned_delivery_mode,
+        version: Some(payload.expected_version + 1),
+        updated_at: time(),
+        updated_by: ic_cdk::caller(),
+    };
+    BIRTH_PLANS.with(|plans| plans.borrow_mut().insert(mother_id, plan.clone()));
+    Ok(plan)
+}
+
+#[ic_cdk::query]
+fn get_birth_plan(mother_id: u64) -> Option<BirthPlan> {
+    BIRTH_PLANS.with(|plans| plans.borrow().get(&mother_id))
+}
+
+// Undelivered mothers with an EDD in the next `days` days (default 42), or already past it,
+// who have no complete birth plan; paged by mother id, and reads are logged
+#[ic_cdk::update]
+fn get_mothers_without_birth_plan(
+    days: Option<u64>,
+    cursor: Option<u64>,
+    limit: Option<u32>,
+) -> Result<Page<MotherProfile>, Error> {
+    let scope = list_scope()?;
+    let horizon = time() + days.unwrap_or(42) * NANOS_PER_DAY;
+    let page = PROFILE_STORAGE.with(|profiles| {
+        BIRTH_PLANS.with(|plans| {
+            let plans = plans.borrow();
+            let mothers = profiles
+                .borrow()
+                .iter()
+                .filter(|(_, profile)| profile.delivery.is_none() && profile.expected_delivery_date <= horizon)
+                .filter(|(_, profile)| in_list_scope(&scope, profile))
+                .filter(|(id, _)| !plans.get(id).is_some_and(|plan| plan.complete))
+                .collect::<Vec<_>>();
+            paginate(mothers.into_iter(), cursor, limit)
+        })
+    });
+    Ok(log_page_reads(page, |profile| profile.id, "get_mothers_without_birth_plan"))
+}
+
+// Week from which routine antenatal anti-D is given
+const ANTI_D_WEEKS: u64 = 28;
+
+fn rh_negative(profile: &MotherProfile) -> bool {
+    profile.blood_type.ends_with('-')
+}
+
+// Record anti-D immunoglobulin given to an Rh-negative mother
+#[ic_cdk::update]
+fn record_anti_d(mother_id: u64, payload: AntiDPayload) -> Result<AntiDDose, Error> {
+    let profile = load_mother_profile(mother_id)?;
+    if !rh_negative(&profile) {
+        return Err(Error::InvalidInput {
+            msg: format!("Mother id={} is {}; anti-D is given to Rh-negative mothers", mother_id, profile.blood_type),
+        });
+    }
+    let now = time();
+    let given_at = payload.given_at.unwrap_or(now);
+    if given_at > now {
+        return Err(Error::InvalidInput {
+            msg: "given_at cannot be in the future".to_string(),
+        });
+    }
+    if !(50..=5000).contains(&payload.dose_mcg) {
+        return Err(Error::ValidationError {
+            msg: "Anti-D dose must be between 50 and 5000 mcg".to_string(),
+        });
+    }
+    if let Some(facility_code) = &payload.facility_code {
+        validate_facility_code(facility_code)?;
+    }
+
+    let id = ANTI_D_ID_SEQ.with(|counter| {
+        let next = *counter.borrow().get() + 1;
+        counter.borrow_mut().set(next).expect("Cannot update anti-D id sequence");
+        next
+    });
+    let dose = AntiDDose {
+        id,
+        mother_id,
+        given_at,
+        dose_mcg: payload.dose_mcg,
+        indication: payload.indication,
+        facility_code: payload.facility_code.or(profile.facility_code),
+        recorded_by: ic_cdk::caller(),
+    };
+    ANTI_D_DOSES.with(|doses| doses.borrow_mut().insert(RecordKey { mother_id, seq: id }, dose.clone()));
+    Ok(dose)
+}
+
+// Whether a mother is Rh-negative, the anti-D she has been given and whether antenatal
+// prophylaxis is due
+#[ic_cdk::query]
+fn get_rh_status(mother_id: u64) -> Result<RhStatus, Error> {
+    let profile = load_mother_profile(mother_id)?;
+    Ok(RhStatus {
+        mother_id,
+        rh_negative: rh_negative(&profile),
+        doses: mother_anti_d_doses(mother_id),
+        prophylaxis_due: anti_d_due(&profile, time(), 0),
+    })
+}
+
+// Undelivered Rh-negative mothers at or within `weeks_ahead` weeks (default 2) of 28 weeks, or past
+// it, with no antenatal anti-D recorded in this pregnancy; paged by mother id, and reads are logged
+#[ic_cdk::update]
+fn get_rh_negative_without_prophylaxis(
+    weeks_ahead: Option<u64>,
+    cursor: Option<u64>,
+    limit: Option<u32>,
+) -> Result<Page<MotherProfile>, Error> {
+    let scope = list_scope()?;
+    let now = time();
+    let weeks_ahead = weeks_ahead.unwrap_or(2);
+    let mothers: Vec<(u64, MotherProfile)> = PROFILE_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .filter(|(_, profile)| anti_d_due(profile, now, weeks_ahead) && in_list_scope(&scope, profile))
+            .collect()
+    });
+    let page = paginate(mothers.into_iter(), cursor, limit);
+    Ok(log_page_reads(page, |profile| profile.id, "get_rh_negative_without_prophylaxis"))
+}
+
+// Rh-negative and pregnant, at least 28 weeks less `weeks_ahead`, with no antenatal dose since
+// the pregnancy began
+fn anti_d_due(profile: &MotherProfile, now: u64, weeks_ahead: u64) -> bool {
+    if !rh_negative(profile) || profile.delivery.is_some() {
+        return false;
+    }
+    if gestational_age_weeks(profile.expected_delivery_date, now) + weeks_ahead < ANTI_D_WEEKS {
+        return false;
+    }
+    let pregnancy_start = profile.expected_delivery_date.saturating_sub(280 * NANOS_PER_DAY);
+    !mother_anti_d_doses(profile.id)
+        .iter()
+        .any(|dose| matches!(dose.indication, AntiDIndication::Antenatal) && dose.given_at >= pregnancy_start)
+}
+
+// Default gestational week by which syphilis and hepatitis B screening should be done
+const SCREENING_DUE_WEEKS: u64 = 16;
+
+// Record a syphilis or hepatitis B screening result; positive results start with treatment not started
+#[ic_cdk::update]
+fn record_screening(mother_id: u64, payload: ScreeningPayload) -> Result<InfectionScreening, Error> {
+    let profile = load_mother_profile(mother_id)?;
+    let now = time();
+    let screened_at = payload.screened_at.unwrap_or(now);
+    if screened_at > now {
+        return Err(Error::InvalidInput {
+            msg: "screened_at cannot be in the future".to_string(),
+        });
+    }
+    if let Some(facility_code) = &payload.facility_code {
+        validate_facility_code(facility_code)?;
+    }
+
+    let id = SCREENING_ID_SEQ.with(|counter| {
+        let next = *counter.borrow().get() + 1;
+        counter.borrow_mut().set(next).expect("Cannot update screening id sequence");
+        next
+    });
+    let screening = InfectionScreening {
+        id,
+        mother_id,
+        test: payload.test,
+        screened_at,
+        result: payload.result,
+        treatment: matches!(payload.result, ScreeningResult::Positive).then_some(TreatmentStatus::NotStarted),
+        doses_required: None,
+        doses_given: None,
+        facility_code: payload.facility_code.or(profile.facility_code),
+        recorded_by: ic_cdk::caller(),
+        updated_at: now,
+    };
+    SCREENINGS.with(|screenings| screenings.borrow_mut().insert(RecordKey { mother_id, seq: id }, screening.clone()));
+    Ok(screening)
+}
+
+// Update treatment of a positive screening
+#[ic_cdk::update]
+fn update_screening_treatment(
+    mother_id: u64,
+    screening_id: u64,
+    payload: TreatmentPayload,
+) -> Result<InfectionScreening, Error> {
+    let key = RecordKey { mother_id, seq: screening_id };
+    let mut screening = SCREENINGS.with(|screenings| screenings.borrow().get(&key)).ok_or(Error::NotFound {
+        msg: format!("Screening id={} not found for mother id={}", screening_id, mother_id),
+    })?;
+    if matches!(screening.result, ScreeningResult::Negative) {
+        return Err(Error::InvalidInput {
+            msg: format!("Screening id={} is negative; only positive results are treated", screening_id),
+        });
+    }
+
+    let doses_required = payload.doses_required.or(screening.doses_required);
+    let doses_given = payload.doses_given.or(screening.doses_given);
+    if doses_required.is_some_and(|doses| !(1..=3).contains(&doses)) {
+        return Err(Error::ValidationError {
+            msg: "doses_required must be between 1 and 3".to_string(),
+        });
+    }
+    if let (Some(required), Some(given)) = (doses_required, doses_given) {
+        if given > required {
+            return Err(Error::ValidationError {
+                msg: format!("doses_given ({}) cannot exceed doses_required ({})", given, required),
+            });
+        }
+        if payload.status == TreatmentStatus::Completed && given < required {
+            return Err(Error::ValidationError {
+                msg: format!("Treatment is not complete: {} of {} doses given", given, required),
+            });
+        }
+    }
+
+    screening.treatment = Some(payload.status);
+    screening.doses_required = doses_required;
+    screening.doses_given = doses_given;
+    screening.updated_at = time();
+    SCREENINGS.with(|screenings| screenings.borrow_mut().insert(key, screening.clone()));
+    Ok(screening)
+}
+
+// A mother's syphilis and hepatitis B screening this pregnancy, which are overdue and which
+// positives are not fully treated
+#[ic_cdk::query]
+fn get_screening_status(mother_id: u64) -> Result<ScreeningStatus, Error> {
+    let profile = load_mother_profile(mother_id)?;
+    Ok(screening_status(&profile, time(), screening_due_weeks()))
+}
+
+// Mothers with a screening overdue or a positive result not fully treated this pregnancy; paged
+// by mother id
+#[ic_cdk::query]
+fn get_screening_follow_ups(cursor: Option<u64>, limit: Option<u32>) -> Page<ScreeningStatus> {
+    let now = time();
+    let due_weeks = screening_due_weeks();
+    let statuses: Vec<(u64, ScreeningStatus)> = PROFILE_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(id, profile)| (id, screening_status(&profile, now, due_weeks)))
+            .filter(|(_, status)| !status.overdue.is_empty() || !status.incomplete_treatment.is_empty())
+            .collect()
+    });
+    paginate(statuses.into_iter(), cursor, limit)
+}
+
+fn screening_due_weeks() -> u64 {
+    canister_config().screening_due_weeks.unwrap_or(SCREENING_DUE_WEEKS)
+}
+
+fn screening_status(profile: &MotherProfile, now: u64, due_weeks: u64) -> ScreeningStatus {
+    let pregnancy_start = profile.expected_delivery_date.saturating_sub(280 * NANOS_PER_DAY);
+    let screenings: Vec<InfectionScreening> = mother_screenings(profile.id)
+        .into_iter()
+        .filter(|screening| screening.screened_at >= pregnancy_start)
+        .collect();
+    let latest = |test: ScreeningTest| {
+        screenings.iter().filter(|screening| screening.test == test).max_by_key(|screening| screening.screened_at).cloned()
+    };
+    let syphilis = latest(ScreeningTest::Syphilis);
+    let hepatitis_b = latest(ScreeningTest::HepatitisB);
+
+    let screening_due = profile.delivery.is_none() && gestational_age_weeks(profile.expected_delivery_date, now) >= due_weeks;
+    let mut overdue = Vec::new();
+    let mut incomplete_treatment = Vec::new();
+    for (test, screening) in [(ScreeningTest::Syphilis, &syphilis), (ScreeningTest::HepatitisB, &hepatitis_b)] {
+        match screening {
+            None if screening_due => overdue.push(test),
+            Some(screening) if screening.treatment.is_some_and(|status| status != TreatmentStatus::Completed) => {
+                incomplete_treatment.push(test)
+            }
+            _ => {}
+        }
+    }
+    ScreeningStatus {
+        mother_id: profile.id,
+        syphilis,
+        hepatitis_b,
+        overdue,
+        incomplete_treatment,
+    }
+}
+
+fn mother_screenings(mother_id: u64) -> Vec<InfectionScreening> {
+    SCREENINGS.with(|screenings| {
+        screenings.borrow().range(mother_record_keys(mother_id)).map(|(_, screening)| screening).collect()
+    })
+}
+
+fn remove_screenings(mother_id: u64) {
+    let keys: Vec<RecordKey> = SCREENINGS
+        .with(|screenings| screenings.borrow().range(mother_record_keys(mother_id)).map(|(key, _)| key).collect());
+    SCREENINGS.with(|screenings| {
+        let mut screenings = screenings.borrow_mut();
+        for key in &keys {
+            screenings.remove(key);
+        }
+    });
+}
+
+fn mother_anti_d_doses(mother_id: u64) -> Vec<AntiDDose> {
+    ANTI_D_DOSES.with(|doses| doses.borrow().range(mother_record_keys(mother_id)).map(|(_, dose)| dose).collect())
+}
+
+fn remove_anti_d_doses(mother_id: u64) {
+    let keys: Vec<RecordKey> =
+        ANTI_D_DOSES.with(|doses| doses.borrow().range(mother_record_keys(mother_id)).map(|(key, _)| key).collect());
+    ANTI_D_DOSES.with(|doses| {
+        let mut doses = doses.borrow_mut();
+        for key in &keys {
+            doses.remove(key);
+        }
+    });
+}
+
+// Days between whole-blood donations; donors who gave more recently are not suggested
+const DONATION_INTERVAL_DAYS: u64 = 90;
+const MAX_DONOR_MATCHES: usize = 50;
+
+// Join the blood donor registry. Donors opt in themselves, or staff register them with their
+// consent; the caller can later update or withdraw the entry.
+#[ic_cdk::update]
+fn register_blood_donor(payload: BloodDonorPayload) -> Result<BloodDonor, Error> {
+    let caller = ic_cdk::caller();
+    if caller == Principal::anonymous() {
+        return Err(Error::AuthorizationError {
+            msg: "Donors must be registered by an authenticated user".to_string(),
+        });
+    }
+    let donor = validate_blood_donor(payload, caller)?;
+    let id = BLOOD_DONOR_ID_SEQ.with(|counter| {
+        let next = *counter.borrow().get() + 1;
+        counter.borrow_mut().set(next).expect("Cannot update blood donor id sequence");
+        next
+    });
+    let donor = BloodDonor { id, ..donor };
+    BLOOD_DONORS.with(|donors| donors.borrow_mut().insert(id, donor.clone()));
+    Ok(donor)
+}
+
+// Replace a donor's details, e.g. after a donation or a move (whoever registered them, controllers
+// and admins)
+#[ic_cdk::update]
+fn update_blood_donor(donor_id: u64, payload: BloodDonorPayload) -> Result<BloodDonor, Error> {
+    let existing = load_blood_donor_for_change(donor_id)?;
+    let donor = BloodDonor {
+        id: donor_id,
+        registered_by: existing.registered_by,
+        registered_at: existing.registered_at,
+        ..validate_blood_donor(payload, existing.registered_by)?
+    };
+    BLOOD_DONORS.with(|donors| donors.borrow_mut().insert(donor_id, donor.clone()));
+    Ok(donor)
+}
+
+// Leave the registry; the entry is deleted (whoever registered the donor, controllers and admins)
+#[ic_cdk::update]
+fn remove_blood_donor(donor_id: u64) -> Result<(), Error> {
+    load_blood_donor_for_change(donor_id)?;
+    BLOOD_DONORS.with(|donors| donors.borrow_mut().remove(&donor_id));
+    Ok(())
+}
+
+fn load_blood_donor_for_change(donor_id: u64) -> Result<BloodDonor, Error> {
+    let donor = BLOOD_DONORS.with(|donors| donors.borrow().get(&donor_id)).ok_or_else(|| Error::NotFound {
+        msg: format!("Blood donor with id={} not found", donor_id),
+    })?;
+    if donor.registered_by != ic_cdk::caller() {
+        ensure_controller()?;
+    }
+    Ok(donor)
+}
+
+fn validate_blood_donor(payload: BloodDonorPayload, registered_by: Principal) -> Result<BloodDonor, Error> {
+    let name = payload.name.trim().to_string();
+    let location = payload.location.trim().to_string();
+    if name.is_empty() || name.len() > 100 {
+        return Err(Error::InvalidInput {
+            msg: "Name must be between 1 and 100 characters".to_string(),
+        });
+    }
+    if location.is_empty() || location.len() > 100 {
+        return Err(Error::InvalidInput {
+            msg: "Location must be between 1 and 100 characters".to_string(),
+        });
+    }
+    validate_blood_type(&payload.blood_type)?;
+    if let Some(facility_code) = &payload.facility_code {
+        validate_facility_code(facility_code)?;
+    }
+    let phone = sms_number(&payload.phone).ok_or_else(|| Error::InvalidInput {
+        msg: "Phone number must be in E.164 form, e.g. +254712345678".to_string(),
+    })?;
+    if payload.last_donated_at.is_some_and(|date| date > time()) {
+        return Err(Error::InvalidInput {
+            msg: "Last donation cannot be in the future".to_string(),
+        });
+    }
+    Ok(BloodDonor {
+        id: 0,
+        name,
+        blood_type: payload.blood_type,
+        location,
+        facility_code: payload.facility_code,
+        phone,
+        contact_consent: payload.contact_consent,
+        last_donated_at: payload.last_donated_at,
+        registered_by,
+        registered_at: time(),
+    })
+}
+
+// Whether a recipient can receive red cells from a donor: O to anyone, A and B to their own group
+// and AB, and Rh-negative blood to anyone while Rh-positive goes only to Rh-positive recipients
+fn red_cells_compatible(recipient: &str, donor: &str) -> bool {
+    let split = |blood_type: &str| {
+        let negative = blood_type.ends_with('-');
+        (blood_type.trim_end_matches(['+', '-']).to_string(), negative)
+    };
+    let (recipient_group, recipient_negative) = split(recipient);
+    let (donor_group, donor_negative) = split(donor);
+    let abo = donor_group == "O" || donor_group == recipient_group || recipient_group == "AB";
+    let rh = donor_negative || !recipient_negative;
+    abo && rh
+}
+
+// Registry donors a mother can receive red cells from, for obstetric haemorrhage preparedness:
+// exact matches first, then donors at her facility, then the rest. Donors who gave blood within
+// DONATION_INTERVAL_DAYS are left out. For her care team, controllers and admins.
+#[ic_cdk::query]
+fn find_compatible_donors(mother_id: u64) -> Result<Vec<DonorMatch>, Error> {
+    if !matches!(ensure_thread_access(mother_id), Ok(false)) {
+        ensure_controller()?;
+    }
+    let profile = load_mother_profile(mother_id)?;
+    validate_blood_type(&profile.blood_type).map_err(|_| Error::ValidationError {
+        msg: format!("Mother id={} has no recorded blood type", mother_id),
+    })?;
+    let rested_since = time().saturating_sub(DONATION_INTERVAL_DAYS * NANOS_PER_DAY);
+
+    let mut matches: Vec<DonorMatch> = BLOOD_DONORS.with(|donors| {
+        donors
+            .borrow()
+            .iter()
+            .map(|(_, donor)| donor)
+            .filter(|donor| red_cells_compatible(&profile.blood_type, &donor.blood_type))
+            .filter(|donor| match donor.last_donated_at {
+                Some(date) => date <= rested_since,
+                None => true,
+            })
+            .map(|donor| DonorMatch {
+                donor_id: donor.id,
+                exact_match: donor.blood_type == profile.blood_type,
+                phone: donor.contact_consent.then_some(donor.phone),
+                name: donor.name,
+                blood_type: donor.blood_type,
+                location: donor.location,
+                facility_code: donor.facility_code,
+                last_donated_at: donor.last_donated_at,
+            })
+            .collect()
+    });
+    matches.sort_by_key(|donor| {
+        let nearby = profile.facility_code.is_some() && donor.facility_code == profile.facility_code;
+        (!donor.exact_match, !nearby, donor.donor_id)
+    });
+    matches.truncate(MAX_DONOR_MATCHES);
+    Ok(matches)
+}
+
+// A mother not seen for longer than this is flagged
+const DATA_QUALITY_VISIT_GAP_DAYS: u64 = 56;
+
+// Profiles at a facility (or all) with missing or implausible fields, paged by mother id, so data
+// clerks can work through them
+#[ic_cdk::query]
+fn get_data_quality_report(
+    facility_code: Option<String>,
+    cursor: Option<u64>,
+    limit: Option<u32>,
+) -> Page<DataQualityFlag> {
+    let now = time();
+    let flags: Vec<(u64, DataQualityFlag)> = PROFILE_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .filter(|(_, profile)| facility_code.is_none() || profile.facility_code == facility_code)
+            .filter_map(|(id, profile)| {
+                let issues = data_quality_issues(&profile, now);
+                if issues.is_empty() {
+                    return None;
+                }
+                let flag = DataQualityFlag {
+                    mother_id: id,
+                    name: profile.name,
+                    facility_code: profile.facility_code,
+                    issues,
+                };
+                Some((id, flag))
+            })
+            .collect()
+    });
+    paginate(flags.into_iter(), cursor, limit)
+}
+
+fn data_quality_issues(profile: &MotherProfile, now: u64) -> Vec<DataQualityIssue> {
+    let mut issues = Vec::new();
+    if validate_emergency_contact(&profile.emergency_contact).is_err() {
+        issues.push(DataQualityIssue::MissingContact);
+    }
+    if validate_age(profile.age).is_err() {
+        issues.push(DataQualityIssue::ImplausibleAge);
+    }
+    if validate_blood_type(&profile.blood_type).is_err() {
+        issues.push(DataQualityIssue::UnknownBloodType);
+    }
+    if profile.expected_delivery_date > now + 300 * NANOS_PER_DAY {
+        issues.push(DataQualityIssue::ImplausibleEdd);
+    }
+    if profile.delivery.is_none() {
+        if profile.expected_delivery_date < now {
+            issues.push(DataQualityIssue::OverdueWithoutDelivery);
+        }
+        // Set at registration, so mothers never seen are measured from then
+        if profile.last_checkup + DATA_QUALITY_VISIT_GAP_DAYS * NANOS_PER_DAY < now {
+            issues.push(DataQualityIssue::NoRecentVisit);
+        }
+    }
+    issues
+}
+
+const FEEDBACK_WINDOW_DAYS: u64 = 30;
+const MAX_FEEDBACK_COMMENT_BYTES: usize = 500;
+// Facilities with fewer responses are left out of the aggregates
+const FEEDBACK_MIN_RESPONSES: u64 = 5;
+
+// Rate a visit, as the mother it was for, within 30 days; one response per visit
+#[ic_cdk::update]
+fn submit_visit_feedback(
+    record_id: u64,
+    waiting_time_rating: u8,
+    respectful_care_rating: u8,
+    comment: Option<String>,
+) -> Result<(), Error> {
+    let mother_id = caller_mother_id()?;
+    let record = get_health_record(record_id)
+        .filter(|record| record.mother_id == mother_id)
+        .ok_or_else(|| Error::NotFound {
+            msg: format!("Visit with id={} not found", record_id),
+        })?;
+    if record.date + FEEDBACK_WINDOW_DAYS * NANOS_PER_DAY < time() {
+        return Err(Error::InvalidInput {
+            msg: format!("Feedback is taken within {} days of the visit", FEEDBACK_WINDOW_DAYS),
+        });
+    }
+    if !(1..=5).contains(&waiting_time_rating) || !(1..=5).contains(&respectful_care_rating) {
+        return Err(Error::ValidationError {
+            msg: "Ratings must be between 1 and 5".to_string(),
+        });
+    }
+    let comment = comment.unwrap_or_default().trim().to_string();
+    if comment.len() > MAX_FEEDBACK_COMMENT_BYTES {
+        return Err(Error::ValidationError {
+            msg: format!("comment must be at most {} bytes", MAX_FEEDBACK_COMMENT_BYTES),
+        });
+    }
+    if VISIT_FEEDBACK.with(|feedback| feedback.borrow().contains_key(&record_id)) {
+        return Err(Error::Conflict {
+            msg: format!("Feedback for visit {} was already given", record_id),
+        });
+    }
+
+    let feedback = VisitFeedback {
+        record_id,
+        facility_code: record.facility_code,
+        waiting_time_rating,
+        respectful_care_rating,
+        comment,
+        submitted_at: time(),
+    };
+    VISIT_FEEDBACK.with(|storage| storage.borrow_mut().insert(record_id, feedback));
+    Ok(())
+}
+
+// Feedback per facility for visits rated in a time range; facilities with fewer than 5
+// responses are left out so that no single mother can be picked out
+#[ic_cdk::query]
+fn get_facility_feedback(
+    from: Option<u64>,
+    to: Option<u64>,
+    cursor: Option<u64>,
+    limit: Option<u32>,
+) -> Page<FacilityFeedback> {
+    let range = from.unwrap_or(0)..=to.unwrap_or(u64::MAX);
+    let mut by_facility: std::collections::BTreeMap<String, Vec<VisitFeedback>> = std::collections::BTreeMap::new();
+    VISIT_FEEDBACK.with(|feedback| {
+        for (_, entry) in feedback.borrow().iter() {
+            if let Some(code) = entry.facility_code.clone().filter(|_| range.contains(&entry.submitted_at)) {
+                by_facility.entry(code).or_default().push(entry);
+            }
+        }
+    });
+
+    let facilities = by_facility
+        .into_iter()
+        .filter(|(_, entries)| entries.len() as u64 >= FEEDBACK_MIN_RESPONSES)
+        .map(|(facility_code, mut entries)| {
+            let responses = entries.len() as f64;
+            entries.sort_by_key(|entry| std::cmp::Reverse(entry.submitted_at));
+            FacilityFeedback {
+                facility_code,
+                responses: entries.len() as u64,
+                average_waiting_time: entries.iter().map(|e| f64::from(e.waiting_time_rating)).sum::<f64>() / responses,
+                average_respectful_care: entries.iter().map(|e| f64::from(e.respectful_care_rating)).sum::<f64>()
+                    / responses,
+                comments: entries.into_iter().map(|entry| entry.comment).filter(|c| !c.is_empty()).collect(),
+            }
+        })
+        .collect();
+    paginate_in_order(facilities, cursor, limit)
+}
+
+const MAX_CARE_TEAM_MEMBERS: usize = 20;
+const MAX_MESSAGE_BYTES: usize = 2048;
+
+// Replace the care team who can message a mother, besides her assigned health worker (controllers
+// and admins only)
+#[ic_cdk::update]
+fn set_care_team(mother_id: u64, members: Vec<Principal>) -> Result<CareTeam, Error> {
+    ensure_controller()?;
+    load_mother_profile(mother_id)?;
+    if members.len() > MAX_CARE_TEAM_MEMBERS || members.contains(&Principal::anonymous()) {
+        return Err(Error::InvalidInput {
+            msg: format!("A care team has at most {} members, none anonymous", MAX_CARE_TEAM_MEMBERS),
+        });
+    }
+    let previous = care_team(mother_id);
+    let mut team = CareTeam::default();
+    for principal in members {
+        if team.members.iter().all(|member| member.principal != principal) {
+            // Members who stay keep their original grant time
+            let granted_at = previous
+                .members
+                .iter()
+                .find(|member| member.principal == principal)
+                .map_or_else(time, |member| member.granted_at);
+            team.members.push(AccessGrant { principal, granted_at });
+        }
+    }
+    CARE_TEAMS.with(|teams| teams.borrow_mut().insert(mother_id, team.clone()));
+    Ok(team)
+}
+
+#[ic_cdk::query]
+fn get_care_team(mother_id: u64) -> CareTeam {
+    care_team(mother_id)
+}
+
+fn care_team(mother_id: u64) -> CareTeam {
+    CARE_TEAMS.with(|teams| teams.borrow().get(&mother_id)).unwrap_or_default()
+}
+
+// Whether the caller is the mother herself (true) or one of her care team (false); anyone else is
+// refused
+fn ensure_thread_access(mother_id: u64) -> Result<bool, Error> {
+    if caller_mother_id().ok() == Some(mother_id) {
+        return Ok(true);
+    }
+    let caller = ic_cdk::caller();
+    let assigned = get_assigned_chw(mother_id).is_some_and(|grant| grant.principal == caller);
+    if assigned || care_team(mother_id).members.iter().any(|member| member.principal == caller) {
+        Ok(false)
+    } else {
+        Err(Error::AuthorizationError {
+            msg: "Only the mother and her care team can use her message thread".to_string(),
+        })
+    }
+}
+
+// Post a message to a mother's thread, as the mother or a member of her care team
+#[ic_cdk::update]
+fn send_message(mother_id: u64, body: String) -> Result<Message, Error> {
+    let from_mother = ensure_thread_access(mother_id)?;
+    ensure_mother_exists(mother_id)?;
+    if body.trim().is_empty() || body.len() > MAX_MESSAGE_BYTES {
+        return Err(Error::ValidationError {
+            msg: format!("Message must be between 1 and {} bytes", MAX_MESSAGE_BYTES),
+        });
+    }
+    validate_sensitive_field("body", &body)?;
+
+    let id = MESSAGE_ID_SEQ.with(|counter| {
+        let next = *counter.borrow().get() + 1;
+        counter.borrow_mut().set(next).expect("Cannot update message id sequence");
+        next
+    });
+    let message = Message {
+        id,
+        mother_id,
+        sender: ic_cdk::caller(),
+        from_mother,
+        body,
+        sent_at: time(),
+        read_at: None,
+    };
+    MESSAGES.with(|messages| messages.borrow_mut().insert(RecordKey { mother_id, seq: id }, message.clone()));
+    Ok(message)
+}
+
+// Page through a mother's thread, oldest first (the mother and her care team)
+#[ic_cdk::query]
+fn get_messages(mother_id: u64, cursor: Option<u64>, limit: Option<u32>) -> Result<Page<Message>, Error> {
+    ensure_thread_access(mother_id)?;
+    let messages: Vec<Message> =
+        MESSAGES.with(|messages| messages.borrow().range(mother_record_keys(mother_id)).map(|(_, message)| message).collect());
+    Ok(paginate(messages.into_iter().map(|message| (message.id, message)), cursor, limit))
+}
+
+// Mark every message from the other side up to `up_to_id` as read; returns how many changed
+#[ic_cdk::update]
+fn mark_messages_read(mother_id: u64, up_to_id: u64) -> Result<u64, Error> {
+    let is_mother = ensure_thread_access(mother_id)?;
+    let unread: Vec<Message> = MESSAGES.with(|messages| {
+        messages
+            .borrow()
+            .range(RecordKey { mother_id, seq: 0 }..=RecordKey { mother_id, seq: up_to_id })
+            .map(|(_, message)| message)
+            .filter(|message| message.from_mother != is_mother && message.read_at.is_none())
+            .collect()
+    });
+    let now = time();
+    MESSAGES.with(|messages| {
+        let mut messages = messages.borrow_mut();
+        for mut message in unread.iter().cloned() {
+            message.read_at = Some(now);
+            messages.insert(RecordKey { mother_id, seq: message.id }, message);
+        }
+    });
+    Ok(unread.len() as u64)
+}
+
+const ABNORMAL_LAB_REVIEW_HOURS: u64 = 24;
+const CRITICAL_FOLLOW_UP_HOURS: u64 = 48;
+const NANOS_PER_HOUR: u64 = 60 * 60 * 1_000_000_000;
+
+// Why a lab result needs a clinician's review: haemoglobin below 11 g/dL, or a positive or
+// reactive qualitative result
+fn lab_abnormality(result: &LabResult) -> Option<String> {
+    let value = normalize_term(&result.value);
+    if HAEMOGLOBIN_TESTS.contains(&normalize_term(&result.test_name).as_str()) {
+        return value
+            .parse::<f64>()
+            .ok()
+            .filter(|hb| *hb < 11.0)
+            .map(|hb| format!("Haemoglobin {} is below 11", hb));
+    }
+    (value.starts_with("positive") || value.starts_with("reactive"))
+        .then(|| format!("{} is {}", result.test_name.trim(), result.value.trim()))
+}
+
+fn raise_clinician_task(mother_id: u64, kind: ClinicianTaskKind, lab_result_id: Option<u64>, detail: String, due_at: u64) {
+    let id = CLINICIAN_TASK_ID_SEQ.with(|counter| {
+        let next = *counter.borrow().get() + 1;
+        counter.borrow_mut().set(next).expect("Cannot update clinician task id sequence");
+        next
+    });
+    let task = ClinicianTask {
+        id,
+        mother_id,
+        kind,
+        lab_result_id,
+        detail,
+        created_at: time(),
+        due_at,
+        completed_at: None,
+        completed_by: None,
+        note: None,
+    };
+    CLINICIAN_TASKS.with(|tasks| tasks.borrow_mut().insert(id, task));
+}
+
+// Hourly: a follow-up task for every mother critical for CRITICAL_FOLLOW_UP_HOURS, once per episode
+fn run_clinician_task_scan() {
+    if MAINTENANCE.with(|maintenance| maintenance.borrow().is_some()) {
+        observe_job("clinician_tasks", None);
+        return;
+    }
+    let now = time();
+    let overdue: Vec<(u64, u64)> = CARE_PROGRESS.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .filter_map(|(mother_id, progress)| progress.critical_since.map(|since| (mother_id, since)))
+            .filter(|(_, since)| since + CRITICAL_FOLLOW_UP_HOURS * NANOS_PER_HOUR <= now)
+            .collect()
+    });
+    let mut raised = 0;
+    for (mother_id, since) in overdue {
+        let followed_up = CLINICIAN_TASKS.with(|tasks| {
+            tasks.borrow().iter().any(|(_, task)| {
+                task.mother_id == mother_id && task.kind == ClinicianTaskKind::CriticalFollowUp && task.created_at >= since
+            })
+        });
+        if !followed_up {
+            raise_clinician_task(
+                mother_id,
+                ClinicianTaskKind::CriticalFollowUp,
+                None,
+                format!("Critical for over {} hours with no improvement recorded", CRITICAL_FOLLOW_UP_HOURS),
+                now,
+            );
+            raised += 1;
+        }
+    }
+    observe_job("clinician_tasks", Some(raised));
+}
+
+// Tasks for mothers on the caller's care teams, earliest due first; controllers and admins see
+// every task. Completed tasks are left out unless `include_completed`.
+#[ic_cdk::query]
+fn get_my_tasks(include_completed: Option<bool>, cursor: Option<u64>, limit: Option<u32>) -> Page<ClinicianTask> {
+    let caller = ic_cdk::caller();
+    let sees_all = ensure_controller().is_ok();
+    let include_completed = include_completed.unwrap_or(false);
+    let mut tasks: Vec<ClinicianTask> = CLINICIAN_TASKS.with(|tasks| {
+        tasks
+            .borrow()
+            .iter()
+            .map(|(_, task)| task)
+            .filter(|task| include_completed || task.completed_at.is_none())
+            .filter(|task| sees_all || care_team(task.mother_id).members.iter().any(|member| member.principal == caller))
+            .collect()
+    });
+    tasks.sort_by_key(|task| task.due_at);
+    paginate_in_order(tasks, cursor, limit)
+}
+
+// Mark a task done, with an optional note of what was done (her care team, controllers and admins)
+#[ic_cdk::update]
+fn complete_task(id: u64, note: Option<String>) -> Result<ClinicianTask, Error> {
+    let mut task = CLINICIAN_TASKS.with(|tasks| tasks.borrow().get(&id)).ok_or_else(|| Error::NotFound {
+        msg: format!("Task with id={} not found", id),
+    })?;
+    let caller = ic_cdk::caller();
+    let on_team = care_team(task.mother_id).members.iter().any(|member| member.principal == caller);
+    if !on_team {
+        ensure_controller().map_err(|_| Error::AuthorizationError {
+            msg: "Only the mother's care team can complete her tasks".to_string(),
+        })?;
+    }
+    if task.completed_at.is_some() {
+        return Err(Error::Conflict {
+            msg: format!("Task id={} is already completed", id),
+        });
+    }
+    if let Some(note) = &note {
+        if note.len() > 500 {
+            return Err(Error::InvalidInput {
+                msg: "The note must be at most 500 characters".to_string(),
+            });
+        }
+        validate_sensitive_field("note", note)?;
+    }
+
+    task.completed_at = Some(time());
+    task.completed_by = Some(caller);
+    task.note = note;
+    CLINICIAN_TASKS.with(|tasks| tasks.borrow_mut().insert(id, task.clone()));
+    Ok(task)
+}
+
+fn remove_clinician_tasks(mother_id: u64) {
+    let ids: Vec<u64> = CLINICIAN_TASKS.with(|tasks| {
+        tasks
+            .borrow()
+            .iter()
+            .filter(|(_, task)| task.mother_id == mother_id)
+            .map(|(id, _)| id)
+            .collect()
+    });
+    CLINICIAN_TASKS.with(|tasks| {
+        let mut tasks = tasks.borrow_mut();
+        for id in &ids {
+            tasks.remove(id);
+        }
+    });
+}
+
+// Teleconsultations are booked at most this far ahead
+const TELECONSULT_MAX_DAYS_AHEAD: u64 = 180;
+
+// Her assigned health worker and care team, controllers and admins
+fn ensure_teleconsult_host(mother_id: u64) -> Result<(), Error> {
+    if let Ok(false) = ensure_thread_access(mother_id) {
+        return Ok(());
+    }
+    ensure_controller().map_err(|_| Error::AuthorizationError {
+        msg: "Only the mother's care team can arrange her teleconsultations".to_string(),
+    })
+}
+
+// Book a teleconsultation for a stable mother; the caller is recorded as the clinician. Critical
+// mothers are refused, since they need to be seen at a facility.
+#[ic_cdk::update]
+fn schedule_teleconsult(payload: TeleConsultPayload) -> Result<TeleConsult, Error> {
+    ensure_teleconsult_host(payload.mother_id)?;
+    let profile = load_mother_profile(payload.mother_id)?;
+    if matches!(profile.health_status, HealthStatus::Critical) {
+        return Err(Error::ValidationError {
+            msg: "A critical mother needs a facility visit, not a teleconsultation".to_string(),
+        });
+    }
+    let now = time();
+    if payload.scheduled_at < now || payload.scheduled_at > now + TELECONSULT_MAX_DAYS_AHEAD * NANOS_PER_DAY {
+        return Err(Error::InvalidInput {
+            msg: format!("A teleconsultation is scheduled from now up to {} days ahead", TELECONSULT_MAX_DAYS_AHEAD),
+        });
+    }
+    if let Some(link) = &payload.join_link {
+        let valid = !link.provider.trim().is_empty()
+            && link.provider.len() <= 50
+            && link.url.starts_with("https://")
+            && link.url.len() <= 500
+            && link.meeting_id.iter().all(|meeting_id| meeting_id.len() <= 100);
+        if !valid {
+            return Err(Error::InvalidInput {
+                msg: "A join link needs a provider of up to 50 characters, an https URL of up to 500 and a meeting id of up to 100".to_string(),
+            });
+        }
+    }
+
+    let id = TELECONSULT_ID_SEQ.with(|counter| {
+        let next = *counter.borrow().get() + 1;
+        counter.borrow_mut().set(next).expect("Cannot update teleconsult id sequence");
+        next
+    });
+    let consult = TeleConsult {
+        id,
+        mother_id: payload.mother_id,
+        scheduled_at: payload.scheduled_at,
+        channel: payload.channel,
+        join_link: payload.join_link.map(|link| JoinLink {
+            provider: link.provider.trim().to_string(),
+            url: link.url,
+            meeting_id: link.meeting_id,
+        }),
+        clinician: ic_cdk::caller(),
+        status: TeleConsultStatus::Scheduled,
+        created_at: now,
+        closed_at: None,
+        duration_minutes: None,
+        outcome_notes: None,
+    };
+    ensure_fits("Teleconsult", id, &consult)?;
+    TELECONSULTS.with(|consults| consults.borrow_mut().insert(id, consult.clone()));
+    Ok(consult)
+}
+
+// Record how a scheduled teleconsultation went: completed with its length and outcome notes, or
+// missed when the mother did not join (her care team, controllers and admins)
+#[ic_cdk::update]
+fn complete_teleconsult(id: u64, outcome: TeleConsultOutcome) -> Result<TeleConsult, Error> {
+    let mut consult = open_teleconsult(id)?;
+    if consult.scheduled_at > time() {
+        return Err(Error::InvalidInput {
+            msg: format!("Teleconsult id={} has not started yet", id),
+        });
+    }
+    if outcome.notes.len() > 2000 {
+        return Err(Error::InvalidInput {
+            msg: "Outcome notes must be at most 2000 characters".to_string(),
+        });
+    }
+    validate_sensitive_field("notes", &outcome.notes)?;
+    if outcome.attended && !outcome.duration_minutes.is_some_and(|minutes| (1..=240).contains(&minutes)) {
+        return Err(Error::InvalidInput {
+            msg: "An attended teleconsultation needs a duration of 1 to 240 minutes".to_string(),
+        });
+    }
+
+    consult.status = if outcome.attended { TeleConsultStatus::Completed } else { TeleConsultStatus::Missed };
+    consult.closed_at = Some(time());
+    consult.duration_minutes = outcome.duration_minutes.filter(|_| outcome.attended);
+    consult.outcome_notes = Some(outcome.notes).filter(|notes| !notes.trim().is_empty());
+    TELECONSULTS.with(|consults| consults.borrow_mut().insert(id, consult.clone()));
+    Ok(consult)
+}
+
+// Call off a scheduled teleconsultation (her care team, controllers and admins)
+#[ic_cdk::update]
+fn cancel_teleconsult(id: u64) -> Result<TeleConsult, Error> {
+    let mut consult = open_teleconsult(id)?;
+    consult.status = TeleConsultStatus::Cancelled;
+    consult.closed_at = Some(time());
+    TELECONSULTS.with(|consults| consults.borrow_mut().insert(id, consult.clone()));
+    Ok(consult)
+}
+
+// A scheduled teleconsultation the caller may close
+fn open_teleconsult(id: u64) -> Result<TeleConsult, Error> {
+    let consult = TELECONSULTS.with(|consults| consults.borrow().get(&id)).ok_or_else(|| Error::NotFound {
+        msg: format!("Teleconsult with id={} not found", id),
+    })?;
+    ensure_teleconsult_host(consult.mother_id)?;
+    if consult.status != TeleConsultStatus::Scheduled {
+        return Err(Error::Conflict {
+            msg: format!("Teleconsult id={} is already closed", id),
+        });
+    }
+    Ok(consult)
+}
+
+// A mother's teleconsultations, earliest first (the mother, her care team, controllers and admins)
+#[ic_cdk::query]
+fn get_mother_teleconsults(mother_id: u64) -> Result<Vec<TeleConsult>, Error> {
+    if ensure_thread_access(mother_id).is_err() {
+        ensure_controller().map_err(|_| Error::AuthorizationError {
+            msg: "Only the mother and her care team can see her teleconsultations".to_string(),
+        })?;
+    }
+    let mut consults: Vec<TeleConsult> = TELECONSULTS.with(|consults| {
+        consults
+            .borrow()
+            .iter()
+            .map(|(_, consult)| consult)
+            .filter(|consult| consult.mother_id == mother_id)
+            .collect()
+    });
+    consults.sort_by_key(|consult| consult.scheduled_at);
+    Ok(consults)
+}
+
+// The caller's scheduled teleconsultations as clinician, earliest first
+#[ic_cdk::query]
+fn get_my_teleconsults(cursor: Option<u64>, limit: Option<u32>) -> Page<TeleConsult> {
+    let caller = ic_cdk::caller();
+    let mut consults: Vec<TeleConsult> = TELECONSULTS.with(|consults| {
+        consults
+            .borrow()
+            .iter()
+            .map(|(_, consult)| consult)
+            .filter(|consult| consult.clinician == caller && consult.status == TeleConsultStatus::Scheduled)
+            .collect()
+    });
+    consults.sort_by_key(|consult| consult.scheduled_at);
+    paginate_in_order(consults, cursor, limit)
+}
+
+fn remove_teleconsults(mother_id: u64) {
+    let ids: Vec<u64> = TELECONSULTS.with(|consults| {
+        consults
+            .borrow()
+            .iter()
+            .filter(|(_, consult)| consult.mother_id == mother_id)
+            .map(|(id, _)| id)
+            .collect()
+    });
+    TELECONSULTS.with(|consults| {
+        let mut consults = consults.borrow_mut();
+        for id in &ids {
+            consults.remove(id);
+        }
+    });
+}
+
+// Record a mother's arrival at a facility's front desk; she waits in triage order until a visit
+// is recorded for her
+#[ic_cdk::update]
+fn check_in(mother_id: u64, facility_code: String) -> Result<CheckIn, Error> {
+    validate_facility_code(&facility_code)?;
+    ensure_mother_exists(mother_id)?;
+    let now = time();
+    let waiting = CHECK_INS.with(|check_ins| {
+        check_ins
+            .borrow()
+            .iter()
+            .any(|(_, check_in)| check_in.mother_id == mother_id && is_waiting(&check_in, now / NANOS_PER_DAY))
+    });
+    if waiting {
+        return Err(Error::Conflict {
+            msg: format!("Mother id={} is already checked in today", mother_id),
+        });
+    }
+
+    let id = CHECK_IN_ID_SEQ.with(|counter| {
+        let next = *counter.borrow().get() + 1;
+        counter.borrow_mut().set(next).expect("Cannot update check-in id sequence");
+        next
+    });
+    let check_in = CheckIn {
+        id,
+        mother_id,
+        facility_code,
+        checked_in_at: now,
+    };
+    CHECK_INS.with(|check_ins| check_ins.borrow_mut().insert(id, check_in.clone()));
+    Ok(check_in)
+}
+
+// Mothers checked in at a facility on the day of `date` and not yet seen, most urgent first. The
+// order is the same for every front desk: health status, then risk, then gestational age, with
+// waiting time rising until it can lift a mother past others of the same status.
+#[ic_cdk::query]
+fn get_triage_order(facility_code: String, date: u64) -> Result<Vec<TriageEntry>, Error> {
+    validate_facility_code(&facility_code)?;
+    let now = time();
+    let day = date / NANOS_PER_DAY;
+    let check_ins: Vec<CheckIn> = CHECK_INS.with(|check_ins| {
+        check_ins
+            .borrow()
+            .iter()
+            .map(|(_, check_in)| check_in)
+            .filter(|check_in| check_in.facility_code == facility_code && is_waiting(check_in, day))
+            .collect()
+    });
+
+    let mut entries: Vec<TriageEntry> = check_ins
+        .into_iter()
+        .filter_map(|check_in| {
+            let profile = PROFILE_STORAGE.with(|storage| storage.borrow().get(&check_in.mother_id))?;
+            let gestational_age = profile
+                .delivery
+                .is_none()
+                .then(|| gestational_age_weeks(profile.expected_delivery_date, now));
+            let risk_tier = if prior_cesarean_near_term(&profile, now) { "HIGH" } else { risk_tier(profile.id) };
+            let waiting_minutes = now.saturating_sub(check_in.checked_in_at) / (60 * 1_000_000_000);
+
+            let status_points = match profile.health_status {
+                HealthStatus::Critical => 600,
+                HealthStatus::NeedsAttention => 300,
+                HealthStatus::Normal => 0,
+            };
+            let risk_points = match risk_tier {
+                "HIGH" => 200,
+                "MEDIUM" => 100,
+                _ => 0,
+            };
+            let gestation_points = match gestational_age {
+                Some(weeks) if weeks >= 37 => 100,
+                Some(weeks) if weeks >= 28 => 50,
+                _ => 0,
+            };
+            // A point per 5 minutes, capped below the gap between two health statuses
+            let waiting_points = (waiting_minutes / 5).min(MAX_WAITING_POINTS) as u32;
+
+            Some(TriageEntry {
+                check_in_id: check_in.id,
+                mother_id: profile.id,
+                name: profile.name,
+                health_status: profile.health_status,
+                risk_tier: risk_tier.to_string(),
+                gestational_age_weeks: gestational_age,
+                checked_in_at: check_in.checked_in_at,
+                waiting_minutes,
+                priority: status_points + risk_points + gestation_points + waiting_points,
+            })
+        })
+        .collect();
+    entries.sort_by(|a, b| b.priority.cmp(&a.priority).then(a.checked_in_at.cmp(&b.checked_in_at)));
+    Ok(entries)
+}
+
+const MAX_WAITING_POINTS: u64 = 150;
+
+// Checked in on `day` (days since the epoch) with no visit recorded since
+fn is_waiting(check_in: &CheckIn, day: u64) -> bool {
+    if check_in.checked_in_at / NANOS_PER_DAY != day {
+        return false;
+    }
+    match latest_health_record(check_in.mother_id) {
+        Some(record) => record.date < check_in.checked_in_at,
+        None => true,
+    }
+}
+
+fn remove_check_ins(mother_id: u64) {
+    let ids: Vec<u64> = CHECK_INS.with(|check_ins| {
+        check_ins
+            .borrow()
+            .iter()
+            .filter(|(_, check_in)| check_in.mother_id == mother_id)
+            .map(|(id, _)| id)
+            .collect()
+    });
+    CHECK_INS.with(|check_ins| {
+        let mut check_ins = check_ins.borrow_mut();
+        for id in &ids {
+            check_ins.remove(id);
+        }
+    });
+}
+
+const MAX_HANDOFF_NOTES_BYTES: usize = 4 * 1024;
+const MAX_FLAGGED_MOTHERS: usize = 50;
+
+// Hand over a shift at a facility: notes for the incoming team and the mothers who need their
+// attention. Replaces the facility's latest handoff; earlier ones are kept.
+#[ic_cdk::update]
+fn create_handoff(facility_code: String, notes: String, flagged_mother_ids: Vec<u64>) -> Result<Handoff, Error> {
+    let caller = ic_cdk::caller();
+    if caller == Principal::anonymous() {
+        return Err(Error::AuthorizationError {
+            msg: "Handoffs must be written by an authenticated clinician".to_string(),
+        });
+    }
+    validate_facility_code(&facility_code)?;
+    if notes.len() > MAX_HANDOFF_NOTES_BYTES {
+        return Err(Error::ValidationError {
+            msg: format!("Handoff notes may be at most {} bytes", MAX_HANDOFF_NOTES_BYTES),
+        });
+    }
+    validate_sensitive_field("notes", &notes)?;
+    let mut flagged_mother_ids = flagged_mother_ids;
+    let mut seen = std::collections::BTreeSet::new();
+    flagged_mother_ids.retain(|id| seen.insert(*id));
+    if flagged_mother_ids.len() > MAX_FLAGGED_MOTHERS {
+        return Err(Error::InvalidInput {
+            msg: format!("At most {} mothers can be flagged in one handoff", MAX_FLAGGED_MOTHERS),
+        });
+    }
+    if notes.trim().is_empty() && flagged_mother_ids.is_empty() {
+        return Err(Error::InvalidInput {
+            msg: "A handoff needs notes or flagged mothers".to_string(),
+        });
+    }
+    flagged_mother_ids.iter().try_for_each(|id| ensure_mother_exists(*id))?;
+
+    let id = HANDOFF_ID_SEQ.with(|counter| {
+        let next = *counter.borrow().get() + 1;
+        counter.borrow_mut().set(next).expect("Cannot update handoff id sequence");
+        next
+    });
+    let handoff = Handoff {
+        id,
+        facility_code: facility_code.clone(),
+        notes,
+        flagged_mother_ids,
+        created_by: caller,
+        created_at: time(),
+    };
+    HANDOFFS.with(|handoffs| handoffs.borrow_mut().insert(id, handoff.clone()));
+    LATEST_HANDOFFS.with(|latest| latest.borrow_mut().insert(StringKey(facility_code), id));
+    Ok(handoff)
+}
+
+// The facility's latest handoff with its flagged mothers' current status and latest visit
+#[ic_cdk::query]
+fn get_latest_handoff(facility_code: String) -> Result<Option<HandoffView>, Error> {
+    validate_facility_code(&facility_code)?;
+    let Some(id) = LATEST_HANDOFFS.with(|latest| latest.borrow().get(&StringKey(facility_code))) else {
+        return Ok(None);
+    };
+    let handoff = HANDOFFS.with(|handoffs| handoffs.borrow().get(&id)).expect("Latest handoff missing");
+    let flagged = handoff
+        .flagged_mother_ids
+        .iter()
+        .filter_map(|id| PROFILE_STORAGE.with(|storage| storage.borrow().get(id)))
+        .map(|profile| {
+            let latest = latest_health_record(profile.id);
+            FlaggedMother {
+                mother_id: profile.id,
+                name: profile.name,
+                health_status: profile.health_status,
+                latest_record_id: latest.as_ref().map(|record| record.id),
+                latest_visit: latest.as_ref().map(|record| record.date),
+                next_appointment: latest.as_ref().map(|record| record.next_appointment),
+            }
+        })
+        .collect();
+    Ok(Some(HandoffView { handoff, flagged }))
+}
+
+// Drop an erased mother from the handoffs that flagged her
+fn remove_handoff_flags(mother_id: u64) {
+    let flagging: Vec<Handoff> = HANDOFFS.with(|handoffs| {
+        handoffs
+            .borrow()
+            .iter()
+            .map(|(_, handoff)| handoff)
+            .filter(|handoff| handoff.flagged_mother_ids.contains(&mother_id))
+            .collect()
+    });
+    HANDOFFS.with(|handoffs| {
+        let mut handoffs = handoffs.borrow_mut();
+        for mut handoff in flagging {
+            handoff.flagged_mother_ids.retain(|id| *id != mother_id);
+            handoffs.insert(handoff.id, handoff);
+        }
+    });
+}
+
+fn remove_messages(mother_id: u64) {
+    let keys: Vec<RecordKey> =
+        MESSAGES.with(|messages| messages.borrow().range(mother_record_keys(mother_id)).map(|(key, _)| key).collect());
+    MESSAGES.with(|messages| {
+        let mut messages = messages.borrow_mut();
+        for key in &keys {
+            messages.remove(key);
+        }
+    });
+    CARE_TEAMS.with(|teams| teams.borrow_mut().remove(&mother_id));
+}
+
+fn normalize_term(term: &str) -> String {
+    term.trim().to_lowercase()
+}
+
+// Code for a free-text symptom: an exact registry match, else the longest registered term it mentions
+fn find_symptom_code(symptom: &str) -> Option<ClinicalCode> {
+    let symptom = normalize_term(symptom);
+    CODE_REGISTRY.with(|registry| {
+        let registry = registry.borrow();
+        registry.get(&StringKey(symptom.clone())).or_else(|| {
+            registry
+                .iter()
+                .filter(|(term, _)| symptom.contains(term.0.as_str()))
+                .max_by_key(|(term, _)| term.0.len())
+                .map(|(_, code)| code)
+        })
+    })
+}
+
+// Apply mutations queued by an offline client. Each mutation succeeds or fails on its own.
+// Merge policy (field-level last-writer-wins): a profile field edit is applied only if it was
+// made after the field's last write on the canister; otherwise it is reported as a conflict and
+// the newer value is kept. Client timestamps later than the canister's clock count as "now".
+// Health records captured offline are dated at their capture time.
+#[ic_cdk::update]
+fn apply_offline_mutations(mutations: Vec<OfflineMutation>) -> Result<Vec<MutationOutcome>, Error> {
+    ensure_batch_size(mutations.len())?;
+
+    let now = time();
+    let outcomes = mutations
+        .into_iter()
+        .enumerate()
+        .map(|(index, mutation)| {
+            let result = match mutation {
+                OfflineMutation::UpdateProfile { mother_id, edited_at, fields } => {
+                    merge_profile_fields(mother_id, edited_at.min(now), fields)
+                        .map(|(applied, conflicts, pending)| (mother_id, applied, conflicts, pending))
+                }
+                OfflineMutation::AddHealthRecord { edited_at, payload } => add_health_record_at(*payload, edited_at.min(now))
+                    .map(|record| (record.id, 1, Vec::new(), Vec::new())),
+            };
+
+            match result {
+                Ok((entity_id, applied, conflicts, pending)) => MutationOutcome {
+                    index: index as u64,
+                    status: mutation_status(applied, conflicts.len(), pending.len()),
+                    entity_id: Some(entity_id),
+                    conflicts,
+                    pending,
+                    error: None,
+                },
+                Err(err) => MutationOutcome {
+                    index: index as u64,
+                    status: MutationStatus::Rejected,
+                    entity_id: None,
+                    conflicts: Vec::new(),
+                    pending: Vec::new(),
+                    error: Some(error_message(err)),
+                },
+            }
+        })
+        .collect();
+
+    Ok(outcomes)
+}
+
+const MAX_BATCH_SIZE: usize = 100;
+
+fn mutation_status(applied: usize, conflicts: usize, pending: usize) -> MutationStatus {
+    match (applied, conflicts) {
+        (0, 0) if pending > 0 => MutationStatus::PendingApproval,
+        (_, 0) => MutationStatus::Applied,
+        (0, _) => MutationStatus::Superseded,
+        _ => MutationStatus::PartiallyApplied,
+    }
+}
+
+// Merge offline field edits into a profile; returns how many fields were applied, the conflicts
+// and the identity edits held for a supervisor
+fn merge_profile_fields(
+    mother_id: u64,
+    edited_at: u64,
+    fields: Vec<ProfileField>,
+) -> Result<(usize, Vec<FieldConflict>, Vec<ChangeRequest>), Error> {
+    let mut profile = load_mother_profile(mother_id)?;
+    let mut clocks = PROFILE_FIELD_CLOCKS
+        .with(|storage| storage.borrow().get(&mother_id))
+        .unwrap_or_default();
+
+    // Reject the whole mutation if any edit is invalid
+    fields.iter().try_for_each(validate_profile_field)?;
+
+    let (fields, pending) = hold_identity_edits(mother_id, fields, Some("Offline edit".to_string()))?;
+
+    let (applied, conflicts) = merge_fields_lww(&mut profile, &mut clocks, fields, edited_at);
+    if applied == 0 {
+        return Ok((0, conflicts, pending));
+    }
+
+    profile.version = next_version(profile.version);
+    profile.updated_at = Some(time());
+    ensure_fits("Mother", mother_id, &profile)?;
+    let previous = PROFILE_STORAGE.with(|storage| storage.borrow_mut().insert(mother_id, profile.clone()));
+    PROFILE_FIELD_CLOCKS.with(|storage| storage.borrow_mut().insert(mother_id, clocks));
+    log_change_with_reason(EntityType::MotherProfile, mother_id, previous.as_ref(), Some(&profile), Some("Offline edit merged"));
+
+    Ok((applied, conflicts, pending))
+}
+
+// Apply each edit made after its field's last write (the profile's creation if never written)
+// and advance that field's clock; the rest are returned as conflicts, with the newer value kept
+fn merge_fields_lww(
+    profile: &mut MotherProfile,
+    clocks: &mut FieldClocks,
+    fields: Vec<ProfileField>,
+    edited_at: u64,
+) -> (usize, Vec<FieldConflict>) {
+    let mut applied = 0;
+    let mut conflicts = Vec::new();
+    for field in fields {
+        let name = profile_field_name(&field);
+        let server_updated_at = clocks
+            .clocks
+            .iter()
+            .find(|(field, _)| field == name)
+            .map_or(profile.created_at, |(_, at)| *at);
+        if edited_at <= server_updated_at {
+            conflicts.push(FieldConflict {
+                field: name.to_string(),
+                client_edited_at: edited_at,
+                server_updated_at,
+            });
+            continue;
+        }
+
+        apply_profile_field(profile, field);
+        clocks.clocks.retain(|(field, _)| field != name);
+        clocks.clocks.push((name.to_string(), edited_at));
+        applied += 1;
+    }
+    (applied, conflicts)
+}
+
+// Update only the supplied profile fields, validating just those
+#[ic_cdk::update]
+fn patch_mother_profile(mother_id: u64, patch: MotherProfilePatch) -> Result<ProfileUpdate, Error> {
+    observe_call("patch_mother_profile", apply_profile_patch(mother_id, patch))
+}
+
+fn apply_profile_patch(mother_id: u64, patch: MotherProfilePatch) -> Result<ProfileUpdate, Error> {
+    let expected_version = patch.expected_version;
+    if patch.reason.as_ref().is_some_and(|reason| reason.len() > 500) {
+        return Err(Error::InvalidInput {
+            msg: "The reason must be at most 500 characters".to_string(),
+        });
+    }
+    let fields: Vec<ProfileField> = [
+        patch.name.map(ProfileField::Name),
+        patch.age.map(ProfileField::Age),
+        patch.blood_type.map(ProfileField::BloodType),
+        patch.expected_delivery_date.map(ProfileField::ExpectedDeliveryDate),
+        patch.medical_history.map(ProfileField::MedicalHistory),
+        patch.emergency_contact.map(ProfileField::EmergencyContact),
+        patch.insurance.map(ProfileField::Insurance),
+        patch.previous_cesareans.map(ProfileField::PreviousCesareans),
+        patch.national_id.map(ProfileField::NationalId),
+    ]
+    .into_iter()
+    .flatten()
+    .collect();
+
+    if fields.is_empty() {
+        return Err(Error::InvalidInput {
+            msg: "Patch does not change any field".to_string(),
+        });
+    }
+    fields.iter().try_for_each(validate_profile_field)?;
+
+    let mut profile = load_mother_profile(mother_id)?;
+    check_version("Mother", mother_id, profile.version, expected_version)?;
+
+    // Identity edits by other roles wait for a supervisor; the rest of the patch applies now
+    let (fields, pending) = hold_identity_edits(mother_id, fields, patch.reason.clone())?;
+    if fields.is_empty() {
+        return Ok(ProfileUpdate { profile, pending });
+    }
+
+    let now = time();
+    let names: Vec<&str> = fields.iter().map(profile_field_name).collect();
+    for field in fields {
+        apply_profile_field(&mut profile, field);
+    }
+
+    profile.version = next_version(profile.version);
+    profile.updated_at = Some(now);
+    ensure_fits("Mother", mother_id, &profile)?;
+    for name in names {
+        set_field_clock(mother_id, name, now);
+    }
+    let previous = PROFILE_STORAGE.with(|storage| storage.borrow_mut().insert(mother_id, profile.clone()));
+    log_change_with_reason(
+        EntityType::MotherProfile,
+        mother_id,
+        previous.as_ref(),
+        Some(&profile),
+        patch.reason.as_deref().or(Some("Profile edited")),
+    );
+
+    Ok(ProfileUpdate { profile, pending })
+}
+
+fn validate_profile_field(field: &ProfileField) -> Result<(), Error> {
+    match field {
+        ProfileField::Name(name) if name.trim().is_empty() => Err(Error::InvalidInput {
+            msg: "Name is required".to_string(),
+        }),
+        ProfileField::Age(age) => validate_age(*age),
+        ProfileField::BloodType(blood_type) => validate_blood_type(blood_type),
+        ProfileField::ExpectedDeliveryDate(edd) => validate_expected_delivery_date(*edd),
+        ProfileField::EmergencyContact(contact) => validate_emergency_contact(contact),
+        ProfileField::Insurance(Some(cover)) => validate_insurance(cover),
+        ProfileField::MedicalHistory(history) => validate_medical_history(history),
+        ProfileField::PreviousCesareans(count) => validate_previous_cesareans(*count),
+        ProfileField::NationalId(national_id) => validate_national_id(national_id),
+        _ => Ok(()),
+    }
+}
+
+fn apply_profile_field(profile: &mut MotherProfile, field: ProfileField) {
+    match field {
+        ProfileField::Name(name) => profile.name = name,
+        ProfileField::Age(age) => profile.age = age,
+        ProfileField::BloodType(blood_type) => profile.blood_type = blood_type,
+        ProfileField::ExpectedDeliveryDate(edd) => {
+            profile.expected_delivery_date = edd;
+            if profile.delivery.is_none() {
+                profile.stage = calculate_pregnancy_stage(edd);
+            }
+        }
+        ProfileField::MedicalHistory(history) => profile.medical_history = history,
+        ProfileField::EmergencyContact(contact) => profile.emergency_contact = contact,
+        ProfileField::Insurance(insurance) => profile.insurance = insurance,
+        ProfileField::PreviousCesareans(count) => profile.previous_cesareans = Some(count),
+        ProfileField::NationalId(national_id) => profile.national_id = Some(national_id.trim().to_uppercase()),
+    }
+}
+
+fn profile_field_name(field: &ProfileField) -> &'static str {
+    match field {
+        ProfileField::Name(_) => "name",
+        ProfileField::Age(_) => "age",
+        ProfileField::BloodType(_) => "blood_type",
+        ProfileField::ExpectedDeliveryDate(_) => "expected_delivery_date",
+        ProfileField::MedicalHistory(_) => "medical_history",
+        ProfileField::EmergencyContact(_) => "emergency_contact",
+        ProfileField::Insurance(_) => "insurance",
+        ProfileField::PreviousCesareans(_) => "previous_cesareans",
+        ProfileField::NationalId(_) => "national_id",
+    }
+}
+
+// Fields that identify the mother. Only controllers and admins change them directly; edits by
+// anyone else become a ChangeRequest for a supervisor.
+fn is_identity_field(field: &ProfileField) -> bool {
+    matches!(field, ProfileField::Name(_) | ProfileField::BloodType(_) | ProfileField::NationalId(_))
+}
+
+// File the identity edits of a caller who is not a controller or admin as a change request;
+// returns the edits that apply right away and the request they are held in
+fn hold_identity_edits(
+    mother_id: u64,
+    fields: Vec<ProfileField>,
+    reason: Option<String>,
+) -> Result<(Vec<ProfileField>, Vec<ChangeRequest>), Error> {
+    if ensure_controller().is_ok() {
+        return Ok((fields, Vec::new()));
+    }
+    let (identity, fields): (Vec<ProfileField>, Vec<ProfileField>) = fields.into_iter().partition(is_identity_field);
+    if identity.is_empty() {
+        return Ok((fields, Vec::new()));
+    }
+    let request = match pending_duplicate(mother_id, &identity) {
+        Some(request) => request,
+        None => file_change_request(mother_id, identity, reason)?,
+    };
+    Ok((fields, vec![request]))
+}
+
+// The caller's pending request for the same edits, e.g. when an offline client replays a batch
+// whose outcome it never received
+fn pending_duplicate(mother_id: u64, fields: &[ProfileField]) -> Option<ChangeRequest> {
+    let caller = ic_cdk::caller();
+    let fields = Encode!(&fields).unwrap();
+    CHANGE_REQUESTS.with(|requests| {
+        requests.borrow().iter().map(|(_, request)| request).find(|request| {
+            request.mother_id == mother_id
+                && request.status == ChangeRequestStatus::Pending
+                && request.requested_by == caller
+                && Encode!(&request.fields).unwrap() == fields
+        })
+    })
+}
+
+fn file_change_request(mother_id: u64, fields: Vec<ProfileField>, reason: Option<String>) -> Result<ChangeRequest, Error> {
+    let id = CHANGE_REQUEST_ID_SEQ.with(|counter| {
+        let next = *counter.borrow().get() + 1;
+        counter.borrow_mut().set(next).expect("Cannot update change request id sequence");
+        next
+    });
+    let request = ChangeRequest {
+        id,
+        mother_id,
+        fields,
+        reason,
+        requested_by: ic_cdk::caller(),
+        requested_at: time(),
+        status: ChangeRequestStatus::Pending,
+        decided_by: None,
+        decided_at: None,
+        decision_note: None,
+    };
+    ensure_fits("Change request", id, &request)?;
+    CHANGE_REQUESTS.with(|requests| requests.borrow_mut().insert(id, request.clone()));
+    Ok(request)
+}
+
+// Grant or revoke the supervisor role (controllers and admins only)
+#[ic_cdk::update]
+fn set_supervisor(principal: Principal, allowed: bool) -> Result<(), Error> {
+    ensure_controller()?;
+    set_grant(&SUPERVISORS, principal, allowed);
+    Ok(())
+}
+
+#[ic_cdk::query]
+fn get_supervisors() -> Result<Vec<AccessGrant>, Error> {
+    ensure_controller()?;
+    list_grants(&SUPERVISORS)
+}
+
+// Supervisors, controllers and admins
+fn ensure_supervisor() -> Result<(), Error> {
+    let caller = StringKey(ic_cdk::caller().to_text());
+    if SUPERVISORS.with(|supervisors| supervisors.borrow().contains_key(&caller)) {
+        return Ok(());
+    }
+    ensure_controller().map_err(|_| Error::AuthorizationError {
+        msg: "Deciding change requests needs the supervisor role".to_string(),
+    })
+}
+
+// Change requests, optionally for one mother and/or with one status, oldest first; paged by
+// request id (supervisors)
+#[ic_cdk::query]
+fn get_change_requests(
+    mother_id: Option<u64>,
+    status: Option<ChangeRequestStatus>,
+    cursor: Option<u64>,
+    limit: Option<u32>,
+) -> Result<Page<ChangeRequest>, Error> {
+    ensure_supervisor()?;
+    Ok(CHANGE_REQUESTS.with(|requests| {
+        let requests = requests.borrow();
+        let matching = requests
+            .iter()
+            .filter(|(_, request)| mother_id.is_none() || mother_id == Some(request.mother_id))
+            .filter(|(_, request)| status.is_none() || status == Some(request.status));
+        paginate(matching, cursor, limit)
+    }))
+}
+
+// Apply a pending change request to the profile. Supervisors cannot approve their own requests.
+#[ic_cdk::update]
+fn approve_change_request(id: u64, note: Option<String>) -> Result<MotherProfile, Error> {
+    let mut request = pending_change_request(id, note.as_deref())?;
+    request.fields.iter().try_for_each(validate_profile_field)?;
+    let mut profile = load_mother_profile(request.mother_id)?;
+    let previous = profile.clone();
+
+    let now = time();
+    let names: Vec<&str> = request.fields.iter().map(profile_field_name).collect();
+    for field in request.fields.iter().cloned() {
+        apply_profile_field(&mut profile, field);
+    }
+    profile.version = next_version(profile.version);
+    profile.updated_at = Some(now);
+    ensure_fits("Mother", profile.id, &profile)?;
+    let reason = match &request.reason {
+        Some(reason) => format!("Change request {} approved: {}", id, reason),
+        None => format!("Change request {} approved", id),
+    };
+
+    let mut batch = WriteBatch::default();
+    let names: Vec<String> = names.into_iter().map(str::to_string).collect();
+    let stored = profile.clone();
+    batch.stage(move || {
+        for name in &names {
+            set_field_clock(stored.id, name, now);
+        }
+        PROFILE_STORAGE.with(|storage| storage.borrow_mut().insert(stored.id, stored.clone()));
+        log_change_with_reason(EntityType::MotherProfile, stored.id, Some(&previous), Some(&stored), Some(&reason));
+    });
+    batch.stage(move || decide_change_request(&mut request, ChangeRequestStatus::Approved, note));
+    batch.commit();
+    Ok(profile)
+}
+
+// Turn down a pending change request; the note tells the requester why
+#[ic_cdk::update]
+fn reject_change_request(id: u64, note: String) -> Result<ChangeRequest, Error> {
+    if note.trim().is_empty() {
+        return Err(Error::InvalidInput {
+            msg: "A note explaining the rejection is required".to_string(),
+        });
+    }
+    let mut request = pending_change_request(id, Some(&note))?;
+    decide_change_request(&mut request, ChangeRequestStatus::Rejected, Some(note));
+    Ok(request)
+}
+
+fn pending_change_request(id: u64, note: Option<&str>) -> Result<ChangeRequest, Error> {
+    ensure_supervisor()?;
+    if note.is_some_and(|note| note.len() > 500) {
+        return Err(Error::InvalidInput {
+            msg: "The note must be at most 500 characters".to_string(),
+        });
+    }
+    let request = CHANGE_REQUESTS.with(|requests| requests.borrow().get(&id)).ok_or_else(|| Error::NotFound {
+        msg: format!("Change request with id={} not found", id),
+    })?;
+    if request.status != ChangeRequestStatus::Pending {
+        return Err(Error::Conflict {
+            msg: format!("Change request id={} has already been decided", id),
+        });
+    }
+    if request.requested_by == ic_cdk::caller() {
+        return Err(Error::AuthorizationError {
+            msg: "Change requests must be decided by someone other than the requester".to_string(),
+        });
+    }
+    Ok(request)
+}
+
+fn decide_change_request(request: &mut ChangeRequest, status: ChangeRequestStatus, note: Option<String>) {
+    request.status = status;
+    request.decided_by = Some(ic_cdk::caller());
+    request.decided_at = Some(time());
+    request.decision_note = note;
+    CHANGE_REQUESTS.with(|requests| requests.borrow_mut().insert(request.id, request.clone()));
+}
+
+fn remove_change_requests(mother_id: u64) {
+    let ids: Vec<u64> = CHANGE_REQUESTS.with(|requests| {
+        requests
+            .borrow()
+            .iter()
+            .filter(|(_, request)| request.mother_id == mother_id)
+            .map(|(id, _)| id)
+            .collect()
+    });
+    CHANGE_REQUESTS.with(|requests| {
+        let mut requests = requests.borrow_mut();
+        for id in &ids {
+            requests.remove(id);
+        }
+    });
+}
+
+// Record an online write to a profile field so later offline edits merge against it
+fn set_field_clock(mother_id: u64, field: &str, at: u64) {
+    PROFILE_FIELD_CLOCKS.with(|storage| {
+        let mut storage = storage.borrow_mut();
+        let mut clocks = storage.get(&mother_id).unwrap_or_default();
+        clocks.clocks.retain(|(name, _)| name != field);
+        clocks.clocks.push((field.to_string(), at));
+        storage.insert(mother_id, clocks);
+    });
+}
+
+// Delta sync for offline clients: entities created, updated or deleted after `since`
+// (nanoseconds), oldest first. Continue with the returned cursor until it is absent. Open to field
+// readers, sensitive readers and controllers; facility staff get the entities of mothers at
+// their facility, and every deletion.
+#[ic_cdk::query]
+fn get_changes_since(since: u64, cursor: Option<u64>) -> Result<ChangePage, Error> {
+    let scope = match ensure_field_reader() {
+        Ok(()) => None,
+        Err(_) => list_scope()?,
+    };
+    let start = cursor.map_or(0, |seq| seq.saturating_add(1)).max(first_change_after(since));
+    let mut changes = Vec::new();
+    let mut next_cursor = None;
+
+    CHANGE_FEED.with(|feed| {
+        for (seq, entry) in feed.borrow().range(start..) {
+            if entry.changed_at <= since {
+                continue;
+            }
+            let entity = match entry.kind {
+                ChangeKind::Upserted => load_sync_entity(entry.entity_type, entry.id),
+                ChangeKind::Deleted => None,
+            };
+            let in_scope = entity.as_ref().is_none_or(|entity| {
+                scope.is_none()
+                    || load_mother_profile(sync_entity_mother_id(entity))
+                        .is_ok_and(|profile| in_list_scope(&scope, &profile))
+            });
+            if !in_scope {
+                continue;
+            }
+            if changes.len() == SYNC_PAGE_SIZE {
+                next_cursor = changes.last().map(|change: &SyncChange| change.seq);
+                break;
+            }
+            changes.push(SyncChange {
+                seq,
+                entity_type: entry.entity_type,
+                id: entry.id,
+                kind: entry.kind,
+                changed_at: entry.changed_at,
+                entity,
+            });
+        }
+    });
+
+    let now = time();
+    Ok(ChangePage {
+        changes,
+        next_cursor,
+        server_time: now,
+        full_resync_required: since > 0 && since < now.saturating_sub(tombstone_retention()),
+    })
+}
+
+// Where to start reading the feed for changes after `since`. Seqs are taken in time order, so
+// changed_at never decreases along the feed and the start is found by bisecting the seqs.
+fn first_change_after(since: u64) -> u64 {
+    let last = CHANGE_SEQ.with(|counter| *counter.borrow().get());
+    CHANGE_FEED.with(|feed| {
+        let feed = feed.borrow();
+        bisect_seqs(last, |from| feed.range(from..).next().map(|(seq, entry)| (seq, entry.changed_at)), since)
+    })
+}
+
+// The lowest position from which the first entry read (via `first_from`) changed after `since`;
+// entries are keyed by seq up to `last`, and their times never decrease
+fn bisect_seqs(last: u64, first_from: impl Fn(u64) -> Option<(u64, u64)>, since: u64) -> u64 {
+    let (mut low, mut high) = (0, last.saturating_add(1));
+    while low < high {
+        let mid = low + (high - low) / 2;
+        match first_from(mid) {
+            Some((seq, changed_at)) if changed_at <= since => low = seq + 1,
+            _ => high = mid,
+        }
+    }
+    low
+}
+
+// The mother a synced entity belongs to
+fn sync_entity_mother_id(entity: &SyncEntity) -> u64 {
+    match entity {
+        SyncEntity::MotherProfile(profile) => profile.id,
+        SyncEntity::HealthRecord(record) => record.mother_id,
+        SyncEntity::Referral(referral) => referral.mother_id,
+        SyncEntity::Payment(payment) => payment.mother_id,
+        SyncEntity::LabResult(lab_result) => lab_result.mother_id,
+        SyncEntity::SelfReport(report) => report.mother_id,
+    }
+}
+
+const SYNC_PAGE_SIZE: usize = 200;
+const DEFAULT_TOMBSTONE_RETENTION_DAYS: u64 = 90;
+
+// List deletions still within the retention window, oldest first
+#[ic_cdk::query]
+fn get_tombstones(cursor: Option<u64>, limit: Option<u32>) -> Page<Tombstone> {
+    let mut tombstones: Vec<Tombstone> =
+        TOMBSTONES.with(|storage| storage.borrow().iter().map(|(_, tombstone)| tombstone).collect());
+    tombstones.sort_by_key(|tombstone| tombstone.deleted_at);
+    paginate_in_order(tombstones, cursor, limit)
+}
+
+// Set how long deletions stay visible to delta sync (controllers only)
+#[ic_cdk::update]
+fn set_tombstone_retention(days: u64) -> Result<(), Error> {
+    ensure_controller()?;
+    if days == 0 || days > 3650 {
+        return Err(Error::InvalidInput {
+            msg: "Retention must be between 1 and 3650 days".to_string(),
+        });
+    }
+
+    SYNC_CONFIG.with(|config| {
+        config
+            .borrow_mut()
+            .set(SyncConfig { tombstone_retention_days: Some(days) })
+            .map_err(|_| Error::SystemError { msg: "Failed to store sync config".to_string() })
+    })?;
+    purge_expired_tombstones();
+    Ok(())
+}
+
+// Tombstone retention window in days
+#[ic_cdk::query]
+fn get_tombstone_retention() -> u64 {
+    SYNC_CONFIG
+        .with(|config| config.borrow().get().tombstone_retention_days)
+        .unwrap_or(DEFAULT_TOMBSTONE_RETENTION_DAYS)
+}
+
+const DEFAULT_ARCHIVE_AFTER_DAYS: u64 = 365;
+const ARCHIVE_BATCH_SIZE: usize = 500;
+const ARCHIVE_BATCH_BYTES: usize = 1_500_000;
+
+// Configure where old health records are archived and how old they must be (controllers only)
+#[ic_cdk::update]
+fn set_archive_config(archive_canister: Principal, archive_after_days: u64) -> Result<(), Error> {
+    ensure_controller()?;
+    if archive_after_days == 0 || archive_after_days > 36500 {
+        return Err(Error::InvalidInput {
+            msg: "Archive age must be between 1 and 36500 days".to_string(),
+        });
+    }
+
+    ARCHIVE_CONFIG.with(|config| {
+        config
+            .borrow_mut()
+            .set(ArchiveConfig {
+                archive_canister: Some(archive_canister),
+                archive_after_days: Some(archive_after_days),
+            })
+            .map(|_| ())
+            .map_err(|_| Error::SystemError { msg: "Failed to store archive config".to_string() })
+    })
+}
+
+#[ic_cdk::query]
+fn get_archive_config() -> ArchiveConfig {
+    ARCHIVE_CONFIG.with(|config| config.borrow().get().clone())
+}
+
+// Move up to ARCHIVE_BATCH_SIZE health records of mothers who have delivered, older than the
+// configured age, to the archive canister. Returns how many were moved; call again until 0.
+#[ic_cdk::update]
+async fn archive_old_records() -> Result<u64, Error> {
+    ensure_controller()?;
+
+    let config = ARCHIVE_CONFIG.with(|config| config.borrow().get().clone());
+    let archive = config
+        .archive_canister
+        .ok_or_else(|| Error::SystemError { msg: "Archive canister is not configured".to_string() })?;
+    let age = config.archive_after_days.unwrap_or(DEFAULT_ARCHIVE_AFTER_DAYS) * NANOS_PER_DAY;
+    let cutoff = time().saturating_sub(age);
+
+    let delivered: Vec<u64> = PROFILE_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .filter(|(_, profile)| matches!(profile.stage, PregnancyStage::PostPartum))
+            .map(|(id, _)| id)
+            .collect()
+    });
+    let mut records: Vec<HealthRecord> = Vec::new();
+    HEALTH_RECORD_STORAGE.with(|storage| {
+        let storage = storage.borrow();
+        for mother_id in delivered {
+            records.extend(
+                storage
+                    .range(mother_record_keys(mother_id))
+                    .map(|(_, record)| record)
+                    .filter(|record| record.date < cutoff),
+            );
+            if records.len() >= ARCHIVE_BATCH_SIZE {
+                break;
+            }
+        }
+    });
+    records.truncate(ARCHIVE_BATCH_SIZE);
+    if records.is_empty() {
+        return Ok(0);
+    }
+
+    // Keep the call well under the 2 MB message limit
+    let mut batch: Vec<ArchivedRecord> = Vec::new();
+    let mut batch_bytes = 0;
+    for record in &records {
+        let data = Encode!(record).unwrap();
+        if !batch.is_empty() && batch_bytes + data.len() > ARCHIVE_BATCH_BYTES {
+            break;
+        }
+        batch_bytes += data.len();
+        batch.push(ArchivedRecord {
+            mother_id: record.mother_id,
+            id: record.id,
+            data,
+        });
+    }
+    records.truncate(batch.len());
+    let result: Result<(Result<u64, ArchiveError>,), _> =
+        ic_cdk::call(archive, "archive_records", (batch,)).await;
+    match result {
+        Ok((Ok(_),)) => {}
+        Ok((Err(err),)) => {
+            return Err(Error::SystemError {
+                msg: format!("Archive rejected records: {:?}", err),
+            })
+        }
+        Err((_, msg)) => {
+            return Err(Error::SystemError {
+                msg: format!("Failed to call archive: {}", msg),
+            })
+        }
+    }
+
+    // Only drop local copies once the archive has stored them, and only those unchanged since
+    // they were sent; an edited record stays local and is archived again by a later call
+    let now = time();
+    let mut moved = 0;
+    for record in &records {
+        let key = RecordKey {
+            mother_id: record.mother_id,
+            seq: record.id,
+        };
+        let unchanged = HEALTH_RECORD_STORAGE.with(|storage| {
+            storage
+                .borrow()
+                .get(&key)
+                .is_some_and(|current| current.version == record.version && current.updated_at == record.updated_at)
+        });
+        if !unchanged {
+            continue;
+        }
+        HEALTH_RECORD_STORAGE.with(|storage| storage.borrow_mut().remove(&key));
+        moved += 1;
+        HEALTH_RECORD_MOTHERS.with(|index| index.borrow_mut().remove(&record.id));
+        ARCHIVE_POINTERS.with(|pointers| {
+            let mut pointers = pointers.borrow_mut();
+            let mut pointer = pointers.get(&record.mother_id).unwrap_or(ArchivePointer {
+                archive_canisters: Vec::new(),
+                record_count: 0,
+                last_archived_at: now,
+            });
+            if !pointer.archive_canisters.contains(&archive) {
+                pointer.archive_canisters.push(archive);
+            }
+            pointer.record_count += 1;
+            pointer.last_archived_at = now;
+            pointers.insert(record.mother_id, pointer);
+        });
+    }
+    Ok(moved)
+}
+
+// A mother's health records, local and archived, by id. A record that is both local and
+// archived is taken from local storage, which holds the latest version.
+async fn mother_health_records(mother_id: u64) -> Result<Vec<HealthRecord>, Error> {
+    let archived = archived_health_records(mother_id).await?;
+    let mut records: std::collections::BTreeMap<u64, HealthRecord> =
+        archived.into_iter().map(|record| (record.id, record)).collect();
+    // Only this mother's key range is read
+    HEALTH_RECORD_STORAGE.with(|storage| {
+        records.extend(storage.borrow().range(mother_record_keys(mother_id)).map(|(_, record)| (record.id, record)))
+    });
+    Ok(records.into_values().collect())
+}
+
+// Fetch a mother's archived health records from every archive that holds some
+async fn archived_health_records(mother_id: u64) -> Result<Vec<HealthRecord>, Error> {
+    let Some(pointer) = ARCHIVE_POINTERS.with(|pointers| pointers.borrow().get(&mother_id)) else {
+        return Ok(Vec::new());
+    };
+
+    let mut records = Vec::new();
+    for archive in pointer.archive_canisters {
+        let result: Result<(Result<Vec<ArchivedRecord>, ArchiveError>,), _> =
+            ic_cdk::call(archive, "get_archived_records", (mother_id,)).await;
+        let archived = match result {
+            Ok((Ok(archived),)) => archived,
+            Ok((Err(err),)) => {
+                return Err(Error::SystemError {
+                    msg: format!("Archive rejected request: {:?}", err),
+                })
+            }
+            Err((_, msg)) => {
+                return Err(Error::SystemError {
+                    msg: format!("Failed to call archive: {}", msg),
+                })
+            }
+        };
+        for entry in archived {
+            let record = Decode!(&entry.data, HealthRecord).map_err(|e| Error::SystemError {
+                msg: format!("Malformed archived record id={}: {}", entry.id, e),
+            })?;
+            records.push(record);
+        }
+    }
+    Ok(records)
+}
+
+// Where a mother's archived health records are, if any were archived
+#[ic_cdk::query]
+fn get_archive_pointer(mother_id: u64) -> Option<ArchivePointer> {
+    ARCHIVE_POINTERS.with(|pointers| pointers.borrow().get(&mother_id))
+}
+
+// Ids are split into fixed ranges: the router keeps the first, shard n gets the (n + 1)th
+const SHARD_ID_SPAN: u64 = 1 << 40;
+const SHARD_WASM_CHUNK_SIZE: usize = 256 * 1024;
+const SHARD_CREATION_CYCLES: u128 = 1_000_000_000_000;
+// Calls the router forwards; admin endpoints are excluded because the router controls its shards
+const FORWARDED_METHODS: [&str; 12] = [
+    "create_mother_profile",
+    "get_mother_profile",
+    "patch_mother_profile",
+    "add_health_record",
+    "get_mother_health_records",
+    "add_lab_result",
+    "get_mother_lab_results",
+    "create_referral",
+    "get_mother_referrals",
+    "record_delivery",
+    "update_insurance",
+    "export_mother",
+];
+
+// Choose how new mothers are assigned to shards (controllers only)
+#[ic_cdk::update]
+fn set_shard_strategy(strategy: ShardStrategy) -> Result<(), Error> {
+    ensure_controller()?;
+
+    SHARD_CONFIG.with(|config| {
+        let mut config = config.borrow_mut();
+        let mut updated = config.get().clone();
+        updated.strategy = Some(strategy);
+        config
+            .set(updated)
+            .map(|_| ())
+            .map_err(|_| Error::SystemError { msg: "Failed to store shard config".to_string() })
+    })
+}
+
+// Upload the wasm module for new shards in chunks of up to 256 KiB; `reset` starts a new module (controllers only)
+#[ic_cdk::update]
+fn upload_shard_wasm(chunk: Vec<u8>, reset: bool) -> Result<u64, Error> {
+    ensure_controller()?;
+    if chunk.len() > SHARD_WASM_CHUNK_SIZE {
+        return Err(Error::InvalidInput {
+            msg: format!("Chunks must be at most {} bytes", SHARD_WASM_CHUNK_SIZE),
+        });
+    }
+
+    SHARD_WASM.with(|storage| {
+        let mut storage = storage.borrow_mut();
+        if reset {
+            let indexes: Vec<u64> = storage.iter().map(|(index, _)| index).collect();
+            for index in indexes {
+                storage.remove(&index);
+            }
+        }
+        let index = storage.len();
+        storage.insert(index, WasmChunk(chunk));
+        Ok(storage.iter().map(|(_, chunk)| chunk.0.len() as u64).sum())
+    })
+}
+
+// Install arguments carrying this canister's config to a new shard
+fn shard_init_args() -> InitArgs {
+    let config = canister_config();
+    let thresholds = config.thresholds;
+    InitArgs {
+        admins: Some(config.admins),
+        facility_name: config.facility_name,
+        locale: Some(config.locale),
+        thresholds: Some(ThresholdOverrides {
+            systolic_high: Some(thresholds.systolic_high),
+            diastolic_high: Some(thresholds.diastolic_high),
+            systolic_low: Some(thresholds.systolic_low),
+            diastolic_low: Some(thresholds.diastolic_low),
+            weight_low: Some(thresholds.weight_low),
+            weight_high: Some(thresholds.weight_high),
+        }),
+        vetkd_key_name: config.vetkd_key_name,
+    }
+}
+
+// Create a shard canister, install the uploaded wasm and hand it the next id range (controllers only).
+// With the Facility strategy, `facility_codes` lists the facilities it serves.
+#[ic_cdk::update]
+async fn spawn_shard(facility_codes: Vec<String>) -> Result<Shard, Error> {
+    use ic_cdk::api::management_canister::main::{
+        create_canister, install_code, CanisterInstallMode, CanisterSettings, CreateCanisterArgument,
+        InstallCodeArgument,
+    };
+
+    ensure_controller()?;
+    if SHARD_ROLE.with(|role| role.borrow().get().router.is_some()) {
+        return Err(Error::InvalidInput {
+            msg: "Shards cannot spawn shards; call the router".to_string(),
+        });
+    }
+    let wasm_module: Vec<u8> =
+        SHARD_WASM.with(|storage| storage.borrow().iter().flat_map(|(_, chunk)| chunk.0).collect());
+    if wasm_module.is_empty() {
+        return Err(Error::InvalidInput {
+            msg: "Upload the shard wasm with upload_shard_wasm first".to_string(),
+        });
+    }
+
+    let index = SHARD_CONFIG.with(|config| config.borrow().get().shards.len() as u64) + 1;
+    let id_start = index * SHARD_ID_SPAN;
+    let id_end = id_start + SHARD_ID_SPAN;
+
+    // The router keeps the first range from now on
+    SHARD_ROLE.with(|role| {
+        role.borrow_mut()
+            .set(ShardRole { router: None, id_end: Some(SHARD_ID_SPAN) })
+            .map(|_| ())
+            .map_err(|_| Error::SystemError { msg: "Failed to store shard role".to_string() })
+    })?;
+
+    let settings = CanisterSettings {
+        controllers: Some(vec![ic_cdk::id(), ic_cdk::caller()]),
+        ..Default::default()
+    };
+    let (record,) = create_canister(CreateCanisterArgument { settings: Some(settings) }, SHARD_CREATION_CYCLES)
+        .await
+        .map_err(|(_, msg)| Error::SystemError { msg: format!("Failed to create shard canister: {}", msg) })?;
+    let canister_id = record.canister_id;
+
+    install_code(InstallCodeArgument {
+        mode: CanisterInstallMode::Install,
+        canister_id,
+        wasm_module,
+        // Shards classify visits and accept admins the same way as the router
+        arg: Encode!(&Some(shard_init_args())).unwrap(),
+    })
+    .await
+    .map_err(|(_, msg)| Error::SystemError { msg: format!("Failed to install shard {}: {}", canister_id, msg) })?;
+
+    let assignment = ShardAssignment {
+        router: ic_cdk::id(),
+        id_start,
+        id_end,
+    };
+    let result: Result<(Result<(), Error>,), _> = ic_cdk::call(canister_id, "configure_shard", (assignment,)).await;
+    match result {
+        Ok((Ok(()),)) => {}
+        Ok((Err(err),)) => return Err(err),
+        Err((_, msg)) => {
+            return Err(Error::SystemError {
+                msg: format!("Failed to configure shard {}: {}", canister_id, msg),
+            })
+        }
+    }
+
+    let shard = Shard {
+        canister_id,
+        id_start,
+        id_end,
+        facility_codes,
+        created_at: time(),
+    };
+    SHARD_CONFIG.with(|config| {
+        let mut config = config.borrow_mut();
+        let mut updated = config.get().clone();
+        // Another spawn may have finished first; this shard's canister is left for the caller to delete
+        if updated.shards.iter().any(|existing| existing.id_start == id_start) {
+            return Err(Error::Conflict {
+                msg: format!("Shard range starting at {} was taken by a concurrent spawn", id_start),
+            });
+        }
+        updated.shards.push(shard.clone());
+        config
+            .set(updated)
+            .map(|_| ())
+            .map_err(|_| Error::SystemError { msg: "Failed to store shard config".to_string() })
+    })?;
+    Ok(shard)
+}
+
+// Called by the router on a freshly installed shard to assign its id range
+#[ic_cdk::update]
+fn configure_shard(assignment: ShardAssignment) -> Result<(), Error> {
+    ensure_controller()?;
+    if ID_SEQUENCES
+        .iter()
+        .any(|entity_type| id_sequence(*entity_type).with(|counter| *counter.borrow().get()) != 0)
+    {
+        return Err(Error::InvalidInput {
+            msg: "Only an empty canister can become a shard".to_string(),
+        });
+    }
+
+    for entity_type in ID_SEQUENCES {
+        id_sequence(entity_type)
+            .with(|counter| counter.borrow_mut().set(assignment.id_start))
+            .map_err(|_| Error::SystemError { msg: "Failed to set ID counter".to_string() })?;
+    }
+    SHARD_ROLE.with(|role| {
+        role.borrow_mut()
+            .set(ShardRole {
+                router: Some(assignment.router),
+                id_end: Some(assignment.id_end),
+            })
+            .map(|_| ())
+            .map_err(|_| Error::SystemError { msg: "Failed to store shard role".to_string() })
+    })
+}
+
+#[ic_cdk::query]
+fn get_shard_config() -> ShardConfig {
+    SHARD_CONFIG.with(|config| config.borrow().get().clone())
+}
+
+#[ic_cdk::query]
+fn get_shard_role() -> ShardRole {
+    SHARD_ROLE.with(|role| role.borrow().get().clone())
+}
+
+// Canister that holds a mother, or should register a new one; this canister if not sharded out
+#[ic_cdk::query]
+fn route(route: ShardRoute) -> Result<Principal, Error> {
+    let config = SHARD_CONFIG.with(|config| config.borrow().get().clone());
+    match route {
+        ShardRoute::Mother(id) if id < SHARD_ID_SPAN => Ok(ic_cdk::id()),
+        ShardRoute::Mother(id) => config
+            .shards
+            .iter()
+            .find(|shard| (shard.id_start..shard.id_end).contains(&id))
+            .map(|shard| shard.canister_id)
+            .ok_or(Error::NotFound {
+                msg: format!("No shard holds mother id={}", id),
+            }),
+        ShardRoute::NewMother(facility_code) => {
+            let shard = match config.strategy.unwrap_or(ShardStrategy::MotherIdRange) {
+                ShardStrategy::MotherIdRange => config.shards.last(),
+                ShardStrategy::Facility => config.shards.iter().find(|shard| {
+                    facility_code
+                        .as_ref()
+                        .is_some_and(|code| shard.facility_codes.contains(code))
+                }),
+            };
+            Ok(shard.map_or(ic_cdk::id(), |shard| shard.canister_id))
+        }
+    }
+}
+
+// Forward a Candid-encoded call to the shard chosen by `route` and return its encoded reply.
+// Shards see the router as the caller.
+#[ic_cdk::update]
+async fn forward_call(target: ShardRoute, method: String, arg: Vec<u8>) -> Result<Vec<u8>, Error> {
+    if !FORWARDED_METHODS.contains(&method.as_str()) {
+        return Err(Error::InvalidInput {
+            msg: format!("{} cannot be forwarded", method),
+        });
+    }
+    let canister_id = route(target)?;
+    if canister_id == ic_cdk::id() {
+        return Err(Error::InvalidInput {
+            msg: "This canister serves the request; call it directly".to_string(),
+        });
+    }
+
+    ic_cdk::api::call::call_raw(canister_id, &method, arg, 0)
+        .await
+        .map_err(|(_, msg)| Error::SystemError { msg: format!("Shard {} rejected call: {}", canister_id, msg) })
+}
+
+fn tombstone_retention() -> u64 {
+    get_tombstone_retention() * NANOS_PER_DAY
+}
+
+// Drop tombstones (and their feed entries) older than the retention window
+fn purge_expired_tombstones() {
+    let cutoff = time().saturating_sub(tombstone_retention());
+    let expired: Vec<EntityKey> = TOMBSTONES.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .filter(|(_, tombstone)| tombstone.deleted_at < cutoff)
+            .map(|(key, _)| key)
+            .collect()
+    });
+
+    for key in expired {
+        TOMBSTONES.with(|storage| storage.borrow_mut().remove(&key));
+        if let Some(seq) = CHANGE_INDEX.with(|index| index.borrow_mut().remove(&key)) {
+            CHANGE_FEED.with(|feed| feed.borrow_mut().remove(&seq));
+        }
+    }
+}
+
+// Page through the changelog from `start` (oldest first, at most 500 events; controllers only)
+#[ic_cdk::query]
+fn get_change_events(start: u64, limit: u64) -> Result<ChangeEventPage, Error> {
+    ensure_controller()?;
+
+    CHANGE_LOG.with(|log| {
+        let log = log.borrow();
+        let end = start.saturating_add(limit.min(MAX_CHANGE_EVENT_PAGE)).min(log.len());
+        let events = (start..end).filter_map(|seq| log.get(seq)).map(open_change_event).collect();
+        Ok(ChangeEventPage {
+            events,
+            next_start: (end < log.len()).then_some(end),
+        })
+    })
+}
+
+const MAX_CHANGE_EVENT_PAGE: u64 = 500;
+
+// Record a mutation: append it to the changelog and publish it to the delta-sync feed
+fn log_change<T: serde::Serialize>(entity_type: EntityType, id: u64, before: Option<&T>, after: Option<&T>) {
+    log_change_with_reason(entity_type, id, before, after, None);
+}
+
+// log_change, keeping why the change was made in the amendment trail
+fn log_change_with_reason<T: serde::Serialize>(
+    entity_type: EntityType,
+    id: u64,
+    before: Option<&T>,
+    after: Option<&T>,
+    reason: Option<&str>,
+) {
+    let to_json = |value: &T| serde_json::to_value(value).unwrap_or_default();
+    let (before, after) = (before.map(to_json), after.map(to_json));
+    update_counters(entity_type, before.as_ref(), after.as_ref());
+    let mother_id = after.as_ref().or(before.as_ref()).and_then(|entity| entity_mother_id(entity_type, id, entity));
+    let (kind, diff) = match (before, after) {
+        (None, Some(after)) => (ChangeEventKind::Created, after),
+        (Some(before), None) => (ChangeEventKind::Deleted, before),
+        (Some(before), Some(after)) => (ChangeEventKind::Updated, json_diff(&before, &after)),
+        (None, None) => return,
+    };
+    append_change_event(entity_type, id, mother_id, kind, diff.to_string(), reason);
+
+    match kind {
+        ChangeEventKind::Deleted => record_deletion(entity_type, id),
+        _ => record_change(entity_type, id, ChangeKind::Upserted),
+    }
+}
+
+// Publish a deletion to the delta-sync feed and keep its tombstone
+fn record_deletion(entity_type: EntityType, id: u64) {
+    record_change(entity_type, id, ChangeKind::Deleted);
+    purge_expired_tombstones();
+    let tombstone = Tombstone { entity_type, id, deleted_at: time() };
+    TOMBSTONES.with(|storage| storage.borrow_mut().insert(EntityKey::new(entity_type, id), tombstone));
+}
+
+// The mother an entity belongs to, from its JSON
+fn entity_mother_id(entity_type: EntityType, id: u64, entity: &serde_json::Value) -> Option<u64> {
+    match entity_type {
+        EntityType::MotherProfile => Some(id),
+        _ => entity["mother_id"].as_u64(),
+    }
+}
+
+// Append to the changelog, sealing the diff and reason under the mother's key when there is one
+fn append_change_event(
+    entity_type: EntityType,
+    id: u64,
+    mother_id: Option<u64>,
+    kind: ChangeEventKind,
+    diff: String,
+    reason: Option<&str>,
+) {
+    let seq = CHANGE_LOG.with(|log| log.borrow().len());
+    let mut event = LoggedChange {
+        seq,
+        entity_type,
+        entity_id: id,
+        kind,
+        diff,
+        actor: ic_cdk::caller(),
+        timestamp: time(),
+        reason: reason.map(str::to_string),
+        mother_id,
+        sealed: None,
+    };
+    if let Some(mother_id) = mother_id {
+        event = seal_logged_change(&mother_log_key(mother_id), event);
+    }
+    let seq = CHANGE_LOG.with(|log| log.borrow().append(&event).expect("Cannot append to change log"));
+    let key = AmendmentKey { entity: EntityKey::new(entity_type, id), seq };
+    AMENDMENT_INDEX.with(|index| index.borrow_mut().insert(key, time()));
+}
+
+// An entity's changes, oldest first: its creation and every later amendment, each with the
+// before/after diff, the actor and the reason (controllers, admins and sensitive readers)
+#[ic_cdk::query]
+fn get_amendments(entity_type: EntityType, entity_id: u64) -> Result<Vec<ChangeEvent>, Error> {
+    ensure_controller().or_else(|_| ensure_sensitive_reader())?;
+
+    let entity = EntityKey::new(entity_type, entity_id);
+    let seqs: Vec<u64> = AMENDMENT_INDEX.with(|index| {
+        index
+            .borrow()
+            .range(AmendmentKey { entity, seq: 0 }..=AmendmentKey { entity, seq: u64::MAX })
+            .map(|(key, _)| key.seq)
+            .collect()
+    });
+    Ok(CHANGE_LOG.with(|log| {
+        let log = log.borrow();
+        seqs.into_iter().filter_map(|seq| log.get(seq)).map(open_change_event).collect()
+    }))
+}
+
+// Index the changelog written before AMENDMENT_INDEX existed
+fn index_amendments() {
+    let events = CHANGE_LOG.with(|log| log.borrow().len());
+    for seq in 0..events {
+        if let Some(event) = CHANGE_LOG.with(|log| log.borrow().get(seq)) {
+            let key = AmendmentKey { entity: EntityKey::new(event.entity_type, event.entity_id), seq };
+            AMENDMENT_INDEX.with(|index| index.borrow_mut().insert(key, event.timestamp));
+        }
+    }
+}
+
+// Copy the plaintext changelog in memories 17 and 18 into the sealed one, keeping every seq,
+// then overwrite the old memories. Diffs of entities already erased are not carried over.
+fn seal_change_log() {
+    let memory = |id| MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(id)));
+    let Ok(legacy) = StableLog::<LoggedChange, Memory, Memory>::init(memory(17), memory(18)) else {
+        return;
+    };
+
+    // Updates only carry the fields that changed; the entity's creation or current state says whose it is
+    let mut owners = std::collections::BTreeMap::new();
+    for seq in 0..legacy.len() {
+        let Some(event) = legacy.get(seq) else { continue };
+        if let Ok(entity) = serde_json::from_str::<serde_json::Value>(&event.diff) {
+            if let Some(mother_id) = entity_mother_id(event.entity_type, event.entity_id, &entity) {
+                owners.entry(EntityKey::new(event.entity_type, event.entity_id)).or_insert(mother_id);
+            }
+        }
+    }
+    for seq in 0..legacy.len() {
+        let mut event = legacy.get(seq).expect("Change log entry missing");
+        let entity = EntityKey::new(event.entity_type, event.entity_id);
+        let mother_id = owners.get(&entity).copied().or_else(|| {
+            load_sync_entity(event.entity_type, event.entity_id).map(|current| sync_entity_mother_id(&current))
+        });
+        let erased = ERASED_ENTITIES.with(|erased| erased.borrow().contains_key(&entity));
+        event = match mother_id {
+            Some(mother_id) if !erased => {
+                event.mother_id = Some(mother_id);
+                seal_logged_change(&mother_log_key(mother_id), event)
+            }
+            // An owner that cannot be told any more means the entity is long gone
+            _ => LoggedChange { diff: ERASED_DIFF.to_string(), reason: None, ..event },
+        };
+        CHANGE_LOG.with(|log| log.borrow().append(&event).expect("Cannot append to change log"));
+        let key = AmendmentKey { entity, seq };
+        AMENDMENT_INDEX.with(|index| index.borrow_mut().insert(key, event.timestamp));
+    }
+
+    drop(legacy);
+    for id in [17, 18] {
+        let memory = memory(id);
+        let zeros = vec![0; WASM_PAGE_SIZE as usize];
+        for page in 0..ic_stable_structures::Memory::size(&memory) {
+            ic_stable_structures::Memory::write(&memory, page * WASM_PAGE_SIZE, &zeros);
+        }
+    }
+}
+
+// Move idempotency keys into the map that records their time. Their real age is unknown, so
+// they count from the upgrade and get a full retry window.
+fn stamp_idempotency_keys() {
+    let now = time();
+    let legacy: Vec<(StringKey, u64)> = LEGACY_IDEMPOTENCY_KEYS.with(|keys| keys.borrow().iter().collect());
+    for (slot, id) in legacy {
+        LEGACY_IDEMPOTENCY_KEYS.with(|keys| keys.borrow_mut().remove(&slot));
+        IDEMPOTENCY_KEYS.with(|keys| keys.borrow_mut().insert(slot, IdempotentResult { id, created_at: now }));
+    }
+}
+
+// A mother's change-log key, drawn from the ratchet the first time she needs one
+fn mother_log_key(mother_id: u64) -> [u8; 32] {
+    if let Some(key) = MOTHER_LOG_KEYS.with(|keys| keys.borrow().get(&mother_id)) {
+        if let Ok(key) = key.key.try_into() {
+            return key;
+        }
+    }
+    let key = next_log_key();
+    MOTHER_LOG_KEYS.with(|keys| keys.borrow_mut().insert(mother_id, SigningKey { key: key.to_vec() }));
+    key
+}
+
+// Draw a key and advance the ratchet, so no key can be derived again from the state left behind
+fn next_log_key() -> [u8; 32] {
+    let mut state = LOG_KEY_RATCHET.with(|ratchet| ratchet.borrow().get().key.clone());
+    if state.is_empty() {
+        // raw_rand cannot be called during an upgrade, when the migration first needs keys.
+        // Split the ULID seed one way instead, so the ULID seed left in memory does not reveal it.
+        let seed = ULID_SEED.with(|seed| seed.borrow().get().seed.clone());
+        state = hmac_sha256(&seed, b"change-log keys").to_vec();
+        let ulid_seed = UlidSeed { seed: hmac_sha256(&seed, b"ulids").to_vec() };
+        ULID_SEED.with(|seed| seed.borrow_mut().set(ulid_seed).ok());
+    }
+    let next = SigningKey { key: hmac_sha256(&state, b"next").to_vec() };
+    LOG_KEY_RATCHET.with(|ratchet| ratchet.borrow_mut().set(next).ok());
+    hmac_sha256(&state, b"key")
+}
+
+// Mix fresh randomness into the ratchet after every install and upgrade
+fn schedule_log_key_ratchet() {
+    ic_cdk_timers::set_timer(std::time::Duration::ZERO, || {
+        ic_cdk::spawn(async {
+            if let Ok((random,)) = ic_cdk::api::management_canister::main::raw_rand().await {
+                LOG_KEY_RATCHET.with(|ratchet| {
+                    let state = ratchet.borrow().get().key.clone();
+                    let mixed = SigningKey { key: hmac_sha256(&random, &state).to_vec() };
+                    ratchet.borrow_mut().set(mixed).ok();
+                });
+            }
+        })
+    });
+}
+
+// Each mother has her own key and each event its own seq, so the seq is a unique nonce
+fn change_nonce(seq: u64) -> XNonce {
+    let mut nonce = XNonce::default();
+    nonce[16..].copy_from_slice(&seq.to_be_bytes());
+    nonce
+}
+
+// The event's entity, bound to its ciphertext so a sealed diff cannot be moved to another entity
+fn change_aad(event: &LoggedChange) -> Vec<u8> {
+    let entity = EntityKey::new(event.entity_type, event.entity_id);
+    [&[entity.tag][..], &entity.id.to_be_bytes()].concat()
+}
+
+fn seal_logged_change(key: &[u8; 32], mut event: LoggedChange) -> LoggedChange {
+    let plaintext = Encode!(&(&event.diff, &event.reason)).unwrap();
+    let aad = change_aad(&event);
+    let sealed = XChaCha20Poly1305::new(key.into())
+        .encrypt(&change_nonce(event.seq), Payload { msg: &plaintext, aad: &aad })
+        .expect("Diffs are far below the cipher's length limit");
+    event.diff = String::new();
+    event.reason = None;
+    event.sealed = Some(sealed);
+    event
+}
+
+fn open_logged_change(key: &[u8; 32], event: &LoggedChange) -> Option<(String, Option<String>)> {
+    let aad = change_aad(event);
+    let plaintext = XChaCha20Poly1305::new(key.into())
+        .decrypt(&change_nonce(event.seq), Payload { msg: event.sealed.as_deref()?, aad: &aad })
+        .ok()?;
+    Decode!(&plaintext, (String, Option<String>)).ok()
+}
+
+// A stored event as returned to callers; once the mother's key is gone her diffs read as erased
+fn open_change_event(event: LoggedChange) -> ChangeEvent {
+    let (diff, reason) = match (event.sealed.is_some(), event.mother_id) {
+        (true, Some(mother_id)) => MOTHER_LOG_KEYS
+            .with(|keys| keys.borrow().get(&mother_id))
+            .and_then(|key| <[u8; 32]>::try_from(key.key).ok())
+            .and_then(|key| open_logged_change(&key, &event))
+            .unwrap_or_else(|| (ERASED_DIFF.to_string(), None)),
+        _ => (event.diff, event.reason),
+    };
+    ChangeEvent {
+        seq: event.seq,
+        entity_type: event.entity_type,
+        entity_id: event.entity_id,
+        kind: event.kind,
+        diff,
+        actor: event.actor,
+        timestamp: event.timestamp,
+        reason,
+    }
+}
+
+const ERASED_DIFF: &str = r#"{"erased":true}"#;
+
+// Move an entity's contribution to the dashboard counters from its old state to its new one
+fn update_counters(entity_type: EntityType, before: Option<&serde_json::Value>, after: Option<&serde_json::Value>) {
+    let removed = before.map_or_else(Vec::new, |entity| counter_keys(entity_type, entity));
+    let added = after.map_or_else(Vec::new, |entity| counter_keys(entity_type, entity));
+    for key in removed.iter().filter(|key| !added.contains(key)) {
+        adjust_counter(key, -1);
+    }
+    for key in added.iter().filter(|key| !removed.contains(key)) {
+        adjust_counter(key, 1);
+    }
+}
+
+// Counters an entity contributes one to
+fn counter_keys(entity_type: EntityType, entity: &serde_json::Value) -> Vec<String> {
+    if !entity.is_object() {
+        return Vec::new();
+    }
+    let field = |name: &str| entity[name].as_str().unwrap_or_default().to_string();
+    match entity_type {
+        EntityType::MotherProfile => {
+            let mut keys = vec![
+                "mothers".to_string(),
+                format!("stage:{}", field("stage")),
+                format!("status:{}", field("health_status")),
+                format!("registrations:{}", record_month(entity["created_at"].as_u64().unwrap_or_default())),
+            ];
+            let facility = entity["facility_code"].as_str();
+            keys.push(aggregate_key(AggregateMetric::Registrations, entity["created_at"].as_u64(), facility));
+            if entity["delivery"].is_null() {
+                keys.push(format!("active:{}", field("stage")));
+            } else {
+                let delivery = &entity["delivery"];
+                let facility = delivery["facility_code"].as_str().or(facility);
+                keys.push(aggregate_key(AggregateMetric::Deliveries, delivery["delivery_date"].as_u64(), facility));
+            }
+            keys
+        }
+        EntityType::HealthRecord => {
+            let date = entity["date"].as_u64();
+            let facility = entity["facility_code"].as_str();
+            let mut keys = vec![
+                "health_records".to_string(),
+                format!("records:{}", record_month(date.unwrap_or_default())),
+                aggregate_key(AggregateMetric::Visits, date, facility),
+            ];
+            let next_appointment = entity["next_appointment"].as_u64().unwrap_or_default();
+            if next_appointment > 0 {
+                keys.push(format!("appointments:{}", record_day(next_appointment)));
+            }
+            if field("health_status") == "Critical" {
+                keys.push(aggregate_key(AggregateMetric::CriticalVisits, date, facility));
+            }
+            keys
+        }
+        EntityType::Referral => vec![
+            "referrals".to_string(),
+            format!("referrals:{}", field("status")),
+            aggregate_key(AggregateMetric::Referrals, entity["created_at"].as_u64(), entity["from_facility"].as_str()),
+        ],
+        EntityType::Payment | EntityType::LabResult | EntityType::SelfReport => Vec::new(),
+    }
+}
+
+// Counter behind `aggregate`: "agg:{metric}:{YYYY-MM-DD}:{facility}"
+fn aggregate_key(metric: AggregateMetric, at: Option<u64>, facility: Option<&str>) -> String {
+    format!(
+        "agg:{}:{}:{}",
+        metric.counter_name(),
+        record_day(at.unwrap_or_default()),
+        facility.unwrap_or("UNASSIGNED")
+    )
+}
+
+// "YYYY-MM" of a timestamp
+fn record_month(timestamp: u64) -> String {
+    format_iso8601(timestamp)[..7].to_string()
+}
+
+// "YYYY-MM-DD" of a timestamp
+fn record_day(timestamp: u64) -> String {
+    format_iso8601(timestamp)[..10].to_string()
+}
+
+fn adjust_counter(key: &str, delta: i64) {
+    COUNTERS.with(|counters| {
+        let mut counters = counters.borrow_mut();
+        let key = StringKey(key.to_string());
+        let value = counters.get(&key).unwrap_or(0).saturating_add_signed(delta);
+        counters.insert(key, value);
+    });
+}
+
+fn counter(key: &str) -> u64 {
+    COUNTERS.with(|counters| counters.borrow().get(&StringKey(key.to_string())).unwrap_or(0))
+}
+
+// Counters with a prefix, keyed by what follows it
+fn counters_with_prefix(prefix: &str) -> Vec<(String, u64)> {
+    COUNTERS.with(|counters| {
+        counters
+            .borrow()
+            .range(StringKey(prefix.to_string())..)
+            .take_while(|(key, _)| key.0.starts_with(prefix))
+            .filter(|(_, count)| *count > 0)
+            .map(|(key, count)| (key.0[prefix.len()..].to_string(), count))
+            .collect()
+    })
+}
+
+// A metric counted per day, week or month within the period, in total or per facility. Reads only
+// the per-day counters, so new charts need no new endpoint or scan.
+#[ic_cdk::query]
+fn aggregate(
+    metric: AggregateMetric,
+    group_by: AggregateGroup,
+    bucket: TimeBucket,
+    period: DateRange,
+) -> Result<Vec<AggregatePoint>, Error> {
+    let from = period.from.unwrap_or(0);
+    let to = period.to.unwrap_or_else(time);
+    if from > to {
+        return Err(Error::InvalidInput {
+            msg: "The period must not end before it starts".to_string(),
+        });
+    }
+    let prefix = format!("agg:{}:", metric.counter_name());
+    let (first_day, last_day) = (record_day(from), record_day(to));
+
+    let mut points: std::collections::BTreeMap<(String, Option<String>), u64> = std::collections::BTreeMap::new();
+    COUNTERS.with(|counters| {
+        for (key, count) in counters.borrow().range(StringKey(format!("{}{}", prefix, first_day))..) {
+            let Some((day, facility)) = key.0.strip_prefix(&prefix).and_then(|rest| rest.split_once(':')) else {
+                break;
+            };
+            if day > last_day.as_str() {
+                break;
+            }
+            let Some(timestamp) = parse_date(day) else {
+                continue;
+            };
+            let bucket = match bucket {
+                TimeBucket::Day => day.to_string(),
+                TimeBucket::Week => {
+                    let day_number = timestamp / NANOS_PER_DAY;
+                    // Day 4 of the epoch was a Monday
+                    record_day((day_number - (day_number + 3) % 7) * NANOS_PER_DAY)
+                }
+                TimeBucket::Month => day[..7].to_string(),
+            };
+            let group = match group_by {
+                AggregateGroup::Total => None,
+                AggregateGroup::Facility => Some(facility.to_string()),
+            };
+            *points.entry((bucket, group)).or_insert(0) += count;
+        }
+    });
+    Ok(points
+        .into_iter()
+        .filter(|(_, value)| *value > 0)
+        .map(|((bucket, group), value)| AggregatePoint { bucket, group, value })
+        .collect())
+}
+
+// Canister-wide counts, read from counters maintained on every write
+#[ic_cdk::query]
+fn get_dashboard_counters() -> DashboardCounters {
+    let critical_cases = counter("status:Critical");
+    let pending_referrals = counter("referrals:Pending");
+    DashboardCounters {
+        mothers: counter("mothers"),
+        by_stage: counters_with_prefix("stage:"),
+        by_health_status: counters_with_prefix("status:"),
+        health_records: counter("health_records"),
+        records_this_month: counter(&format!("records:{}", record_month(time()))),
+        referrals: counter("referrals"),
+        critical_cases,
+        pending_referrals,
+        open_alerts: critical_cases + pending_referrals,
+    }
+}
+
+// Registrations, active pregnancies, appointments and open health worker alerts at a glance,
+// read from the maintained counters
+#[ic_cdk::query]
+fn get_dashboard_stats() -> DashboardStats {
+    let now = time();
+    let upcoming_appointments = (0..canister_config().appointment_reminder_days)
+        .map(|day| counter(&format!("appointments:{}", record_day(now + day * NANOS_PER_DAY))))
+        .sum();
+    let critical_cases = counter("status:Critical");
+    let pending_referrals = counter("referrals:Pending");
+    let open_chw_alerts = counter(OPEN_CHW_ALERTS_COUNTER);
+    DashboardStats {
+        registrations_this_month: counter(&format!("registrations:{}", record_month(now))),
+        active_by_stage: counters_with_prefix("active:"),
+        by_health_status: counters_with_prefix("status:"),
+        critical_cases,
+        pending_referrals,
+        open_chw_alerts,
+        open_alerts: critical_cases + pending_referrals + open_chw_alerts,
+        upcoming_appointments,
+        generated_at: now,
+    }
+}
+
+// Mothers grouped by expected delivery month, with their visits, risk and outcomes, so each
+// delivery cohort's care can be followed
+#[ic_cdk::query]
+fn get_cohort_report(year: u32, month: u32) -> Result<CohortReport, Error> {
+    if !(1970..=9999).contains(&year) || !(1..=12).contains(&month) {
+        return Err(Error::InvalidInput {
+            msg: "year must be from 1970 and month between 1 and 12".to_string(),
+        });
+    }
+    let edd_month = format!("{:04}-{:02}", year, month);
+    let cohort: Vec<MotherProfile> = PROFILE_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, profile)| profile)
+            .filter(|profile| record_month(profile.expected_delivery_date) == edd_month)
+            .collect()
+    });
+
+    let mut visits = std::collections::BTreeMap::new();
+    let mut risk = std::collections::BTreeMap::new();
+    let mut outcomes = std::collections::BTreeMap::new();
+    let mut delivery_modes = std::collections::BTreeMap::new();
+    let mut total_visits = 0u64;
+    let mut delivered = 0;
+    let mut facility_deliveries = 0;
+    for profile in &cohort {
+        let visit_count = CARE_PROGRESS
+            .with(|progress| progress.borrow().get(&profile.id))
+            .map_or(0, |progress| progress.visit_count);
+        total_visits += u64::from(visit_count);
+        let bucket = match visit_count {
+            0 => "0",
+            1..=3 => "1-3",
+            4..=7 => "4-7",
+            _ => "8+",
+        };
+        *visits.entry(bucket.to_string()).or_insert(0) += 1;
+        *risk.entry(risk_tier(profile.id).to_string()).or_insert(0) += 1;
+        if let Some(delivery) = &profile.delivery {
+            delivered += 1;
+            *outcomes.entry(format!("{:?}", delivery.outcome)).or_insert(0) += 1;
+            *delivery_modes.entry(format!("{:?}", delivery.mode)).or_insert(0) += 1;
+            if delivery.facility_code.is_some() {
+                facility_deliveries += 1;
+            }
+        }
+    }
+
+    let mothers = cohort.len() as u64;
+    Ok(CohortReport {
+        edd_month,
+        mothers,
+        average_visits: if mothers == 0 { 0.0 } else { total_visits as f64 / mothers as f64 },
+        visits: visits.into_iter().collect(),
+        risk: risk.into_iter().collect(),
+        delivered,
+        outcomes: outcomes.into_iter().collect(),
+        delivery_modes: delivery_modes.into_iter().collect(),
+        facility_deliveries,
+    })
+}
+
+// History or lab entries showing a second (or later) tetanus toxoid dose
+const TT2_PLUS_TERMS: [&str; 4] = ["tt2", "tt3", "tt4", "tt5"];
+// Prescriptions counted as iron and folic acid supplementation
+const IFAS_TERMS: [&str; 3] = ["ifas", "iron", "folic"];
+
+// ANC coverage per facility for the mothers whose expected delivery date falls in the period.
+// Visits are the live health records dated before delivery; archived records are not counted.
+#[ic_cdk::query]
+fn get_anc_coverage(period: DateRange, facility_code: Option<String>) -> Result<Vec<AncCoverage>, Error> {
+    ensure_sensitive_reader()?;
+    let from = period.from.unwrap_or(0);
+    let to = period.to.unwrap_or(u64::MAX);
+    if from > to {
+        return Err(Error::InvalidInput {
+            msg: "The period must not end before it starts".to_string(),
+        });
+    }
+
+    Ok(anc_coverage(from..=to, facility_code.as_deref()))
+}
+
+fn anc_coverage(due: std::ops::RangeInclusive<u64>, facility_code: Option<&str>) -> Vec<AncCoverage> {
+    let mut lab_tests: std::collections::BTreeMap<u64, Vec<String>> = std::collections::BTreeMap::new();
+    LAB_RESULT_STORAGE.with(|storage| {
+        for (_, result) in storage.borrow().iter() {
+            lab_tests.entry(result.mother_id).or_default().push(normalize_term(&result.test_name));
+        }
+    });
+    let mut ifas_mothers = std::collections::BTreeSet::new();
+    PRESCRIPTIONS.with(|storage| {
+        for (_, prescription) in storage.borrow().iter() {
+            let medication = normalize_term(&prescription.medication);
+            if IFAS_TERMS.iter().any(|term| medication.contains(term)) {
+                ifas_mothers.insert(prescription.mother_id);
+            }
+        }
+    });
+
+    // Per facility: mothers, ANC1 < 12 weeks, ANC4+, ANC8+, TT2+, IFAS
+    let mut tallies: std::collections::BTreeMap<String, [u64; 6]> = std::collections::BTreeMap::new();
+    let profiles: Vec<MotherProfile> = PROFILE_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, profile)| profile)
+            .filter(|profile| due.contains(&profile.expected_delivery_date))
+            .collect()
+    });
+    for profile in profiles {
+        let facility = profile.facility_code.clone().unwrap_or_else(|| "UNASSIGNED".to_string());
+        if facility_code.is_some_and(|wanted| wanted != facility) {
+            continue;
+        }
+        let delivered_at = profile.delivery.as_ref().map_or(u64::MAX, |delivery| delivery.delivery_date);
+        let visit_dates: Vec<u64> = HEALTH_RECORD_STORAGE.with(|storage| {
+            storage
+                .borrow()
+                .range(mother_record_keys(profile.id))
+                .map(|(_, record)| record.date)
+                .filter(|date| *date < delivered_at)
+                .collect()
+        });
+        let tt2_plus = profile
+            .medical_history
+            .iter()
+            .map(|entry| normalize_term(entry))
+            .chain(lab_tests.get(&profile.id).cloned().unwrap_or_default())
+            .any(|entry| TT2_PLUS_TERMS.iter().any(|term| entry.contains(term)));
+
+        let tally = tallies.entry(facility).or_default();
+        tally[0] += 1;
+        if visit_dates
+            .iter()
+            .min()
+            .is_some_and(|first| gestational_age_weeks(profile.expected_delivery_date, *first) < 12)
+        {
+            tally[1] += 1;
+        }
+        if visit_dates.len() >= 4 {
+            tally[2] += 1;
+        }
+        if visit_dates.len() >= 8 {
+            tally[3] += 1;
+        }
+        if tt2_plus {
+            tally[4] += 1;
+        }
+        if ifas_mothers.contains(&profile.id) {
+            tally[5] += 1;
+        }
+    }
+
+    tallies
+        .into_iter()
+        .map(|(facility_code, tally)| {
+            let share = |count: u64| percent(count, tally[0]).unwrap_or(0.0);
+            AncCoverage {
+                facility_code,
+                mothers: tally[0],
+                anc1_before_12_weeks: share(tally[1]),
+                anc4_plus: share(tally[2]),
+                anc8_plus: share(tally[3]),
+                tt2_plus: share(tally[4]),
+                ifas: share(tally[5]),
+            }
+        })
+        .collect()
+}
+
+// Every facility's value for a metric over the period, ranked, with the trend against the period
+// of the same length just before it. County officers only.
+#[ic_cdk::query]
+fn compare_facilities(metric: FacilityMetric, period: DateRange) -> Result<Vec<FacilityComparison>, Error> {
+    ensure_county_officer()?;
+    let to = period.to.unwrap_or_else(time);
+    let Some(from) = period.from.filter(|from| *from < to) else {
+        return Err(Error::InvalidInput {
+            msg: "The period needs a start before its end".to_string(),
+        });
+    };
+    let previous_from = from.saturating_sub(to - from);
+
+    let current = facility_metric_values(metric, from..to);
+    let previous = facility_metric_values(metric, previous_from..from);
+    let mut comparisons: Vec<FacilityComparison> = current
+        .into_iter()
+        .map(|(facility_code, value)| {
+            let previous_value = previous.get(&facility_code).copied().unwrap_or(0.0);
+            let trend = if value > previous_value {
+                "up"
+            } else if value < previous_value {
+                "down"
+            } else {
+                "flat"
+            };
+            FacilityComparison { facility_code, value, rank: 0, previous_value, trend: trend.to_string() }
+        })
+        .collect();
+    comparisons.sort_by(|a, b| b.value.total_cmp(&a.value).then_with(|| a.facility_code.cmp(&b.facility_code)));
+    // Facilities with the same value share a rank
+    for index in 0..comparisons.len() {
+        comparisons[index].rank = if index > 0 && comparisons[index].value == comparisons[index - 1].value {
+            comparisons[index - 1].rank
+        } else {
+            index as u32 + 1
+        };
+    }
+    Ok(comparisons)
+}
+
+fn facility_metric_values(
+    metric: FacilityMetric,
+    period: std::ops::Range<u64>,
+) -> std::collections::BTreeMap<String, f64> {
+    if let FacilityMetric::Anc4Coverage = metric {
+        if period.is_empty() {
+            return std::collections::BTreeMap::new();
+        }
+        return anc_coverage(period.start..=period.end - 1, None)
+            .into_iter()
+            .map(|coverage| (coverage.facility_code, coverage.anc4_plus))
+            .collect();
+    }
+    facility_summary_rows(period, None)
+        .into_iter()
+        .filter_map(|row| match row {
+            ReportRow::Facility(row) => {
+                let value = match metric {
+                    FacilityMetric::Registrations => row.registrations,
+                    FacilityMetric::Visits => row.visits,
+                    FacilityMetric::CriticalVisits => row.critical_visits,
+                    FacilityMetric::ReferralsMade => row.referrals_made,
+                    FacilityMetric::Deliveries | FacilityMetric::Anc4Coverage => row.deliveries,
+                };
+                Some((row.facility_code, value as f64))
+            }
+            ReportRow::Defaulter(_) => None,
+        })
+        .collect()
+}
+
+// Snapshots are deleted once this old
+const REPORT_SNAPSHOT_TTL_DAYS: u64 = 400;
+// A missed appointment makes a mother a defaulter after this many days without a visit
+const DEFAULTER_GRACE_DAYS: u64 = 7;
+
+// Schedule a recurring report. The first run is at the next period boundary (midnight UTC, Monday
+// or the first of the month).
+#[ic_cdk::update]
+fn schedule_report(payload: ReportSchedulePayload) -> Result<ReportSchedule, Error> {
+    ensure_controller()?;
+    let now = time();
+    let id = REPORT_SCHEDULE_ID_SEQ.with(|counter| {
+        let next = *counter.borrow().get() + 1;
+        counter.borrow_mut().set(next).expect("Cannot update report schedule id sequence");
+        next
+    });
+    let schedule = ReportSchedule {
+        id,
+        kind: payload.kind,
+        frequency: payload.frequency,
+        facility_code: payload.facility_code.filter(|code| !code.trim().is_empty()),
+        next_run_at: next_report_run(payload.frequency, now),
+        last_snapshot_id: None,
+        created_by: ic_cdk::caller(),
+        created_at: now,
+    };
+    REPORT_SCHEDULES.with(|schedules| schedules.borrow_mut().insert(id, schedule.clone()));
+    Ok(schedule)
+}
+
+// Stop a schedule; snapshots it already produced are kept
+#[ic_cdk::update]
+fn cancel_report_schedule(id: u64) -> Result<(), Error> {
+    ensure_controller()?;
+    REPORT_SCHEDULES
+        .with(|schedules| schedules.borrow_mut().remove(&id))
+        .map(|_| ())
+        .ok_or(Error::NotFound { msg: format!("No report schedule with id={}", id) })
+}
+
+#[ic_cdk::query]
+fn get_report_schedules() -> Result<Vec<ReportSchedule>, Error> {
+    ensure_controller()?;
+    Ok(REPORT_SCHEDULES.with(|schedules| schedules.borrow().iter().map(|(_, schedule)| schedule).collect()))
+}
+
+// Snapshots produced by a schedule, newest first, without their rows
+#[ic_cdk::query]
+fn get_report_snapshots(schedule_id: u64) -> Result<Vec<ReportSnapshotHeader>, Error> {
+    ensure_sensitive_reader()?;
+    let mut snapshots: Vec<ReportSnapshotHeader> = REPORT_SNAPSHOTS.with(|snapshots| {
+        snapshots
+            .borrow()
+            .iter()
+            .map(|(_, header)| header)
+            .filter(|header| header.schedule_id == schedule_id)
+            .collect()
+    });
+    snapshots.reverse();
+    Ok(snapshots)
+}
+
+#[ic_cdk::query]
+fn get_report_snapshot(id: u64) -> Result<ReportSnapshot, Error> {
+    ensure_sensitive_reader()?;
+    let header = REPORT_SNAPSHOTS
+        .with(|snapshots| snapshots.borrow().get(&id))
+        .ok_or(Error::NotFound { msg: format!("No report snapshot with id={}", id) })?;
+    let rows = REPORT_ROWS.with(|rows| rows.borrow().range(mother_record_keys(id)).map(|(_, row)| row).collect());
+    Ok(ReportSnapshot { header, rows })
+}
+
+// Start of the period boundary after `after`
+fn next_report_run(frequency: ReportFrequency, after: u64) -> u64 {
+    let day = (after / NANOS_PER_DAY) as i64;
+    let next_day = match frequency {
+        ReportFrequency::Daily => day + 1,
+        // Day 4 of the epoch was a Monday
+        ReportFrequency::Weekly => day + 7 - (day - 4).rem_euclid(7),
+        ReportFrequency::Monthly => {
+            let (year, month, _) = civil_from_days(day);
+            if month == 12 {
+                days_from_civil(year + 1, 1, 1)
+            } else {
+                days_from_civil(year, month + 1, 1)
+            }
+        }
+    };
+    next_day as u64 * NANOS_PER_DAY
+}
+
+// Start of the period that ends at the boundary `run_at`
+fn report_period_start(frequency: ReportFrequency, run_at: u64) -> u64 {
+    match frequency {
+        ReportFrequency::Daily => run_at.saturating_sub(NANOS_PER_DAY),
+        ReportFrequency::Weekly => run_at.saturating_sub(7 * NANOS_PER_DAY),
+        ReportFrequency::Monthly => {
+            let (year, month, _) = civil_from_days((run_at / NANOS_PER_DAY) as i64 - 1);
+            days_from_civil(year, month, 1) as u64 * NANOS_PER_DAY
+        }
+    }
+}
+
+// Generate every report whose run is due. Runs hourly from a timer, so interactive queries only
+// read the stored snapshots.
+fn run_due_reports() {
+    if MAINTENANCE.with(|maintenance| maintenance.borrow().is_some()) {
+        observe_job("scheduled_reports", None);
+        return;
+    }
+    let now = time();
+    let due: Vec<ReportSchedule> = REPORT_SCHEDULES.with(|schedules| {
+        schedules
+            .borrow()
+            .iter()
+            .map(|(_, schedule)| schedule)
+            .filter(|schedule| schedule.next_run_at <= now)
+            .collect()
+    });
+    let generated = due.len() as u64;
+    for mut schedule in due {
+        let period_end = schedule.next_run_at;
+        let period_start = report_period_start(schedule.frequency, period_end);
+        let rows = match schedule.kind {
+            ReportKind::FacilityMonthlySummary => {
+                facility_summary_rows(period_start..period_end, schedule.facility_code.as_deref())
+            }
+            ReportKind::DefaulterList => defaulter_rows(period_end, schedule.facility_code.as_deref()),
+        };
+
+        let id = REPORT_SNAPSHOT_ID_SEQ.with(|counter| {
+            let next = *counter.borrow().get() + 1;
+            counter.borrow_mut().set(next).expect("Cannot update report snapshot id sequence");
+            next
+        });
+        REPORT_ROWS.with(|storage| {
+            let mut storage = storage.borrow_mut();
+            for (index, row) in rows.iter().enumerate() {
+                storage.insert(RecordKey { mother_id: id, seq: index as u64 }, row.clone());
+            }
+        });
+        let header = ReportSnapshotHeader {
+            id,
+            schedule_id: schedule.id,
+            kind: schedule.kind,
+            facility_code: schedule.facility_code.clone(),
+            period_start,
+            period_end,
+            generated_at: now,
+            row_count: rows.len() as u64,
+        };
+        REPORT_SNAPSHOTS.with(|snapshots| snapshots.borrow_mut().insert(id, header));
+
+        // A canister that was stopped skips the runs it missed rather than catching up on each
+        schedule.next_run_at = next_report_run(schedule.frequency, now.max(period_end));
+        schedule.last_snapshot_id = Some(id);
+        REPORT_SCHEDULES.with(|schedules| schedules.borrow_mut().insert(schedule.id, schedule));
+    }
+    purge_expired_report_snapshots(now);
+    observe_job("scheduled_reports", Some(generated));
+}
+
+fn facility_summary_rows(period: std::ops::Range<u64>, facility_code: Option<&str>) -> Vec<ReportRow> {
+    let mut rows: std::collections::BTreeMap<String, FacilitySummaryRow> = std::collections::BTreeMap::new();
     PROFILE_STORAGE.with(|storage| {
+        for (_, profile) in storage.borrow().iter() {
+            let registered = period.contains(&profile.created_at);
+            let delivered = profile.delivery.as_ref().is_some_and(|delivery| period.contains(&delivery.delivery_date));
+            if !registered && !delivered {
+                continue;
+            }
+            let entry = summary_row(&mut rows, profile.facility_code.clone());
+            entry.registrations += u64::from(registered);
+            entry.deliveries += u64::from(delivered);
+        }
+    });
+    HEALTH_RECORD_STORAGE.with(|storage| {
+        for (_, record) in storage.borrow().iter().filter(|(_, record)| period.contains(&record.date)) {
+            let entry = summary_row(&mut rows, record.facility_code.clone());
+            entry.visits += 1;
+            entry.critical_visits += u64::from(matches!(record.health_status, HealthStatus::Critical));
+        }
+    });
+    REFERRAL_STORAGE.with(|storage| {
+        for (_, referral) in storage.borrow().iter().filter(|(_, referral)| period.contains(&referral.created_at)) {
+            summary_row(&mut rows, Some(referral.from_facility)).referrals_made += 1;
+        }
+    });
+    rows.into_values()
+        .filter(|row| facility_code.is_none() || facility_code == Some(row.facility_code.as_str()))
+        .map(ReportRow::Facility)
+        .collect()
+}
+
+fn summary_row(
+    rows: &mut std::collections::BTreeMap<String, FacilitySummaryRow>,
+    facility: Option<String>,
+) -> &mut FacilitySummaryRow {
+    let facility = facility.unwrap_or_else(|| "UNASSIGNED".to_string());
+    rows.entry(facility.clone()).or_insert_with(|| FacilitySummaryRow {
+        facility_code: facility,
+        registrations: 0,
+        visits: 0,
+        critical_visits: 0,
+        referrals_made: 0,
+        deliveries: 0,
+    })
+}
+
+// Undelivered mothers whose latest visit booked an appointment more than the grace period before `at`
+fn defaulter_rows(at: u64, facility_code: Option<&str>) -> Vec<ReportRow> {
+    let cutoff = at.saturating_sub(DEFAULTER_GRACE_DAYS * NANOS_PER_DAY);
+    let profiles: Vec<MotherProfile> = PROFILE_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, profile)| profile)
+            .filter(|profile| profile.delivery.is_none())
+            .filter(|profile| facility_code.is_none() || profile.facility_code.as_deref() == facility_code)
+            .collect()
+    });
+    profiles
+        .into_iter()
+        .filter_map(|profile| {
+            let latest = latest_health_record(profile.id)?;
+            if latest.next_appointment == 0 || latest.next_appointment > cutoff {
+                return None;
+            }
+            Some(ReportRow::Defaulter(Defaulter {
+                mother_id: profile.id,
+                name: profile.name,
+                facility_code: profile.facility_code,
+                emergency_contact: profile.emergency_contact,
+                missed_appointment: latest.next_appointment,
+                last_visit: latest.date,
+            }))
+        })
+        .collect()
+}
+
+fn purge_expired_report_snapshots(now: u64) {
+    let cutoff = now.saturating_sub(REPORT_SNAPSHOT_TTL_DAYS * NANOS_PER_DAY);
+    let expired: Vec<u64> = REPORT_SNAPSHOTS.with(|snapshots| {
+        snapshots
+            .borrow()
+            .iter()
+            .filter(|(_, header)| header.generated_at < cutoff)
+            .map(|(id, _)| id)
+            .collect()
+    });
+    for id in expired {
+        REPORT_SNAPSHOTS.with(|snapshots| snapshots.borrow_mut().remove(&id));
+        let keys: Vec<RecordKey> =
+            REPORT_ROWS.with(|rows| rows.borrow().range(mother_record_keys(id)).map(|(key, _)| key).collect());
+        REPORT_ROWS.with(|rows| {
+            let mut rows = rows.borrow_mut();
+            for key in &keys {
+                rows.remove(key);
+            }
+        });
+    }
+}
+
+// Drop an erased mother from stored defaulter lists
+fn remove_defaulter_rows(mother_id: u64) {
+    let keys: Vec<RecordKey> = REPORT_ROWS.with(|rows| {
+        rows.borrow()
+            .iter()
+            .filter(|(_, row)| matches!(row, ReportRow::Defaulter(defaulter) if defaulter.mother_id == mother_id))
+            .map(|(key, _)| key)
+            .collect()
+    });
+    REPORT_ROWS.with(|rows| {
+        let mut rows = rows.borrow_mut();
+        for key in &keys {
+            rows.remove(key);
+        }
+    });
+}
+
+// Weeks before the checked one that form its baseline
+const ANOMALY_BASELINE_WEEKS: u64 = 8;
+// Standard deviations from the baseline mean that count as anomalous
+const ANOMALY_SD_FACTOR: f64 = 3.0;
+// Weekly counts (spikes) or baseline means (drops) below this are too small to judge
+const ANOMALY_MIN_COUNT: f64 = 5.0;
+const MAX_ANOMALIES: u64 = 500;
+
+// Monday-based week of a timestamp, counted from the epoch (a Thursday)
+fn week_of(timestamp: u64) -> u64 {
+    (timestamp / NANOS_PER_DAY + 3) / 7
+}
+
+fn week_start(week: u64) -> u64 {
+    (week * 7).saturating_sub(3) * NANOS_PER_DAY
+}
+
+// Compare each facility's counts for the last full week against the weeks before it. Runs daily
+// from a timer and does nothing once that week has been checked.
+fn run_anomaly_scan() {
+    if MAINTENANCE.with(|maintenance| maintenance.borrow().is_some()) {
+        observe_job("anomaly_scan", None);
+        return;
+    }
+    let week = week_of(time()).saturating_sub(1);
+    if week <= ANOMALY_SCAN_WEEK.with(|cell| *cell.borrow().get()) {
+        observe_job("anomaly_scan", None);
+        return;
+    }
+    let first_week = week.saturating_sub(ANOMALY_BASELINE_WEEKS);
+    let window = week_start(first_week)..week_start(week + 1);
+
+    // (facility, metric) -> count per week of the window, oldest first
+    let mut counts: std::collections::BTreeMap<(String, &str), Vec<u64>> = std::collections::BTreeMap::new();
+    let mut tally = |facility: String, metric: &'static str, at: u64| {
+        if window.contains(&at) {
+            let weeks = counts
+                .entry((facility, metric))
+                .or_insert_with(|| vec![0; (week - first_week + 1) as usize]);
+            weeks[(week_of(at) - first_week) as usize] += 1;
+        }
+    };
+    let facilities: std::collections::BTreeMap<u64, String> = PROFILE_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(id, profile)| (id, profile.facility_code.unwrap_or_else(|| "UNASSIGNED".to_string())))
+            .collect()
+    });
+    let facility_of =
+        |mother_id: u64| facilities.get(&mother_id).cloned().unwrap_or_else(|| "UNASSIGNED".to_string());
+    HEALTH_RECORD_STORAGE.with(|storage| {
+        for (_, record) in storage.borrow().iter() {
+            if matches!(record.health_status, HealthStatus::Critical) {
+                let facility = record.facility_code.clone().unwrap_or_else(|| facility_of(record.mother_id));
+                tally(facility, "critical_cases", record.date);
+            }
+        }
+    });
+    LAB_RESULT_STORAGE.with(|storage| {
+        for (_, result) in storage.borrow().iter() {
+            let anemic = HAEMOGLOBIN_TESTS.contains(&normalize_term(&result.test_name).as_str())
+                && result.value.trim().parse::<f64>().is_ok_and(|value| value < 11.0);
+            if anemic {
+                tally(facility_of(result.mother_id), "anemia_flags", result.date);
+            }
+        }
+    });
+    REFERRAL_STORAGE.with(|storage| {
+        for (_, referral) in storage.borrow().iter() {
+            tally(referral.from_facility, "referrals", referral.created_at);
+        }
+    });
+
+    let now = time();
+    let mut found = 0;
+    for ((facility_code, metric), weeks) in counts {
+        let (latest, baseline) = weeks.split_last().expect("window has weeks");
+        let mean = baseline.iter().sum::<u64>() as f64 / baseline.len() as f64;
+        let variance =
+            baseline.iter().map(|count| (*count as f64 - mean).powi(2)).sum::<f64>() / baseline.len() as f64;
+        let sd = variance.sqrt();
+        let margin = ANOMALY_SD_FACTOR * sd.max(1.0);
+        let count = *latest as f64;
+        let direction = if count >= ANOMALY_MIN_COUNT && count > mean + margin {
+            "spike"
+        } else if mean >= ANOMALY_MIN_COUNT && count < mean - margin {
+            "drop"
+        } else {
+            continue;
+        };
+        let id = ANOMALY_ID_SEQ.with(|counter| {
+            let next = *counter.borrow().get() + 1;
+            counter.borrow_mut().set(next).expect("Cannot update anomaly id sequence");
+            next
+        });
+        let anomaly = Anomaly {
+            id,
+            facility_code,
+            metric: metric.to_string(),
+            week_start: week_start(week),
+            count: *latest,
+            baseline_mean: mean,
+            baseline_sd: sd,
+            direction: direction.to_string(),
+            detected_at: now,
+        };
+        found += 1;
+        ANOMALIES.with(|anomalies| {
+            let mut anomalies = anomalies.borrow_mut();
+            anomalies.insert(id, anomaly);
+            if id > MAX_ANOMALIES {
+                anomalies.remove(&(id - MAX_ANOMALIES));
+            }
+        });
+    }
+    ANOMALY_SCAN_WEEK.with(|cell| cell.borrow_mut().set(week).expect("Cannot update anomaly scan week"));
+    observe_job("anomaly_scan", Some(found));
+}
+
+// Facility counts that departed from their baseline, newest first. A spike may be an outbreak of
+// complications or duplicate entries; a drop often means visits are not being recorded.
+#[ic_cdk::query]
+fn get_anomalies() -> Vec<Anomaly> {
+    let mut anomalies: Vec<Anomaly> =
+        ANOMALIES.with(|anomalies| anomalies.borrow().iter().map(|(_, anomaly)| anomaly).collect());
+    anomalies.reverse();
+    anomalies
+}
+
+// Count entities written before the counters existed, once
+fn backfill_counters() {
+    if COUNTERS.with(|counters| !counters.borrow().is_empty()) {
+        return;
+    }
+
+    for (key, value) in recount_counters() {
+        COUNTERS.with(|counters| counters.borrow_mut().insert(StringKey(key), value));
+    }
+}
+
+// Replace every counter with a recount, e.g. after new counters are introduced
+fn rebuild_counters() {
+    let expected = recount_counters();
+    let stale: Vec<StringKey> = COUNTERS.with(|counters| {
+        counters.borrow().iter().map(|(key, _)| key).filter(|key| !expected.contains_key(&key.0)).collect()
+    });
+    COUNTERS.with(|counters| {
+        let mut counters = counters.borrow_mut();
+        for key in &stale {
+            counters.remove(key);
+        }
+        for (key, value) in expected {
+            counters.insert(StringKey(key), value);
+        }
+    });
+}
+
+// Dashboard counters computed from scratch over all stored entities
+fn recount_counters() -> std::collections::BTreeMap<String, u64> {
+    let mut entities = Vec::new();
+    PROFILE_STORAGE.with(|s| {
+        entities.extend(s.borrow().iter().map(|(_, p)| (EntityType::MotherProfile, serde_json::to_value(p))))
+    });
+    HEALTH_RECORD_STORAGE.with(|s| {
+        entities.extend(s.borrow().iter().map(|(_, r)| (EntityType::HealthRecord, serde_json::to_value(r))))
+    });
+    REFERRAL_STORAGE.with(|s| {
+        entities.extend(s.borrow().iter().map(|(_, r)| (EntityType::Referral, serde_json::to_value(r))))
+    });
+    let mut counts = std::collections::BTreeMap::new();
+    for (entity_type, entity) in entities {
+        if let Ok(entity) = entity {
+            for key in counter_keys(entity_type, &entity) {
+                *counts.entry(key).or_insert(0) += 1;
+            }
+        }
+    }
+    let open_alerts = CHW_ALERTS.with(|alerts| {
+        alerts.borrow().iter().filter(|(_, alert)| alert.acknowledged_at.is_none()).count() as u64
+    });
+    if open_alerts > 0 {
+        counts.insert(OPEN_CHW_ALERTS_COUNTER.to_string(), open_alerts);
+    }
+    // Erased mothers still count
+    ERASURES.with(|erasures| {
+        for (_, erasure) in erasures.borrow().iter() {
+            for (key, count) in erasure.retained_counters {
+                *counts.entry(key).or_insert(0) += count;
+            }
+        }
+    });
+    counts
+}
+
+// Fields that differ between two JSON objects, as {"field": {"old": .., "new": ..}}
+fn json_diff(before: &serde_json::Value, after: &serde_json::Value) -> serde_json::Value {
+    let (Some(before), Some(after)) = (before.as_object(), after.as_object()) else {
+        return serde_json::json!({ "old": before, "new": after });
+    };
+
+    let null = serde_json::Value::Null;
+    let changed = before
+        .keys()
+        .chain(after.keys().filter(|key| !before.contains_key(*key)))
+        .filter_map(|key| {
+            let old = before.get(key).unwrap_or(&null);
+            let new = after.get(key).unwrap_or(&null);
+            (old != new).then(|| (key.clone(), serde_json::json!({ "old": old, "new": new })))
+        })
+        .collect();
+    serde_json::Value::Object(changed)
+}
+
+// Publish a change to the delta-sync feed, replacing the entity's previous entry
+fn record_change(entity_type: EntityType, id: u64, kind: ChangeKind) {
+    let seq = CHANGE_SEQ.with(|counter| {
+        let next = *counter.borrow().get() + 1;
+        counter.borrow_mut().set(next).expect("Cannot update change sequence");
+        next
+    });
+
+    let key = EntityKey::new(entity_type, id);
+    if let Some(previous) = CHANGE_INDEX.with(|index| index.borrow_mut().insert(key, seq)) {
+        CHANGE_FEED.with(|feed| feed.borrow_mut().remove(&previous));
+    }
+    CHANGE_FEED.with(|feed| {
+        feed.borrow_mut().insert(seq, ChangeEntry { entity_type, id, kind, changed_at: time() })
+    });
+}
+
+fn load_sync_entity(entity_type: EntityType, id: u64) -> Option<SyncEntity> {
+    match entity_type {
+        EntityType::MotherProfile => PROFILE_STORAGE.with(|s| s.borrow().get(&id)).map(SyncEntity::MotherProfile),
+        EntityType::HealthRecord => get_health_record(id).map(SyncEntity::HealthRecord),
+        EntityType::Referral => REFERRAL_STORAGE.with(|s| s.borrow().get(&id)).map(SyncEntity::Referral),
+        EntityType::Payment => PAYMENT_STORAGE.with(|s| s.borrow().get(&id)).map(SyncEntity::Payment),
+        EntityType::LabResult => LAB_RESULT_STORAGE.with(|s| s.borrow().get(&id)).map(SyncEntity::LabResult),
+        EntityType::SelfReport => SELF_REPORT_STORAGE.with(|s| s.borrow().get(&id)).map(SyncEntity::SelfReport),
+    }
+}
+
+#[ic_cdk::init]
+fn init(args: Option<InitArgs>) {
+    // A fresh canister starts with the current layout
+    set_schema_version(latest_schema_version());
+    apply_init_args(args.unwrap_or_default());
+    schedule_ulid_seed();
+    schedule_ussd_pin_key();
+    schedule_log_key_ratchet();
+    schedule_periodic_jobs();
+}
+
+#[ic_cdk::pre_upgrade]
+fn pre_upgrade() {
+    // Stable memory is frozen during a backup or restore, and a restored snapshot must be loaded as is
+    if MAINTENANCE.with(|maintenance| maintenance.borrow().is_some()) {
+        return;
+    }
+
+    // All state lives in stable structures, so there is nothing to save; only make sure the
+    // version stamp matches the layout this build wrote, for the next build's migrations
+    if schema_version() < latest_schema_version() {
+        set_schema_version(latest_schema_version());
+    }
+}
+
+#[ic_cdk::post_upgrade]
+fn post_upgrade(args: Option<InitArgs>) {
+    run_migrations();
+    if let Some(args) = args {
+        apply_init_args(args);
+    }
+    schedule_ulid_seed();
+    schedule_ussd_pin_key();
+    schedule_log_key_ratchet();
+    schedule_periodic_jobs();
+}
+
+// Upgrade step that brings stored data to the layout of `version`
+struct Migration {
+    version: u64,
+    description: &'static str,
+    run: fn(),
+}
+
+// Every layout change, in order. Append new migrations here, never reorder or remove: a
+// canister's schema version is the last one it has applied.
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "Move profiles and health records out of maps with the 2048-byte bound",
+        run: migrate_legacy_storage,
+    },
+    Migration {
+        version: 2,
+        description: "Publish existing entities to the change feed",
+        run: backfill_change_feed,
+    },
+    Migration {
+        version: 3,
+        description: "Compute dashboard counters from existing data",
+        run: backfill_counters,
+    },
+    Migration {
+        version: 4,
+        description: "Assign ULIDs to existing entities",
+        run: backfill_ulids,
+    },
+    Migration {
+        version: 5,
+        description: "Give each entity type its own id sequence and key the change index and tombstones by type",
+        run: split_id_sequences,
+    },
+    Migration {
+        version: 6,
+        description: "Count registrations per month, active pregnancies, appointments per day and open alerts",
+        run: rebuild_counters,
+    },
+    Migration {
+        version: 7,
+        description: "Count registrations, visits, critical visits, referrals and deliveries per day and facility",
+        run: rebuild_counters,
+    },
+    Migration {
+        version: 8,
+        description: "Index the changelog by entity for the amendment trail",
+        run: index_amendments,
+    },
+    Migration {
+        version: 9,
+        description: "Seal the changelog's diffs under per-mother keys and scrub the plaintext log",
+        run: seal_change_log,
+    },
+    Migration {
+        version: 10,
+        description: "Stamp idempotency keys with the time they were stored so they can expire",
+        run: stamp_idempotency_keys,
+    },
+];
+
+fn latest_schema_version() -> u64 {
+    MIGRATIONS.last().map_or(0, |migration| migration.version)
+}
+
+fn schema_version() -> u64 {
+    SCHEMA_VERSION.with(|version| *version.borrow().get())
+}
+
+fn set_schema_version(version: u64) {
+    SCHEMA_VERSION.with(|cell| cell.borrow_mut().set(version).expect("Cannot update schema version"));
+}
+
+// Apply the migrations this canister has not seen yet. A trap rolls back the whole upgrade,
+// so data is never left half-migrated.
+fn run_migrations() {
+    let current = schema_version();
+    if current > latest_schema_version() {
+        ic_cdk::trap(&format!(
+            "Stored schema version {} is newer than this build ({}); refusing to downgrade",
+            current,
+            latest_schema_version()
+        ));
+    }
+
+    for migration in MIGRATIONS.iter().filter(|migration| migration.version > current) {
+        ic_cdk::print(format!("Running migration {}: {}", migration.version, migration.description));
+        (migration.run)();
+        set_schema_version(migration.version);
+    }
+}
+
+// Stored schema version and the migrations this build knows about
+#[ic_cdk::query]
+fn get_schema_status() -> SchemaStatus {
+    let version = schema_version();
+    SchemaStatus {
+        version,
+        latest: latest_schema_version(),
+        migrations: MIGRATIONS
+            .iter()
+            .map(|migration| MigrationInfo {
+                version: migration.version,
+                description: migration.description.to_string(),
+                applied: migration.version <= version,
+            })
+            .collect(),
+    }
+}
+
+// Move profiles and health records out of maps with the old 2048-byte bound, and health
+// records stored under their bare id into the per-mother key space
+fn migrate_legacy_storage() {
+    let profiles: Vec<(u64, LegacyBounded<MotherProfile>)> =
+        LEGACY_PROFILE_STORAGE.with(|storage| storage.borrow().iter().collect());
+    for (id, profile) in profiles {
+        PROFILE_STORAGE.with(|storage| storage.borrow_mut().insert(id, profile.0));
+        LEGACY_PROFILE_STORAGE.with(|storage| storage.borrow_mut().remove(&id));
+    }
+
+    let records: Vec<(u64, LegacyBounded<HealthRecord>)> =
+        LEGACY_HEALTH_RECORD_STORAGE.with(|storage| storage.borrow().iter().collect());
+    for (id, record) in records {
+        store_health_record(&record.0);
+        LEGACY_HEALTH_RECORD_STORAGE.with(|storage| storage.borrow_mut().remove(&id));
+    }
+
+    let records: Vec<(RecordKey, LegacyBounded<HealthRecord>)> =
+        LEGACY_KEYED_HEALTH_RECORD_STORAGE.with(|storage| storage.borrow().iter().collect());
+    for (key, record) in records {
+        store_health_record(&record.0);
+        LEGACY_KEYED_HEALTH_RECORD_STORAGE.with(|storage| storage.borrow_mut().remove(&key));
+    }
+}
+
+// Ids used to come from one counter shared by every entity type. Each type's sequence resumes
+// after it, so no id is reused within a type, and per-entity maps are re-keyed by type.
+fn split_id_sequences() {
+    let shared = MOTHER_ID_SEQ.with(|counter| *counter.borrow().get());
+    for entity_type in ID_SEQUENCES {
+        id_sequence(entity_type).with(|counter| {
+            if *counter.borrow().get() < shared {
+                counter.borrow_mut().set(shared).expect("Cannot seed id sequence");
+            }
+        });
+    }
+
+    let index: Vec<(u64, u64)> = LEGACY_CHANGE_INDEX.with(|index| index.borrow().iter().collect());
+    for (id, seq) in index {
+        if let Some(entry) = CHANGE_FEED.with(|feed| feed.borrow().get(&seq)) {
+            let key = EntityKey::new(entry.entity_type, id);
+            if CHANGE_INDEX.with(|index| index.borrow().contains_key(&key)) {
+                // Republished by an earlier migration in this upgrade; the newer entry wins
+                CHANGE_FEED.with(|feed| feed.borrow_mut().remove(&seq));
+            } else {
+                CHANGE_INDEX.with(|index| index.borrow_mut().insert(key, seq));
+            }
+        }
+        LEGACY_CHANGE_INDEX.with(|index| index.borrow_mut().remove(&id));
+    }
+
+    let tombstones: Vec<(u64, Tombstone)> = LEGACY_TOMBSTONES.with(|storage| storage.borrow().iter().collect());
+    for (id, tombstone) in tombstones {
+        let key = EntityKey::new(tombstone.entity_type, id);
+        TOMBSTONES.with(|storage| storage.borrow_mut().insert(key, tombstone));
+        LEGACY_TOMBSTONES.with(|storage| storage.borrow_mut().remove(&id));
+    }
+}
+
+// Entities written before the change feed existed are published once on the first upgrade
+fn backfill_change_feed() {
+    if CHANGE_SEQ.with(|counter| *counter.borrow().get()) > 0 {
+        return;
+    }
+
+    let mut existing: Vec<(EntityType, u64)> = Vec::new();
+    PROFILE_STORAGE.with(|s| existing.extend(s.borrow().iter().map(|(id, _)| (EntityType::MotherProfile, id))));
+    HEALTH_RECORD_STORAGE.with(|s| existing.extend(s.borrow().iter().map(|(key, _)| (EntityType::HealthRecord, key.seq))));
+    REFERRAL_STORAGE.with(|s| existing.extend(s.borrow().iter().map(|(id, _)| (EntityType::Referral, id))));
+    PAYMENT_STORAGE.with(|s| existing.extend(s.borrow().iter().map(|(id, _)| (EntityType::Payment, id))));
+    LAB_RESULT_STORAGE.with(|s| existing.extend(s.borrow().iter().map(|(id, _)| (EntityType::LabResult, id))));
+    SELF_REPORT_STORAGE.with(|s| existing.extend(s.borrow().iter().map(|(id, _)| (EntityType::SelfReport, id))));
+    for (entity_type, id) in existing {
+        record_change(entity_type, id, ChangeKind::Upserted);
+    }
+}
+
+// Give entities written before ULIDs existed their external identifier
+fn backfill_ulids() {
+    let profiles: Vec<MotherProfile> = PROFILE_STORAGE
+        .with(|s| s.borrow().iter().filter(|(_, p)| p.ulid.is_none()).map(|(_, p)| p).collect());
+    for mut profile in profiles {
+        profile.ulid = Some(assign_ulid(EntityType::MotherProfile, profile.id));
+        PROFILE_STORAGE.with(|s| s.borrow_mut().insert(profile.id, profile.clone()));
+        record_change(EntityType::MotherProfile, profile.id, ChangeKind::Upserted);
+    }
+
+    let records: Vec<HealthRecord> = HEALTH_RECORD_STORAGE
+        .with(|s| s.borrow().iter().filter(|(_, r)| r.ulid.is_none()).map(|(_, r)| r).collect());
+    for mut record in records {
+        record.ulid = Some(assign_ulid(EntityType::HealthRecord, record.id));
+        store_health_record(&record);
+        record_change(EntityType::HealthRecord, record.id, ChangeKind::Upserted);
+    }
+
+    let referrals: Vec<Referral> = REFERRAL_STORAGE
+        .with(|s| s.borrow().iter().filter(|(_, r)| r.ulid.is_none()).map(|(_, r)| r).collect());
+    for mut referral in referrals {
+        referral.ulid = Some(assign_ulid(EntityType::Referral, referral.id));
+        REFERRAL_STORAGE.with(|s| s.borrow_mut().insert(referral.id, referral.clone()));
+        record_change(EntityType::Referral, referral.id, ChangeKind::Upserted);
+    }
+
+    let payments: Vec<Payment> = PAYMENT_STORAGE
+        .with(|s| s.borrow().iter().filter(|(_, p)| p.ulid.is_none()).map(|(_, p)| p).collect());
+    for mut payment in payments {
+        payment.ulid = Some(assign_ulid(EntityType::Payment, payment.id));
+        PAYMENT_STORAGE.with(|s| s.borrow_mut().insert(payment.id, payment.clone()));
+        record_change(EntityType::Payment, payment.id, ChangeKind::Upserted);
+    }
+
+    let lab_results: Vec<LabResult> = LAB_RESULT_STORAGE
+        .with(|s| s.borrow().iter().filter(|(_, l)| l.ulid.is_none()).map(|(_, l)| l).collect());
+    for mut lab_result in lab_results {
+        lab_result.ulid = Some(assign_ulid(EntityType::LabResult, lab_result.id));
+        LAB_RESULT_STORAGE.with(|s| s.borrow_mut().insert(lab_result.id, lab_result.clone()));
+        record_change(EntityType::LabResult, lab_result.id, ChangeKind::Upserted);
+    }
+}
+
+// Look up any entity by its ULID
+#[ic_cdk::update]
+fn get_entity_by_ulid(ulid: String) -> Result<SyncEntity, Error> {
+    let not_found = || Error::NotFound {
+        msg: format!("No entity with ulid={}", ulid),
+    };
+    let target = ULID_INDEX
+        .with(|index| index.borrow().get(&StringKey(ulid.trim().to_uppercase())))
+        .ok_or_else(not_found)?;
+    let entity = load_sync_entity(target.entity_type, target.id).ok_or_else(not_found)?;
+    let mother_id = match &entity {
+        SyncEntity::MotherProfile(profile) => profile.id,
+        SyncEntity::HealthRecord(record) => record.mother_id,
+        SyncEntity::Referral(referral) => referral.mother_id,
+        SyncEntity::Payment(payment) => payment.mother_id,
+        SyncEntity::LabResult(result) => result.mother_id,
+        SyncEntity::SelfReport(report) => report.mother_id,
+    };
+    log_read(mother_id, "get_entity_by_ulid");
+    Ok(entity)
+}
+
+const MAX_EXTERNAL_IDENTIFIERS: usize = 20;
+
+// Record the identifier a mother has in another system, so integrations can match her across
+// systems. An identifier belongs to one mother, and a mother has one identifier per system.
+#[ic_cdk::update]
+fn add_external_identifier(mother_id: u64, system: String, value: String) -> Result<Vec<ExternalIdentifier>, Error> {
+    ensure_mother_exists(mother_id)?;
+    let (system, value) = (system.trim().to_string(), value.trim().to_string());
+    if system.is_empty() || system.len() > 200 || system.contains(char::is_whitespace) || value.is_empty() || value.len() > 100 {
+        return Err(Error::InvalidInput {
+            msg: "The system must be 1 to 200 characters without spaces, and the value 1 to 100".to_string(),
+        });
+    }
+    let key = external_identifier_key(&system, &value);
+    match EXTERNAL_IDENTIFIER_INDEX.with(|index| index.borrow().get(&key)) {
+        Some(owner) if owner == mother_id => return Ok(external_identifiers(mother_id)),
+        Some(owner) => {
+            return Err(Error::Conflict {
+                msg: format!("{} {} already identifies mother {}", system, value, owner),
+            })
+        }
+        None => {}
+    }
+
+    let mut stored = EXTERNAL_IDENTIFIERS.with(|storage| storage.borrow().get(&mother_id)).unwrap_or_default();
+    if let Some(existing) = stored.identifiers.iter().find(|identifier| identifier.system == system) {
+        return Err(Error::Conflict {
+            msg: format!("Mother {} already has {} {}; remove it first", mother_id, system, existing.value),
+        });
+    }
+    if stored.identifiers.len() >= MAX_EXTERNAL_IDENTIFIERS {
+        return Err(Error::InvalidInput {
+            msg: format!("A mother can have at most {} external identifiers", MAX_EXTERNAL_IDENTIFIERS),
+        });
+    }
+    stored.identifiers.push(ExternalIdentifier {
+        system,
+        value,
+        added_by: ic_cdk::caller(),
+        added_at: time(),
+    });
+    EXTERNAL_IDENTIFIERS.with(|storage| storage.borrow_mut().insert(mother_id, stored.clone()));
+    EXTERNAL_IDENTIFIER_INDEX.with(|index| index.borrow_mut().insert(key, mother_id));
+    Ok(stored.identifiers)
+}
+
+// Remove a mother's identifier in a system
+#[ic_cdk::update]
+fn remove_external_identifier(mother_id: u64, system: String) -> Result<Vec<ExternalIdentifier>, Error> {
+    let mut stored = EXTERNAL_IDENTIFIERS.with(|storage| storage.borrow().get(&mother_id)).unwrap_or_default();
+    let position = stored
+        .identifiers
+        .iter()
+        .position(|identifier| identifier.system == system.trim())
+        .ok_or_else(|| Error::NotFound {
+            msg: format!("Mother {} has no identifier in {}", mother_id, system.trim()),
+        })?;
+    let removed = stored.identifiers.remove(position);
+    EXTERNAL_IDENTIFIER_INDEX.with(|index| index.borrow_mut().remove(&external_identifier_key(&removed.system, &removed.value)));
+    EXTERNAL_IDENTIFIERS.with(|storage| {
+        if stored.identifiers.is_empty() {
+            storage.borrow_mut().remove(&mother_id);
+        } else {
+            storage.borrow_mut().insert(mother_id, stored.clone());
+        }
+    });
+    Ok(stored.identifiers)
+}
+
+#[ic_cdk::query]
+fn get_external_identifiers(mother_id: u64) -> Vec<ExternalIdentifier> {
+    external_identifiers(mother_id)
+}
+
+// Look up the mother an identifier from another system belongs to
+#[ic_cdk::update]
+fn get_mother_by_external_identifier(system: String, value: String) -> Result<MotherProfile, Error> {
+    let profile = external_identifier_mother(system.trim(), value.trim())
+        .and_then(|mother_id| load_mother_profile(mother_id).ok())
+        .ok_or_else(|| Error::NotFound {
+            msg: format!("No mother with {} {}", system.trim(), value.trim()),
+        })?;
+    log_read(profile.id, "get_mother_by_external_identifier");
+    Ok(profile)
+}
+
+fn external_identifiers(mother_id: u64) -> Vec<ExternalIdentifier> {
+    EXTERNAL_IDENTIFIERS
+        .with(|storage| storage.borrow().get(&mother_id))
+        .map(|stored| stored.identifiers)
+        .unwrap_or_default()
+}
+
+fn external_identifier_mother(system: &str, value: &str) -> Option<u64> {
+    EXTERNAL_IDENTIFIER_INDEX.with(|index| index.borrow().get(&external_identifier_key(system, value)))
+}
+
+// Systems and values can be longer than a StringKey holds, so the index is keyed by their hash
+fn external_identifier_key(system: &str, value: &str) -> StringKey {
+    let mut hasher = Sha256::new();
+    hasher.update(system.as_bytes());
+    hasher.update([0]);
+    hasher.update(value.as_bytes());
+    StringKey(hasher.finalize().iter().map(|byte| format!("{:02x}", byte)).collect())
+}
+
+fn remove_external_identifiers(mother_id: u64) {
+    for identifier in external_identifiers(mother_id) {
+        EXTERNAL_IDENTIFIER_INDEX.with(|index| index.borrow_mut().remove(&external_identifier_key(&identifier.system, &identifier.value)));
+    }
+    EXTERNAL_IDENTIFIERS.with(|storage| storage.borrow_mut().remove(&mother_id));
+}
+
+// Generate and index a ULID: 48-bit millisecond timestamp + 80 bits derived from the
+// raw_rand seed, this canister's id and the entity id (unique within the canister)
+fn assign_ulid(entity_type: EntityType, id: u64) -> String {
+    let ulid = new_ulid(entity_type, id);
+    index_ulid(&ulid, entity_type, id);
+    ulid
+}
+
+// A ULID for an entity, not yet indexed; stage index_ulid with the entity's own write
+fn new_ulid(entity_type: EntityType, id: u64) -> String {
+    let seed = ULID_SEED.with(|seed| seed.borrow().get().seed.clone());
+    let mut hasher = Sha256::new();
+    hasher.update(&seed);
+    hasher.update(ic_cdk::id().as_slice());
+    // Ids are only unique within an entity type
+    hasher.update([EntityKey::new(entity_type, id).tag]);
+    hasher.update(id.to_be_bytes());
+    let random = hasher.finalize();
+    encode_ulid(time() / 1_000_000, &random[..10])
+}
+
+// 48-bit millisecond timestamp and 80 random bits in Crockford base32
+fn encode_ulid(millis: u64, random: &[u8]) -> String {
+    let value = random[..10]
+        .iter()
+        .fold(millis as u128 & 0xFFFF_FFFF_FFFF, |value, byte| (value << 8) | *byte as u128);
+
+    const CROCKFORD: &[u8; 32] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+    (0..26)
+        .map(|i| CROCKFORD[((value >> (125 - 5 * i)) & 0x1F) as usize] as char)
+        .collect()
+}
+
+fn index_ulid(ulid: &str, entity_type: EntityType, id: u64) {
+    ULID_INDEX.with(|index| {
+        index.borrow_mut().insert(StringKey(ulid.to_string()), EntityRef { entity_type, id })
+    });
+}
+
+// Keep the ULID of an imported entity so it stays the same across canisters,
+// unless it is already in use here (e.g. a bundle imported back into its source).
+// Not yet indexed; stage index_ulid with the entity's own write.
+fn adopt_ulid(entity_type: EntityType, id: u64, ulid: Option<String>) -> String {
+    match ulid {
+        Some(ulid)
+            if ulid.len() == 26
+                && !ULID_INDEX.with(|index| index.borrow().contains_key(&StringKey(ulid.clone()))) =>
+        {
+            ulid
+        }
+        _ => new_ulid(entity_type, id),
+    }
+}
+
+// Fetch the ULID seed from raw_rand once; calls cannot be made from init/post_upgrade directly
+fn schedule_ulid_seed() {
+    if ULID_SEED.with(|seed| !seed.borrow().get().seed.is_empty()) {
+        return;
+    }
+    ic_cdk_timers::set_timer(std::time::Duration::ZERO, || {
+        ic_cdk::spawn(async {
+            if let Ok((random,)) = ic_cdk::api::management_canister::main::raw_rand().await {
+                ULID_SEED.with(|seed| seed.borrow_mut().set(UlidSeed { seed: random }).ok());
+            }
+        })
+    });
+}
+
+// Generate the USSD PIN key from raw_rand once, the same way as the ULID seed
+fn schedule_ussd_pin_key() {
+    if USSD_PIN_KEY.with(|key| !key.borrow().get().key.is_empty()) {
+        return;
+    }
+    ic_cdk_timers::set_timer(std::time::Duration::ZERO, || {
+        ic_cdk::spawn(async {
+            if let Ok((random,)) = ic_cdk::api::management_canister::main::raw_rand().await {
+                USSD_PIN_KEY.with(|key| {
+                    if key.borrow().get().key.is_empty() {
+                        key.borrow_mut().set(SigningKey { key: random }).ok();
+                    }
+                });
+            }
+        })
+    });
+}
+
+thread_local! {
+    // (method, "ok" or the error variant) -> calls since the last upgrade
+    static CALL_METRICS: RefCell<std::collections::BTreeMap<(&'static str, &'static str), u64>> =
+        const { RefCell::new(std::collections::BTreeMap::new()) };
+
+    // Periodic job -> runs since the last upgrade
+    static JOB_METRICS: RefCell<std::collections::BTreeMap<&'static str, JobRuns>> =
+        const { RefCell::new(std::collections::BTreeMap::new()) };
+}
+
+// Outcome of a periodic job's runs, kept on the heap for /metrics
+#[derive(Clone, Copy, Default)]
+struct JobRuns {
+    runs: u64,
+    skipped: u64,
+    last_run_at: u64,
+    // Items the last run produced, e.g. anomalies or snapshots
+    last_items: u64,
+}
+
+// Count a call to `method` by its outcome and pass the result through
+fn observe_call<T>(method: &'static str, result: Result<T, Error>) -> Result<T, Error> {
+    let outcome = result.as_ref().map_or_else(Error::variant_name, |_| "ok");
+    CALL_METRICS.with(|metrics| *metrics.borrow_mut().entry((method, outcome)).or_insert(0) += 1);
+    result
+}
+
+// Record a periodic job's run; `None` items means it had nothing to do
+fn observe_job(job: &'static str, items: Option<u64>) {
+    JOB_METRICS.with(|metrics| {
+        let mut metrics = metrics.borrow_mut();
+        let runs = metrics.entry(job).or_default();
+        match items {
+            Some(items) => {
+                runs.runs += 1;
+                runs.last_run_at = time();
+                runs.last_items = items;
+            }
+            None => runs.skipped += 1,
+        }
+    });
+}
+
+// Timers do not survive upgrades, so the periodic jobs are set up again each time
+fn schedule_periodic_jobs() {
+    ic_cdk_timers::set_timer_interval(std::time::Duration::from_secs(24 * 60 * 60), run_anomaly_scan);
+    ic_cdk_timers::set_timer_interval(std::time::Duration::from_secs(60 * 60), run_due_reports);
+    ic_cdk_timers::set_timer_interval(std::time::Duration::from_secs(24 * 60 * 60), purge_deleted);
+    ic_cdk_timers::set_timer_interval(std::time::Duration::from_secs(24 * 60 * 60), run_orphan_scan);
+    ic_cdk_timers::set_timer_interval(std::time::Duration::from_secs(60 * 60), run_clinician_task_scan);
+    ic_cdk_timers::set_timer_interval(std::time::Duration::from_secs(5 * 60), run_sms_dispatch);
+}
+
+const WASM_PAGE_SIZE: u64 = 64 * 1024;
+
+// Snapshot chunk size; a chunk plus Candid overhead stays under the 2MB message limit
+const BACKUP_CHUNK_SIZE: u64 = 1024 * 1024;
+
+fn backup_cipher(key: &[u8]) -> Result<XChaCha20Poly1305, Error> {
+    XChaCha20Poly1305::new_from_slice(key).map_err(|_| Error::InvalidInput {
+        msg: "Backup key must be 32 bytes".to_string(),
+    })
+}
+
+// Associated data binding the wrapped key to the rest of the manifest
+fn manifest_aad(manifest: &BackupManifest) -> Vec<u8> {
+    let mut aad = manifest.source_canister.as_slice().to_vec();
+    for value in [
+        manifest.created_at,
+        manifest.schema_version,
+        manifest.size_bytes,
+        manifest.chunk_size,
+        manifest.chunk_count,
+    ] {
+        aad.extend_from_slice(&value.to_be_bytes());
+    }
+    aad.extend_from_slice(&manifest.nonce);
+    aad
+}
+
+fn wrap_backup_key(key: &[u8], manifest: &BackupManifest, data_key: &[u8; 32]) -> Result<Vec<u8>, Error> {
+    backup_cipher(key)?
+        .encrypt(XNonce::from_slice(&manifest.nonce), Payload { msg: data_key, aad: &manifest_aad(manifest) })
+        .map_err(|_| Error::SystemError { msg: "Failed to wrap the backup key".to_string() })
+}
+
+// The manifest's data key, opened with the operator's key
+fn unwrap_backup_key(key: &[u8], manifest: &BackupManifest) -> Result<[u8; 32], Error> {
+    if manifest.nonce.len() != 24 {
+        return Err(Error::InvalidInput {
+            msg: "Not a backup manifest of this version".to_string(),
+        });
+    }
+    let opened = backup_cipher(key)?
+        .decrypt(
+            XNonce::from_slice(&manifest.nonce),
+            Payload { msg: &manifest.wrapped_key, aad: &manifest_aad(manifest) },
+        )
+        .map_err(|_| Error::AuthorizationError {
+            msg: "Wrong backup key, or the manifest was altered".to_string(),
+        })?;
+    opened.try_into().map_err(|_| Error::InvalidInput {
+        msg: "The manifest's data key is malformed".to_string(),
+    })
+}
+
+// Each snapshot has its own data key, so the chunk index is a unique nonce
+fn chunk_nonce(index: u64) -> XNonce {
+    let mut nonce = XNonce::default();
+    nonce[16..].copy_from_slice(&index.to_be_bytes());
+    nonce
+}
+
+fn seal_chunk(data_key: &[u8; 32], index: u64, mut data: Vec<u8>) -> BackupChunk {
+    let tag = XChaCha20Poly1305::new(data_key.into())
+        .encrypt_in_place_detached(&chunk_nonce(index), &[], &mut data)
+        .expect("Chunks are far below the cipher's length limit");
+    BackupChunk { index, data, tag: tag.to_vec() }
+}
+
+fn open_chunk(data_key: &[u8; 32], chunk: BackupChunk) -> Result<Vec<u8>, Error> {
+    let BackupChunk { index, mut data, tag } = chunk;
+    let corrupt = || Error::ValidationError {
+        msg: format!("Chunk {} is corrupt or was altered", index),
+    };
+    if tag.len() != 16 {
+        return Err(corrupt());
+    }
+    XChaCha20Poly1305::new(data_key.into())
+        .decrypt_in_place_detached(&chunk_nonce(index), &[], &mut data, Tag::from_slice(&tag))
+        .map_err(|_| corrupt())?;
+    Ok(data)
+}
+
+async fn random_bytes() -> Result<Vec<u8>, Error> {
+    let (random,) = ic_cdk::api::management_canister::main::raw_rand()
+        .await
+        .map_err(|(_, msg)| Error::SystemError { msg: format!("Failed to get randomness: {}", msg) })?;
+    Ok(random)
+}
+
+// Freeze writes and describe an encrypted snapshot of all stable memory (controllers only).
+// Read it with get_backup_chunk, then call finish_maintenance to accept writes again. The
+// operator's key is only used here, to wrap the snapshot's fresh data key.
+#[ic_cdk::update]
+async fn start_backup(key: Vec<u8>) -> Result<BackupManifest, Error> {
+    ensure_controller()?;
+    backup_cipher(&key)?;
+
+    let data_key: [u8; 32] = random_bytes().await?[..32].try_into().expect("raw_rand returns 32 bytes");
+    let nonce = random_bytes().await?[..24].to_vec();
+    if MAINTENANCE.with(|maintenance| maintenance.borrow().is_some()) {
+        return Err(Error::Conflict {
+            msg: "A backup or restore is already in progress".to_string(),
+        });
+    }
+
+    let size_bytes = ic_cdk::api::stable::stable64_size() * WASM_PAGE_SIZE;
+    let mut manifest = BackupManifest {
+        source_canister: ic_cdk::id(),
+        created_at: time(),
+        schema_version: schema_version(),
+        nonce,
+        size_bytes,
+        chunk_size: BACKUP_CHUNK_SIZE,
+        chunk_count: size_bytes.div_ceil(BACKUP_CHUNK_SIZE),
+        wrapped_key: Vec::new(),
+    };
+    manifest.wrapped_key = wrap_backup_key(&key, &manifest, &data_key)?;
+    MAINTENANCE.with(|maintenance| {
+        *maintenance.borrow_mut() = Some(Maintenance::Backup { manifest: manifest.clone(), data_key })
+    });
+    Ok(manifest)
+}
+
+// Encrypted chunk `index` of the running backup (controllers only)
+#[ic_cdk::query]
+fn get_backup_chunk(index: u64) -> Result<BackupChunk, Error> {
+    ensure_controller()?;
+    let backup = MAINTENANCE.with(|maintenance| match &*maintenance.borrow() {
+        Some(Maintenance::Backup { manifest, data_key }) => Some((manifest.clone(), *data_key)),
+        _ => None,
+    });
+    let (manifest, data_key) = backup.ok_or(Error::NotFound {
+        msg: "No backup is in progress; call start_backup".to_string(),
+    })?;
+    if index >= manifest.chunk_count {
+        return Err(Error::InvalidInput {
+            msg: format!("The backup has {} chunks", manifest.chunk_count),
+        });
+    }
+
+    let offset = index * manifest.chunk_size;
+    let mut data = vec![0; manifest.chunk_size.min(manifest.size_bytes - offset) as usize];
+    ic_cdk::api::stable::stable64_read(offset, &mut data);
+    Ok(seal_chunk(&data_key, index, data))
+}
+
+// Prepare this canister to receive a snapshot (controllers only). It must be freshly installed;
+// writes are refused from here until the canister is upgraded after finish_restore.
+#[ic_cdk::update]
+fn start_restore(manifest: BackupManifest, key: Vec<u8>) -> Result<(), Error> {
+    ensure_controller()?;
+    let data_key = unwrap_backup_key(&key, &manifest)?;
+    if MAINTENANCE.with(|maintenance| maintenance.borrow().is_some()) {
+        return Err(Error::Conflict {
+            msg: "A backup or restore is already in progress".to_string(),
+        });
+    }
+    let empty = PROFILE_STORAGE.with(|storage| storage.borrow().is_empty())
+        && ID_SEQUENCES
+            .iter()
+            .all(|entity_type| id_sequence(*entity_type).with(|counter| *counter.borrow().get()) == 0);
+    if !empty {
+        return Err(Error::InvalidInput {
+            msg: "Snapshots can only be restored into a freshly installed canister".to_string(),
+        });
+    }
+    if manifest.schema_version > latest_schema_version() {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "The snapshot has schema version {}, newer than this build ({})",
+                manifest.schema_version,
+                latest_schema_version()
+            ),
+        });
+    }
+
+    let pages = ic_cdk::api::stable::stable64_size();
+    let needed = manifest.size_bytes.div_ceil(WASM_PAGE_SIZE);
+    if needed > pages {
+        ic_cdk::api::stable::stable64_grow(needed - pages).map_err(|e| Error::SystemError {
+            msg: format!("Failed to grow stable memory: {:?}", e),
+        })?;
+    }
+
+    let received = vec![false; manifest.chunk_count as usize];
+    MAINTENANCE.with(|maintenance| {
+        *maintenance.borrow_mut() = Some(Maintenance::Restore { manifest, data_key, received })
+    });
+    Ok(())
+}
+
+// Verify, decrypt and write one chunk of the snapshot being restored (controllers only)
+#[ic_cdk::update]
+fn put_restore_chunk(chunk: BackupChunk) -> Result<(), Error> {
+    ensure_controller()?;
+    MAINTENANCE.with(|maintenance| {
+        let mut maintenance = maintenance.borrow_mut();
+        let Some(Maintenance::Restore { manifest, data_key, received }) = &mut *maintenance else {
+            return Err(Error::NotFound {
+                msg: "No restore is in progress; call start_restore".to_string(),
+            });
+        };
+        let offset = chunk.index.saturating_mul(manifest.chunk_size);
+        let expected_len = manifest.chunk_size.min(manifest.size_bytes.saturating_sub(offset));
+        if chunk.index >= manifest.chunk_count || chunk.data.len() as u64 != expected_len {
+            return Err(Error::InvalidInput {
+                msg: format!("Chunk {} does not belong to this snapshot", chunk.index),
+            });
+        }
+        let index = chunk.index as usize;
+        let data = open_chunk(data_key, chunk)?;
+        ic_cdk::api::stable::stable64_write(offset, &data);
+        received[index] = true;
+        Ok(())
+    })
+}
+
+// End a backup and accept writes again, or check that a restore is complete (controllers only).
+// A restored canister must then be upgraded (same wasm, mode upgrade) to load the snapshot.
+#[ic_cdk::update]
+fn finish_maintenance() -> Result<String, Error> {
+    ensure_controller()?;
+    MAINTENANCE.with(|maintenance| {
+        let mut maintenance = maintenance.borrow_mut();
+        match &*maintenance {
+            None => Err(Error::NotFound {
+                msg: "No backup or restore is in progress".to_string(),
+            }),
+            Some(Maintenance::Backup { .. }) => {
+                *maintenance = None;
+                Ok("Backup finished; writes are accepted again".to_string())
+            }
+            Some(Maintenance::Restore { received, .. }) => {
+                let missing = received.iter().filter(|received| !**received).count();
+                if missing > 0 {
+                    return Err(Error::InvalidInput {
+                        msg: format!("{} chunks have not been restored yet", missing),
+                    });
+                }
+                Ok("Restore complete; upgrade the canister to load the restored data".to_string())
+            }
+        }
+    })
+}
+
+// Scan storage for broken references and index inconsistencies (controllers only). With
+// `repair`, indexes, derived state and counters are fixed; orphaned clinical data and payments
+// are only reported, since deleting them needs a person's judgement.
+#[ic_cdk::update]
+fn check_invariants(repair: bool) -> Result<InvariantReport, Error> {
+    ensure_controller()?;
+
+    let mut issues = Vec::new();
+    let mut report = |check: InvariantCheck, id: Option<u64>, detail: String, repaired: bool| {
+        issues.push(InvariantIssue { check, id, detail, repaired });
+    };
+    let mother_exists = |id: u64| {
+        PROFILE_STORAGE.with(|storage| storage.borrow().contains_key(&id))
+            || DELETED_PROFILES.with(|deleted| deleted.borrow().contains_key(&id))
+    };
+
+    let records: Vec<HealthRecord> =
+        HEALTH_RECORD_STORAGE.with(|storage| storage.borrow().iter().map(|(_, record)| record).collect());
+    for record in &records {
+        let indexed = HEALTH_RECORD_MOTHERS.with(|index| index.borrow().get(&record.id));
+        if indexed != Some(record.mother_id) {
+            if repair {
+                HEALTH_RECORD_MOTHERS.with(|index| index.borrow_mut().insert(record.id, record.mother_id));
+            }
+            report(
+                InvariantCheck::RecordIndexMismatch,
+                Some(record.id),
+                format!(
+                    "Health record id={} is indexed under mother {:?}, stored under {}",
+                    record.id, indexed, record.mother_id
+                ),
+                repair,
+            );
+        }
+    }
+
+    let indexed: Vec<(u64, u64)> = HEALTH_RECORD_MOTHERS.with(|index| index.borrow().iter().collect());
+    for (record_id, mother_id) in indexed {
+        let key = RecordKey { mother_id, seq: record_id };
+        if !HEALTH_RECORD_STORAGE.with(|storage| storage.borrow().contains_key(&key)) {
+            if repair {
+                HEALTH_RECORD_MOTHERS.with(|index| index.borrow_mut().remove(&record_id));
+            }
+            report(
+                InvariantCheck::StaleRecordIndex,
+                Some(record_id),
+                format!("Index entry for missing health record id={}", record_id),
+                repair,
+            );
+        }
+    }
+
+    for orphan in find_orphans() {
+        report(orphan.check, orphan.id, orphan.detail, false);
+    }
+
+    let ulids: Vec<(StringKey, EntityRef)> = ULID_INDEX.with(|index| index.borrow().iter().collect());
+    for (ulid, entity) in ulids {
+        if load_sync_entity(entity.entity_type, entity.id).is_none() {
+            if repair {
+                ULID_INDEX.with(|index| index.borrow_mut().remove(&ulid));
+            }
+            report(
+                InvariantCheck::DanglingUlid,
+                Some(entity.id),
+                format!("ULID {} points at missing {:?} id={}", ulid.0, entity.entity_type, entity.id),
+                repair,
+            );
+        }
+    }
+
+    let change_index: Vec<(EntityKey, u64)> = CHANGE_INDEX.with(|index| index.borrow().iter().collect());
+    for (key, seq) in change_index {
+        let entry = CHANGE_FEED.with(|feed| feed.borrow().get(&seq));
+        let consistent = matches!(entry, Some(entry) if EntityKey::new(entry.entity_type, entry.id) == key);
+        if !consistent {
+            if repair {
+                CHANGE_INDEX.with(|index| index.borrow_mut().remove(&key));
+            }
+            report(
+                InvariantCheck::DanglingChangeIndex,
+                Some(key.id),
+                format!("Change index entry for id={} points at missing feed entry {}", key.id, seq),
+                repair,
+            );
+        }
+    }
+
+    let progress: Vec<u64> = CARE_PROGRESS.with(|s| s.borrow().iter().map(|(id, _)| id).collect());
+    for id in progress.into_iter().filter(|id| !mother_exists(*id)) {
+        if repair {
+            CARE_PROGRESS.with(|s| s.borrow_mut().remove(&id));
+        }
+        report(InvariantCheck::OrphanCareProgress, Some(id), format!("Care progress of missing mother id={}", id), repair);
+    }
+    let clocks: Vec<u64> = PROFILE_FIELD_CLOCKS.with(|s| s.borrow().iter().map(|(id, _)| id).collect());
+    for id in clocks.into_iter().filter(|id| !mother_exists(*id)) {
+        if repair {
+            PROFILE_FIELD_CLOCKS.with(|s| s.borrow_mut().remove(&id));
+        }
+        report(InvariantCheck::OrphanFieldClocks, Some(id), format!("Field clocks of missing mother id={}", id), repair);
+    }
+
+    let expected = recount_counters();
+    let stored: Vec<(StringKey, u64)> = COUNTERS.with(|counters| counters.borrow().iter().collect());
+    let mut keys: Vec<String> = stored.iter().map(|(key, _)| key.0.clone()).collect();
+    keys.extend(expected.keys().cloned());
+    keys.sort();
+    keys.dedup();
+    for key in keys {
+        let actual = stored.iter().find(|(stored, _)| stored.0 == key).map_or(0, |(_, value)| *value);
+        let recount = expected.get(&key).copied().unwrap_or(0);
+        if actual != recount {
+            if repair {
+                COUNTERS.with(|counters| counters.borrow_mut().insert(StringKey(key.clone()), recount));
+            }
+            report(
+                InvariantCheck::CounterDrift,
+                None,
+                format!("Counter {} is {}, recount gives {}", key, actual, recount),
+                repair,
+            );
+        }
+    }
+
+    let repaired = issues.iter().filter(|issue| issue.repaired).count() as u64;
+    Ok(InvariantReport {
+        checked_at: time(),
+        profiles_scanned: PROFILE_STORAGE.with(|storage| storage.borrow().len()),
+        health_records_scanned: records.len() as u64,
+        issues,
+        repaired,
+    })
+}
+
+// Clinical data, payments and messages whose mother no longer exists. A soft-deleted mother
+// still exists until she is purged.
+fn find_orphans() -> Vec<InvariantIssue> {
+    let mother_exists = |id: u64| {
+        PROFILE_STORAGE.with(|storage| storage.borrow().contains_key(&id))
+            || DELETED_PROFILES.with(|deleted| deleted.borrow().contains_key(&id))
+    };
+    let mut references: Vec<(InvariantCheck, &str, u64, u64)> = Vec::new();
+    for storage in [&HEALTH_RECORD_STORAGE, &DELETED_RECORDS] {
+        storage.with(|s| {
+            references.extend(
+                s.borrow().iter().map(|(key, _)| (InvariantCheck::OrphanHealthRecord, "Health record", key.seq, key.mother_id)),
+            )
+        });
+    }
+    LAB_RESULT_STORAGE.with(|s| {
+        references.extend(s.borrow().iter().map(|(id, r)| (InvariantCheck::OrphanLabResult, "Lab result", id, r.mother_id)))
+    });
+    REFERRAL_STORAGE.with(|s| {
+        references.extend(s.borrow().iter().map(|(id, r)| (InvariantCheck::OrphanReferral, "Referral", id, r.mother_id)))
+    });
+    PAYMENT_STORAGE.with(|s| {
+        references.extend(s.borrow().iter().map(|(id, p)| (InvariantCheck::OrphanPayment, "Payment", id, p.mother_id)))
+    });
+    PRESCRIPTIONS.with(|s| {
+        references.extend(s.borrow().iter().map(|(id, p)| (InvariantCheck::OrphanPrescription, "Prescription", id, p.mother_id)))
+    });
+    SELF_REPORT_STORAGE.with(|s| {
+        references.extend(s.borrow().iter().map(|(id, r)| (InvariantCheck::OrphanSelfReport, "Self report", id, r.mother_id)))
+    });
+    CHW_ALERTS.with(|s| {
+        references.extend(s.borrow().iter().map(|(id, a)| (InvariantCheck::OrphanChwAlert, "CHW alert", id, a.mother_id)))
+    });
+    MESSAGES.with(|s| {
+        references.extend(s.borrow().iter().map(|(key, _)| (InvariantCheck::OrphanMessage, "Message", key.seq, key.mother_id)))
+    });
+
+    references
+        .into_iter()
+        .filter(|(_, _, _, mother_id)| !mother_exists(*mother_id))
+        .map(|(check, entity, id, mother_id)| InvariantIssue {
+            check,
+            id: Some(id),
+            detail: format!("{} id={} belongs to missing mother id={}", entity, id, mother_id),
+            repaired: false,
+        })
+        .collect()
+}
+
+const MAX_ORPHANS_REPORTED: usize = 500;
+
+// Daily: look for orphans, e.g. left behind by writes made before insert-time checks existed
+fn run_orphan_scan() {
+    if MAINTENANCE.with(|maintenance| maintenance.borrow().is_some()) {
+        observe_job("orphan_scan", None);
+        return;
+    }
+    let mut orphans = find_orphans();
+    let total = orphans.len() as u64;
+    orphans.truncate(MAX_ORPHANS_REPORTED);
+    let report = OrphanReport {
+        scanned_at: time(),
+        total,
+        orphans,
+    };
+    ORPHAN_REPORT.with(|cell| cell.borrow_mut().set(report).expect("Cannot store orphan report"));
+    observe_job("orphan_scan", Some(total));
+}
+
+// Findings of the last daily orphan scan; none before the first run (controllers and admins)
+#[ic_cdk::query]
+fn get_orphan_report() -> Result<Option<OrphanReport>, Error> {
+    ensure_controller()?;
+    let report = ORPHAN_REPORT.with(|cell| cell.borrow().get().clone());
+    Ok(if report.scanned_at == 0 { None } else { Some(report) })
+}
+
+const DEMO_FACILITIES: [&str; 3] = ["DEMO-KISUMU", "DEMO-NAKURU", "DEMO-MOMBASA"];
+const DEMO_FIRST_NAMES: [&str; 12] = [
+    "Achieng", "Wanjiru", "Amina", "Nafula", "Chebet", "Atieno",
+    "Mumbua", "Zawadi", "Njeri", "Akinyi", "Halima", "Wairimu",
+];
+const DEMO_LAST_NAMES: [&str; 10] = [
+    "Otieno", "Kamau", "Hassan", "Wekesa", "Kiprop", "Odhiambo", "Mutua", "Mwangi", "Omondi", "Ali",
+];
+const DEMO_HISTORY: [&str; 5] = ["Anaemia", "Previous C-section", "Asthma", "Malaria in pregnancy", "Gestational diabetes"];
+const DEMO_SYMPTOMS: [&str; 6] = ["nausea", "fatigue", "swelling", "headache", "back pain", "dizziness"];
+
+// xorshift64: reproducible within one seeding call, no randomness beacon needed for fake data
+struct DemoRng(u64);
+
+impl DemoRng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn range(&mut self, low: u64, high: u64) -> u64 {
+        low + self.next() % (high - low + 1)
+    }
+
+    fn pick<'a>(&mut self, items: &[&'a str]) -> &'a str {
+        items[(self.next() % items.len() as u64) as usize]
+    }
+
+    fn chance(&mut self, percent: u64) -> bool {
+        self.next() % 100 < percent
+    }
+}
+
+// Generate synthetic mothers with a visit history and upcoming appointments (controllers only,
+// demo_data flag). Demo mothers are registered at DEMO-* facilities and removed by clear_demo_data.
+#[ic_cdk::update]
+fn seed_demo_data(n_mothers: u32) -> Result<DemoDataSummary, Error> {
+    ensure_controller()?;
+    ensure_feature("demo_data")?;
+    ensure_batch_size(n_mothers as usize)?;
+
+    let now = time();
+    let mut rng = DemoRng(now | 1);
+    let mut summary = DemoDataSummary { mothers: 0, health_records: 0 };
+
+    for _ in 0..n_mothers {
+        let days_to_delivery = rng.range(7, 270);
+        let history: Vec<String> = (0..rng.range(0, 2)).map(|_| rng.pick(&DEMO_HISTORY).to_string()).collect();
+        let previous_cesareans = history.iter().any(|entry| entry == "Previous C-section").then_some(1);
+        let profile = create_mother_profile(MotherProfilePayload {
+            name: format!("{} {}", rng.pick(&DEMO_FIRST_NAMES), rng.pick(&DEMO_LAST_NAMES)),
+            age: rng.range(17, 42) as u8,
+            blood_type: rng.pick(&["O+", "O+", "A+", "B+", "AB+", "O-", "A-"]).to_string(),
+            expected_delivery_date: now + days_to_delivery * NANOS_PER_DAY,
+            medical_history: history,
+            emergency_contact: format!("+2547{:08}", rng.range(0, 99_999_999)),
+            insurance: None,
+            facility_code: Some(rng.pick(&DEMO_FACILITIES).to_string()),
+            idempotency_key: None,
+            previous_cesareans,
+            national_id: None,
+        })?;
+        DEMO_MOTHERS.with(|mothers| mothers.borrow_mut().insert(profile.id, now));
+        summary.mothers += 1;
+
+        // Four-weekly visits since booking at around 12 weeks, the last one setting the next appointment
+        let weeks_pregnant = 40u64.saturating_sub(days_to_delivery / 7);
+        let visits = weeks_pregnant.saturating_sub(12) / 4 + 1;
+        let base_weight = rng.range(50, 80) as f32;
+        for visit in (0..visits.min(8)).rev() {
+            let date = now.saturating_sub(visit * 28 * NANOS_PER_DAY + rng.range(0, 3) * NANOS_PER_DAY);
+            let (systolic, diastolic) = if rng.chance(10) {
+                (rng.range(140, 165), rng.range(90, 110))
+            } else {
+                (rng.range(100, 130), rng.range(65, 85))
+            };
+            let symptoms = if rng.chance(30) { vec![rng.pick(&DEMO_SYMPTOMS).to_string()] } else { Vec::new() };
+            insert_health_record(
+                HealthRecordPayload {
+                    mother_id: profile.id,
+                    blood_pressure: format!("{}/{}", systolic, diastolic),
+                    weight: base_weight + (weeks_pregnant.saturating_sub(visit * 4) as f32) * 0.3,
+                    symptoms,
+                    notes: "Synthetic demo visit".to_string(),
+                    next_appointment: date + 28 * NANOS_PER_DAY,
+                    insurance_eligible: None,
+                    facility_code: None,
+                    idempotency_key: None,
+                    fundal_height_cm: None,
+                    fetal_heart_rate: None,
+                    urine_protein: None,
+                    urine_glucose: None,
+                    edema: None,
+                    muac_cm: None,
+                    note_template: None,
+                    note_sections: None,
+                    checklist_done: None,
+                },
+                date,
+            )?;
+            summary.health_records += 1;
+        }
+    }
+
+    Ok(summary)
+}
+
+// Remove every mother created by seed_demo_data, with her records (controllers only)
+#[ic_cdk::update]
+fn clear_demo_data() -> Result<DemoDataSummary, Error> {
+    ensure_controller()?;
+
+    let mothers: Vec<u64> = DEMO_MOTHERS.with(|mothers| mothers.borrow().iter().map(|(id, _)| id).collect());
+    let mut summary = DemoDataSummary { mothers: 0, health_records: 0 };
+    for id in mothers {
+        summary.health_records += remove_mother(id);
+        summary.mothers += 1;
+        DEMO_MOTHERS.with(|mothers| mothers.borrow_mut().remove(&id));
+    }
+    FACILITY_METRICS.with(|metrics| {
+        for facility_code in DEMO_FACILITIES {
+            metrics.borrow_mut().remove(&StringKey(facility_code.to_string()));
+        }
+    });
+    Ok(summary)
+}
+
+// Delete a mother's profile and health records, with their indexes; returns the number of records
+fn remove_mother(id: u64) -> u64 {
+    let records: Vec<(RecordKey, HealthRecord)> =
+        HEALTH_RECORD_STORAGE.with(|storage| storage.borrow().range(mother_record_keys(id)).collect());
+    for (key, record) in &records {
+        HEALTH_RECORD_STORAGE.with(|storage| storage.borrow_mut().remove(key));
+        HEALTH_RECORD_MOTHERS.with(|mothers| mothers.borrow_mut().remove(&record.id));
+        remove_record_companions(record);
+        log_change(EntityType::HealthRecord, record.id, Some(record), None);
+    }
+    remove_mother_companions(id);
+
+    if let Some(profile) = PROFILE_STORAGE.with(|storage| storage.borrow_mut().remove(&id)) {
+        remove_profile_companions(&profile);
+        log_change(EntityType::MotherProfile, id, Some(&profile), None);
+    }
+    records.len() as u64
+}
+
+// Everything else kept about a mother besides her profile and health records
+fn remove_mother_companions(id: u64) {
+    for report in mother_self_reports(id) {
+        SELF_REPORT_STORAGE.with(|storage| storage.borrow_mut().remove(&report.id));
+        if let Some(ulid) = &report.ulid {
+            ULID_INDEX.with(|index| index.borrow_mut().remove(&StringKey(ulid.clone())));
+        }
+        log_change(EntityType::SelfReport, report.id, Some(&report), None);
+    }
+    remove_chw_alerts(id);
+    remove_wellness_journal(id);
+    remove_kick_counts(id);
+    remove_contractions(id);
+    remove_prescriptions(id);
+    remove_messages(id);
+    remove_defaulter_rows(id);
+    remove_anti_d_doses(id);
+    remove_screenings(id);
+    remove_change_requests(id);
+    remove_clinician_tasks(id);
+    remove_handoff_flags(id);
+    remove_check_ins(id);
+    remove_sms_messages(id);
+    remove_ussd_pins(id);
+    remove_transport_vouchers(id);
+    remove_dispatches(id);
+    remove_dispensings(id);
+    remove_external_identifiers(id);
+    remove_teleconsults(id);
+    PMTCT_RECORDS.with(|storage| storage.borrow_mut().remove(&id));
+    BIRTH_PLANS.with(|plans| plans.borrow_mut().remove(&id));
+    MOTHER_CHWS.with(|chws| chws.borrow_mut().remove(&id));
+}
+
+fn remove_record_companions(record: &HealthRecord) {
+    remove_record_addenda(record.id);
+    VISIT_FEEDBACK.with(|feedback| feedback.borrow_mut().remove(&record.id));
+    if let Some(ulid) = &record.ulid {
+        ULID_INDEX.with(|index| index.borrow_mut().remove(&StringKey(ulid.clone())));
+    }
+}
+
+fn remove_profile_companions(profile: &MotherProfile) {
+    CARE_PROGRESS.with(|storage| storage.borrow_mut().remove(&profile.id));
+    PROFILE_FIELD_CLOCKS.with(|storage| storage.borrow_mut().remove(&profile.id));
+    if let Some(ulid) = &profile.ulid {
+        ULID_INDEX.with(|index| index.borrow_mut().remove(&StringKey(ulid.clone())));
+    }
+}
+
+// Her assigned health worker and care team, controllers and admins
+fn ensure_record_keeper(mother_id: u64) -> Result<(), Error> {
+    if let Ok(false) = ensure_thread_access(mother_id) {
+        return Ok(());
+    }
+    ensure_controller().map_err(|_| Error::AuthorizationError {
+        msg: "Only the mother's care team can delete or restore her records".to_string(),
+    })
+}
+
+// The mother herself, her care team, staff at her facility, sensitive readers, or controllers
+fn ensure_mother_reader(profile: &MotherProfile) -> Result<(), Error> {
+    if ensure_thread_access(profile.id).is_ok() || ensure_sensitive_reader().is_ok() {
+        return Ok(());
+    }
+    if let Some(facility_code) = &profile.facility_code {
+        if ensure_facility_staff(facility_code).is_ok() {
+            return Ok(());
+        }
+    }
+    ensure_controller().map_err(|_| Error::AuthorizationError {
+        msg: "Only the mother, her care team and staff at her facility can read her data".to_string(),
+    })
+}
+
+// Mothers a caller may list across: every one for controllers and sensitive readers (None), those
+// at their own facility for facility staff; anyone else is refused
+fn list_scope() -> Result<Option<String>, Error> {
+    if ensure_controller().is_ok() || ensure_sensitive_reader().is_ok() {
+        return Ok(None);
+    }
+    let caller = StringKey(ic_cdk::caller().to_text());
+    match FACILITY_STAFF.with(|staff_list| staff_list.borrow().get(&caller)) {
+        Some(staff) => Ok(Some(staff.facility_code)),
+        None => Err(Error::AuthorizationError {
+            msg: "Only facility staff, sensitive readers and controllers can list mothers".to_string(),
+        }),
+    }
+}
+
+fn in_list_scope(scope: &Option<String>, profile: &MotherProfile) -> bool {
+    scope.is_none() || profile.facility_code == *scope
+}
+
+// An erased mother's data cannot come back, even if a deletion of it was still pending
+fn ensure_not_erased(mother_id: u64) -> Result<(), Error> {
+    if ERASURES.with(|erasures| erasures.borrow().contains_key(&mother_id)) {
+        return Err(Error::InvalidInput {
+            msg: format!("Mother id={} has been erased", mother_id),
+        });
+    }
+    Ok(())
+}
+
+// Days a deletion can be undone before the daily purge removes it for good
+const DELETION_RETENTION_DAYS: u64 = 30;
+
+// Soft-delete a mother's profile, e.g. a duplicate registration, with her health records. Both
+// disappear from every query and can be restored until the retention window ends.
+#[ic_cdk::update]
+fn delete_mother_profile(mother_id: u64, expected_version: u64, reason: String) -> Result<Deletion, Error> {
+    ensure_record_keeper(mother_id)?;
+    validate_deletion_reason(&reason)?;
+    let profile = load_mother_profile(mother_id)?;
+    check_version("Mother", mother_id, profile.version, expected_version)?;
+
+    let records: Vec<(RecordKey, HealthRecord)> =
+        HEALTH_RECORD_STORAGE.with(|storage| storage.borrow().range(mother_record_keys(mother_id)).collect());
+
+    let mut batch = WriteBatch::default();
+    let logged = reason.clone();
+    batch.stage(move || {
+        for (key, record) in &records {
+            HEALTH_RECORD_STORAGE.with(|storage| storage.borrow_mut().remove(key));
+            HEALTH_RECORD_MOTHERS.with(|mothers| mothers.borrow_mut().remove(&record.id));
+            DELETED_RECORDS.with(|deleted| deleted.borrow_mut().insert(*key, record.clone()));
+            log_change_with_reason(EntityType::HealthRecord, record.id, Some(record), None, Some(&logged));
+        }
+        PROFILE_STORAGE.with(|storage| storage.borrow_mut().remove(&mother_id));
+        DELETED_PROFILES.with(|deleted| deleted.borrow_mut().insert(mother_id, profile.clone()));
+        log_change_with_reason(EntityType::MotherProfile, mother_id, Some(&profile), None, Some(&logged));
+    });
+    let deletion = stage_deletion(&mut batch, EntityType::MotherProfile, mother_id, mother_id, reason);
+    batch.commit();
+    Ok(deletion)
+}
+
+// Soft-delete a health record entered in error; signed records take an addendum instead
+#[ic_cdk::update]
+fn delete_health_record(record_id: u64, reason: String) -> Result<Deletion, Error> {
+    validate_deletion_reason(&reason)?;
+    let record = get_health_record(record_id).ok_or_else(|| Error::NotFound {
+        msg: format!("Health record with id={} not found", record_id),
+    })?;
+    ensure_record_keeper(record.mother_id)?;
+    if record.signature.is_some() {
+        return Err(Error::InvalidInput {
+            msg: format!("Health record id={} is signed; file an addendum instead", record_id),
+        });
+    }
+
+    let mut batch = WriteBatch::default();
+    let deletion = stage_deletion(&mut batch, EntityType::HealthRecord, record_id, record.mother_id, reason);
+    let logged = deletion.reason.clone();
+    batch.stage(move || {
+        let key = RecordKey { mother_id: record.mother_id, seq: record_id };
+        HEALTH_RECORD_STORAGE.with(|storage| storage.borrow_mut().remove(&key));
+        HEALTH_RECORD_MOTHERS.with(|mothers| mothers.borrow_mut().remove(&record_id));
+        DELETED_RECORDS.with(|deleted| deleted.borrow_mut().insert(key, record.clone()));
+        log_change_with_reason(EntityType::HealthRecord, record_id, Some(&record), None, Some(&logged));
+    });
+    batch.commit();
+    Ok(deletion)
+}
+
+// Undo delete_mother_profile, bringing back the health records deleted with her
+#[ic_cdk::update]
+fn restore_mother_profile(mother_id: u64) -> Result<MotherProfile, Error> {
+    ensure_record_keeper(mother_id)?;
+    ensure_not_erased(mother_id)?;
+    let key = EntityKey::new(EntityType::MotherProfile, mother_id);
+    DELETIONS.with(|deletions| deletions.borrow_mut().remove(&key)).ok_or_else(|| Error::NotFound {
+        msg: format!("No deleted mother with id={}", mother_id),
+    })?;
+    let profile = DELETED_PROFILES
+        .with(|deleted| deleted.borrow_mut().remove(&mother_id))
+        .expect("Deleted mother without her profile");
+    PROFILE_STORAGE.with(|storage| storage.borrow_mut().insert(mother_id, profile.clone()));
+    log_change_with_reason(EntityType::MotherProfile, mother_id, None, Some(&profile), Some("Restored"));
+
+    // Records deleted on their own before she was keep their own deletion
+    let records: Vec<(RecordKey, HealthRecord)> =
+        DELETED_RECORDS.with(|deleted| deleted.borrow().range(mother_record_keys(mother_id)).collect());
+    for (key, record) in records {
+        let deleted_alone = DELETIONS
+            .with(|deletions| deletions.borrow().contains_key(&EntityKey::new(EntityType::HealthRecord, record.id)));
+        if !deleted_alone {
+            DELETED_RECORDS.with(|deleted| deleted.borrow_mut().remove(&key));
+            store_health_record(&record);
+            log_change_with_reason(EntityType::HealthRecord, record.id, None, Some(&record), Some("Restored"));
+        }
+    }
+    Ok(profile)
+}
+
+// Undo delete_health_record; her profile must not be deleted
+#[ic_cdk::update]
+fn restore_health_record(record_id: u64) -> Result<HealthRecord, Error> {
+    let key = EntityKey::new(EntityType::HealthRecord, record_id);
+    let deletion = DELETIONS.with(|deletions| deletions.borrow().get(&key)).ok_or_else(|| Error::NotFound {
+        msg: format!("No deleted health record with id={}", record_id),
+    })?;
+    ensure_record_keeper(deletion.mother_id)?;
+    ensure_not_erased(deletion.mother_id)?;
+    if DELETED_PROFILES.with(|deleted| deleted.borrow().contains_key(&deletion.mother_id)) {
+        return Err(Error::InvalidInput {
+            msg: format!("Mother id={} is deleted; restore her profile first", deletion.mother_id),
+        });
+    }
+    DELETIONS.with(|deletions| deletions.borrow_mut().remove(&key));
+    let record = DELETED_RECORDS
+        .with(|deleted| deleted.borrow_mut().remove(&RecordKey { mother_id: deletion.mother_id, seq: record_id }))
+        .expect("Deleted health record missing");
+    store_health_record(&record);
+    log_change_with_reason(EntityType::HealthRecord, record_id, None, Some(&record), Some("Restored"));
+    Ok(record)
+}
+
+// Deletions that can still be restored, oldest first
+#[ic_cdk::query]
+fn get_deleted_entities(cursor: Option<u64>, limit: Option<u32>) -> Page<Deletion> {
+    let mut deletions: Vec<Deletion> =
+        DELETIONS.with(|deletions| deletions.borrow().iter().map(|(_, deletion)| deletion).collect());
+    deletions.sort_by_key(|deletion| deletion.deleted_at);
+    paginate_in_order(deletions, cursor, limit)
+}
+
+fn validate_deletion_reason(reason: &str) -> Result<(), Error> {
+    if reason.trim().is_empty() || reason.len() > 500 {
+        return Err(Error::InvalidInput {
+            msg: "A reason of up to 500 characters is required".to_string(),
+        });
+    }
+    Ok(())
+}
+
+fn stage_deletion(batch: &mut WriteBatch, entity_type: EntityType, id: u64, mother_id: u64, reason: String) -> Deletion {
+    let now = time();
+    let retention = canister_config().deletion_retention_days.unwrap_or(DELETION_RETENTION_DAYS);
+    let deletion = Deletion {
+        entity_type,
+        id,
+        mother_id,
+        reason: reason.trim().to_string(),
+        deleted_by: ic_cdk::caller(),
+        deleted_at: now,
+        purge_after: now + retention * NANOS_PER_DAY,
+    };
+    let stored = deletion.clone();
+    batch.stage(move || {
+        DELETIONS.with(|deletions| deletions.borrow_mut().insert(EntityKey::new(entity_type, id), stored));
+    });
+    deletion
+}
+
+// Daily: remove deletions past their retention window for good, and expired idempotency keys
+fn purge_deleted() {
+    if MAINTENANCE.with(|maintenance| maintenance.borrow().is_some()) {
+        observe_job("purge_deleted", None);
+        return;
+    }
+    let now = time();
+    let due: Vec<(EntityKey, Deletion)> = DELETIONS.with(|deletions| {
+        deletions.borrow().iter().filter(|(_, deletion)| deletion.purge_after <= now).collect()
+    });
+    for (key, deletion) in &due {
+        DELETIONS.with(|deletions| deletions.borrow_mut().remove(key));
+        match deletion.entity_type {
+            EntityType::MotherProfile => {
+                let records: Vec<(RecordKey, HealthRecord)> = DELETED_RECORDS
+                    .with(|deleted| deleted.borrow().range(mother_record_keys(deletion.mother_id)).collect());
+                for (record_key, record) in records {
+                    DELETED_RECORDS.with(|deleted| deleted.borrow_mut().remove(&record_key));
+                    DELETIONS.with(|deletions| {
+                        deletions.borrow_mut().remove(&EntityKey::new(EntityType::HealthRecord, record.id))
+                    });
+                    remove_record_companions(&record);
+                }
+                remove_mother_companions(deletion.mother_id);
+                if let Some(profile) = DELETED_PROFILES.with(|deleted| deleted.borrow_mut().remove(&deletion.mother_id)) {
+                    remove_profile_companions(&profile);
+                }
+            }
+            _ => {
+                let record_key = RecordKey { mother_id: deletion.mother_id, seq: deletion.id };
+                if let Some(record) = DELETED_RECORDS.with(|deleted| deleted.borrow_mut().remove(&record_key)) {
+                    remove_record_companions(&record);
+                }
+            }
+        }
+    }
+    observe_job("purge_deleted", Some(due.len() as u64));
+    purge_idempotency_keys(now);
+}
+
+// Let a mother sign in with her own principal, e.g. her Internet Identity, replacing any earlier
+// link of that principal (controllers and admins only)
+#[ic_cdk::update]
+fn link_mother_principal(mother_id: u64, principal: Principal) -> Result<(), Error> {
+    ensure_controller()?;
+    if principal == Principal::anonymous() {
+        return Err(Error::InvalidInput {
+            msg: "The anonymous principal cannot be linked to a mother".to_string(),
+        });
+    }
+    load_mother_profile(mother_id)?;
+    MOTHER_PRINCIPALS.with(|links| links.borrow_mut().insert(StringKey(principal.to_text()), mother_id));
+    Ok(())
+}
+
+#[ic_cdk::update]
+fn unlink_mother_principal(principal: Principal) -> Result<(), Error> {
+    ensure_controller()?;
+    MOTHER_PRINCIPALS
+        .with(|links| links.borrow_mut().remove(&StringKey(principal.to_text())))
+        .map(|_| ())
+        .ok_or_else(|| Error::NotFound {
+            msg: format!("Principal {} is not linked to a mother", principal),
+        })
+}
+
+fn unlink_mother_principals(mother_id: u64) {
+    let linked: Vec<StringKey> = MOTHER_PRINCIPALS.with(|links| {
+        links.borrow().iter().filter(|(_, id)| *id == mother_id).map(|(key, _)| key).collect()
+    });
+    MOTHER_PRINCIPALS.with(|links| {
+        let mut links = links.borrow_mut();
+        for key in &linked {
+            links.remove(key);
+        }
+    });
+}
+
+// Reads kept per mother; older ones are dropped
+const ACCESS_LOG_CAP: usize = 200;
+
+// Note a read of a mother's data in her access log
+fn log_read(mother_id: u64, method: &str) {
+    let seq = ACCESS_SEQ.with(|counter| {
+        let next = *counter.borrow().get() + 1;
+        counter.borrow_mut().set(next).expect("Cannot update access log sequence");
+        next
+    });
+    let entry = AccessEntry {
+        caller: ic_cdk::caller(),
+        timestamp: time(),
+        method: method.to_string(),
+    };
+    ACCESS_LOG.with(|log| {
+        let mut log = log.borrow_mut();
+        log.insert(RecordKey { mother_id, seq }, entry);
+        let keys: Vec<RecordKey> = log.range(mother_record_keys(mother_id)).map(|(key, _)| key).collect();
+        for key in keys.iter().take(keys.len().saturating_sub(ACCESS_LOG_CAP)) {
+            log.remove(key);
+        }
+    });
+}
+
+// Note a read of each mother on a page of a list across mothers
+fn log_page_reads<T>(page: Page<T>, mother_id: impl Fn(&T) -> u64, method: &str) -> Page<T> {
+    let mut mothers: Vec<u64> = page.items.iter().map(mother_id).collect();
+    mothers.sort_unstable();
+    mothers.dedup();
+    for mother in mothers {
+        log_read(mother, method);
+    }
+    page
+}
+
+fn access_log(mother_id: u64) -> Vec<AccessEntry> {
+    ACCESS_LOG.with(|log| log.borrow().range(mother_record_keys(mother_id)).map(|(_, entry)| entry).collect())
+}
+
+// Who has read a mother's profile or records, oldest first (the mother herself, controllers and admins)
+#[ic_cdk::query]
+fn get_access_log(mother_id: u64, cursor: Option<u64>, limit: Option<u32>) -> Result<Page<AccessEntry>, Error> {
+    if caller_mother_id().ok() != Some(mother_id) {
+        ensure_controller()?;
+    }
+    load_mother_profile(mother_id)?;
+    Ok(ACCESS_LOG.with(|log| {
+        paginate(log.borrow().range(mother_record_keys(mother_id)).map(|(key, entry)| (key.seq, entry)), cursor, limit)
+    }))
+}
+
+// The mother the caller signs in as
+fn caller_mother_id() -> Result<u64, Error> {
+    let caller = ic_cdk::caller();
+    let mother_id = MOTHER_PRINCIPALS
+        .with(|links| links.borrow().get(&StringKey(caller.to_text())))
+        .ok_or_else(|| Error::AuthorizationError {
+            msg: "The caller is not linked to a mother".to_string(),
+        })?;
+    ensure_mother_exists(mother_id)?;
+    Ok(mother_id)
+}
+
+const MIN_SHARE_TOKEN_TTL_SECONDS: u64 = 60;
+const MAX_SHARE_TOKEN_TTL_SECONDS: u64 = 30 * 24 * 60 * 60;
+
+// Give a specialist or lab read access to a mother's data until the token expires. Callable by the
+// mother herself, controllers and admins; hand the returned token to the reader out of band.
+#[ic_cdk::update]
+async fn create_share_token(mother_id: u64, scope: ShareScope, ttl_seconds: u64) -> Result<IssuedShareToken, Error> {
+    if caller_mother_id().ok() != Some(mother_id) {
+        ensure_controller()?;
+    }
+    if !(MIN_SHARE_TOKEN_TTL_SECONDS..=MAX_SHARE_TOKEN_TTL_SECONDS).contains(&ttl_seconds) {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "ttl_seconds must be between {} and {}",
+                MIN_SHARE_TOKEN_TTL_SECONDS, MAX_SHARE_TOKEN_TTL_SECONDS
+            ),
+        });
+    }
+    load_mother_profile(mother_id)?;
+
+    let (random,) = ic_cdk::api::management_canister::main::raw_rand()
+        .await
+        .map_err(|(_, msg)| Error::SystemError { msg: format!("Failed to generate share token: {}", msg) })?;
+    let token: String = random.iter().map(|byte| format!("{:02x}", byte)).collect();
+
+    purge_expired_share_tokens();
+    let now = time();
+    let grant = ShareToken {
+        token_hash: share_token_hash(&token),
+        mother_id,
+        scope,
+        created_by: ic_cdk::caller(),
+        created_at: now,
+        expires_at: now + ttl_seconds * 1_000_000_000,
+    };
+    SHARE_TOKENS.with(|tokens| tokens.borrow_mut().insert(StringKey(grant.token_hash.clone()), grant.clone()));
+    Ok(IssuedShareToken {
+        token,
+        token_hash: grant.token_hash,
+        expires_at: grant.expires_at,
+    })
+}
+
+// Read a mother's data with a share token, within its scope; every use is logged in her access log
+#[ic_cdk::update]
+async fn read_shared_record(token: String) -> Result<SharedRecord, Error> {
+    let grant = valid_share_token(&token)?;
+    let mother_id = grant.mother_id;
+    let profile = load_mother_profile(mother_id)?;
+    let mut shared = SharedRecord {
+        scope: grant.scope,
+        expires_at: grant.expires_at,
+        mother_id,
+        profile: share_covers(grant.scope, ShareScope::Profile).then_some(profile),
+        health_records: Vec::new(),
+        addenda: Vec::new(),
+        lab_results: Vec::new(),
+    };
+    if share_covers(grant.scope, ShareScope::HealthRecords) {
+        let records = mother_health_records(mother_id).await?;
+        shared.addenda = records.iter().flat_map(|record| record_addenda(record.id)).collect();
+        shared.health_records = records;
+    }
+    if share_covers(grant.scope, ShareScope::LabResults) {
+        shared.lab_results = mother_lab_results(mother_id);
+    }
+    log_read(mother_id, &format!("read_shared_record ({:?} share)", grant.scope));
+    Ok(shared)
+}
+
+// Unexpired share tokens for a mother (the mother herself, controllers and admins)
+#[ic_cdk::query]
+fn get_share_tokens(mother_id: u64) -> Result<Vec<ShareToken>, Error> {
+    if caller_mother_id().ok() != Some(mother_id) {
+        ensure_controller()?;
+    }
+    let now = time();
+    Ok(SHARE_TOKENS.with(|tokens| {
+        tokens
+            .borrow()
+            .iter()
+            .map(|(_, grant)| grant)
+            .filter(|grant| grant.mother_id == mother_id && grant.expires_at > now)
+            .collect()
+    }))
+}
+
+// Withdraw a share token before it expires (the mother it was issued for, controllers and admins)
+#[ic_cdk::update]
+fn revoke_share_token(token_hash: String) -> Result<(), Error> {
+    let grant = SHARE_TOKENS
+        .with(|tokens| tokens.borrow().get(&StringKey(token_hash.clone())))
+        .ok_or_else(|| Error::NotFound {
+            msg: format!("Share token {} not found", token_hash),
+        })?;
+    if caller_mother_id().ok() != Some(grant.mother_id) {
+        ensure_controller()?;
+    }
+    SHARE_TOKENS.with(|tokens| tokens.borrow_mut().remove(&StringKey(token_hash)));
+    Ok(())
+}
+
+// Whether a share of `scope` includes `part` of her data
+fn share_covers(scope: ShareScope, part: ShareScope) -> bool {
+    scope == part || scope == ShareScope::Full
+}
+
+// The grant behind an unexpired share token; an expired one is removed
+fn valid_share_token(token: &str) -> Result<ShareToken, Error> {
+    let token_hash = share_token_hash(token);
+    let grant = SHARE_TOKENS
+        .with(|tokens| tokens.borrow().get(&StringKey(token_hash.clone())))
+        .ok_or_else(|| Error::AuthorizationError {
+            msg: "Unknown or revoked share token".to_string(),
+        })?;
+    if grant.expires_at <= time() {
+        SHARE_TOKENS.with(|tokens| tokens.borrow_mut().remove(&StringKey(token_hash)));
+        return Err(Error::AuthorizationError {
+            msg: "Share token has expired".to_string(),
+        });
+    }
+    Ok(grant)
+}
+
+fn share_token_hash(token: &str) -> String {
+    Sha256::digest(token.as_bytes()).iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn purge_expired_share_tokens() {
+    let now = time();
+    remove_share_tokens(|grant| grant.expires_at <= now);
+}
+
+fn remove_share_tokens(matches: impl Fn(&ShareToken) -> bool) {
+    let keys: Vec<StringKey> = SHARE_TOKENS.with(|tokens| {
+        tokens.borrow().iter().filter(|(_, grant)| matches(grant)).map(|(key, _)| key).collect()
+    });
+    SHARE_TOKENS.with(|tokens| {
+        let mut tokens = tokens.borrow_mut();
+        for key in &keys {
+            tokens.remove(key);
+        }
+    });
+}
+
+// Everything held about the calling mother as JSON: her profile, visits, lab results, appointments,
+// referrals, payments, self reports, sensitive fields, wellness journal, kick counts,
+// contractions, prescriptions, adherence, messages and birth plan, every change to them with who
+// made it and when, and the recent reads of her data
+#[ic_cdk::query]
+fn request_my_data() -> Result<String, Error> {
+    let mother_id = caller_mother_id()?;
+    let export = mother_export(load_mother_profile(mother_id)?);
+
+    let mut entities = std::collections::BTreeSet::from([EntityKey::new(EntityType::MotherProfile, mother_id)]);
+    entities.extend(export.health_records.iter().map(|record| EntityKey::new(EntityType::HealthRecord, record.id)));
+    entities.extend(export.lab_results.iter().map(|result| EntityKey::new(EntityType::LabResult, result.id)));
+    entities.extend(export.referrals.iter().map(|referral| EntityKey::new(EntityType::Referral, referral.id)));
+    entities.extend(export.payments.iter().map(|payment| EntityKey::new(EntityType::Payment, payment.id)));
+    entities.extend(export.self_reports.iter().map(|report| EntityKey::new(EntityType::SelfReport, report.id)));
+
+    let activity: Vec<serde_json::Value> = CHANGE_LOG.with(|log| {
+        let log = log.borrow();
+        (0..log.len())
+            .filter_map(|seq| log.get(seq))
+            .filter(|event| entities.contains(&EntityKey::new(event.entity_type, event.entity_id)))
+            .map(|event| {
+                serde_json::json!({
+                    "timestamp": event.timestamp,
+                    "actor": event.actor.to_text(),
+                    "entity_type": format!("{:?}", event.entity_type),
+                    "entity_id": event.entity_id,
+                    "change": format!("{:?}", event.kind),
+                })
+            })
+            .collect()
+    });
+
+    let mut document = export_mother_value(&export);
+    document["sensitive_fields"] = serde_json::json!(SENSITIVE_FIELDS.with(|storage| storage.borrow().get(&mother_id)));
+    document["pmtct"] = serde_json::json!(PMTCT_RECORDS.with(|storage| storage.borrow().get(&mother_id)));
+    document["wellness_journal"] = serde_json::json!(wellness_entries(mother_id, 0..=u64::MAX));
+    document["kick_counts"] = serde_json::json!(get_kick_counts(mother_id));
+    let contractions: Vec<Contraction> = CONTRACTIONS.with(|contractions| {
+        contractions
+            .borrow()
+            .range(mother_record_keys(mother_id))
+            .map(|(key, duration_seconds)| Contraction { started_at: key.seq, duration_seconds })
+            .collect()
+    });
+    document["contractions"] = serde_json::json!(contractions);
+    document["prescriptions"] = serde_json::json!(mother_prescriptions(mother_id));
+    document["adherence"] = serde_json::json!(mother_adherence(mother_id, false));
+    let messages: Vec<Message> =
+        MESSAGES.with(|messages| messages.borrow().range(mother_record_keys(mother_id)).map(|(_, message)| message).collect());
+    document["messages"] = serde_json::json!(messages);
+    document["birth_plan"] = serde_json::json!(get_birth_plan(mother_id));
+    document["anti_d_doses"] = serde_json::json!(mother_anti_d_doses(mother_id));
+    document["screenings"] = serde_json::json!(mother_screenings(mother_id));
+    document["reads"] = serde_json::json!(access_log(mother_id));
+    document["activity"] = serde_json::Value::Array(activity);
+    Ok(document.to_string())
+}
+
+// What erase_mother_data expects as confirmation, so a mistyped id cannot erase the wrong mother
+fn erasure_confirmation(mother_id: u64) -> String {
+    format!("ERASE MOTHER {}", mother_id)
+}
+
+// Irreversibly remove a mother's personal data: her profile, health records, lab results, referrals
+// and payments, with their indexes, here and in every archive canister. Dashboard counters and
+// facility metrics keep her contribution, and an audit entry records the erasure (controllers and
+// admins only).
+#[ic_cdk::update]
+async fn erase_mother_data(mother_id: u64, confirmation: String) -> Result<ErasureRecord, Error> {
+    ensure_controller()?;
+    if confirmation != erasure_confirmation(mother_id) {
+        return Err(Error::InvalidInput {
+            msg: format!("Confirmation must be \"{}\"", erasure_confirmation(mother_id)),
+        });
+    }
+    // A soft-deleted mother can be erased too, without waiting for the purge
+    let deleted_profile = DELETED_PROFILES.with(|deleted| deleted.borrow().get(&mother_id));
+    let profile = match &deleted_profile {
+        Some(profile) => profile.clone(),
+        None => load_mother_profile(mother_id)?,
+    };
+
+    let payments: Vec<Payment> = PAYMENT_STORAGE.with(|storage| {
+        storage.borrow().iter().map(|(_, payment)| payment).filter(|payment| payment.mother_id == mother_id).collect()
+    });
+    if payments.iter().any(|payment| matches!(payment.status, PaymentStatus::Processing)) {
+        return Err(Error::InvalidInput {
+            msg: "A payment for this mother is being settled; retry once it completes, or resolve it if stranded".to_string(),
+        });
+    }
+    if payments.iter().any(|payment| matches!(payment.status, PaymentStatus::NeedsReconciliation)) {
+        return Err(Error::InvalidInput {
+            msg: "A payment for this mother needs reconciliation; resolve it first".to_string(),
+        });
+    }
+    let lab_results: Vec<LabResult> = LAB_RESULT_STORAGE.with(|storage| {
+        storage.borrow().iter().map(|(_, result)| result).filter(|result| result.mother_id == mother_id).collect()
+    });
+    let referrals: Vec<Referral> = REFERRAL_STORAGE.with(|storage| {
+        storage.borrow().iter().map(|(_, referral)| referral).filter(|referral| referral.mother_id == mother_id).collect()
+    });
+    let records: Vec<(RecordKey, HealthRecord)> =
+        HEALTH_RECORD_STORAGE.with(|storage| storage.borrow().range(mother_record_keys(mother_id)).collect());
+    let deleted_records: Vec<(RecordKey, HealthRecord)> =
+        DELETED_RECORDS.with(|deleted| deleted.borrow().range(mother_record_keys(mother_id)).collect());
+
+    // Counters are left as they are; keep her share so recounts still match them. Soft deletion
+    // already took deleted entities out of the counters.
+    let mut retained = std::collections::BTreeMap::new();
+    let mut retain = |entity_type: EntityType, entity: serde_json::Result<serde_json::Value>| {
+        for key in entity.map(|entity| counter_keys(entity_type, &entity)).unwrap_or_default() {
+            *retained.entry(key).or_insert(0) += 1;
+        }
+    };
+    if deleted_profile.is_none() {
+        retain(EntityType::MotherProfile, serde_json::to_value(&profile));
+    }
+    records.iter().for_each(|(_, record)| retain(EntityType::HealthRecord, serde_json::to_value(record)));
+    referrals.iter().for_each(|referral| retain(EntityType::Referral, serde_json::to_value(referral)));
+
+    let archive_canisters = ARCHIVE_POINTERS
+        .with(|pointers| pointers.borrow().get(&mother_id))
+        .map(|pointer| pointer.archive_canisters)
+        .unwrap_or_default();
+    // A batch being archived right now may hold her records, and is delivered before our call
+    let mut pending = archive_canisters.clone();
+    if let Some(current) = ARCHIVE_CONFIG.with(|config| config.borrow().get().archive_canister) {
+        if !pending.contains(&current) {
+            pending.push(current);
+        }
+    }
+
+    let mut erasure = ErasureRecord {
+        mother_id,
+        erased_at: time(),
+        erased_by: ic_cdk::caller(),
+        health_records: (records.len() + deleted_records.len()) as u64,
+        lab_results: lab_results.len() as u64,
+        referrals: referrals.len() as u64,
+        payments: payments.len() as u64,
+        archive_canisters,
+        archive_erasure_pending: Some(pending),
+        retained_counters: retained.into_iter().collect(),
+    };
+
+    let mut batch = WriteBatch::default();
+    batch.stage(move || {
+        for (key, record) in &records {
+            HEALTH_RECORD_STORAGE.with(|storage| storage.borrow_mut().remove(key));
+            HEALTH_RECORD_MOTHERS.with(|mothers| mothers.borrow_mut().remove(&record.id));
+            remove_record_addenda(record.id);
+            VISIT_FEEDBACK.with(|feedback| feedback.borrow_mut().remove(&record.id));
+            retire_erased_entity(EntityType::HealthRecord, record.id, mother_id, &record.ulid);
+        }
+        // Soft deletions of hers would otherwise still be restorable
+        for (key, record) in &deleted_records {
+            DELETED_RECORDS.with(|deleted| deleted.borrow_mut().remove(key));
+            DELETIONS
+                .with(|deletions| deletions.borrow_mut().remove(&EntityKey::new(EntityType::HealthRecord, record.id)));
+            remove_record_addenda(record.id);
+            VISIT_FEEDBACK.with(|feedback| feedback.borrow_mut().remove(&record.id));
+            retire_erased_entity(EntityType::HealthRecord, record.id, mother_id, &record.ulid);
+        }
+        DELETIONS.with(|deletions| deletions.borrow_mut().remove(&EntityKey::new(EntityType::MotherProfile, mother_id)));
+        DELETED_PROFILES.with(|deleted| deleted.borrow_mut().remove(&mother_id));
+    });
+    batch.stage(move || {
+        for result in &lab_results {
+            LAB_RESULT_STORAGE.with(|storage| storage.borrow_mut().remove(&result.id));
+            retire_erased_entity(EntityType::LabResult, result.id, mother_id, &result.ulid);
+        }
+        for referral in &referrals {
+            REFERRAL_STORAGE.with(|storage| storage.borrow_mut().remove(&referral.id));
+            retire_erased_entity(EntityType::Referral, referral.id, mother_id, &referral.ulid);
+        }
+        for payment in &payments {
+            PAYMENT_STORAGE.with(|storage| storage.borrow_mut().remove(&payment.id));
+            retire_erased_entity(EntityType::Payment, payment.id, mother_id, &payment.ulid);
+        }
+        for report in mother_self_reports(mother_id) {
+            SELF_REPORT_STORAGE.with(|storage| storage.borrow_mut().remove(&report.id));
+            retire_erased_entity(EntityType::SelfReport, report.id, mother_id, &report.ulid);
+        }
+    });
+    batch.stage(move || {
+        remove_chw_alerts(mother_id);
+        remove_wellness_journal(mother_id);
+        remove_kick_counts(mother_id);
+        remove_contractions(mother_id);
+        remove_prescriptions(mother_id);
+        remove_messages(mother_id);
+        remove_defaulter_rows(mother_id);
+        remove_anti_d_doses(mother_id);
+        remove_screenings(mother_id);
+        remove_change_requests(mother_id);
+        remove_clinician_tasks(mother_id);
+        remove_handoff_flags(mother_id);
+        remove_check_ins(mother_id);
+        remove_sms_messages(mother_id);
+        remove_ussd_pins(mother_id);
+        remove_transport_vouchers(mother_id);
+        remove_dispatches(mother_id);
+        remove_dispensings(mother_id);
+        remove_external_identifiers(mother_id);
+        remove_teleconsults(mother_id);
+        PMTCT_RECORDS.with(|storage| storage.borrow_mut().remove(&mother_id));
+        BIRTH_PLANS.with(|plans| plans.borrow_mut().remove(&mother_id));
+        MOTHER_CHWS.with(|chws| chws.borrow_mut().remove(&mother_id));
+        PROFILE_STORAGE.with(|storage| storage.borrow_mut().remove(&mother_id));
+        CARE_PROGRESS.with(|storage| storage.borrow_mut().remove(&mother_id));
+        PROFILE_FIELD_CLOCKS.with(|storage| storage.borrow_mut().remove(&mother_id));
+        DEMO_MOTHERS.with(|mothers| mothers.borrow_mut().remove(&mother_id));
+        unlink_mother_principals(mother_id);
+        remove_share_tokens(|grant| grant.mother_id == mother_id);
+        SENSITIVE_FIELDS.with(|storage| storage.borrow_mut().remove(&mother_id));
+        let reads: Vec<RecordKey> =
+            ACCESS_LOG.with(|log| log.borrow().range(mother_record_keys(mother_id)).map(|(key, _)| key).collect());
+        ACCESS_LOG.with(|log| {
+            let mut log = log.borrow_mut();
+            for key in &reads {
+                log.remove(key);
+            }
+        });
+        retire_erased_entity(EntityType::MotherProfile, mother_id, mother_id, &profile.ulid);
+
+        // Pending exports may hold her rows
+        let jobs: Vec<ExportJob> = EXPORT_JOBS.with(|storage| storage.borrow().iter().map(|(_, job)| job).collect());
+        jobs.iter().for_each(remove_export_job);
+
+        ARCHIVE_POINTERS.with(|pointers| pointers.borrow_mut().remove(&mother_id));
+        // Her diffs in the changelog can no longer be opened
+        MOTHER_LOG_KEYS.with(|keys| keys.borrow_mut().remove(&mother_id));
+    });
+    let stored = erasure.clone();
+    batch.stage(move || {
+        ERASURES.with(|erasures| erasures.borrow_mut().insert(mother_id, stored));
+    });
+    batch.commit();
+
+    erase_from_archives(&mut erasure).await;
+    Ok(erasure)
+}
+
+// Erase a mother's records in each archive still pending, and keep the ones that could not be
+// reached on her audit entry
+async fn erase_from_archives(erasure: &mut ErasureRecord) {
+    let mut unreached = Vec::new();
+    // Entries from before archives were erased automatically have every archive pending
+    let pending = erasure.archive_erasure_pending.take().unwrap_or_else(|| erasure.archive_canisters.clone());
+    for archive in pending {
+        let result: Result<(Result<u64, ArchiveError>,), _> =
+            ic_cdk::call(archive, "erase_mother", (erasure.mother_id,)).await;
+        if !matches!(result, Ok((Ok(_),))) {
+            unreached.push(archive);
+        }
+    }
+    erasure.archive_erasure_pending = Some(unreached);
+    ERASURES.with(|erasures| erasures.borrow_mut().insert(erasure.mother_id, erasure.clone()));
+}
+
+// Try again to erase an erased mother's records in archives that could not be reached at the time
+// (controllers and admins only)
+#[ic_cdk::update]
+async fn retry_archive_erasure(mother_id: u64) -> Result<ErasureRecord, Error> {
+    ensure_controller()?;
+    let mut erasure = ERASURES.with(|erasures| erasures.borrow().get(&mother_id)).ok_or(Error::NotFound {
+        msg: format!("Mother with id={} has not been erased", mother_id),
+    })?;
+    erase_from_archives(&mut erasure).await;
+    Ok(erasure)
+}
+
+// Drop an erased entity's ulid and record its deletion without its content
+fn retire_erased_entity(entity_type: EntityType, id: u64, mother_id: u64, ulid: &Option<String>) {
+    if let Some(ulid) = ulid {
+        ULID_INDEX.with(|index| index.borrow_mut().remove(&StringKey(ulid.clone())));
+    }
+    ERASED_ENTITIES.with(|erased| erased.borrow_mut().insert(EntityKey::new(entity_type, id), mother_id));
+    // Carries nothing personal, so it is not sealed under the key being destroyed
+    let reason = Some("Erased at the mother's request");
+    append_change_event(entity_type, id, None, ChangeEventKind::Deleted, ERASED_DIFF.to_string(), reason);
+    record_deletion(entity_type, id);
+}
+
+// Audit entries of every erasure, oldest mother id first (controllers and admins only)
+#[ic_cdk::query]
+fn get_erasures(cursor: Option<u64>, limit: Option<u32>) -> Result<Page<ErasureRecord>, Error> {
+    ensure_controller()?;
+    Ok(ERASURES.with(|erasures| paginate(erasures.borrow().iter(), cursor, limit)))
+}
+
+// Entity counts and memory use, for capacity planning (controllers only)
+#[ic_cdk::query]
+fn get_storage_stats() -> Result<StorageStats, Error> {
+    ensure_controller()?;
+
+    let stores = vec![
+        PROFILE_STORAGE.with(|s| store_stats("profiles", s.borrow().iter())),
+        HEALTH_RECORD_STORAGE.with(|s| store_stats("health_records", s.borrow().iter())),
+        LAB_RESULT_STORAGE.with(|s| store_stats("lab_results", s.borrow().iter())),
+        REFERRAL_STORAGE.with(|s| store_stats("referrals", s.borrow().iter())),
+        PAYMENT_STORAGE.with(|s| store_stats("payments", s.borrow().iter())),
+        CARE_PROGRESS.with(|s| store_stats("care_progress", s.borrow().iter())),
+        FACILITY_METRICS.with(|s| store_stats("facility_metrics", s.borrow().iter())),
+        CODE_REGISTRY.with(|s| store_stats("clinical_codes", s.borrow().iter())),
+        PROFILE_FIELD_CLOCKS.with(|s| store_stats("profile_field_clocks", s.borrow().iter())),
+        CHANGE_FEED.with(|s| store_stats("change_feed", s.borrow().iter())),
+        TOMBSTONES.with(|s| store_stats("tombstones", s.borrow().iter())),
+        IDEMPOTENCY_KEYS.with(|s| store_stats("idempotency_keys", s.borrow().iter())),
+        ULID_INDEX.with(|s| store_stats("ulid_index", s.borrow().iter())),
+        ARCHIVE_POINTERS.with(|s| store_stats("archive_pointers", s.borrow().iter())),
+        // The changelog only grows, so it is counted rather than scanned
+        StoreStats {
+            store: "change_log".to_string(),
+            entries: CHANGE_LOG.with(|log| log.borrow().len()),
+            largest_entry_bytes: 0,
+        },
+    ];
+
+    let largest = stores.iter().max_by_key(|store| store.largest_entry_bytes);
+    let largest_record_bytes = largest.map_or(0, |store| store.largest_entry_bytes);
+    let largest_record_store = largest
+        .filter(|store| store.largest_entry_bytes > 0)
+        .map(|store| store.store.clone());
+    let stable_memory_pages = ic_cdk::api::stable::stable64_size();
+
+    Ok(StorageStats {
+        stores,
+        stable_memory_pages,
+        stable_memory_bytes: stable_memory_pages * WASM_PAGE_SIZE,
+        heap_bytes: heap_bytes(),
+        largest_record_bytes,
+        largest_record_store,
+    })
+}
+
+// Encoded vs stored size of compressed stores (controllers only)
+#[ic_cdk::query]
+fn get_compression_stats() -> Result<Vec<CompressionStats>, Error> {
+    ensure_controller()?;
+
+    Ok(vec![
+        PROFILE_STORAGE.with(|s| compression_stats("profiles", s.borrow().iter())),
+        HEALTH_RECORD_STORAGE.with(|s| compression_stats("health_records", s.borrow().iter())),
+    ])
+}
+
+fn compression_stats<K, V: Storable>(store: &str, entries: impl Iterator<Item = (K, V)>) -> CompressionStats {
+    let mut stats = CompressionStats {
+        store: store.to_string(),
+        entries: 0,
+        encoded_bytes: 0,
+        stored_bytes: 0,
+    };
+    for (_, value) in entries {
+        stats.entries += 1;
+        let stored = value.to_bytes();
+        stats.encoded_bytes += decompress_stored(open_envelope(&stored).1).len() as u64;
+        stats.stored_bytes += stored.len() as u64;
+    }
+    stats
+}
+
+fn store_stats<K, V: Storable>(store: &str, entries: impl Iterator<Item = (K, V)>) -> StoreStats {
+    let mut stats = StoreStats {
+        store: store.to_string(),
+        entries: 0,
+        largest_entry_bytes: 0,
+    };
+    for (_, value) in entries {
+        stats.entries += 1;
+        stats.largest_entry_bytes = stats.largest_entry_bytes.max(value.to_bytes().len() as u64);
+    }
+    stats
+}
+
+#[cfg(target_arch = "wasm32")]
+fn heap_bytes() -> u64 {
+    core::arch::wasm32::memory_size(0) as u64 * WASM_PAGE_SIZE
+}
+
+// Only meaningful inside the canister
+#[cfg(not(target_arch = "wasm32"))]
+fn heap_bytes() -> u64 {
+    0
+}
+
+// Identifier used for an entity in exports: its ULID, or the local id for entities without one
+fn external_id(ulid: &Option<String>, id: u64) -> String {
+    ulid.clone().unwrap_or_else(|| id.to_string())
+}
+
+// Read-only JSON API served through the IC HTTP gateway. Responses carry no names or
+// contact details since gateway requests are unauthenticated.
+//   GET /stats                 canister-wide counts
+//   GET /metrics               operational metrics in Prometheus text format
+//   GET /mothers/{id}/summary  care summary for one mother, with a share token for her as
+//                              `Authorization: Bearer <token>`
+// Summaries, SMS delivery reports (POST /sms/status/{token}) and dispatch updates
+// (POST /dispatch/status/{token}) are upgraded to http_request_update.
+#[ic_cdk::query]
+fn http_request(request: HttpRequest) -> HttpResponse {
+    if request.method.eq_ignore_ascii_case("POST") && callback_token(&request.url).is_some() {
+        return HttpResponse {
+            status_code: 200,
+            headers: Vec::new(),
+            body: Vec::new(),
+            upgrade: Some(true),
+        };
+    }
+    if !feature_enabled("http_gateway") {
+        return http_json(404, serde_json::json!({ "error": "The HTTP gateway is disabled" }));
+    }
+    if !request.method.eq_ignore_ascii_case("GET") {
+        return http_json(405, serde_json::json!({ "error": "Only GET is supported" }));
+    }
+
+    let path = request.url.split(['?', '#']).next().unwrap_or_default();
+    let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+    match segments.as_slice() {
+        // Each use of a share token is logged, which needs an update call
+        ["mothers", _, "summary"] => match bearer_token(&request) {
+            Some(_) => HttpResponse {
+                status_code: 200,
+                headers: Vec::new(),
+                body: Vec::new(),
+                upgrade: Some(true),
+            },
+            None => http_json(401, serde_json::json!({ "error": "A share token is required" })),
+        },
+        ["stats"] => http_json(200, http_stats()),
+        ["metrics"] => HttpResponse {
+            status_code: 200,
+            headers: vec![
+                ("Content-Type".to_string(), "text/plain; version=0.0.4".to_string()),
+                ("Cache-Control".to_string(), "no-store".to_string()),
+            ],
+            body: http_metrics().into_bytes(),
+            upgrade: None,
+        },
+        _ => http_json(404, serde_json::json!({ "error": "Not found" })),
+    }
+}
+
+fn bearer_token(request: &HttpRequest) -> Option<&str> {
+    request
+        .headers
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case("authorization"))
+        .and_then(|(_, value)| value.strip_prefix("Bearer "))
+        .map(str::trim)
+        .filter(|token| !token.is_empty())
+}
+
+// A mother's summary for the holder of a share token for her profile; the read is logged
+fn shared_mother_summary(request: &HttpRequest, id: &str) -> HttpResponse {
+    if !feature_enabled("http_gateway") {
+        return http_json(404, serde_json::json!({ "error": "The HTTP gateway is disabled" }));
+    }
+    let grant = match bearer_token(request).map(valid_share_token) {
+        Some(Ok(grant)) => grant,
+        Some(Err(_)) => return http_json(401, serde_json::json!({ "error": "Unknown, revoked or expired share token" })),
+        None => return http_json(401, serde_json::json!({ "error": "A share token is required" })),
+    };
+    // A token for another mother or a narrower scope is answered as if she did not exist
+    let shared = id.parse() == Ok(grant.mother_id) && share_covers(grant.scope, ShareScope::Profile);
+    match http_mother_summary(grant.mother_id).filter(|_| shared) {
+        Some(summary) => {
+            log_read(grant.mother_id, &format!("GET /mothers/{}/summary ({:?} share)", id, grant.scope));
+            http_json(200, summary)
+        }
+        None => http_json(404, serde_json::json!({ "error": "Mother not found" })),
+    }
+}
+
+fn http_stats() -> serde_json::Value {
+    let counters = get_dashboard_counters();
+    let by_stage: std::collections::BTreeMap<String, u64> = counters.by_stage.into_iter().collect();
+    let by_status: std::collections::BTreeMap<String, u64> = counters.by_health_status.into_iter().collect();
+
+    serde_json::json!({
+        "generated_at": format_iso8601(time()),
+        "mothers": counters.mothers,
+        "health_records": counters.health_records,
+        "referrals": counters.referrals,
+        "by_stage": by_stage,
+        "by_health_status": by_status,
+    })
+}
+
+// Call outcomes and job runs are kept on the heap, so they restart from zero after an upgrade,
+// which Prometheus treats as a counter reset
+fn http_metrics() -> String {
+    use std::fmt::Write;
+
+    let mut out = String::new();
+    let mut family = |name: &str, kind: &str, help: &str, samples: Vec<(String, u64)>| {
+        let _ = writeln!(out, "# HELP mamapack_{} {}", name, help);
+        let _ = writeln!(out, "# TYPE mamapack_{} {}", name, kind);
+        for (labels, value) in samples {
+            let _ = writeln!(out, "mamapack_{}{} {}", name, labels, value);
+        }
+    };
+
+    let calls: Vec<((&str, &str), u64)> =
+        CALL_METRICS.with(|metrics| metrics.borrow().iter().map(|(key, count)| (*key, *count)).collect());
+    family(
+        "calls_total",
+        "counter",
+        "Write calls by method and outcome (ok or the error variant)",
+        calls
+            .iter()
+            .map(|((method, outcome), count)| (format!("{{method=\"{}\",outcome=\"{}\"}}", method, outcome), *count))
+            .collect(),
+    );
+    family(
+        "errors_total",
+        "counter",
+        "Failed write calls by error variant",
+        calls
+            .iter()
+            .filter(|((_, outcome), _)| *outcome != "ok")
+            .fold(std::collections::BTreeMap::new(), |mut errors, ((_, variant), count)| {
+                *errors.entry(*variant).or_insert(0) += count;
+                errors
+            })
+            .into_iter()
+            .map(|(variant, count)| (format!("{{variant=\"{}\"}}", variant), count))
+            .collect(),
+    );
+
+    let entities = [
+        ("mother_profile", PROFILE_STORAGE.with(|s| s.borrow().len())),
+        ("health_record", HEALTH_RECORD_STORAGE.with(|s| s.borrow().len())),
+        ("lab_result", LAB_RESULT_STORAGE.with(|s| s.borrow().len())),
+        ("referral", REFERRAL_STORAGE.with(|s| s.borrow().len())),
+        ("payment", PAYMENT_STORAGE.with(|s| s.borrow().len())),
+        ("self_report", SELF_REPORT_STORAGE.with(|s| s.borrow().len())),
+        ("message", MESSAGES.with(|s| s.borrow().len())),
+        ("report_snapshot", REPORT_SNAPSHOTS.with(|s| s.borrow().len())),
+        ("anomaly", ANOMALIES.with(|s| s.borrow().len())),
+    ];
+    family(
+        "entities",
+        "gauge",
+        "Stored entities by type",
+        entities.iter().map(|(entity, count)| (format!("{{type=\"{}\"}}", entity), *count)).collect(),
+    );
+    family(
+        "open_alerts",
+        "gauge",
+        "Critical cases, pending referrals and open health worker alerts",
+        vec![
+            ("{kind=\"critical_case\"}".to_string(), counter("status:Critical")),
+            ("{kind=\"pending_referral\"}".to_string(), counter("referrals:Pending")),
+            ("{kind=\"chw_alert\"}".to_string(), counter(OPEN_CHW_ALERTS_COUNTER)),
+        ],
+    );
+
+    let jobs: Vec<(&str, JobRuns)> =
+        JOB_METRICS.with(|metrics| metrics.borrow().iter().map(|(job, runs)| (*job, *runs)).collect());
+    let job_samples = |value: fn(&JobRuns) -> u64| {
+        jobs.iter().map(|(job, runs)| (format!("{{job=\"{}\"}}", job), value(runs))).collect::<Vec<_>>()
+    };
+    family("job_runs_total", "counter", "Periodic job runs that did work", job_samples(|runs| runs.runs));
+    family(
+        "job_skipped_total",
+        "counter",
+        "Periodic job runs with nothing to do or skipped during maintenance",
+        job_samples(|runs| runs.skipped),
+    );
+    family(
+        "job_last_run_timestamp_seconds",
+        "gauge",
+        "When the job last did work",
+        job_samples(|runs| runs.last_run_at / 1_000_000_000),
+    );
+    family("job_last_items", "gauge", "Items the job's last run produced", job_samples(|runs| runs.last_items));
+
+    family(
+        "stable_memory_pages",
+        "gauge",
+        "Stable memory size in 64 KiB pages",
+        vec![(String::new(), ic_cdk::api::stable::stable64_size())],
+    );
+    out
+}
+
+fn http_mother_summary(mother_id: u64) -> Option<serde_json::Value> {
+    let profile = load_mother_profile(mother_id).ok()?;
+    let latest = latest_health_record(mother_id);
+    let visits = HEALTH_RECORD_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .range(mother_record_keys(mother_id))
+            .count()
+    });
+
+    Some(serde_json::json!({
+        "id": profile.id,
+        "stage": format!("{:?}", profile.stage),
+        "health_status": format!("{:?}", profile.health_status),
+        "risk_tier": risk_tier(mother_id),
+        "expected_delivery_date": &format_iso8601(profile.expected_delivery_date)[..10],
+        "gestational_age_weeks": gestational_age_weeks(profile.expected_delivery_date, time()),
+        "facility_code": profile.facility_code,
+        "visits": visits,
+        "last_visit": latest.as_ref().map(|record| format_iso8601(record.date)),
+        "next_appointment": latest.as_ref().map(|record| format_iso8601(record.next_appointment)),
+    }))
+}
+
+fn http_json(status_code: u16, body: serde_json::Value) -> HttpResponse {
+    HttpResponse {
+        status_code,
+        headers: vec![
+            ("Content-Type".to_string(), "application/json".to_string()),
+            ("Cache-Control".to_string(), "no-store".to_string()),
+        ],
+        body: body.to_string().into_bytes(),
+        upgrade: None,
+    }
+}
+
+// Cycles attached to each HTTPS outcall; the unused part is refunded
+const OUTCALL_CYCLES: u128 = 2_000_000_000;
+const OUTCALL_MAX_RESPONSE_BYTES: u64 = 4096;
+
+// Three 160-character segments
+const MAX_SMS_BODY_CHARS: usize = 480;
+const MAX_SMS_ATTEMPTS: u8 = 3;
+// Messages sent per dispatcher run
+const SMS_BATCH_SIZE: usize = 10;
+// A message still sending after this long lost its reply (e.g. to an upgrade) and is sent again
+const SMS_SENDING_TIMEOUT_MINUTES: u64 = 15;
+
+fn sms_gateway() -> Option<SmsGatewayConfig> {
+    SMS_SETTINGS
+        .with(|settings| settings.borrow().get().gateway.clone())
+        .filter(|gateway| gateway.enabled && gateway.base_url.is_some())
+}
+
+// Configure the SMS provider the dispatcher sends reminders and alerts through (controllers and admins)
+#[ic_cdk::update]
+fn set_sms_gateway(gateway: SmsGatewayConfig) -> Result<SmsGatewayView, Error> {
+    ensure_controller()?;
+    validate_sms_gateway(&gateway)?;
+
+    let view = sms_gateway_view(&gateway);
+    SMS_SETTINGS.with(|settings| {
+        settings
+            .borrow_mut()
+            .set(SmsSettings { gateway: Some(gateway) })
+            .map(|_| ())
+            .map_err(|_| Error::SystemError { msg: "Failed to store SMS settings".to_string() })
+    })?;
+    Ok(view)
+}
+
+#[ic_cdk::query]
+fn get_sms_gateway() -> Result<Option<SmsGatewayView>, Error> {
+    ensure_controller()?;
+    Ok(SMS_SETTINGS.with(|settings| settings.borrow().get().gateway.as_ref().map(sms_gateway_view)))
+}
+
+fn validate_sms_gateway(gateway: &SmsGatewayConfig) -> Result<(), Error> {
+    let invalid = |msg: &str| Err(Error::InvalidInput { msg: msg.to_string() });
+    if gateway.account.trim().is_empty() || gateway.account.len() > 100 {
+        return invalid("Account must be between 1 and 100 characters");
+    }
+    if gateway.api_key.trim().is_empty() || gateway.api_key.len() > 200 {
+        return invalid("API key must be between 1 and 200 characters");
+    }
+    if gateway.callback_token.len() < 16
+        || gateway.callback_token.len() > 64
+        || !gateway.callback_token.chars().all(|c| c.is_ascii_alphanumeric())
+    {
+        return invalid("Callback token must be 16 to 64 letters and digits");
+    }
+    match &gateway.sender_id {
+        Some(sender_id) if sender_id.trim().is_empty() || sender_id.len() > 20 => {
+            return invalid("Sender id must be between 1 and 20 characters");
+        }
+        None if gateway.provider == SmsProvider::Twilio => {
+            return invalid("Twilio needs the From number as sender_id");
+        }
+        _ => {}
+    }
+    match &gateway.base_url {
+        Some(base_url) if base_url.starts_with("https://") && base_url.len() <= 200 => Ok(()),
+        _ => invalid("Base URL must be the https:// address of a relay, at most 200 characters"),
+    }
+}
+
+fn sms_gateway_view(gateway: &SmsGatewayConfig) -> SmsGatewayView {
+    let hint_start = gateway.api_key.len().saturating_sub(4);
+    SmsGatewayView {
+        provider: gateway.provider,
+        account: gateway.account.clone(),
+        api_key_hint: format!("...{}", gateway.api_key.get(hint_start..).unwrap_or_default()),
+        sender_id: gateway.sender_id.clone(),
+        base_url: gateway.base_url.clone(),
+        callback_url: sms_callback_url(gateway),
+        enabled: gateway.enabled,
+    }
+}
+
+fn sms_callback_url(gateway: &SmsGatewayConfig) -> String {
+    format!("https://{}.raw.icp0.io/sms/status/{}", ic_cdk::id().to_text(), gateway.callback_token)
+}
+
+// The service and token of a status callback path: /sms/status/{token} or /dispatch/status/{token}
+fn callback_token(url: &str) -> Option<(&str, &str)> {
+    let path = url.split(['?', '#']).next().unwrap_or_default();
+    match path.split('/').filter(|s| !s.is_empty()).collect::<Vec<_>>().as_slice() {
+        [service @ ("sms" | "dispatch"), "status", token] => Some((service, token)),
+        _ => None,
+    }
+}
+
+// A phone number the provider accepts: E.164, + and 8 to 15 digits, ignoring spaces and dashes
+fn sms_number(contact: &str) -> Option<String> {
+    let number: String = contact.chars().filter(|c| !c.is_whitespace() && *c != '-').collect();
+    let digits = number.strip_prefix('+')?;
+    ((8..=15).contains(&digits.len()) && digits.chars().all(|c| c.is_ascii_digit())).then_some(number)
+}
+
+// Queue a message to the mother's number once per idempotency key; None when no gateway is enabled,
+// her number is not in E.164 form or the key was already used
+fn queue_sms(mother_id: u64, kind: SmsKind, idempotency_key: String, body: String) -> Option<u64> {
+    sms_gateway()?;
+    let to = sms_number(&load_mother_profile(mother_id).ok()?.emergency_contact)?;
+    let key = StringKey(idempotency_key.clone());
+    if SMS_KEYS.with(|keys| keys.borrow().contains_key(&key)) {
+        return None;
+    }
+
+    let id = SMS_ID_SEQ.with(|counter| {
+        let next = *counter.borrow().get() + 1;
+        counter.borrow_mut().set(next).expect("Cannot update SMS id sequence");
+        next
+    });
+    let now = time();
+    let message = SmsMessage {
+        id,
+        mother_id,
+        kind,
+        to,
+        body: body.chars().take(MAX_SMS_BODY_CHARS).collect(),
+        idempotency_key,
+        status: SmsStatus::Queued,
+        attempts: 0,
+        provider_message_id: None,
+        error: None,
+        created_at: now,
+        updated_at: now,
+    };
+    SMS_OUTBOX.with(|outbox| outbox.borrow_mut().insert(id, message));
+    SMS_KEYS.with(|keys| keys.borrow_mut().insert(key, id));
+    Some(id)
+}
+
+// Reminders for mothers whose next appointment falls within the coming day, once per appointment day
+fn queue_appointment_reminders(now: u64) -> u64 {
+    let due: Vec<(u64, u64)> = PROFILE_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .filter(|(_, profile)| profile.delivery.is_none())
+            .filter_map(|(id, _)| latest_health_record(id).map(|record| (id, record.next_appointment)))
+            .filter(|(_, appointment)| *appointment > now && *appointment <= now + NANOS_PER_DAY)
+            .collect()
+    });
+    let mut queued = 0;
+    for (mother_id, appointment) in due {
+        let day = record_day(appointment);
+        let body = format!("MamaPack: reminder of your antenatal visit on {}. Please bring your mother's card.", day);
+        if queue_sms(mother_id, SmsKind::AppointmentReminder, format!("reminder:{}:{}", mother_id, day), body).is_some() {
+            queued += 1;
+        }
+    }
+    queued
+}
+
+// Queue due reminders, then send a batch of queued messages through the provider
+fn run_sms_dispatch() {
+    if MAINTENANCE.with(|maintenance| maintenance.borrow().is_some()) || sms_gateway().is_none() {
+        observe_job("sms_dispatch", None);
+        return;
+    }
+    let now = time();
+    queue_appointment_reminders(now);
+
+    let stale = now.saturating_sub(SMS_SENDING_TIMEOUT_MINUTES * 60 * 1_000_000_000);
+    let batch: Vec<u64> = SMS_OUTBOX.with(|outbox| {
+        outbox
+            .borrow()
+            .iter()
+            .filter(|(_, message)| match message.status {
+                SmsStatus::Queued => true,
+                SmsStatus::Sending => message.updated_at <= stale,
+                _ => false,
+            })
+            .map(|(id, _)| id)
+            .take(SMS_BATCH_SIZE)
+            .collect()
+    });
+    observe_job("sms_dispatch", Some(batch.len() as u64));
+    for id in batch {
+        ic_cdk::spawn(send_sms(id));
+    }
+}
+
+// Send one message. It is marked Sending before the call so the next run does not send it again;
+// failures are retried up to MAX_SMS_ATTEMPTS times.
+async fn send_sms(id: u64) {
+    use ic_cdk::api::management_canister::http_request::http_request;
+
+    let Some(gateway) = sms_gateway() else {
+        return;
+    };
+    let Some(mut message) = SMS_OUTBOX.with(|outbox| outbox.borrow().get(&id)) else {
+        return;
+    };
+    message.status = SmsStatus::Sending;
+    message.attempts = message.attempts.saturating_add(1);
+    message.updated_at = time();
+    SMS_OUTBOX.with(|outbox| outbox.borrow_mut().insert(id, message.clone()));
+
+    let outcome = match http_request(sms_request(&gateway, &message), OUTCALL_CYCLES).await {
+        Ok((response,)) => sms_outcome(&response),
+        Err((_, msg)) => Err(msg),
+    };
+
+    // Reload: a delivery report may have arrived while waiting
+    let Some(mut message) = SMS_OUTBOX.with(|outbox| outbox.borrow().get(&id)) else {
+        return;
+    };
+    if message.status != SmsStatus::Sending {
+        return;
+    }
+    match outcome {
+        Ok(provider_message_id) => {
+            if provider_message_id.len() <= 128 {
+                SMS_PROVIDER_IDS
+                    .with(|ids| ids.borrow_mut().insert(StringKey(provider_message_id.clone()), id));
+            }
+            message.status = SmsStatus::Sent;
+            message.provider_message_id = Some(provider_message_id);
+            message.error = None;
+        }
+        Err(error) => {
+            message.status = if message.attempts >= MAX_SMS_ATTEMPTS { SmsStatus::Failed } else { SmsStatus::Queued };
+            message.error = Some(error.chars().take(200).collect());
+        }
+    }
+    message.updated_at = time();
+    SMS_OUTBOX.with(|outbox| outbox.borrow_mut().insert(id, message));
+}
+
+// The send request, addressed to the relay. Neither provider honours Idempotency-Key, and each
+// replica of the subnet sends its own copy of the request; the relay forwards the first copy per
+// key and returns that reply to the rest, so the mother gets one text and replicas see one reply.
+fn sms_request(
+    gateway: &SmsGatewayConfig,
+    message: &SmsMessage,
+) -> ic_cdk::api::management_canister::http_request::CanisterHttpRequestArgument {
+    use ic_cdk::api::management_canister::http_request::{
+        CanisterHttpRequestArgument, HttpHeader, HttpMethod, TransformContext,
+    };
+
+    // sms_gateway only returns a gateway with a relay
+    let relay = gateway.base_url.clone().unwrap_or_default();
+    let header = |name: &str, value: String| HttpHeader { name: name.to_string(), value };
+    let mut headers = vec![
+        header("Content-Type", "application/x-www-form-urlencoded".to_string()),
+        header("Accept", "application/json".to_string()),
+        header("Idempotency-Key", format!("mamapack-{}", message.idempotency_key)),
+    ];
+    let (url, fields) = match gateway.provider {
+        SmsProvider::AfricasTalking => {
+            headers.push(header("apiKey", gateway.api_key.clone()));
+            let mut fields = vec![
+                ("username", gateway.account.clone()),
+                ("to", message.to.clone()),
+                ("message", message.body.clone()),
+            ];
+            if let Some(sender_id) = &gateway.sender_id {
+                fields.push(("from", sender_id.clone()));
+            }
+            (relay, fields)
+        }
+        SmsProvider::Twilio => {
+            let credentials = format!("{}:{}", gateway.account, gateway.api_key);
+            headers.push(header("Authorization", format!("Basic {}", base64_encode(credentials.as_bytes()))));
+            let fields = vec![
+                ("To", message.to.clone()),
+                ("From", gateway.sender_id.clone().unwrap_or_default()),
+                ("Body", message.body.clone()),
+                ("StatusCallback", sms_callback_url(gateway)),
+            ];
+            (format!("{}/Accounts/{}/Messages.json", relay, gateway.account), fields)
+        }
+    };
+
+    CanisterHttpRequestArgument {
+        url,
+        max_response_bytes: Some(OUTCALL_MAX_RESPONSE_BYTES),
+        method: HttpMethod::POST,
+        headers,
+        body: Some(form_encode(&fields).into_bytes()),
+        transform: Some(TransformContext::from_name(
+            "transform_sms_response".to_string(),
+            Encode!(&gateway.provider).unwrap(),
+        )),
+    }
+}
+
+// The provider's message id from its reply to a send
+fn parse_sms_response(
+    provider: SmsProvider,
+    response: &ic_cdk::api::management_canister::http_request::HttpResponse,
+) -> Result<String, String> {
+    let status = response.status.0.to_string();
+    if !status.starts_with('2') {
+        return Err(format!("Provider returned HTTP {}", status));
+    }
+    let body: serde_json::Value =
+        serde_json::from_slice(&response.body).map_err(|_| "Provider reply is not JSON".to_string())?;
+    let id = match provider {
+        SmsProvider::AfricasTalking => {
+            let recipient = &body["SMSMessageData"]["Recipients"][0];
+            if recipient["status"].as_str() != Some("Success") {
+                return Err(format!(
+                    "Provider rejected the message: {}",
+                    recipient["status"].as_str().unwrap_or("no recipient")
+                ));
+            }
+            recipient["messageId"].as_str()
+        }
+        SmsProvider::Twilio => body["sid"].as_str(),
+    };
+    id.map(str::to_string).ok_or_else(|| "Provider reply has no message id".to_string())
+}
+
+// Replicas must agree on the reply, and besides headers the provider's body carries values such
+// as cost and timestamps. Reduce it to the outcome: the status and the message id, or the error.
+#[ic_cdk::query]
+fn transform_sms_response(
+    args: ic_cdk::api::management_canister::http_request::TransformArgs,
+) -> ic_cdk::api::management_canister::http_request::HttpResponse {
+    let outcome = match Decode!(&args.context, SmsProvider) {
+        Ok(provider) => parse_sms_response(provider, &args.response),
+        Err(_) => Err("Unknown provider".to_string()),
+    };
+    let body = match outcome {
+        Ok(message_id) => serde_json::json!({ "message_id": message_id }),
+        Err(error) => serde_json::json!({ "error": error }),
+    };
+    ic_cdk::api::management_canister::http_request::HttpResponse {
+        status: args.response.status,
+        headers: Vec::new(),
+        body: body.to_string().into_bytes(),
+    }
+}
+
+// The outcome transform_sms_response reduced the provider's reply to
+fn sms_outcome(response: &ic_cdk::api::management_canister::http_request::HttpResponse) -> Result<String, String> {
+    let body: serde_json::Value = serde_json::from_slice(&response.body).unwrap_or_default();
+    match (body["message_id"].as_str(), body["error"].as_str()) {
+        (Some(message_id), _) => Ok(message_id.to_string()),
+        (None, Some(error)) => Err(error.to_string()),
+        (None, None) => Err("Provider reply has no message id".to_string()),
+    }
+}
+
+// The dispatch service's reply may differ between replicas (its job id, timestamps), so keep
+// only the status and the reference it echoes, which is ours and the same for every replica
+#[ic_cdk::query]
+fn transform_dispatch_response(
+    args: ic_cdk::api::management_canister::http_request::TransformArgs,
+) -> ic_cdk::api::management_canister::http_request::HttpResponse {
+    let reply: serde_json::Value = serde_json::from_slice(&args.response.body).unwrap_or_default();
+    let body = serde_json::json!({ "reference": reply["reference"].as_str() });
+    ic_cdk::api::management_canister::http_request::HttpResponse {
+        status: args.response.status,
+        headers: Vec::new(),
+        body: body.to_string().into_bytes(),
+    }
+}
+
+// Summaries read with a share token, and status callbacks from the SMS provider and the
+// dispatch service
+#[ic_cdk::update]
+fn http_request_update(request: HttpRequest) -> HttpResponse {
+    if request.method.eq_ignore_ascii_case("GET") {
+        let path = request.url.split(['?', '#']).next().unwrap_or_default();
+        return match path.split('/').filter(|s| !s.is_empty()).collect::<Vec<_>>().as_slice() {
+            ["mothers", id, "summary"] => shared_mother_summary(&request, id),
+            _ => http_json(404, serde_json::json!({ "error": "Not found" })),
+        };
+    }
+    if !request.method.eq_ignore_ascii_case("POST") {
+        return http_json(405, serde_json::json!({ "error": "Only GET and POST are supported" }));
+    }
+    match callback_token(&request.url) {
+        Some(("sms", token)) => sms_delivery_report(&request, token),
+        Some(("dispatch", token)) => dispatch_status_report(&request, token),
+        _ => http_json(404, serde_json::json!({ "error": "Not found" })),
+    }
+}
+
+// Delivery reports from the provider, posted as a form: Africa's Talking sends `id` and `status`,
+// Twilio `MessageSid` and `MessageStatus`
+fn sms_delivery_report(request: &HttpRequest, token: &str) -> HttpResponse {
+    let Some(gateway) = SMS_SETTINGS.with(|settings| settings.borrow().get().gateway.clone()) else {
+        return http_json(404, serde_json::json!({ "error": "Not found" }));
+    };
+    if token != gateway.callback_token {
+        return http_json(404, serde_json::json!({ "error": "Not found" }));
+    }
+
+    let form = form_decode(&String::from_utf8_lossy(&request.body));
+    let field = |names: [&str; 2]| names.iter().find_map(|name| form.get(*name)).cloned().unwrap_or_default();
+    let provider_message_id = field(["id", "MessageSid"]);
+    let status = match field(["status", "MessageStatus"]).to_ascii_lowercase().as_str() {
+        "success" | "delivered" => SmsStatus::Delivered,
+        "failed" | "rejected" | "undelivered" => SmsStatus::Failed,
+        "sent" | "submitted" | "buffered" | "queued" | "sending" | "accepted" => SmsStatus::Sent,
+        _ => return http_json(400, serde_json::json!({ "error": "Unknown status" })),
+    };
+    if provider_message_id.len() > 128 {
+        return http_json(400, serde_json::json!({ "error": "Invalid message id" }));
+    }
+    let Some(id) = SMS_PROVIDER_IDS.with(|ids| ids.borrow().get(&StringKey(provider_message_id))) else {
+        return http_json(404, serde_json::json!({ "error": "Unknown message" }));
+    };
+
+    if let Some(mut message) = SMS_OUTBOX.with(|outbox| outbox.borrow().get(&id)) {
+        // Reports can arrive out of order; a final status is not overwritten
+        if !matches!(message.status, SmsStatus::Delivered | SmsStatus::Failed) {
+            message.status = status;
+            if status == SmsStatus::Failed {
+                let reason = field(["failureReason", "ErrorCode"]);
+                message.error = Some(format!("Not delivered: {}", reason).chars().take(200).collect());
+            }
+            message.updated_at = time();
+            SMS_OUTBOX.with(|outbox| outbox.borrow_mut().insert(id, message));
+        }
+    }
+    http_json(200, serde_json::json!({ "ok": true }))
+}
+
+// Outgoing messages, optionally for one mother or with one status, paged by message id (controllers and admins)
+#[ic_cdk::query]
+fn get_sms_messages(
+    mother_id: Option<u64>,
+    status: Option<SmsStatus>,
+    cursor: Option<u64>,
+    limit: Option<u32>,
+) -> Result<Page<SmsMessage>, Error> {
+    ensure_controller()?;
+    Ok(SMS_OUTBOX.with(|outbox| {
+        let outbox = outbox.borrow();
+        let messages = outbox.iter().filter(|(_, message)| {
+            let mother_matches = match mother_id {
+                Some(id) => message.mother_id == id,
+                None => true,
+            };
+            let status_matches = match status {
+                Some(status) => message.status == status,
+                None => true,
+            };
+            mother_matches && status_matches
+        });
+        paginate(messages, cursor, limit)
+    }))
+}
+
+fn remove_sms_messages(mother_id: u64) {
+    let removed: Vec<SmsMessage> = SMS_OUTBOX.with(|outbox| {
+        outbox
+            .borrow()
+            .iter()
+            .filter(|(_, message)| message.mother_id == mother_id)
+            .map(|(_, message)| message)
+            .collect()
+    });
+    for message in removed {
+        SMS_OUTBOX.with(|outbox| outbox.borrow_mut().remove(&message.id));
+        SMS_KEYS.with(|keys| keys.borrow_mut().remove(&StringKey(message.idempotency_key)));
+        if let Some(provider_message_id) = message.provider_message_id {
+            SMS_PROVIDER_IDS.with(|ids| ids.borrow_mut().remove(&StringKey(provider_message_id)));
+        }
+    }
+}
+
+const MAX_DISPATCH_LOCATION_CHARS: usize = 200;
+const MAX_DISPATCH_CONDITION_CHARS: usize = 500;
+
+// Configure the dispatch API ambulance requests are posted to (controllers and admins)
+#[ic_cdk::update]
+fn set_dispatch_api(api: DispatchApiConfig) -> Result<DispatchApiView, Error> {
+    ensure_controller()?;
+    if !api.url.starts_with("https://") || api.url.len() > 200 {
+        return Err(Error::InvalidInput {
+            msg: "Dispatch URL must be an https:// address of at most 200 characters".to_string(),
+        });
+    }
+    if api.api_key.trim().is_empty() || api.api_key.len() > 200 {
+        return Err(Error::InvalidInput {
+            msg: "API key must be between 1 and 200 characters".to_string(),
+        });
+    }
+    if api.callback_token.len() < 16
+        || api.callback_token.len() > 64
+        || !api.callback_token.chars().all(|c| c.is_ascii_alphanumeric())
+    {
+        return Err(Error::InvalidInput {
+            msg: "Callback token must be 16 to 64 letters and digits".to_string(),
+        });
+    }
+
+    let view = dispatch_api_view(&api);
+    DISPATCH_SETTINGS.with(|settings| {
+        settings
+            .borrow_mut()
+            .set(DispatchSettings { api: Some(api) })
+            .map(|_| ())
+            .map_err(|_| Error::SystemError { msg: "Failed to store dispatch settings".to_string() })
+    })?;
+    Ok(view)
+}
+
+#[ic_cdk::query]
+fn get_dispatch_api() -> Result<Option<DispatchApiView>, Error> {
+    ensure_controller()?;
+    Ok(DISPATCH_SETTINGS.with(|settings| settings.borrow().get().api.as_ref().map(dispatch_api_view)))
+}
+
+fn dispatch_api_view(api: &DispatchApiConfig) -> DispatchApiView {
+    let hint_start = api.api_key.len().saturating_sub(4);
+    DispatchApiView {
+        url: api.url.clone(),
+        api_key_hint: format!("...{}", api.api_key.get(hint_start..).unwrap_or_default()),
+        callback_url: dispatch_callback_url(api),
+        enabled: api.enabled,
+    }
+}
+
+fn dispatch_callback_url(api: &DispatchApiConfig) -> String {
+    format!("https://{}.raw.icp0.io/dispatch/status/{}", ic_cdk::id().to_text(), api.callback_token)
+}
+
+// The alert's health worker, the mother's care team, controllers and admins
+fn ensure_alert_responder(alert: &ChwAlert) -> Result<(), Error> {
+    let caller = ic_cdk::caller();
+    if alert.chw == Some(caller) || care_team(alert.mother_id).members.iter().any(|member| member.principal == caller) {
+        return Ok(());
+    }
+    ensure_controller().map_err(|_| Error::AuthorizationError {
+        msg: "Only the alert's health worker and the mother's care team can dispatch for it".to_string(),
+    })
+}
+
+fn load_chw_alert(id: u64) -> Result<ChwAlert, Error> {
+    CHW_ALERTS.with(|alerts| alerts.borrow().get(&id)).ok_or_else(|| Error::NotFound {
+        msg: format!("Alert with id={} not found", id),
+    })
+}
+
+// Save a dispatch and mirror its status on the alert
+fn store_dispatch(dispatch: &Dispatch) {
+    DISPATCHES.with(|dispatches| dispatches.borrow_mut().insert(dispatch.alert_id, dispatch.clone()));
+    if let Ok(mut alert) = load_chw_alert(dispatch.alert_id) {
+        alert.dispatch_status = Some(dispatch.status);
+        CHW_ALERTS.with(|alerts| alerts.borrow_mut().insert(alert.id, alert));
+    }
+}
+
+// Confirm an alert as an obstetric emergency and request an ambulance from the dispatch API. A
+// failed or cancelled dispatch can be requested again; an active one cannot.
+#[ic_cdk::update]
+async fn request_dispatch(alert_id: u64, request: DispatchRequest) -> Result<Dispatch, Error> {
+    use ic_cdk::api::management_canister::http_request::{
+        http_request, CanisterHttpRequestArgument, HttpHeader, HttpMethod, TransformContext,
+    };
+
+    let alert = load_chw_alert(alert_id)?;
+    ensure_alert_responder(&alert)?;
+    let api = DISPATCH_SETTINGS
+        .with(|settings| settings.borrow().get().api.clone())
+        .filter(|api| api.enabled)
+        .ok_or_else(|| Error::SystemError { msg: "Dispatch API is not configured".to_string() })?;
+    let location = request.location.trim().to_string();
+    let condition = request.condition.trim().to_string();
+    if location.is_empty() || location.chars().count() > MAX_DISPATCH_LOCATION_CHARS {
+        return Err(Error::InvalidInput {
+            msg: format!("Location must be between 1 and {} characters", MAX_DISPATCH_LOCATION_CHARS),
+        });
+    }
+    if condition.is_empty() || condition.chars().count() > MAX_DISPATCH_CONDITION_CHARS {
+        return Err(Error::InvalidInput {
+            msg: format!("Condition summary must be between 1 and {} characters", MAX_DISPATCH_CONDITION_CHARS),
+        });
+    }
+    validate_facility_code(&request.destination_facility)?;
+    let profile = load_mother_profile(alert.mother_id)?;
+
+    let previous = DISPATCHES.with(|dispatches| dispatches.borrow().get(&alert_id));
+    if let Some(previous) = &previous {
+        if !matches!(previous.status, DispatchStatus::Failed | DispatchStatus::Cancelled) {
+            return Err(Error::Conflict {
+                msg: format!("An ambulance was already requested for alert id={}", alert_id),
+            });
+        }
+    }
+    let now = time();
+    let mut dispatch = Dispatch {
+        alert_id,
+        mother_id: alert.mother_id,
+        location,
+        condition,
+        destination_facility: request.destination_facility,
+        reference: format!("mamapack-alert-{}", alert_id),
+        external_id: None,
+        status: DispatchStatus::Requesting,
+        attempts: previous.map_or(0, |previous| previous.attempts).saturating_add(1),
+        error: None,
+        requested_by: ic_cdk::caller(),
+        requested_at: now,
+        updated_at: now,
+    };
+    // Stored before the call so a second request for the alert is refused while this one is in flight
+    store_dispatch(&dispatch);
+
+    // No name or contact details leave the canister
+    let body = serde_json::json!({
+        "reference": dispatch.reference,
+        "location": dispatch.location,
+        "condition": dispatch.condition,
+        "destination_facility": dispatch.destination_facility,
+        "patient_age": profile.age,
+        "gestational_age_weeks": profile.delivery.is_none()
+            .then(|| gestational_age_weeks(profile.expected_delivery_date, now)),
+        "callback_url": dispatch_callback_url(&api),
+    });
+    let header = |name: &str, value: String| HttpHeader { name: name.to_string(), value };
+    let outcall = CanisterHttpRequestArgument {
+        url: api.url.clone(),
+        max_response_bytes: Some(OUTCALL_MAX_RESPONSE_BYTES),
+        method: HttpMethod::POST,
+        headers: vec![
+            header("Content-Type", "application/json".to_string()),
+            header("Authorization", format!("Bearer {}", api.api_key)),
+            // The subnet's replicas each post this request. The service opens one job per key;
+            // the attempt number tells a deliberate re-request after a failure from those copies.
+            header("Idempotency-Key", format!("{}-{}", dispatch.reference, dispatch.attempts)),
+        ],
+        body: Some(body.to_string().into_bytes()),
+        transform: Some(TransformContext::from_name("transform_dispatch_response".to_string(), Vec::new())),
+    };
+    // The job id comes with the first status callback; see dispatch_status_report
+    let outcome = match http_request(outcall, OUTCALL_CYCLES).await {
+        Ok((response,)) if response.status.0.to_string().starts_with('2') => Ok(()),
+        Ok((response,)) => Err(format!("Dispatch API returned HTTP {}", response.status.0)),
+        Err((_, msg)) => Err(msg),
+    };
+
+    // A status update may have arrived while waiting
+    if let Some(current) = DISPATCHES.with(|dispatches| dispatches.borrow().get(&alert_id)) {
+        if current.status != DispatchStatus::Requesting {
+            return Ok(current);
+        }
+    }
+    match outcome {
+        Ok(()) => dispatch.status = DispatchStatus::Requested,
+        Err(error) => {
+            dispatch.status = DispatchStatus::Failed;
+            dispatch.error = Some(error.chars().take(200).collect());
+        }
+    }
+    dispatch.updated_at = time();
+    store_dispatch(&dispatch);
+    Ok(dispatch)
+}
+
+#[ic_cdk::query]
+fn get_alert_dispatch(alert_id: u64) -> Result<Option<Dispatch>, Error> {
+    ensure_alert_responder(&load_chw_alert(alert_id)?)?;
+    Ok(DISPATCHES.with(|dispatches| dispatches.borrow().get(&alert_id)))
+}
+
+// Record a status the dispatch service gave by phone or radio rather than by callback
+#[ic_cdk::update]
+fn update_dispatch_status(alert_id: u64, status: DispatchStatus) -> Result<Dispatch, Error> {
+    ensure_alert_responder(&load_chw_alert(alert_id)?)?;
+    let mut dispatch = DISPATCHES.with(|dispatches| dispatches.borrow().get(&alert_id)).ok_or_else(|| {
+        Error::NotFound {
+            msg: format!("No ambulance was requested for alert id={}", alert_id),
+        }
+    })?;
+    if status == DispatchStatus::Requesting {
+        return Err(Error::InvalidInput {
+            msg: "Requesting is set by request_dispatch".to_string(),
+        });
+    }
+    dispatch.status = status;
+    dispatch.updated_at = time();
+    store_dispatch(&dispatch);
+    Ok(dispatch)
+}
+
+// Status updates from the dispatch service, posted as JSON with the `reference` it was sent, its
+// own job `id` and a `status`: accepted, assigned, en_route, arrived, completed, cancelled or failed
+fn dispatch_status_report(request: &HttpRequest, token: &str) -> HttpResponse {
+    let Some(api) = DISPATCH_SETTINGS.with(|settings| settings.borrow().get().api.clone()) else {
+        return http_json(404, serde_json::json!({ "error": "Not found" }));
+    };
+    if token != api.callback_token {
+        return http_json(404, serde_json::json!({ "error": "Not found" }));
+    }
+
+    let Ok(report) = serde_json::from_slice::<serde_json::Value>(&request.body) else {
+        return http_json(400, serde_json::json!({ "error": "Body must be JSON" }));
+    };
+    let status = match report["status"].as_str().unwrap_or_default().to_ascii_lowercase().as_str() {
+        "accepted" | "requested" => DispatchStatus::Requested,
+        "assigned" => DispatchStatus::Assigned,
+        "en_route" | "dispatched" => DispatchStatus::EnRoute,
+        "arrived" | "on_scene" => DispatchStatus::Arrived,
+        "completed" => DispatchStatus::Completed,
+        "cancelled" => DispatchStatus::Cancelled,
+        "failed" => DispatchStatus::Failed,
+        _ => return http_json(400, serde_json::json!({ "error": "Unknown status" })),
+    };
+    let alert_id = report["reference"]
+        .as_str()
+        .and_then(|reference| reference.strip_prefix("mamapack-alert-"))
+        .and_then(|id| id.parse::<u64>().ok());
+    let Some(mut dispatch) = alert_id.and_then(|id| DISPATCHES.with(|dispatches| dispatches.borrow().get(&id))) else {
+        return http_json(404, serde_json::json!({ "error": "Unknown dispatch" }));
+    };
+
+    dispatch.status = status;
+    if let Some(external_id) = report["id"].as_str().filter(|id| !id.is_empty()) {
+        dispatch.external_id = Some(external_id.chars().take(64).collect());
+    }
+    if status == DispatchStatus::Failed {
+        dispatch.error = report["reason"].as_str().map(|reason| reason.chars().take(200).collect());
+    }
+    dispatch.updated_at = time();
+    store_dispatch(&dispatch);
+    http_json(200, serde_json::json!({ "ok": true }))
+}
+
+fn remove_dispatches(mother_id: u64) {
+    let ids: Vec<u64> = DISPATCHES.with(|dispatches| {
+        dispatches
+            .borrow()
+            .iter()
+            .filter(|(_, dispatch)| dispatch.mother_id == mother_id)
+            .map(|(id, _)| id)
+            .collect()
+    });
+    DISPATCHES.with(|dispatches| {
+        let mut dispatches = dispatches.borrow_mut();
+        for id in &ids {
+            dispatches.remove(id);
+        }
+    });
+}
+
+// USSD screens show about 160 characters
+const USSD_REPLY_CHARS: usize = 160;
+const USSD_MAX_FAILED_PINS: u8 = 5;
+const USSD_LOCKOUT_MINUTES: u64 = 30;
+
+// Grant or revoke the USSD gateway role (controllers and admins only)
+#[ic_cdk::update]
+fn set_ussd_gateway(principal: Principal, allowed: bool) -> Result<(), Error> {
+    ensure_controller()?;
+    set_grant(&USSD_GATEWAYS, principal, allowed);
+    Ok(())
+}
+
+#[ic_cdk::query]
+fn get_ussd_gateways() -> Result<Vec<AccessGrant>, Error> {
+    ensure_controller()?;
+    list_grants(&USSD_GATEWAYS)
+}
+
+// USSD gateways, controllers and admins
+fn ensure_ussd_gateway() -> Result<(), Error> {
+    let caller = StringKey(ic_cdk::caller().to_text());
+    if USSD_GATEWAYS.with(|gateways| gateways.borrow().contains_key(&caller)) {
+        return Ok(());
+    }
+    ensure_controller().map_err(|_| Error::AuthorizationError {
+        msg: "Only registered USSD gateways can sign mothers in by phone".to_string(),
+    })
+}
+
+// Let a mother use the USSD menus from her phone with a 4 to 6 digit PIN, replacing any earlier
+// PIN for that number (the mother, her care team, controllers and admins)
+#[ic_cdk::update]
+fn set_ussd_pin(mother_id: u64, phone: String, pin: String) -> Result<(), Error> {
+    if ensure_thread_access(mother_id).is_err() {
+        ensure_controller()?;
+    }
+    ensure_mother_exists(mother_id)?;
+    let phone = sms_number(&phone).ok_or_else(|| Error::InvalidInput {
+        msg: "Phone number must be in E.164 form, e.g. +254712345678".to_string(),
+    })?;
+    if !(4..=6).contains(&pin.len()) || !pin.chars().all(|c| c.is_ascii_digit()) {
+        return Err(Error::InvalidInput {
+            msg: "PIN must be 4 to 6 digits".to_string(),
+        });
+    }
+    let key = StringKey(phone.clone());
+    if let Some(existing) = USSD_PINS.with(|pins| pins.borrow().get(&key)) {
+        if existing.mother_id != mother_id {
+            return Err(Error::Conflict {
+                msg: format!("{} is already registered to another mother", phone),
+            });
+        }
+    }
+
+    let entry = UssdPin {
+        mother_id,
+        pin_hash: ussd_pin_hash(&phone, &pin)?,
+        failed_attempts: 0,
+        locked_until: None,
+        updated_at: time(),
+    };
+    USSD_PINS.with(|pins| pins.borrow_mut().insert(key, entry));
+    Ok(())
+}
+
+// Stop a phone number from signing in over USSD (its mother, her care team, controllers and admins)
+#[ic_cdk::update]
+fn remove_ussd_pin(phone: String) -> Result<(), Error> {
+    let key = StringKey(sms_number(&phone).unwrap_or(phone));
+    let entry = USSD_PINS.with(|pins| pins.borrow().get(&key)).ok_or_else(|| Error::NotFound {
+        msg: "No USSD PIN is set for this number".to_string(),
+    })?;
+    if ensure_thread_access(entry.mother_id).is_err() {
+        ensure_controller()?;
+    }
+    USSD_PINS.with(|pins| pins.borrow_mut().remove(&key));
+    Ok(())
+}
+
+// HMAC-SHA256 of the number and PIN under the canister's USSD PIN key. A PIN has at most a million
+// values, so a plain hash read from stable memory or a backup would give it away.
+fn ussd_pin_hash(phone: &str, pin: &str) -> Result<String, Error> {
+    let key = USSD_PIN_KEY.with(|key| key.borrow().get().key.clone());
+    if key.is_empty() {
+        return Err(Error::SystemError {
+            msg: "USSD PINs are not available yet; try again shortly".to_string(),
+        });
+    }
+    let message = [phone.as_bytes(), &[0], pin.as_bytes()].concat();
+    Ok(hmac_sha256(&key, &message).iter().map(|byte| format!("{:02x}", byte)).collect())
+}
+
+// Unkeyed hash of PINs set before the key existed; replaced by ussd_pin_hash at the next sign-in
+fn legacy_ussd_pin_hash(phone: &str, pin: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(b"mama-pack-ussd-pin");
+    hasher.update(phone.as_bytes());
+    hasher.update([0]);
+    hasher.update(pin.as_bytes());
+    hasher.finalize().iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+// The mother signed in by phone and PIN. Wrong PINs are counted, and after USSD_MAX_FAILED_PINS in
+// a row the number is locked for USSD_LOCKOUT_MINUTES; the USSD endpoints are updates so the count
+// is kept.
+fn ussd_sign_in(phone: &str, pin: &str) -> Result<MotherProfile, Error> {
+    ensure_ussd_gateway()?;
+    let denied = || Error::AuthorizationError {
+        msg: "Wrong phone number or PIN".to_string(),
+    };
+    let phone = sms_number(phone).ok_or_else(denied)?;
+    let key = StringKey(phone.clone());
+    let mut entry = USSD_PINS.with(|pins| pins.borrow().get(&key)).ok_or_else(denied)?;
+    let now = time();
+    if entry.locked_until.is_some_and(|until| until > now) {
+        return Err(Error::AuthorizationError {
+            msg: "Too many wrong PINs; try again later".to_string(),
+        });
+    }
+
+    let pin_hash = ussd_pin_hash(&phone, pin)?;
+    let legacy = entry.pin_hash == legacy_ussd_pin_hash(&phone, pin);
+    if entry.pin_hash != pin_hash && !legacy {
+        entry.failed_attempts = entry.failed_attempts.saturating_add(1);
+        if entry.failed_attempts >= USSD_MAX_FAILED_PINS {
+            entry.failed_attempts = 0;
+            entry.locked_until = Some(now + USSD_LOCKOUT_MINUTES * 60 * 1_000_000_000);
+        }
+        USSD_PINS.with(|pins| pins.borrow_mut().insert(key, entry));
+        return Err(denied());
+    }
+    if entry.failed_attempts > 0 || entry.locked_until.is_some() || legacy {
+        entry.failed_attempts = 0;
+        entry.locked_until = None;
+        entry.pin_hash = pin_hash;
+        USSD_PINS.with(|pins| pins.borrow_mut().insert(key, entry.clone()));
+    }
+    load_mother_profile(entry.mother_id)
+}
+
+fn ussd_reply(text: String) -> String {
+    text.chars().take(USSD_REPLY_CHARS).collect()
+}
+
+// One-line USSD screen with the mother's next appointment
+#[ic_cdk::update]
+fn ussd_next_appointment(phone: String, pin: String) -> Result<String, Error> {
+    let profile = ussd_sign_in(&phone, &pin)?;
+    let now = time();
+    let reply = match latest_health_record(profile.id).filter(|record| record.next_appointment > now) {
+        Some(record) => {
+            let days = (record.next_appointment - now).div_ceil(NANOS_PER_DAY);
+            let place = record.facility_code.or(profile.facility_code).unwrap_or_else(|| "your clinic".to_string());
+            format!("Next visit: {} (in {} days) at {}.", record_day(record.next_appointment), days, place)
+        }
+        None => "No visit booked. Please visit your clinic to book one.".to_string(),
+    };
+    Ok(ussd_reply(reply))
+}
+
+// The mother's top recommendations as one USSD screen
+#[ic_cdk::update]
+fn ussd_last_advice(phone: String, pin: String) -> Result<String, Error> {
+    let profile = ussd_sign_in(&phone, &pin)?;
+    let actions: Vec<String> =
+        get_recommendations(profile.id)?.into_iter().take(3).map(|recommendation| recommendation.action).collect();
+    let reply = if actions.is_empty() {
+        "No new advice. Keep attending your antenatal visits.".to_string()
+    } else {
+        format!("Advice: {}.", actions.join("; "))
+    };
+    Ok(ussd_reply(reply))
+}
+
+// Where to go: the facility of her latest visit, or the one she is registered at. Facilities have no
+// locations on file, so this is her own facility rather than the geographically nearest one.
+#[ic_cdk::update]
+fn ussd_nearest_facility(phone: String, pin: String) -> Result<String, Error> {
+    let profile = ussd_sign_in(&phone, &pin)?;
+    let facility = latest_health_record(profile.id).and_then(|record| record.facility_code).or(profile.facility_code);
+    // Without a facility on file, the deployment's own facility
+    let reply = match facility.or(canister_config().facility_name) {
+        Some(facility) => format!("Your facility: {}. In an emergency go there now.", facility),
+        None => "No facility on file. In an emergency go to the nearest health facility.".to_string(),
+    };
+    Ok(ussd_reply(reply))
+}
+
+fn remove_ussd_pins(mother_id: u64) {
+    let phones: Vec<StringKey> = USSD_PINS.with(|pins| {
+        pins.borrow().iter().filter(|(_, entry)| entry.mother_id == mother_id).map(|(phone, _)| phone).collect()
+    });
+    USSD_PINS.with(|pins| {
+        let mut pins = pins.borrow_mut();
+        for phone in &phones {
+            pins.remove(phone);
+        }
+    });
+}
+
+// application/x-www-form-urlencoded
+fn form_encode(fields: &[(&str, String)]) -> String {
+    let encode = |value: &str| {
+        value
+            .bytes()
+            .map(|byte| match byte {
+                b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => (byte as char).to_string(),
+                b' ' => "+".to_string(),
+                _ => format!("%{:02X}", byte),
+            })
+            .collect::<String>()
+    };
+    fields
+        .iter()
+        .map(|(name, value)| format!("{}={}", encode(name), encode(value)))
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+fn form_decode(body: &str) -> std::collections::BTreeMap<String, String> {
+    let decode = |text: &str| {
+        let bytes = text.as_bytes();
+        let mut decoded = Vec::with_capacity(bytes.len());
+        let mut i = 0;
+        while i < bytes.len() {
+            match bytes[i] {
+                b'+' => decoded.push(b' '),
+                b'%' if i + 2 < bytes.len() => {
+                    match u8::from_str_radix(std::str::from_utf8(&bytes[i + 1..i + 3]).unwrap_or("-"), 16) {
+                        Ok(byte) => {
+                            decoded.push(byte);
+                            i += 2;
+                        }
+                        Err(_) => decoded.push(b'%'),
+                    }
+                }
+                byte => decoded.push(byte),
+            }
+            i += 1;
+        }
+        String::from_utf8_lossy(&decoded).into_owned()
+    };
+    body.split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .map(|(name, value)| (decode(name), decode(value)))
+        .collect()
+}
+
+fn base64_encode(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let b = [chunk[0], chunk.get(1).copied().unwrap_or(0), chunk.get(2).copied().unwrap_or(0)];
+        let n = (b[0] as u32) << 16 | (b[1] as u32) << 8 | b[2] as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[(n >> (18 - 6 * i) & 63) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+// Render a mother's profile as a FHIR R4 Patient resource (JSON)
+#[ic_cdk::update]
+fn get_fhir_patient(mother_id: u64) -> Result<String, Error> {
+    ensure_feature("fhir_export")?;
+    let profile = load_mother_profile(mother_id)?;
+    log_read(mother_id, "get_fhir_patient");
+    Ok(fhir_patient(&profile).to_string())
+}
+
+// Render a mother's health records as a FHIR R4 Bundle of Encounter and Observation resources (JSON)
+#[ic_cdk::update]
+fn get_fhir_health_records(mother_id: u64) -> Result<String, Error> {
+    ensure_feature("fhir_export")?;
+    let profile = load_mother_profile(mother_id)?;
+    log_read(mother_id, "get_fhir_health_records");
+
+    let patient_id = external_id(&profile.ulid, profile.id);
+    let mut entries = vec![fhir_entry(fhir_blood_type_observation(&profile))];
+    HEALTH_RECORD_STORAGE.with(|storage| {
+        for (_, record) in storage.borrow().range(mother_record_keys(mother_id)) {
+            entries.extend(fhir_record_resources(&record, &patient_id).into_iter().map(fhir_entry));
+        }
+    });
+    for lab_result in mother_lab_results(mother_id) {
+        entries.push(fhir_entry(fhir_lab_observation(&lab_result, &patient_id)));
+    }
+
+    let bundle = serde_json::json!({
+        "resourceType": "Bundle",
+        "type": "collection",
+        "timestamp": format_iso8601(time()),
+        "total": entries.len(),
+        "entry": entries,
+    });
+    Ok(bundle.to_string())
+}
+
+// Ingest a FHIR R4 Bundle (JSON) of Patient and Observation resources pushed from an EMR.
+// Patients are matched to mothers on file or registered; vital signs and symptoms become one
+// health record per encounter (or per day without one); laboratory observations become lab
+// results. Each entry succeeds or fails on its own.
+#[ic_cdk::update]
+fn import_fhir_bundle(bundle: String, facility_code: Option<String>) -> Result<FhirImportReport, Error> {
+    ensure_feature("fhir_import")?;
+    if let Some(facility_code) = &facility_code {
+        validate_facility_code(facility_code)?;
+    }
+    let bundle: serde_json::Value = serde_json::from_str(&bundle).map_err(|error| Error::InvalidInput {
+        msg: format!("Invalid FHIR JSON: {}", error),
+    })?;
+    if bundle["resourceType"] != "Bundle" {
+        return Err(Error::InvalidInput {
+            msg: "Expected a FHIR Bundle".to_string(),
+        });
+    }
+    let entries: Vec<&serde_json::Value> = bundle["entry"].as_array().into_iter().flatten().collect();
+    if entries.len() > MAX_FHIR_IMPORT_ENTRIES {
+        return Err(Error::InvalidInput {
+            msg: format!("A bundle may contain at most {} entries", MAX_FHIR_IMPORT_ENTRIES),
+        });
+    }
+    let mut report = FhirImportReport {
+        entries_processed: entries.len() as u64,
+        ..Default::default()
+    };
+
+    // Blood group and expected delivery date observations complete the patients they refer to
+    let mut facts: std::collections::BTreeMap<&str, FhirPatientFacts> = std::collections::BTreeMap::new();
+    for resource in entries.iter().map(|entry| &entry["resource"]).filter(|resource| resource["resourceType"] == "Observation") {
+        let Some(subject) = resource["subject"]["reference"].as_str() else {
+            continue;
+        };
+        match fhir_loinc_code(resource) {
+            Some(LOINC_BLOOD_GROUP) => facts.entry(subject).or_default().blood_type = fhir_text_value(resource),
+            Some(LOINC_ESTIMATED_DELIVERY_DATE) => {
+                facts.entry(subject).or_default().expected_delivery_date =
+                    resource["valueDateTime"].as_str().and_then(fhir_date)
+            }
+            _ => {}
+        }
+    }
+
+    // References to patients and encounters as the bundle's entries make them
+    let mut patients: std::collections::BTreeMap<String, u64> = std::collections::BTreeMap::new();
+    let mut encounters: std::collections::BTreeMap<String, u64> = std::collections::BTreeMap::new();
+    let mut known_mothers: std::collections::BTreeMap<(String, String, u64), u64> = PROFILE_STORAGE.with(|storage| {
         storage
             .borrow()
             .iter()
-            .filter(|(_, profile)| matches!(profile.health_status, HealthStatus::Critical))
-            .map(|(_, profile)| profile.clone())
+            .map(|(id, profile)| (import_dedup_key(&profile.name, &profile.emergency_contact, profile.expected_delivery_date), id))
             .collect()
+    });
+    for (index, entry) in entries.iter().enumerate() {
+        let resource = &entry["resource"];
+        let kind = resource["resourceType"].as_str().unwrap_or_default();
+        let references: Vec<String> = entry["fullUrl"]
+            .as_str()
+            .map(str::to_string)
+            .into_iter()
+            .chain(resource["id"].as_str().map(|id| format!("{}/{}", kind, id)))
+            .collect();
+        match kind {
+            "Patient" => {
+                let patient_facts = references.iter().find_map(|reference| facts.get(reference.as_str()));
+                match fhir_import_patient(resource, patient_facts, &facility_code, &mut known_mothers) {
+                    Ok((mother_id, created)) => {
+                        if created {
+                            report.profiles_created.push(mother_id);
+                        } else {
+                            report.profiles_matched.push(mother_id);
+                        }
+                        patients.extend(references.into_iter().map(|reference| (reference, mother_id)));
+                    }
+                    Err(msg) => report.errors.push(FhirEntryError { entry: index as u64, msg }),
+                }
+            }
+            "Encounter" => {
+                if let Some(start) = resource["period"]["start"].as_str().and_then(fhir_date) {
+                    encounters.extend(references.into_iter().map(|reference| (reference, start)));
+                }
+            }
+            "Observation" => {}
+            _ => report.skipped_entries.push(index as u64),
+        }
+    }
+
+    let mut visits: std::collections::BTreeMap<(u64, String), FhirVisit> = std::collections::BTreeMap::new();
+    for (index, entry) in entries.iter().enumerate() {
+        let resource = &entry["resource"];
+        if resource["resourceType"] != "Observation" {
+            continue;
+        }
+        if let Err(msg) = fhir_import_observation(index as u64, resource, &patients, &encounters, &mut visits, &mut report) {
+            report.errors.push(FhirEntryError { entry: index as u64, msg });
+        }
+    }
+
+    for visit in visits.into_values() {
+        match fhir_import_visit(&visit, &facility_code) {
+            Ok(Some(record_id)) => report.records_created.push(record_id),
+            Ok(None) => report.duplicate_entries.extend(&visit.entries),
+            Err(msg) => report.errors.push(FhirEntryError { entry: visit.entries[0], msg }),
+        }
+    }
+    report.duplicate_entries.sort_unstable();
+    Ok(report)
+}
+
+// Export Candid interface
+ic_cdk::export_candid!();
+
+fn validate_mother_profile(payload: &MotherProfilePayload) -> Result<(), Error> {
+    validate_expected_delivery_date(payload.expected_delivery_date)?;
+    validate_profile_fields(payload)
+}
+
+// Validate everything but the EDD, which register imports may place in the past
+fn validate_profile_fields(payload: &MotherProfilePayload) -> Result<(), Error> {
+    validate_age(payload.age)?;
+    validate_blood_type(&payload.blood_type)?;
+    validate_emergency_contact(&payload.emergency_contact)?;
+    validate_medical_history(&payload.medical_history)?;
+    if let Some(count) = payload.previous_cesareans {
+        validate_previous_cesareans(count)?;
+    }
+    if let Some(national_id) = &payload.national_id {
+        validate_national_id(national_id)?;
+    }
+
+    // Validate insurance cover
+    if let Some(cover) = &payload.insurance {
+        validate_insurance(cover)?;
+    }
+
+    // Validate facility code
+    if let Some(facility_code) = &payload.facility_code {
+        validate_facility_code(facility_code)?;
+    }
+
+    Ok(())
+}
+
+fn validate_age(age: u8) -> Result<(), Error> {
+    if !(13..=65).contains(&age) {
+        return Err(Error::InvalidInput {
+            msg: "Invalid age range. Must be between 13 and 65".to_string(),
+        });
+    }
+    Ok(())
+}
+
+fn validate_blood_type(blood_type: &str) -> Result<(), Error> {
+    let valid_blood_types = ["A+", "A-", "B+", "B-", "AB+", "AB-", "O+", "O-"];
+    if !valid_blood_types.contains(&blood_type) {
+        return Err(Error::InvalidInput {
+            msg: "Invalid blood type".to_string(),
+        });
+    }
+    Ok(())
+}
+
+fn validate_expected_delivery_date(expected_delivery_date: u64) -> Result<(), Error> {
+    if expected_delivery_date <= time() {
+        return Err(Error::InvalidInput {
+            msg: "Expected delivery date must be in the future".to_string(),
+        });
+    }
+    Ok(())
+}
+
+fn validate_emergency_contact(emergency_contact: &str) -> Result<(), Error> {
+    if emergency_contact.trim().is_empty() {
+        return Err(Error::InvalidInput {
+            msg: "Emergency contact is required".to_string(),
+        });
+    }
+    Ok(())
+}
+
+fn validate_facility_code(facility_code: &str) -> Result<(), Error> {
+    let valid = !facility_code.is_empty()
+        && facility_code.len() <= 32
+        && facility_code
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+
+    if !valid {
+        return Err(Error::InvalidInput {
+            msg: "Invalid facility code".to_string(),
+        });
+    }
+
+    Ok(())
+}
+
+fn validate_insurance(cover: &InsuranceCover) -> Result<(), Error> {
+    let member_number = cover.member_number.trim();
+    if member_number.is_empty() {
+        return Err(Error::InvalidInput {
+            msg: "Insurance member number is required".to_string(),
+        });
+    }
+
+    // NHIF member numbers are purely numeric
+    let valid = match cover.scheme {
+        InsuranceScheme::Nhif => {
+            (6..=12).contains(&member_number.len())
+                && member_number.chars().all(|c| c.is_ascii_digit())
+        }
+        InsuranceScheme::Private | InsuranceScheme::Other => {
+            member_number.len() <= 30
+                && member_number
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '/')
+        }
+    };
+
+    if !valid {
+        return Err(Error::InvalidInput {
+            msg: "Invalid insurance member number".to_string(),
+        });
+    }
+
+    Ok(())
+}
+
+// Per-mother export helpers
+fn export_mother_json(export: &MotherExport) -> String {
+    export_mother_value(export).to_string()
+}
+
+fn export_mother_value(export: &MotherExport) -> serde_json::Value {
+    let payments: Vec<serde_json::Value> = export
+        .payments
+        .iter()
+        .map(|payment| {
+            serde_json::json!({
+                "id": payment.id,
+                "record_id": payment.record_id,
+                "purpose": format!("{:?}", payment.purpose),
+                "amount": payment.amount,
+                "status": format!("{:?}", payment.status),
+                "block_index": payment.block_index.as_ref().map(|index| index.0.to_string()),
+                "created_at": payment.created_at,
+                "settled_at": payment.settled_at,
+            })
+        })
+        .collect();
+
+    serde_json::json!({
+        "exported_at": time(),
+        "profile": export.profile,
+        "health_records": export.health_records,
+        "addenda": export.addenda,
+        "lab_results": export.lab_results,
+        "appointments": export.appointments,
+        "referrals": export.referrals,
+        "payments": payments,
+        "self_reports": export.self_reports,
+    })
+}
+
+// CSV export as one table per section, separated by blank lines
+fn export_mother_csv(export: &MotherExport) -> String {
+    let profile = &export.profile;
+    let mut out = String::new();
+
+    out.push_str("# Profile\n");
+    csv_row(&mut out, &[
+        "id", "ulid", "name", "age", "blood_type", "expected_delivery_date", "stage",
+        "health_status", "emergency_contact", "facility_code", "medical_history",
+    ]);
+    csv_row(&mut out, &[
+        &profile.id.to_string(),
+        profile.ulid.as_deref().unwrap_or_default(),
+        &profile.name,
+        &profile.age.to_string(),
+        &profile.blood_type,
+        &format_iso8601(profile.expected_delivery_date)[..10],
+        &format!("{:?}", profile.stage),
+        &format!("{:?}", profile.health_status),
+        &profile.emergency_contact,
+        profile.facility_code.as_deref().unwrap_or_default(),
+        &profile.medical_history.join("; "),
+    ]);
+
+    out.push_str("\n# Health records\n");
+    csv_row(&mut out, &[
+        "id", "ulid", "date", "facility_code", "blood_pressure", "weight", "symptoms", "health_status", "notes",
+    ]);
+    for record in &export.health_records {
+        csv_row(&mut out, &[
+            &record.id.to_string(),
+            record.ulid.as_deref().unwrap_or_default(),
+            &format_iso8601(record.date),
+            record.facility_code.as_deref().unwrap_or_default(),
+            &record.blood_pressure,
+            &record.weight.to_string(),
+            &record.symptoms.join("; "),
+            &format!("{:?}", record.health_status),
+            &record.notes,
+        ]);
+    }
+
+    out.push_str("\n# Addenda\n");
+    csv_row(&mut out, &["id", "record_id", "created_at", "kind", "author", "text"]);
+    for addendum in &export.addenda {
+        csv_row(&mut out, &[
+            &addendum.id.to_string(),
+            &addendum.record_id.to_string(),
+            &format_iso8601(addendum.created_at),
+            &format!("{:?}", addendum.kind),
+            &addendum.author.to_text(),
+            &addendum.text,
+        ]);
+    }
+
+    out.push_str("\n# Lab results\n");
+    csv_row(&mut out, &["id", "ulid", "date", "test_name", "value", "unit", "code_system", "code"]);
+    for lab_result in &export.lab_results {
+        csv_row(&mut out, &[
+            &lab_result.id.to_string(),
+            lab_result.ulid.as_deref().unwrap_or_default(),
+            &format_iso8601(lab_result.date),
+            &lab_result.test_name,
+            &lab_result.value,
+            lab_result.unit.as_deref().unwrap_or_default(),
+            &lab_result.code.as_ref().map(|c| format!("{:?}", c.system)).unwrap_or_default(),
+            lab_result.code.as_ref().map(|c| c.code.as_str()).unwrap_or_default(),
+        ]);
+    }
+
+    out.push_str("\n# Appointments\n");
+    csv_row(&mut out, &["record_id", "booked_at", "scheduled_for"]);
+    for appointment in &export.appointments {
+        csv_row(&mut out, &[
+            &appointment.record_id.to_string(),
+            &format_iso8601(appointment.booked_at),
+            &format_iso8601(appointment.scheduled_for),
+        ]);
+    }
+
+    out.push_str("\n# Referrals\n");
+    csv_row(&mut out, &["id", "ulid", "created_at", "from_facility", "to_facility", "reason", "status"]);
+    for referral in &export.referrals {
+        csv_row(&mut out, &[
+            &referral.id.to_string(),
+            referral.ulid.as_deref().unwrap_or_default(),
+            &format_iso8601(referral.created_at),
+            &referral.from_facility,
+            &referral.to_facility,
+            &referral.reason,
+            &format!("{:?}", referral.status),
+        ]);
+    }
+
+    out.push_str("\n# Payments\n");
+    csv_row(&mut out, &["id", "ulid", "created_at", "purpose", "amount", "status"]);
+    for payment in &export.payments {
+        csv_row(&mut out, &[
+            &payment.id.to_string(),
+            payment.ulid.as_deref().unwrap_or_default(),
+            &format_iso8601(payment.created_at),
+            &format!("{:?}", payment.purpose),
+            &payment.amount.to_string(),
+            &format!("{:?}", payment.status),
+        ]);
+    }
+
+    out.push_str("\n# Self reports\n");
+    csv_row(&mut out, &["id", "ulid", "reported_at", "symptoms", "note", "triage", "alert_id"]);
+    for report in &export.self_reports {
+        csv_row(&mut out, &[
+            &report.id.to_string(),
+            report.ulid.as_deref().unwrap_or_default(),
+            &format_iso8601(report.reported_at),
+            &report.symptoms.join("; "),
+            &report.note,
+            &format!("{:?}", report.triage),
+            &report.alert_id.map(|id| id.to_string()).unwrap_or_default(),
+        ]);
+    }
+
+    out
+}
+
+// Append one CSV row, quoting fields that contain separators, quotes or line breaks
+fn csv_row(out: &mut String, fields: &[&str]) {
+    let escaped: Vec<String> = fields
+        .iter()
+        .map(|field| {
+            if field.contains([',', '"', '\n', '\r']) {
+                format!("\"{}\"", field.replace('"', "\"\""))
+            } else {
+                field.to_string()
+            }
+        })
+        .collect();
+    out.push_str(&escaped.join(","));
+    out.push('\n');
+}
+
+// FHIR R4 mapping helpers
+const FHIR_MOTHER_ID_SYSTEM: &str = "urn:mama-pack:mother-id";
+const FHIR_ULID_SYSTEM: &str = "urn:mama-pack:ulid";
+const LOINC_SYSTEM: &str = "http://loinc.org";
+const SNOMED_SYSTEM: &str = "http://snomed.info/sct";
+
+// Resource ids use ULIDs so bundles from several canisters can be merged without collisions
+fn fhir_patient(profile: &MotherProfile) -> serde_json::Value {
+    let mut identifiers = vec![serde_json::json!({ "system": FHIR_MOTHER_ID_SYSTEM, "value": profile.id.to_string() })];
+    if let Some(ulid) = &profile.ulid {
+        identifiers.push(serde_json::json!({ "system": FHIR_ULID_SYSTEM, "value": ulid }));
+    }
+    for identifier in external_identifiers(profile.id) {
+        identifiers.push(serde_json::json!({ "system": identifier.system, "value": identifier.value }));
+    }
+
+    serde_json::json!({
+        "resourceType": "Patient",
+        "id": external_id(&profile.ulid, profile.id),
+        "identifier": identifiers,
+        "active": true,
+        "name": [{ "text": profile.name }],
+        "gender": "female",
+        "contact": [{
+            "relationship": [{ "text": "Emergency contact" }],
+            "telecom": [{ "system": "phone", "value": profile.emergency_contact }],
+        }],
     })
 }
 
-// Get critical cases
-#[ic_cdk::query]
-fn get_critical_cases() -> Vec<MotherProfile> {
-    PROFILE_STORAGE.with(|storage| {
+fn fhir_blood_type_observation(profile: &MotherProfile) -> serde_json::Value {
+    let patient_id = external_id(&profile.ulid, profile.id);
+    serde_json::json!({
+        "resourceType": "Observation",
+        "id": format!("{}-blood-type", patient_id),
+        "status": "final",
+        "code": fhir_loinc("882-1", "ABO and Rh group"),
+        "subject": { "reference": format!("Patient/{}", patient_id) },
+        "valueString": profile.blood_type,
+    })
+}
+
+// One Encounter per visit plus Observations for its vitals and symptoms
+fn fhir_record_resources(record: &HealthRecord, patient_id: &str) -> Vec<serde_json::Value> {
+    let record_id = external_id(&record.ulid, record.id);
+    let subject = serde_json::json!({ "reference": format!("Patient/{}", patient_id) });
+    let encounter = serde_json::json!({ "reference": format!("Encounter/{}", record_id) });
+    let effective = format_iso8601(record.date);
+
+    let mut resources = vec![serde_json::json!({
+        "resourceType": "Encounter",
+        "id": record_id,
+        "status": "finished",
+        "class": {
+            "system": "http://terminology.hl7.org/CodeSystem/v3-ActCode",
+            "code": "AMB",
+            "display": "ambulatory",
+        },
+        "type": [{ "text": "Antenatal care visit" }],
+        "subject": subject,
+        "period": { "start": effective },
+    })];
+
+    if let Some((systolic, diastolic)) = parse_blood_pressure(&record.blood_pressure) {
+        resources.push(serde_json::json!({
+            "resourceType": "Observation",
+            "id": format!("{}-bp", record_id),
+            "status": "final",
+            "category": [fhir_vital_signs_category()],
+            "code": fhir_registry_code("blood pressure", "85354-9", "Blood pressure panel with all children optional"),
+            "subject": subject,
+            "encounter": encounter,
+            "effectiveDateTime": effective,
+            "component": [
+                {
+                    "code": fhir_loinc("8480-6", "Systolic blood pressure"),
+                    "valueQuantity": fhir_quantity(systolic as f64, "mm[Hg]"),
+                },
+                {
+                    "code": fhir_loinc("8462-4", "Diastolic blood pressure"),
+                    "valueQuantity": fhir_quantity(diastolic as f64, "mm[Hg]"),
+                },
+            ],
+        }));
+    }
+
+    resources.push(serde_json::json!({
+        "resourceType": "Observation",
+        "id": format!("{}-weight", record_id),
+        "status": "final",
+        "category": [fhir_vital_signs_category()],
+        "code": fhir_registry_code("body weight", "29463-7", "Body weight"),
+        "subject": subject,
+        "encounter": encounter,
+        "effectiveDateTime": effective,
+        "valueQuantity": fhir_quantity(record.weight as f64, "kg"),
+    }));
+
+    for (index, symptom) in record.symptoms.iter().enumerate() {
+        // Prefer the code attached when the record was created. Records from before the codes
+        // were kept aligned with the symptoms are looked up in the registry again.
+        let code = match &record.symptom_codes {
+            Some(codes) if codes.len() == record.symptoms.len() => codes[index].clone(),
+            _ => find_symptom_code(symptom),
+        };
+        let code = code
+            .map(|code| fhir_codeable_concept(&code, symptom))
+            .unwrap_or_else(|| serde_json::json!({ "text": symptom }));
+        resources.push(serde_json::json!({
+            "resourceType": "Observation",
+            "id": format!("{}-symptom-{}", record_id, index),
+            "status": "final",
+            "code": code,
+            "subject": subject,
+            "encounter": encounter,
+            "effectiveDateTime": effective,
+            "valueBoolean": true,
+        }));
+    }
+
+    resources
+}
+
+fn fhir_lab_observation(lab_result: &LabResult, patient_id: &str) -> serde_json::Value {
+    let code = match &lab_result.code {
+        Some(code) => fhir_codeable_concept(code, &lab_result.test_name),
+        None => serde_json::json!({ "text": lab_result.test_name }),
+    };
+
+    let mut observation = serde_json::json!({
+        "resourceType": "Observation",
+        "id": format!("lab-{}", external_id(&lab_result.ulid, lab_result.id)),
+        "status": "final",
+        "category": [{
+            "coding": [{
+                "system": "http://terminology.hl7.org/CodeSystem/observation-category",
+                "code": "laboratory",
+            }],
+        }],
+        "code": code,
+        "subject": { "reference": format!("Patient/{}", patient_id) },
+        "effectiveDateTime": format_iso8601(lab_result.date),
+    });
+
+    // Numeric results with a unit become quantities; anything else is reported as text
+    match (lab_result.value.trim().parse::<f64>(), &lab_result.unit) {
+        (Ok(value), Some(unit)) => observation["valueQuantity"] = fhir_quantity(value, unit),
+        _ => observation["valueString"] = serde_json::json!(lab_result.value),
+    }
+    observation
+}
+
+fn fhir_codeable_concept(code: &ClinicalCode, text: &str) -> serde_json::Value {
+    let system = match code.system {
+        CodeSystem::Loinc => LOINC_SYSTEM,
+        CodeSystem::Snomed => SNOMED_SYSTEM,
+    };
+    serde_json::json!({
+        "coding": [{ "system": system, "code": code.code, "display": code.display }],
+        "text": text,
+    })
+}
+
+// Code from the registry for a term, falling back to the given LOINC code
+fn fhir_registry_code(term: &str, loinc_code: &str, display: &str) -> serde_json::Value {
+    match lookup_clinical_code(term.to_string()) {
+        Some(code) => fhir_codeable_concept(&code, display),
+        None => fhir_loinc(loinc_code, display),
+    }
+}
+
+fn fhir_entry(resource: serde_json::Value) -> serde_json::Value {
+    let full_url = format!(
+        "{}/{}",
+        resource["resourceType"].as_str().unwrap_or_default(),
+        resource["id"].as_str().unwrap_or_default()
+    );
+    serde_json::json!({ "fullUrl": full_url, "resource": resource })
+}
+
+fn fhir_loinc(code: &str, display: &str) -> serde_json::Value {
+    serde_json::json!({
+        "coding": [{ "system": LOINC_SYSTEM, "code": code, "display": display }],
+        "text": display,
+    })
+}
+
+fn fhir_quantity(value: f64, unit: &str) -> serde_json::Value {
+    serde_json::json!({
+        "value": value,
+        "unit": unit,
+        "system": "http://unitsofmeasure.org",
+        "code": unit,
+    })
+}
+
+fn fhir_vital_signs_category() -> serde_json::Value {
+    serde_json::json!({
+        "coding": [{
+            "system": "http://terminology.hl7.org/CodeSystem/observation-category",
+            "code": "vital-signs",
+        }],
+    })
+}
+
+const MAX_FHIR_IMPORT_ENTRIES: usize = 500;
+const LOINC_BLOOD_GROUP: &str = "882-1";
+const LOINC_ESTIMATED_DELIVERY_DATE: &str = "11778-8";
+const LOINC_BLOOD_PRESSURE_PANEL: &str = "85354-9";
+const LOINC_SYSTOLIC: &str = "8480-6";
+const LOINC_DIASTOLIC: &str = "8462-4";
+const LOINC_BODY_WEIGHT: &str = "29463-7";
+const LOINC_FUNDAL_HEIGHT: &str = "11881-0";
+const LOINC_FETAL_HEART_RATE: &str = "55283-6";
+const LOINC_MUAC: &str = "56072-2";
+
+// What a bundle's observations say about a patient, needed to register her
+#[derive(Default)]
+struct FhirPatientFacts {
+    blood_type: Option<String>,
+    expected_delivery_date: Option<u64>,
+}
+
+// Vital signs and symptoms observed at one visit, in kg, cm, mmHg and bpm
+#[derive(Default)]
+struct FhirVisit {
+    mother_id: u64,
+    date: u64,
+    entries: Vec<u64>,
+    systolic: Option<f64>,
+    diastolic: Option<f64>,
+    weight: Option<f64>,
+    fundal_height_cm: Option<f64>,
+    fetal_heart_rate: Option<f64>,
+    muac_cm: Option<f64>,
+    symptoms: Vec<String>,
+}
+
+// The mother a Patient resource stands for: one exported from here (matched by ULID), one holding
+// one of its identifiers, one on file with the same name, phone and EDD, or a new profile.
+// Returns whether she was created.
+fn fhir_import_patient(
+    resource: &serde_json::Value,
+    facts: Option<&FhirPatientFacts>,
+    facility_code: &Option<String>,
+    known_mothers: &mut std::collections::BTreeMap<(String, String, u64), u64>,
+) -> Result<(u64, bool), String> {
+    let exported = resource["identifier"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter(|identifier| identifier["system"] == FHIR_ULID_SYSTEM)
+        .chain(std::iter::once(&resource["id"]))
+        .find_map(|identifier| identifier["value"].as_str().or(identifier.as_str()).and_then(fhir_ulid_mother));
+    if let Some(mother_id) = exported {
+        return Ok((mother_id, false));
+    }
+    let identifiers: Vec<(&str, &str)> = resource["identifier"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter(|identifier| identifier["system"] != FHIR_ULID_SYSTEM && identifier["system"] != FHIR_MOTHER_ID_SYSTEM)
+        .filter_map(|identifier| Some((identifier["system"].as_str()?, identifier["value"].as_str()?)))
+        .collect();
+    if let Some(mother_id) = identifiers.iter().find_map(|(system, value)| external_identifier_mother(system.trim(), value.trim())) {
+        return Ok((mother_id, false));
+    }
+    // The mother is matched or registered by her details from here on; keep the identifiers she
+    // carries so the next bundle matches her by them. Any that would conflict are left off.
+    let keep_identifiers = |mother_id: u64| {
+        for (system, value) in &identifiers {
+            let _ = add_external_identifier(mother_id, system.to_string(), value.to_string());
+        }
+    };
+
+    let name = fhir_patient_name(resource).ok_or("A patient needs a name")?;
+    let birth_date = resource["birthDate"].as_str().and_then(fhir_date).ok_or("A patient needs a birthDate")?;
+    let age = (time().saturating_sub(birth_date) / NANOS_PER_DAY * 100 / 36_525).min(u64::from(u8::MAX)) as u8;
+    let emergency_contact = fhir_phone(&resource["contact"][0]["telecom"])
+        .or_else(|| fhir_phone(&resource["telecom"]))
+        .ok_or("A patient needs a contact phone number")?;
+    let blood_type = facts
+        .and_then(|facts| facts.blood_type.clone())
+        .ok_or("A new patient needs an ABO and Rh group observation (LOINC 882-1)")?;
+    let expected_delivery_date = facts
+        .and_then(|facts| facts.expected_delivery_date)
+        .ok_or("A new patient needs an estimated delivery date observation (LOINC 11778-8)")?;
+
+    let key = import_dedup_key(&name, &emergency_contact, expected_delivery_date);
+    if let Some(mother_id) = known_mothers.get(&key) {
+        keep_identifiers(*mother_id);
+        return Ok((*mother_id, false));
+    }
+    let profile = create_mother_profile(MotherProfilePayload {
+        name,
+        age,
+        blood_type,
+        expected_delivery_date,
+        medical_history: Vec::new(),
+        emergency_contact,
+        insurance: None,
+        facility_code: facility_code.clone(),
+        idempotency_key: None,
+        previous_cesareans: None,
+        national_id: None,
+    })
+    .map_err(error_message)?;
+    known_mothers.insert(key, profile.id);
+    keep_identifiers(profile.id);
+    Ok((profile.id, true))
+}
+
+// File an observation: laboratory results directly, vital signs and symptoms into their visit
+fn fhir_import_observation(
+    index: u64,
+    resource: &serde_json::Value,
+    patients: &std::collections::BTreeMap<String, u64>,
+    encounters: &std::collections::BTreeMap<String, u64>,
+    visits: &mut std::collections::BTreeMap<(u64, String), FhirVisit>,
+    report: &mut FhirImportReport,
+) -> Result<(), String> {
+    let code = fhir_loinc_code(resource);
+    // Read when registering the patient
+    if matches!(code, Some(LOINC_BLOOD_GROUP | LOINC_ESTIMATED_DELIVERY_DATE)) {
+        return Ok(());
+    }
+    if matches!(resource["status"].as_str(), Some("entered-in-error" | "cancelled")) {
+        report.skipped_entries.push(index);
+        return Ok(());
+    }
+    let mother_id = fhir_subject(resource, patients)?;
+    let encounter = resource["encounter"]["reference"].as_str();
+    let date = resource["effectiveDateTime"]
+        .as_str()
+        .and_then(fhir_date)
+        .or_else(|| encounter.and_then(|reference| encounters.get(reference).copied()))
+        .ok_or("An observation needs an effectiveDateTime or an encounter with a start")?;
+
+    let laboratory = resource["category"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .flat_map(|category| category["coding"].as_array().into_iter().flatten())
+        .any(|coding| coding["code"] == "laboratory");
+    if laboratory {
+        return match fhir_import_lab_result(resource, mother_id, date)? {
+            Some(lab_result_id) => {
+                report.lab_results_created.push(lab_result_id);
+                Ok(())
+            }
+            None => {
+                report.duplicate_entries.push(index);
+                Ok(())
+            }
+        };
+    }
+
+    let symptom = Some(resource)
+        .filter(|resource| resource["valueBoolean"] == true)
+        .and_then(|resource| fhir_concept_text(&resource["code"]));
+    let mapped = [
+        LOINC_BLOOD_PRESSURE_PANEL,
+        LOINC_SYSTOLIC,
+        LOINC_DIASTOLIC,
+        LOINC_BODY_WEIGHT,
+        LOINC_FUNDAL_HEIGHT,
+        LOINC_FETAL_HEART_RATE,
+        LOINC_MUAC,
+    ];
+    if !code.is_some_and(|code| mapped.contains(&code)) && symptom.is_none() {
+        report.skipped_entries.push(index);
+        return Ok(());
+    }
+
+    // Readings are converted before the visit is touched, so an entry with a bad unit adds nothing
+    let parts: Vec<&serde_json::Value> = match code {
+        Some(LOINC_BLOOD_PRESSURE_PANEL) => resource["component"].as_array().into_iter().flatten().collect(),
+        _ => vec![resource],
+    };
+    let mut readings = Vec::new();
+    for part in parts {
+        let part_code = fhir_loinc_code(part);
+        let unit = match part_code {
+            Some(LOINC_SYSTOLIC | LOINC_DIASTOLIC) => "mm[Hg]",
+            Some(LOINC_BODY_WEIGHT) => "kg",
+            Some(LOINC_FUNDAL_HEIGHT | LOINC_MUAC) => "cm",
+            Some(LOINC_FETAL_HEART_RATE) => "/min",
+            _ => continue,
+        };
+        readings.push((part_code, fhir_quantity_in(part, unit)?));
+    }
+
+    // Observations of one encounter, or of one day without an encounter, make a visit
+    let key = match encounter {
+        Some(reference) => reference.to_string(),
+        None => format!("day:{}", date / NANOS_PER_DAY),
+    };
+    let visit = visits.entry((mother_id, key)).or_insert_with(|| FhirVisit {
+        mother_id,
+        date,
+        ..Default::default()
+    });
+    visit.date = visit.date.min(date);
+    visit.entries.push(index);
+    if !code.is_some_and(|code| mapped.contains(&code)) {
+        visit.symptoms.extend(symptom);
+    }
+    for (code, value) in readings {
+        match code {
+            Some(LOINC_SYSTOLIC) => visit.systolic = value,
+            Some(LOINC_DIASTOLIC) => visit.diastolic = value,
+            Some(LOINC_BODY_WEIGHT) => visit.weight = value,
+            Some(LOINC_FUNDAL_HEIGHT) => visit.fundal_height_cm = value,
+            Some(LOINC_FETAL_HEART_RATE) => visit.fetal_heart_rate = value,
+            Some(LOINC_MUAC) => visit.muac_cm = value,
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+// An observation's valueQuantity in the unit a health record keeps it in, matched on the UCUM
+// `code` (else `unit`). Units with an exact conversion are converted; any other unit, or none,
+// fails the entry rather than storing a value on the wrong scale.
+fn fhir_quantity_in(resource: &serde_json::Value, unit: &str) -> Result<Option<f64>, String> {
+    let quantity = &resource["valueQuantity"];
+    let Some(value) = quantity["value"].as_f64() else {
+        return Ok(None);
+    };
+    let given = quantity["code"].as_str().or(quantity["unit"].as_str()).unwrap_or_default();
+    let factor = match (given, unit) {
+        (given, unit) if given == unit => 1.0,
+        ("{beats}/min", "/min") => 1.0,
+        ("[lb_av]", "kg") => 0.453_592_37,
+        ("g", "kg") => 0.001,
+        ("mm", "cm") => 0.1,
+        // 1 kPa = 1000 / 133.322387415 mmHg
+        ("kPa", "mm[Hg]") => 1000.0 / 133.322_387_415,
+        ("", _) => return Err(format!("A quantity needs a UCUM unit; expected {}", unit)),
+        _ => return Err(format!("Unsupported unit {} for a value in {}", given, unit)),
+    };
+    Ok(Some(value * factor))
+}
+
+// A laboratory observation as a lab result; None when the same result is already on file
+fn fhir_import_lab_result(resource: &serde_json::Value, mother_id: u64, date: u64) -> Result<Option<u64>, String> {
+    let test_name = fhir_concept_text(&resource["code"]).ok_or("A laboratory observation needs a code")?;
+    let (value, unit) = match resource["valueQuantity"]["value"].as_f64() {
+        Some(value) => (
+            value.to_string(),
+            resource["valueQuantity"]["unit"]
+                .as_str()
+                .or(resource["valueQuantity"]["code"].as_str())
+                .map(str::to_string),
+        ),
+        None => (fhir_text_value(resource).ok_or("A laboratory observation needs a value")?, None),
+    };
+    let on_file = mother_lab_results(mother_id).iter().any(|lab_result| {
+        lab_result.date / NANOS_PER_DAY == date / NANOS_PER_DAY
+            && lab_result.test_name.eq_ignore_ascii_case(&test_name)
+            && lab_result.value == value
+    });
+    if on_file {
+        return Ok(None);
+    }
+    let payload = LabResultPayload {
+        mother_id,
+        test_name,
+        value,
+        unit,
+        code: fhir_clinical_code(&resource["code"]),
+    };
+    insert_lab_result_at(payload, date)
+        .map(|lab_result| Some(lab_result.id))
+        .map_err(error_message)
+}
+
+// A visit as a health record; None when the mother already has a visit that day
+fn fhir_import_visit(visit: &FhirVisit, facility_code: &Option<String>) -> Result<Option<u64>, String> {
+    let weight = visit.weight.ok_or("A visit needs a body weight observation (LOINC 29463-7)")?;
+    let visit_day = visit.date / NANOS_PER_DAY;
+    let already_recorded = HEALTH_RECORD_STORAGE.with(|storage| {
         storage
             .borrow()
-            .iter()
-            .filter(|(_, profile)| matches!(profile.health_status, HealthStatus::Critical))
-            .map(|(_, profile)| profile.clone())
-            .collect()
-    })
+            .range(mother_record_keys(visit.mother_id))
+            .any(|(_, record)| record.date / NANOS_PER_DAY == visit_day)
+    });
+    if already_recorded {
+        return Ok(None);
+    }
+
+    let blood_pressure = match (visit.systolic, visit.diastolic) {
+        (Some(systolic), Some(diastolic)) => format!("{}/{}", systolic.round(), diastolic.round()),
+        _ => String::new(),
+    };
+    let record = insert_health_record(
+        HealthRecordPayload {
+            mother_id: visit.mother_id,
+            blood_pressure,
+            weight: weight as f32,
+            symptoms: visit.symptoms.clone(),
+            notes: String::new(),
+            next_appointment: 0,
+            insurance_eligible: None,
+            facility_code: facility_code.clone(),
+            idempotency_key: None,
+            fundal_height_cm: visit.fundal_height_cm.map(|height| height as f32),
+            fetal_heart_rate: visit.fetal_heart_rate.map(|rate| rate.round() as u16),
+            urine_protein: None,
+            urine_glucose: None,
+            edema: None,
+            muac_cm: visit.muac_cm.map(|muac| muac as f32),
+            note_template: None,
+            note_sections: None,
+            checklist_done: None,
+        },
+        visit.date,
+    )
+    .map_err(error_message)?;
+    Ok(Some(record.id))
 }
 
-// Get upcoming appointments
-#[ic_cdk::query]
-fn get_upcoming_appointments(days: u64) -> Vec<(MotherProfile, HealthRecord)> {
-    let now = time();
-    let target = now + (days * 24 * 60 * 60 * 1_000_000_000);
-    
-    HEALTH_RECORD_STORAGE.with(|record_storage| {
-        PROFILE_STORAGE.with(|profile_storage| {
-            let records = record_storage.borrow();
-            let profiles = profile_storage.borrow();
-            
-            records
-                .iter()
-                .filter(|(_, record)| {
-                    record.next_appointment > now && record.next_appointment <= target
-                })
-                .filter_map(|(_, record)| {
-                    profiles
-                        .get(&record.mother_id)
-                        .map(|profile| (profile.clone(), record.clone()))
-                })
-                .collect()
-        })
+// The mother an observation is about: a patient in the bundle, or one exported from here
+fn fhir_subject(resource: &serde_json::Value, patients: &std::collections::BTreeMap<String, u64>) -> Result<u64, String> {
+    let reference = resource["subject"]["reference"].as_str().ok_or("An observation needs a subject")?;
+    patients
+        .get(reference)
+        .copied()
+        .or_else(|| reference.strip_prefix("Patient/").and_then(fhir_ulid_mother))
+        .ok_or_else(|| format!("Unknown subject {}", reference))
+}
+
+fn fhir_ulid_mother(ulid: &str) -> Option<u64> {
+    ULID_INDEX
+        .with(|index| index.borrow().get(&StringKey(ulid.trim().to_uppercase())))
+        .filter(|target| target.entity_type == EntityType::MotherProfile)
+        .map(|target| target.id)
+}
+
+fn fhir_loinc_code(resource: &serde_json::Value) -> Option<&str> {
+    resource["code"]["coding"]
+        .as_array()?
+        .iter()
+        .find(|coding| coding["system"] == LOINC_SYSTEM)
+        .and_then(|coding| coding["code"].as_str())
+}
+
+fn fhir_clinical_code(concept: &serde_json::Value) -> Option<ClinicalCode> {
+    concept["coding"].as_array()?.iter().find_map(|coding| {
+        let system = match coding["system"].as_str()? {
+            LOINC_SYSTEM => CodeSystem::Loinc,
+            SNOMED_SYSTEM => CodeSystem::Snomed,
+            _ => return None,
+        };
+        let code = coding["code"].as_str()?.to_string();
+        let display = coding["display"].as_str().unwrap_or(&code).to_string();
+        Some(ClinicalCode { system, code, display })
     })
 }
 
-// Export Candid interface
-ic_cdk::export_candid!();
+// A CodeableConcept's text, else the display or code of its first coding
+fn fhir_concept_text(concept: &serde_json::Value) -> Option<String> {
+    let coding = &concept["coding"][0];
+    [&concept["text"], &coding["display"], &coding["code"]]
+        .into_iter()
+        .filter_map(|value| value.as_str())
+        .map(str::trim)
+        .find(|text| !text.is_empty())
+        .map(str::to_string)
+}
 
-fn validate_mother_profile(payload: &MotherProfilePayload) -> Result<(), Error> {
-    // Validate age
-    if payload.age < 13 || payload.age > 65 {
-        return Err(Error::InvalidInput {
-            msg: "Invalid age range. Must be between 13 and 65".to_string(),
-        });
+fn fhir_text_value(resource: &serde_json::Value) -> Option<String> {
+    resource["valueString"]
+        .as_str()
+        .map(|value| value.trim().to_string())
+        .filter(|value| !value.is_empty())
+        .or_else(|| fhir_concept_text(&resource["valueCodeableConcept"]))
+}
+
+fn fhir_patient_name(resource: &serde_json::Value) -> Option<String> {
+    let name = &resource["name"][0];
+    if let Some(text) = name["text"].as_str().map(str::trim).filter(|text| !text.is_empty()) {
+        return Some(text.to_string());
     }
+    let parts: Vec<&str> = name["given"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|given| given.as_str())
+        .chain(name["family"].as_str())
+        .collect();
+    Some(parts.join(" ")).filter(|name| !name.trim().is_empty())
+}
 
-    // Validate blood type
-    let valid_blood_types = ["A+", "A-", "B+", "B-", "AB+", "AB-", "O+", "O-"];
-    if !valid_blood_types.contains(&payload.blood_type.as_str()) {
-        return Err(Error::InvalidInput {
-            msg: "Invalid blood type".to_string(),
-        });
+fn fhir_phone(telecom: &serde_json::Value) -> Option<String> {
+    telecom
+        .as_array()?
+        .iter()
+        .find(|contact| contact["system"] == "phone")
+        .and_then(|contact| contact["value"].as_str())
+        .map(|phone| phone.trim().to_string())
+}
+
+// FHIR date or dateTime, to the day
+fn fhir_date(value: &str) -> Option<u64> {
+    value.get(..10).and_then(parse_date)
+}
+
+// DHIS2 data element codes for the monthly aggregate report
+const DHIS2_ANC_FIRST_VISITS: &str = "ANC_1ST_VISITS";
+const DHIS2_ANC_FOURTH_VISITS: &str = "ANC_4TH_VISITS";
+const DHIS2_DELIVERIES: &str = "DELIVERIES";
+const DHIS2_REFERRALS: &str = "REFERRALS";
+
+// Parse a DHIS2 monthly period ("YYYYMM") into a [start, end) nanosecond range
+fn parse_monthly_period(period: &str) -> Result<(u64, u64), Error> {
+    let invalid = || Error::InvalidInput {
+        msg: "Period must be a month in the format YYYYMM".to_string(),
+    };
+
+    if period.len() != 6 || !period.chars().all(|c| c.is_ascii_digit()) {
+        return Err(invalid());
+    }
+    let year: i64 = period[..4].parse().map_err(|_| invalid())?;
+    let month: u32 = period[4..].parse().map_err(|_| invalid())?;
+    if !(1..=12).contains(&month) || year < 1970 {
+        return Err(invalid());
     }
 
-    // Validate expected delivery date
-    let now = time();
-    if payload.expected_delivery_date <= now {
-        return Err(Error::InvalidInput {
-            msg: "Expected delivery date must be in the future".to_string(),
-        });
+    let (next_year, next_month) = if month == 12 { (year + 1, 1) } else { (year, month + 1) };
+    Ok((
+        days_to_nanos(days_from_civil(year, month, 1)),
+        days_to_nanos(days_from_civil(next_year, next_month, 1)),
+    ))
+}
+
+// Date helpers
+const NANOS_PER_DAY: u64 = 24 * 60 * 60 * 1_000_000_000;
+
+// Gestational age in completed weeks at a given time, counting back 280 days from the EDD
+fn gestational_age_weeks(edd: u64, at: u64) -> u64 {
+    let pregnancy_start = edd.saturating_sub(280 * NANOS_PER_DAY);
+    at.saturating_sub(pregnancy_start) / (7 * NANOS_PER_DAY)
+}
+
+// Convert a (year, month, day) civil date to days since the Unix epoch
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let month = month as i64;
+    let doy = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+fn days_to_nanos(days: i64) -> u64 {
+    days.max(0) as u64 * NANOS_PER_DAY
+}
+
+// Parse a "YYYY-MM-DD" date into a nanosecond timestamp at midnight UTC
+fn parse_date(date: &str) -> Option<u64> {
+    let mut parts = date.trim().splitn(3, '-');
+    let year: i64 = parts.next()?.parse().ok()?;
+    let month: u32 = parts.next()?.parse().ok()?;
+    let day: u32 = parts.next()?.parse().ok()?;
+    if year < 1970 || !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
     }
+    Some(days_to_nanos(days_from_civil(year, month, day)))
+}
 
-    // Validate emergency contact
-    if payload.emergency_contact.trim().is_empty() {
-        return Err(Error::InvalidInput {
-            msg: "Emergency contact is required".to_string(),
+// Convert days since the Unix epoch to a (year, month, day) civil date
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+// Format a nanosecond timestamp as an ISO 8601 UTC date-time
+fn format_iso8601(timestamp: u64) -> String {
+    let seconds = (timestamp / 1_000_000_000) as i64;
+    let (year, month, day) = civil_from_days(seconds.div_euclid(86_400));
+    let time_of_day = seconds.rem_euclid(86_400);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        time_of_day / 3_600,
+        time_of_day % 3_600 / 60,
+        time_of_day % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ulid_encodes_timestamp_and_randomness() {
+        let ulid = encode_ulid(1_469_918_176_385, &[0; 10]);
+        assert_eq!(ulid.len(), 26);
+        assert_eq!(&ulid[..10], "01ARYZ6S41");
+        assert_eq!(&ulid[10..], "0000000000000000");
+        assert_eq!(&encode_ulid(0, &[0xFF; 10])[10..], "ZZZZZZZZZZZZZZZZ");
+        // Later timestamps sort later
+        assert!(encode_ulid(2, &[0; 10]) > encode_ulid(1, &[0xFF; 10]));
+    }
+
+    #[test]
+    fn paginate_in_order_uses_positions() {
+        let first = paginate_in_order(vec!["c", "a", "b"], None, Some(2));
+        assert_eq!(first.items, ["c", "a"]);
+        assert_eq!(first.total, 3);
+        assert_eq!(first.next_cursor, Some(2));
+        let rest = paginate_in_order(vec!["c", "a", "b"], first.next_cursor, Some(2));
+        assert_eq!(rest.items, ["b"]);
+        assert_eq!(rest.next_cursor, None);
+    }
+
+    #[test]
+    fn paginate_walks_records_of_two_mothers_once() {
+        // Record ids interleave between the mothers, as get_upcoming_appointments sees them
+        let records = [(1, 10), (2, 20), (3, 10), (4, 20), (5, 10)];
+        let mut cursor = None;
+        let mut seen = Vec::new();
+        loop {
+            let page = paginate(records.iter().map(|&(id, mother)| (id, (id, mother))), cursor, Some(2));
+            assert_eq!(page.total, 5);
+            seen.extend(page.items);
+            match page.next_cursor {
+                Some(next) => cursor = Some(next),
+                None => break,
+            }
+        }
+        assert_eq!(seen, records);
+    }
+
+    fn backup_manifest() -> BackupManifest {
+        BackupManifest {
+            source_canister: Principal::anonymous(),
+            created_at: 1,
+            schema_version: 2,
+            nonce: vec![7; 24],
+            size_bytes: 3,
+            chunk_size: 4,
+            chunk_count: 1,
+            wrapped_key: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn backup_chunks_round_trip_and_detect_tampering() {
+        let data_key = [9; 32];
+        let chunk = seal_chunk(&data_key, 3, b"stable memory".to_vec());
+        assert_ne!(chunk.data, b"stable memory");
+        assert_eq!(open_chunk(&data_key, chunk.clone()).ok().as_deref(), Some(&b"stable memory"[..]));
+
+        // A chunk replayed at another index, altered or opened with another key is refused
+        assert!(open_chunk(&data_key, BackupChunk { index: 4, ..chunk.clone() }).is_err());
+        let mut altered = chunk.clone();
+        altered.data[0] ^= 1;
+        assert!(open_chunk(&data_key, altered).is_err());
+        assert!(open_chunk(&[8; 32], chunk).is_err());
+    }
+
+    #[test]
+    fn backup_key_unwraps_only_with_its_manifest() {
+        let operator_key = [1; 32];
+        let data_key = [2; 32];
+        let mut manifest = backup_manifest();
+        manifest.wrapped_key = wrap_backup_key(&operator_key, &manifest, &data_key).ok().unwrap();
+        assert_eq!(unwrap_backup_key(&operator_key, &manifest).ok(), Some(data_key));
+        assert!(unwrap_backup_key(&[3; 32], &manifest).is_err());
+        assert!(unwrap_backup_key(&operator_key[..16], &manifest).is_err());
+
+        manifest.chunk_count = 2;
+        assert!(unwrap_backup_key(&operator_key, &manifest).is_err());
+    }
+
+    fn outcall_response(
+        status: u64,
+        body: serde_json::Value,
+    ) -> ic_cdk::api::management_canister::http_request::HttpResponse {
+        ic_cdk::api::management_canister::http_request::HttpResponse {
+            status: candid::Nat::from(status),
+            headers: Vec::new(),
+            body: body.to_string().into_bytes(),
+        }
+    }
+
+    #[test]
+    fn sms_replies_yield_the_provider_message_id() {
+        let africas_talking = outcall_response(
+            201,
+            serde_json::json!({
+                "SMSMessageData": { "Recipients": [{ "status": "Success", "messageId": "ATXid_1" }] },
+            }),
+        );
+        assert_eq!(parse_sms_response(SmsProvider::AfricasTalking, &africas_talking), Ok("ATXid_1".to_string()));
+
+        let rejected = outcall_response(
+            201,
+            serde_json::json!({ "SMSMessageData": { "Recipients": [{ "status": "InvalidPhoneNumber" }] } }),
+        );
+        assert_eq!(
+            parse_sms_response(SmsProvider::AfricasTalking, &rejected),
+            Err("Provider rejected the message: InvalidPhoneNumber".to_string())
+        );
+
+        let twilio = outcall_response(201, serde_json::json!({ "sid": "SM123", "price": null }));
+        assert_eq!(parse_sms_response(SmsProvider::Twilio, &twilio), Ok("SM123".to_string()));
+        let unauthorized = outcall_response(401, serde_json::json!({ "code": 20003 }));
+        assert_eq!(parse_sms_response(SmsProvider::Twilio, &unauthorized), Err("Provider returned HTTP 401".to_string()));
+    }
+
+    #[test]
+    fn sms_outcome_reads_the_reduced_reply() {
+        let sent = outcall_response(200, serde_json::json!({ "message_id": "SM123" }));
+        assert_eq!(sms_outcome(&sent), Ok("SM123".to_string()));
+        let failed = outcall_response(500, serde_json::json!({ "error": "Provider returned HTTP 500" }));
+        assert_eq!(sms_outcome(&failed), Err("Provider returned HTTP 500".to_string()));
+    }
+
+
+    #[test]
+    fn red_cells_follow_abo_and_rh_rules() {
+        for (recipient, donor) in [("AB+", "O-"), ("A+", "A-"), ("AB-", "B-")] {
+            assert!(red_cells_compatible(recipient, donor), "{recipient} should accept {donor}");
+        }
+        for (recipient, donor) in [("A-", "A+"), ("B+", "A+"), ("O+", "AB+"), ("O-", "O+")] {
+            assert!(!red_cells_compatible(recipient, donor), "{recipient} should refuse {donor}");
+        }
+    }
+
+
+    #[test]
+    fn fhir_helpers_read_codes_text_and_dates() {
+        let observation = serde_json::json!({
+            "code": {
+                "coding": [
+                    { "system": SNOMED_SYSTEM, "code": "27113001" },
+                    { "system": LOINC_SYSTEM, "code": "29463-7" },
+                ],
+                "text": "  Body weight ",
+            },
         });
+        assert_eq!(fhir_loinc_code(&observation), Some("29463-7"));
+        assert_eq!(fhir_concept_text(&observation["code"]), Some("Body weight".to_string()));
+        let uncoded = serde_json::json!({ "coding": [{ "code": "R51" }] });
+        assert_eq!(fhir_concept_text(&uncoded), Some("R51".to_string()));
+
+        assert_eq!(fhir_date("1970-01-02T08:00:00Z"), Some(NANOS_PER_DAY));
+        assert_eq!(fhir_date("02/01/1970"), None);
     }
 
-    Ok(())
-}
\ No newline at end of file
+    #[test]
+    fn fhir_quantities_convert_to_record_units() {
+        let quantity = |value: serde_json::Value| serde_json::json!({ "valueQuantity": value });
+        let close = |reading: Result<Option<f64>, String>, expected: f64| {
+            let reading = reading.unwrap().unwrap();
+            assert!((reading - expected).abs() < 1e-6, "{reading} != {expected}");
+        };
+
+        close(fhir_quantity_in(&quantity(serde_json::json!({ "value": 64, "code": "kg" })), "kg"), 64.0);
+        close(fhir_quantity_in(&quantity(serde_json::json!({ "value": 100, "code": "[lb_av]" })), "kg"), 45.359_237);
+        close(fhir_quantity_in(&quantity(serde_json::json!({ "value": 3250, "code": "g" })), "kg"), 3.25);
+        close(fhir_quantity_in(&quantity(serde_json::json!({ "value": 245, "code": "mm" })), "cm"), 24.5);
+        close(fhir_quantity_in(&quantity(serde_json::json!({ "value": 16, "code": "kPa" })), "mm[Hg]"), 120.009_852);
+        close(fhir_quantity_in(&quantity(serde_json::json!({ "value": 140, "unit": "/min" })), "/min"), 140.0);
+
+        assert!(fhir_quantity_in(&quantity(serde_json::json!({ "value": 60, "code": "[in_i]" })), "cm").is_err());
+        assert!(fhir_quantity_in(&quantity(serde_json::json!({ "value": 60 })), "kg").is_err());
+        assert_eq!(fhir_quantity_in(&serde_json::json!({ "valueBoolean": true }), "kg"), Ok(None));
+    }
+
+
+    fn logged_change(seq: u64) -> LoggedChange {
+        LoggedChange {
+            seq,
+            entity_type: EntityType::MotherProfile,
+            entity_id: 7,
+            kind: ChangeEventKind::Updated,
+            diff: r#"{"phone":{"old":"0712000000","new":"0722000000"}}"#.to_string(),
+            actor: Principal::anonymous(),
+            timestamp: 0,
+            reason: Some("Number changed".to_string()),
+            mother_id: Some(7),
+            sealed: None,
+        }
+    }
+
+    #[test]
+    fn sealed_changes_open_only_with_the_mothers_key() {
+        let key = [7; 32];
+        let sealed = seal_logged_change(&key, logged_change(3));
+        assert!(sealed.diff.is_empty() && sealed.reason.is_none());
+        let ciphertext = sealed.sealed.clone().unwrap();
+        assert!(!ciphertext.windows(10).any(|window| window == b"0722000000"));
+
+        let (diff, reason) = open_logged_change(&key, &sealed).unwrap();
+        assert_eq!(diff, logged_change(3).diff);
+        assert_eq!(reason.as_deref(), Some("Number changed"));
+
+        // Erasure destroys the key; no other key opens her diffs
+        assert_eq!(open_logged_change(&[8; 32], &sealed), None);
+        let moved = LoggedChange { entity_id: 8, ..sealed.clone() };
+        assert_eq!(open_logged_change(&key, &moved), None);
+        let replayed = LoggedChange { seq: 4, ..sealed };
+        assert_eq!(open_logged_change(&key, &replayed), None);
+    }
+
+
+    #[test]
+    fn share_scopes_cover_only_their_part() {
+        let parts = [ShareScope::Profile, ShareScope::HealthRecords, ShareScope::LabResults];
+        for scope in parts {
+            for part in parts {
+                assert_eq!(share_covers(scope, part), scope == part, "{scope:?} share and {part:?}");
+            }
+            assert!(share_covers(ShareScope::Full, scope));
+        }
+    }
+
+    #[test]
+    fn share_tokens_are_stored_as_their_sha256() {
+        assert_eq!(share_token_hash("abc"), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+        assert_ne!(share_token_hash("abc"), share_token_hash("abd"));
+    }
+
+
+    #[test]
+    fn sync_starts_at_the_first_change_after_since() {
+        // seq -> changed_at, with gaps where entities changed again later
+        let feed = std::collections::BTreeMap::from([(2, 10), (3, 10), (7, 20), (9, 30), (12, 30), (15, 40)]);
+        let first_from = |from: u64| feed.range(from..).next().map(|(seq, changed_at)| (*seq, *changed_at));
+        let start = |since| feed.range(bisect_seqs(15, first_from, since)..).next().map(|(seq, _)| *seq);
+
+        assert_eq!(start(0), Some(2));
+        assert_eq!(start(10), Some(7));
+        assert_eq!(start(25), Some(9));
+        assert_eq!(start(30), Some(15));
+        assert_eq!(start(40), None);
+        assert_eq!(bisect_seqs(0, |_| None, 5), 0);
+    }
+
+
+    #[test]
+    fn csv_rows_parse_quoted_fields_and_optional_visits() {
+        assert_eq!(
+            parse_csv_line(r#"a,"b, c","say ""hi""","""#).unwrap(),
+            vec!["a", "b, c", r#"say "hi""#, ""]
+        );
+        assert!(parse_csv_line(r#"a,"b"#).is_err());
+
+        let registration =
+            parse_import_row("Jane Doe,28,O+,2020-03-01,0712345678,anaemia; asthma,KNH-01,,,,,,").unwrap();
+        assert_eq!(registration.profile.expected_delivery_date, parse_date("2020-03-01").unwrap());
+        assert_eq!(registration.profile.medical_history, vec!["anaemia", "asthma"]);
+        assert!(registration.visit.is_none());
+        // A past EDD is a mother who has delivered; only the other profile fields are checked
+        assert!(validate_profile_fields(&registration.profile).is_ok());
+        let edd = registration.profile.expected_delivery_date;
+        assert_eq!(registration.dedup_key(), import_dedup_key(" jane doe ", "0712345678", edd));
+
+        let row = parse_import_row(
+            r#"Jane Doe,28,O+,2020-03-01,0712345678,,KNH-01,2020-01-10,120/80,64.5,"headache; nausea",ok,2020-02-10"#,
+        )
+        .unwrap();
+        let (visit_date, visit) = row.visit.unwrap();
+        assert_eq!(visit_date, parse_date("2020-01-10").unwrap());
+        assert_eq!(visit.weight, 64.5);
+        assert_eq!(visit.symptoms, vec!["headache", "nausea"]);
+        assert_eq!(visit.next_appointment, parse_date("2020-02-10").unwrap());
+
+        // A bad visit rejects the whole row before any mother is created
+        let bad_visit = parse_import_row("Jane Doe,28,O+,2020-03-01,0712345678,,,2020-01-10,120/80,heavy,,,");
+        assert_eq!(bad_visit.err().as_deref(), Some("Invalid weight"));
+        assert!(parse_import_row("Jane Doe,28,O+,2020-03-01").is_err());
+        let bad_blood_type = parse_import_row("Jane Doe,28,Z,2020-03-01,0712345678,,,,,,,,").unwrap();
+        assert!(validate_profile_fields(&bad_blood_type.profile).is_err());
+    }
+
+
+    fn test_payment(status: PaymentStatus, ledger_created_at: Option<u64>, updated_at: u64) -> Payment {
+        Payment {
+            id: 7,
+            mother_id: 1,
+            record_id: None,
+            purpose: PaymentPurpose::ServiceFee,
+            amount: 500,
+            recipient: Principal::anonymous(),
+            status,
+            block_index: None,
+            failure_reason: None,
+            created_at: 0,
+            settled_at: None,
+            version: Some(2),
+            updated_at: Some(updated_at),
+            ulid: None,
+            ledger_created_at,
+        }
+    }
+
+    #[test]
+    fn payment_retries_reuse_the_ledger_time_within_the_dedup_window() {
+        let first = 1_000;
+        let within = first + LEDGER_TX_WINDOW_NANOS;
+        let after = within + 1;
+
+        assert_eq!(ledger_created_at_time(&test_payment(PaymentStatus::Pending, None, 0), within), within);
+        assert_eq!(ledger_created_at_time(&test_payment(PaymentStatus::Failed, Some(first), 0), within), first);
+        assert_eq!(ledger_created_at_time(&test_payment(PaymentStatus::Failed, Some(first), 0), after), after);
+        // An unconfirmed attempt may have landed, so it is never retried under a new time
+        let unconfirmed = test_payment(PaymentStatus::NeedsReconciliation, Some(first), 0);
+        assert_eq!(ledger_created_at_time(&unconfirmed, after), first);
+
+        // Payments stuck in flight become resolvable once the window has passed
+        assert!(!stranded_in_flight(&test_payment(PaymentStatus::Processing, Some(first), first), within));
+        assert!(stranded_in_flight(&test_payment(PaymentStatus::Processing, Some(first), first), after + first));
+        assert!(!stranded_in_flight(&test_payment(PaymentStatus::Pending, None, first), after + first));
+    }
+
+    #[test]
+    fn transfer_outcomes_settle_fail_or_need_reconciliation() {
+        let outcome = |result, unconfirmed| {
+            let mut payment = test_payment(PaymentStatus::Processing, Some(1), 1);
+            apply_transfer_outcome(&mut payment, result, unconfirmed, 50);
+            payment
+        };
+
+        let settled = outcome(Ok((Ok(Nat::from(9u64)),)), false);
+        assert!(matches!(settled.status, PaymentStatus::Settled));
+        assert_eq!(settled.settled_at, Some(50));
+        let duplicate = outcome(Ok((Err(TransferError::Duplicate { duplicate_of: Nat::from(4u64) }),)), true);
+        assert!(matches!(duplicate.status, PaymentStatus::Settled));
+        assert_eq!(duplicate.block_index, Some(Nat::from(4u64)));
+
+        assert!(matches!(outcome(Ok((Err(TransferError::TooOld),)), true).status, PaymentStatus::NeedsReconciliation));
+        assert!(matches!(outcome(Ok((Err(TransferError::TooOld),)), false).status, PaymentStatus::Failed));
+        let rejected = outcome(Err((RejectionCode::CanisterReject, "no".to_string())), false);
+        assert!(matches!(rejected.status, PaymentStatus::Failed));
+        let unknown = outcome(Err((RejectionCode::SysTransient, "timeout".to_string())), false);
+        assert!(matches!(unknown.status, PaymentStatus::NeedsReconciliation));
+    }
+
+
+    #[test]
+    fn idempotency_keys_expire_after_the_retry_window() {
+        let result = IdempotentResult { id: 4, created_at: 1_000 };
+        assert!(!idempotency_key_expired(&result, 1_000));
+        assert!(!idempotency_key_expired(&result, 1_000 + IDEMPOTENCY_KEY_TTL_NANOS));
+        assert!(idempotency_key_expired(&result, 1_001 + IDEMPOTENCY_KEY_TTL_NANOS));
+        // A clock reading before the key was stored never expires it
+        assert!(!idempotency_key_expired(&result, 0));
+    }
+
+
+    #[test]
+    fn offline_edits_merge_field_by_field_last_writer_wins() {
+        let mut profile = MotherProfile {
+            id: 1,
+            name: "Jane Doe".to_string(),
+            age: 28,
+            blood_type: "O+".to_string(),
+            expected_delivery_date: 0,
+            stage: PregnancyStage::SecondTrimester,
+            health_status: HealthStatus::Normal,
+            created_at: 100,
+            last_checkup: 100,
+            medical_history: Vec::new(),
+            emergency_contact: "0712345678".to_string(),
+            insurance: None,
+            facility_code: None,
+            delivery: None,
+            origin: None,
+            version: Some(1),
+            updated_at: Some(100),
+            ulid: None,
+            previous_cesareans: None,
+            national_id: None,
+        };
+        let mut clocks = FieldClocks { clocks: vec![("age".to_string(), 300)] };
+
+        // Contact was never written since creation; age was written at 300
+        let fields = vec![ProfileField::EmergencyContact("0700000000".to_string()), ProfileField::Age(30)];
+        let (applied, conflicts) = merge_fields_lww(&mut profile, &mut clocks, fields, 200);
+        assert_eq!(applied, 1);
+        assert_eq!(profile.emergency_contact, "0700000000");
+        assert_eq!(profile.age, 28);
+        assert_eq!(conflicts.len(), 1);
+        assert_eq!((conflicts[0].field.as_str(), conflicts[0].server_updated_at), ("age", 300));
+        assert!(clocks.clocks.contains(&("emergency_contact".to_string(), 200)));
+
+        // An edit at the same instant as the last write loses; a later one wins and moves the clock
+        let (applied, _) = merge_fields_lww(&mut profile, &mut clocks, vec![ProfileField::Age(31)], 300);
+        assert_eq!(applied, 0);
+        let (applied, _) = merge_fields_lww(&mut profile, &mut clocks, vec![ProfileField::Age(31)], 301);
+        assert_eq!((applied, profile.age), (1, 31));
+        assert_eq!(clocks.clocks.iter().filter(|(field, _)| field == "age").count(), 1);
+        // Edits older than the profile itself always lose
+        let (applied, _) = merge_fields_lww(&mut profile, &mut clocks, vec![ProfileField::PreviousCesareans(1)], 50);
+        assert_eq!(applied, 0);
+
+        assert!(matches!(mutation_status(2, 0, 0), MutationStatus::Applied));
+        assert!(matches!(mutation_status(1, 1, 0), MutationStatus::PartiallyApplied));
+        assert!(matches!(mutation_status(0, 2, 0), MutationStatus::Superseded));
+        assert!(matches!(mutation_status(0, 0, 1), MutationStatus::PendingApproval));
+        assert!(matches!(mutation_status(1, 0, 1), MutationStatus::Applied));
+    }
+}